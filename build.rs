@@ -1,5 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::compile_protos("proto/timestamp.proto")?;
     tonic_build::compile_protos("proto/imu.proto")?;
     tonic_build::compile_protos("proto/gps.proto")?;
+    tonic_build::compile_protos("proto/trip.proto")?;
     Ok(())
 }
\ No newline at end of file
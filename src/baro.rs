@@ -0,0 +1,209 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::data_conv::ImuShort;
+use crate::data_defs::{Length, Pressure};
+use crate::fake_gps::decode_fields;
+use crate::gps::GpsData;
+use crate::imu::ImuData;
+
+/// ISA sea-level pressure, Pa
+pub const STANDARD_P0: f64 = 101_325.0;
+
+const SCALE: f64 = 44330.8;
+const EXPONENT: f64 = 0.190263;
+
+/// Height above the level where the pressure is `p0`
+pub fn pressure_to_altitude(pressure: Pressure, p0: Pressure) -> Length {
+    Length::from_meters(SCALE * (1.0 - (pressure.as_pascals() / p0.as_pascals()).powf(EXPONENT)))
+}
+
+/// Pressure at `altitude` above the level where the pressure is `p0`
+pub fn altitude_to_pressure(altitude: Length, p0: Pressure) -> Pressure {
+    p0 * (1.0 - altitude.as_meters() / SCALE).powf(1.0 / EXPONENT)
+}
+
+/// The reference pressure that puts a reading of `pressure` at `altitude`
+pub fn reference_pressure(pressure: Pressure, altitude: Length) -> Pressure {
+    pressure / (1.0 - altitude.as_meters() / SCALE).powf(1.0 / EXPONENT)
+}
+
+/// Settings for a `Barometer`
+#[derive(Debug, Clone)]
+pub struct BaroConfig {
+    /// Reference pressure to use until the first GPS calibration
+    pub p0: Pressure,
+    /// Calibrate from GPS at all. Without it p0 stays fixed.
+    pub gps_calibration: bool,
+    /// A fix needs at least this many satellites to calibrate from
+    pub min_sats: u8,
+    /// and an HDOP no worse than this
+    pub max_hdop: f32,
+    /// Time constant for following GPS once calibrated, s. GPS altitude is
+    /// noisy, so each fix only nudges p0.
+    pub time_constant: f64,
+    /// How far apart a pressure reading and a fix can be and still be
+    /// paired, ms
+    pub max_pairing_gap: u64,
+    /// How much pressure history to keep for pairing, ms
+    pub history: u64,
+}
+
+impl Default for BaroConfig {
+    fn default() -> Self {
+        BaroConfig {
+            p0: Pressure::from_pascals(STANDARD_P0),
+            gps_calibration: true,
+            min_sats: 6,
+            max_hdop: 2.0,
+            time_constant: 300.0,
+            max_pairing_gap: 500,
+            history: 60_000,
+        }
+    }
+}
+
+/// Turns pressure into altitude for one device, keeping its reference
+/// pressure in line with GPS while the weather changes
+#[derive(Debug, Clone)]
+pub struct Barometer {
+    config: BaroConfig,
+    p0: Pressure,
+    /// pitime of the last fix used, or None if not calibrated yet
+    calibrated_at: Option<u64>,
+    /// Recent (pitime, pressure) readings, oldest first
+    recent: VecDeque<(u64, Pressure)>,
+}
+
+impl Barometer {
+    pub fn new(config: BaroConfig) -> Barometer {
+        let p0 = config.p0;
+        Barometer { config, p0, calibrated_at: None, recent: VecDeque::new() }
+    }
+
+    /// Current reference pressure
+    pub fn p0(&self) -> Pressure {
+        self.p0
+    }
+
+    pub fn is_calibrated(&self) -> bool {
+        self.calibrated_at.is_some()
+    }
+
+    pub fn altitude(&self, pressure: Pressure) -> Length {
+        pressure_to_altitude(pressure, self.p0)
+    }
+
+    /// Remember a pressure reading so a later fix can be paired with it
+    pub fn observe(&mut self, pitime: u64, pressure: Pressure) {
+        if self.recent.back().is_some_and(|&(t, _)| pitime < t) {
+            // Out of order, e.g. a resent batch. Keep the history sorted.
+            let at = self.recent.partition_point(|&(t, _)| t <= pitime);
+            self.recent.insert(at, (pitime, pressure));
+        } else {
+            self.recent.push_back((pitime, pressure));
+        }
+        let newest = self.recent.back().map_or(0, |&(t, _)| t);
+        while self.recent.front().is_some_and(|&(t, _)| t + self.config.history < newest) {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Record the reading in `row` and fill in its altitude
+    pub fn apply(&mut self, row: &mut ImuShort) {
+        let pressure = Pressure::from_pascals(row.pressure.into());
+        self.observe(row.pitime, pressure);
+        row.altitude = Some(self.altitude(pressure).as_meters() as f32);
+    }
+
+    /// Altitude for a message, recording its pressure on the way
+    pub fn update(&mut self, d: &ImuData) -> Length {
+        let pressure = Pressure::from_pascals(d.pressure.into());
+        self.observe(d.timestamp, pressure);
+        self.altitude(pressure)
+    }
+
+    /// Good enough to calibrate from: a valid fix with enough satellites
+    /// and a low HDOP
+    pub fn usable_fix(&self, fix: &GpsData) -> bool {
+        let (status, nsats, valid, _, _) = decode_fields(fix.status_nsats_vuc);
+        valid && status > 0 && nsats >= self.config.min_sats && fix.hdop <= self.config.max_hdop
+    }
+
+    /// Pressure reading nearest to `pitime`, if one is close enough
+    fn pressure_at(&self, pitime: u64) -> Option<Pressure> {
+        self.recent
+            .iter()
+            .map(|&(t, p)| (t.abs_diff(pitime), p))
+            .filter(|&(gap, _)| gap <= self.config.max_pairing_gap)
+            .min_by_key(|&(gap, _)| gap)
+            .map(|(_, p)| p)
+    }
+
+    /// Pull p0 towards the value that makes the barometer agree with this
+    /// fix. The first usable fix sets p0 outright. Returns whether the fix
+    /// was used.
+    pub fn calibrate(&mut self, fix: &GpsData) -> bool {
+        if !self.config.gps_calibration || !self.usable_fix(fix) {
+            return false;
+        }
+        let pressure = match self.pressure_at(fix.pitime) {
+            Some(p) => p,
+            None => return false,
+        };
+        let target = reference_pressure(pressure, Length::from_meters(fix.alt.into()));
+
+        self.p0 = match self.calibrated_at {
+            None => target,
+            Some(last) if fix.pitime > last => {
+                let dt = (fix.pitime - last) as f64 / 1000.0;
+                let alpha = dt / (self.config.time_constant + dt);
+                self.p0 + (target - self.p0) * alpha
+            }
+            // Already seen something at least as new
+            Some(_) => return false,
+        };
+        self.calibrated_at = Some(fix.pitime);
+        true
+    }
+}
+
+/// A barometer per device, for the server
+#[derive(Debug, Default)]
+pub struct Barometers {
+    config: BaroConfig,
+    devices: Mutex<HashMap<u64, Barometer>>,
+}
+
+impl Barometers {
+    pub fn new(config: BaroConfig) -> Barometers {
+        Barometers { config, devices: Mutex::new(HashMap::new()) }
+    }
+
+    /// Fill in the altitude of each row using its device's barometer
+    pub fn apply(&self, uuid: u64, rows: &mut [ImuShort]) {
+        let mut devices = self.devices.lock().unwrap();
+        let baro = devices.entry(uuid).or_insert_with(|| Barometer::new(self.config.clone()));
+        for row in rows {
+            baro.apply(row);
+        }
+    }
+
+    /// Calibrate each fix's device. Returns how many fixes were used.
+    pub fn calibrate(&self, fixes: &[GpsData]) -> usize {
+        let mut devices = self.devices.lock().unwrap();
+        let mut used = 0;
+        for fix in fixes {
+            let baro = devices.entry(fix.uuid).or_insert_with(|| Barometer::new(self.config.clone()));
+            if baro.calibrate(fix) {
+                used += 1;
+            }
+        }
+        used
+    }
+
+    /// Current reference pressure for a device, if it has been seen
+    pub fn p0(&self, uuid: u64) -> Option<Pressure> {
+        self.devices.lock().unwrap().get(&uuid).map(Barometer::p0)
+    }
+}
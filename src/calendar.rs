@@ -0,0 +1,53 @@
+pub const MS_PER_DAY: u64 = 86_400_000;
+
+/// Days from 1970-01-01 to a date in the proleptic Gregorian calendar
+pub fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let m = m as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The date `days` after 1970-01-01, as (year, month, day)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + (m <= 2) as i64, m, d)
+}
+
+/// ms since the epoch as ISO 8601 UTC, e.g. 2024-02-29T23:59:59.250Z
+pub fn iso_time(ms: u64) -> String {
+    let (y, m, d) = civil_from_days((ms / MS_PER_DAY) as i64);
+    let ms_of_day = ms % MS_PER_DAY;
+    let (s, ms) = (ms_of_day / 1000, ms_of_day % 1000);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", y, m, d, s / 3600, s / 60 % 60, s % 60, ms)
+}
+
+/// The other way to `iso_time`. Fractions of a second are optional and
+/// kept to the ms; the time must be UTC.
+pub fn parse_iso_time(s: &str) -> Option<u64> {
+    let (date, time) = s.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|f| f.parse::<u32>().ok());
+    let (y, m, d) = (date.next()??, date.next()??, date.next()??);
+    let (hms, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut hms = hms.splitn(3, ':').map(|f| f.parse::<u64>().ok());
+    let (h, min, sec) = (hms.next()??, hms.next()??, hms.next()??);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) || h > 23 || min > 59 || sec > 60 {
+        return None;
+    }
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let ms = format!("{:0<3}", fraction)[..3].parse::<u64>().ok()?;
+    let days = u64::try_from(days_from_civil(y as i64, m, d)).ok()?;
+    Some(days * MS_PER_DAY + ((h * 60 + min) * 60 + sec) * 1000 + ms)
+}
@@ -1,44 +1,131 @@
-// use imu::data_server_client::DataServerClient;
-mod fake_imu;
-use crate::fake_imu::generate_imu_data;
-mod fake_gps;
-use crate::fake_gps::generate_gps_data;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use imu::imu_data_server_client::ImuDataServerClient;
-use gps::gps_data_server_client::GpsDataServerClient;
+use grpc_tests::device_db::{open_device_db, DbHealth, DeviceDbConfig};
+use grpc_tests::fake_imu::generate_imu_data;
+use grpc_tests::fake_gps::generate_drive_data;
+use grpc_tests::gps_sim::GpsSimConfig;
+use grpc_tests::imu_source::SamplerConfig;
+use grpc_tests::replay::{make_batches, read_exported, read_recording, replay, ReplaySpeed};
+use grpc_tests::upload::Uploader;
 
-pub mod gps {
-    tonic::include_proto!("gps");
-}
-pub mod imu {
-    tonic::include_proto!("imu");
-}
+const SERVER: &str = "http://[::1]:50051";
+
+const USAGE: &str = "usage:
+    client
+    client replay (--db <file> [--uuid <n>] | --imu <file.pb> [--gps <file.pb>])
+                  [--speed realtime|max|<factor>] [--window-ms <ms>]
+    client upload [--db <file>] [--uuid <n>] [--batch <n>]";
 
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
-    let mut imu_client = ImuDataServerClient::connect("http://[::1]:50051").await?;
-    let mut gps_client = GpsDataServerClient::connect("http://[::1]:50051").await?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => send_synthetic().await,
+        Some("replay") => send_replay(&args[1..]).await,
+        Some("upload") => send_backlog(&args[1..]).await,
+        Some(_) => Err(USAGE.into()),
+    }
+}
+
+/// Send a simulated drive to the server
+async fn send_synthetic() -> Result<(), Box<dyn std::error::Error>> {
+
+    let mut uploader = Uploader::connect(SERVER, GpsSimConfig::default().uuid).await?;
 
     let no_of_lines = 940;
 
-    let request = tonic::Request::new(generate_imu_data(no_of_lines));
-    let response = imu_client.send_imu(request).await?;
+    let (imu_data, gps_data) = generate_drive_data(no_of_lines);
+
+    let response = uploader.send_imu(imu_data).await?;
 
     println!("IMU RESPONSE={:?}", response);
 
-    let request = tonic::Request::new(generate_gps_data(no_of_lines));
-    let response = gps_client.send_gps(request).await?;
+    let response = uploader.send_gps(gps_data).await?;
 
     println!("GPS RESPONSE={:?}", response);
 
-    let request = tonic::Request::new(generate_imu_data(no_of_lines));
-    let response = imu_client.send_imu(request).await?;
+    let response = uploader.send_imu(generate_imu_data(no_of_lines)).await?;
 
     println!("IMU RESPONSE={:?}", response);
 
 
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Stream a recorded drive to the server, keeping its original timing
+async fn send_replay(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+
+    let mut db: Option<PathBuf> = None;
+    let mut imu_file: Option<PathBuf> = None;
+    let mut gps_file: Option<PathBuf> = None;
+    let mut uuid: Option<u64> = None;
+    let mut speed = ReplaySpeed::RealTime;
+    let mut window = Duration::from_millis(1000);
+
+    let mut it = args.iter();
+    while let Some(flag) = it.next() {
+        let value = it.next().ok_or(USAGE)?;
+        match flag.as_str() {
+            "--db" => db = Some(value.into()),
+            "--imu" => imu_file = Some(value.into()),
+            "--gps" => gps_file = Some(value.into()),
+            "--uuid" => uuid = Some(value.parse()?),
+            "--speed" => speed = value.parse()?,
+            "--window-ms" => window = Duration::from_millis(value.parse()?),
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    let recording = match (db, imu_file) {
+        (Some(db), None) => {
+            let conn = rusqlite::Connection::open_with_flags(db, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            read_recording(&conn, uuid)?
+        }
+        (None, Some(imu_file)) => read_exported(&imu_file, gps_file.as_deref())?,
+        _ => return Err(USAGE.into()),
+    };
+    println!("Replaying {} IMU and {} GPS lines at {:?}", recording.imu.len(), recording.gps.len(), speed);
+
+    let mut uploader = Uploader::connect(SERVER, recording.uuid).await?;
+
+    let batches = make_batches(recording, window);
+    let stats = replay(&mut uploader, batches, speed).await?;
+
+    println!("REPLAY DONE={:?}", stats);
+    Ok(())
+}
+
+/// Send what a logger's database holds that the server doesn't have yet,
+/// crashes first
+async fn send_backlog(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+
+    let mut db = PathBuf::from("./my_imu.db3");
+    let mut uuid = SamplerConfig::default().uuid;
+    let mut batch = 1000;
+
+    let mut it = args.iter();
+    while let Some(flag) = it.next() {
+        let value = it.next().ok_or(USAGE)?;
+        match flag.as_str() {
+            "--db" => db = value.into(),
+            "--uuid" => uuid = value.parse()?,
+            "--batch" => batch = value.parse()?,
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    let (conn, health) = open_device_db(&db, &DeviceDbConfig::default())?;
+    if let DbHealth::Quarantined { quarantined, problem, .. } = health {
+        println!("{} was corrupt ({}), moved to {}", db.display(), problem, quarantined.display());
+    }
+
+    let mut uploader = Uploader::connect(SERVER, uuid).await?;
+    let stats = uploader.upload_backlog(&conn, batch).await?;
+
+    println!("UPLOAD DONE={:?}", stats);
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use rusqlite::{named_params, Connection, Result, Row};
+
+use crate::data_conv::ImuShort;
+use crate::events::gravity;
+use crate::imu::Orientation;
+use crate::schema::{self, CRASH_COLUMNS};
+use crate::writer::Record;
+
+/// The imu table's protected twin, holding the lines captured around a
+/// crash at the full sample rate
+pub const CRASH_TABLE: &str = "crash_imu";
+
+/// When the device treats a jolt as a crash, and how much it keeps
+#[derive(Debug, Clone)]
+pub struct CrashConfig {
+    /// Acceleration in any direction, g with gravity taken out, that
+    /// triggers a capture. The same as an impact in events.rs.
+    pub impact_g: f32,
+    /// Lines kept from before the impact
+    pub before: Duration,
+    /// Lines kept from after it
+    pub after: Duration,
+    /// How long the jolt must stay under impact_g before another impact
+    /// counts as a new crash
+    pub release: Duration,
+}
+
+impl Default for CrashConfig {
+    fn default() -> Self {
+        CrashConfig {
+            impact_g: 2.5,
+            before: Duration::from_secs(10),
+            after: Duration::from_secs(5),
+            release: Duration::from_secs(1),
+        }
+    }
+}
+
+pub use crate::imu::Crash;
+
+/// How hard a line was jolted, g, with gravity taken out using its pose
+pub fn jolt(row: &ImuShort) -> f32 {
+    let g = gravity(&Orientation { roll: row.pose_roll, pitch: row.pose_pitch, ..Default::default() });
+    ((row.accel_x - g.x).powi(2) + (row.accel_y - g.y).powi(2) + (row.accel_z - g.z).powi(2)).sqrt()
+}
+
+/// Watches every line the IMU gives, before any downsampling, keeping the
+/// last `before` of them. An impact writes the crash, then those lines and
+/// every line for `after` the jolt ends, to the protected tables. Lines
+/// past impact_g until it is released, as EventDetector does, belong to
+/// the same crash; an impact after that is a crash of its own, and
+/// stretches the capture.
+pub struct CrashRecorder {
+    config: CrashConfig,
+    recent: VecDeque<ImuShort>,
+    /// pitime the capture runs to, while there is one
+    capturing: Option<u64>,
+    /// pitime of the last line past impact_g, until it is released
+    impact: Option<u64>,
+}
+
+impl CrashRecorder {
+    pub fn new(config: CrashConfig) -> CrashRecorder {
+        CrashRecorder { config, recent: VecDeque::new(), capturing: None, impact: None }
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capturing.is_some()
+    }
+
+    /// Take the next line, in time order. Returns the crashes and crash
+    /// lines to write. A crash is written on the line that triggers it, so
+    /// it is on the card however soon after the power goes.
+    pub fn push(&mut self, row: &ImuShort) -> Vec<Record> {
+        let (before, after) = (self.config.before.as_millis() as u64, self.config.after.as_millis() as u64);
+        let release = self.config.release.as_millis() as u64;
+        let mut captures = Vec::new();
+        if self.capturing.is_some_and(|end| row.pitime > end) {
+            self.capturing = None;
+        }
+
+        while self.recent.front().is_some_and(|r| r.pitime + before < row.pitime) {
+            self.recent.pop_front();
+        }
+
+        let peak_g = jolt(row);
+        let past = peak_g >= self.config.impact_g;
+        if self.impact.is_some_and(|last| !past && row.pitime.saturating_sub(last) > release) {
+            self.impact = None;
+        }
+        if past {
+            let window_end = row.pitime + after;
+            if self.impact.is_none() {
+                let window_start = match self.capturing {
+                    Some(_) => row.pitime.saturating_sub(before),
+                    None => self.recent.front().map_or(row.pitime, |r| r.pitime),
+                };
+                captures.push(Record::Crash(Crash { uuid: row.uuid, pitime: row.pitime, peak_g, window_start, window_end }));
+            }
+            captures.extend(self.recent.drain(..).map(Record::CrashImu));
+            self.capturing = Some(self.capturing.map_or(window_end, |end| end.max(window_end)));
+            self.impact = Some(row.pitime);
+        }
+
+        if self.capturing.is_some() {
+            captures.push(Record::CrashImu(row.clone()));
+        } else {
+            self.recent.push_back(row.clone());
+        }
+        captures
+    }
+}
+
+/// Write a crash to the crashes table
+pub fn insert_crash(conn: &Connection, crash: &Crash) -> Result<usize> {
+    let mut stmt = conn.prepare_cached(&schema::insert_sql("crashes", CRASH_COLUMNS))?;
+    stmt.execute(named_params! {
+        ":uuid": crash.uuid as i64,
+        ":pitime": crash.pitime as i64,
+        ":peak_g": crash.peak_g,
+        ":window_start": crash.window_start as i64,
+        ":window_end": crash.window_end as i64,
+        ":uploaded": false,
+    })
+}
+
+/// Build a Crash from a row of the crashes table
+pub fn crash_from_row(row: &Row) -> Result<Crash> {
+    Ok(Crash {
+        uuid: row.get::<_, i64>("uuid")? as u64,
+        pitime: row.get("pitime")?,
+        peak_g: row.get("peak_g")?,
+        window_start: row.get("window_start")?,
+        window_end: row.get("window_end")?,
+    })
+}
+
+/// Every crash the device has captured, oldest first
+pub fn read_crashes(conn: &Connection) -> Result<Vec<Crash>> {
+    let mut stmt = conn.prepare("SELECT * FROM crashes ORDER BY lineno")?;
+    let rows = stmt.query_map([], crash_from_row)?;
+    rows.collect()
+}
+
+/// Crashes the server hasn't been sent yet, oldest first. Those written
+/// before crashes were uploaded have no flag, and haven't been.
+pub fn unsent_crashes(conn: &Connection) -> Result<Vec<Crash>> {
+    let mut stmt = conn.prepare_cached("SELECT * FROM crashes WHERE uploaded IS NOT 1 ORDER BY lineno")?;
+    let rows = stmt.query_map([], crash_from_row)?;
+    rows.collect()
+}
+
+/// Flag crashes as uploaded once the server has them. Returns how many
+/// were flagged.
+pub fn mark_crashes_sent(conn: &Connection, crashes: &[Crash]) -> Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let mut flagged = 0;
+    {
+        let mut stmt = tx.prepare_cached("UPDATE crashes SET uploaded = 1 WHERE uuid = ?1 AND pitime = ?2")?;
+        for crash in crashes {
+            flagged += stmt.execute((crash.uuid as i64, crash.pitime as i64))?;
+        }
+    }
+    tx.commit()?;
+    Ok(flagged)
+}
+
+/// The oldest n lines that haven't been uploaded yet, with the table they
+/// came from. Crash captures go before the rest of the backlog.
+pub fn next_upload(conn: &Connection, n: usize) -> Result<(&'static str, Vec<ImuShort>)> {
+    for table in [CRASH_TABLE, "imu"] {
+        let mut stmt =
+            conn.prepare_cached(&format!("SELECT * FROM {} WHERE uploaded = false ORDER BY lineno ASC LIMIT ?1", table))?;
+        let rows = stmt.query_map([n as i64], ImuShort::from_row)?.collect::<Result<Vec<_>>>()?;
+        if !rows.is_empty() {
+            return Ok((table, rows));
+        }
+    }
+    Ok(("imu", Vec::new()))
+}
+
+/// Flag lines from `table` as uploaded and confirmed once the server has
+/// them. Returns how many were flagged.
+pub fn mark_confirmed(conn: &Connection, table: &str, rows: &[ImuShort]) -> Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let mut flagged = 0;
+    {
+        let mut stmt = tx.prepare_cached(&format!("UPDATE {} SET uploaded = 1, confirmed = 1 WHERE lineno = ?1", table))?;
+        for row in rows {
+            flagged += stmt.execute([row.line])?;
+        }
+    }
+    tx.commit()?;
+    Ok(flagged)
+}
@@ -0,0 +1,108 @@
+// CSV in and out of the imu and gps tables: a drive for someone to look
+// at in a spreadsheet, or test fixtures written by hand.
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use rusqlite::{Connection, OpenFlags};
+
+use grpc_tests::csv_io::{export_csv, read_gps_csv, read_imu_csv, CsvConfig, CsvTable};
+use grpc_tests::data_conv::insert_imu_short;
+use grpc_tests::device_db::{open_device_db, DeviceDbConfig};
+use grpc_tests::store::{insert_gps_data, ServerStore, TelemetryStore};
+
+const USAGE: &str = "usage: csv export --db <file> --table imu|gps [--uuid <n>] [--out <file>]
+           [--delimiter <c>|tab] [--time ms|iso]
+       csv import --db <file> --table imu|gps --in <file> [--server]
+           [--delimiter <c>|tab] [--time ms|iso]";
+
+fn delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(format!("Unknown delimiter '{}'", s)),
+    }
+}
+
+/// Load into the device's database, as the logger would have written it
+fn import_device(db: &Path, table: CsvTable, input: File, config: &CsvConfig) -> Result<usize, Box<dyn std::error::Error>> {
+    let (conn, _) = open_device_db(db, &DeviceDbConfig::default())?;
+    let tx = conn.unchecked_transaction()?;
+    let mut stored = 0;
+    match table {
+        CsvTable::Imu => {
+            for row in read_imu_csv(BufReader::new(input), config)? {
+                stored += insert_imu_short(&tx, &row)?;
+            }
+        }
+        CsvTable::Gps => {
+            for d in read_gps_csv(BufReader::new(input), config)? {
+                stored += insert_gps_data(&tx, &d)?;
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(stored)
+}
+
+/// Load into the server's store, skipping lines it already has
+fn import_server(db: &Path, table: CsvTable, input: File, config: &CsvConfig) -> Result<usize, Box<dyn std::error::Error>> {
+    let store = ServerStore::open(db)?;
+    let stored = match table {
+        CsvTable::Imu => store.append_imu(&read_imu_csv(BufReader::new(input), config)?),
+        CsvTable::Gps => store.append_gps(&read_gps_csv(BufReader::new(input), config)?),
+    };
+    stored.map_err(|e| e as Box<dyn std::error::Error>)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let command = args.next().ok_or(USAGE)?;
+    let mut db: Option<PathBuf> = None;
+    let mut table: Option<CsvTable> = None;
+    let mut uuid: Option<u64> = None;
+    let mut path: Option<PathBuf> = None;
+    let mut server = false;
+    let mut config = CsvConfig::default();
+
+    while let Some(flag) = args.next() {
+        if flag == "--server" {
+            server = true;
+            continue;
+        }
+        let value = args.next().ok_or(USAGE)?;
+        match flag.as_str() {
+            "--db" => db = Some(value.into()),
+            "--table" => table = Some(value.parse()?),
+            "--uuid" => uuid = Some(value.parse()?),
+            "--out" | "--in" => path = Some(value.into()),
+            "--delimiter" => config.delimiter = delimiter(&value)?,
+            "--time" => config.time_format = value.parse()?,
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let (db, table) = (db.ok_or(USAGE)?, table.ok_or(USAGE)?);
+
+    match command.as_str() {
+        "export" => {
+            let conn = Connection::open_with_flags(&db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            let out: Box<dyn Write> = match &path {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stdout().lock()),
+            };
+            let n = export_csv(&conn, table, uuid, out, &config)?;
+            eprintln!("{} rows written", n);
+        }
+        "import" => {
+            let input = File::open(path.ok_or(USAGE)?)?;
+            let stored = if server {
+                import_server(&db, table, input, &config)?
+            } else {
+                import_device(&db, table, input, &config)?
+            };
+            println!("{} rows stored", stored);
+        }
+        _ => return Err(USAGE.into()),
+    }
+    Ok(())
+}
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use rusqlite::Connection;
+
+use crate::data_conv::ImuShort;
+use crate::gps::GpsData;
+use crate::calendar::{iso_time, parse_iso_time};
+use crate::schema::{GPS_COLUMNS, IMU_COLUMNS};
+use crate::store::gps_from_row;
+use crate::timestamp::Timestamp;
+
+/// How pitime and gps_time are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeFormat {
+    /// ms since the epoch, as the tables have them
+    Millis,
+    /// ISO 8601 UTC to the ms, e.g. 2024-02-29T23:59:59.250Z
+    Iso,
+}
+
+impl FromStr for TimeFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ms" => Ok(TimeFormat::Millis),
+            "iso" => Ok(TimeFormat::Iso),
+            _ => Err(format!("Unknown time format '{}'", s)),
+        }
+    }
+}
+
+/// Which table a CSV holds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CsvTable {
+    Imu,
+    Gps,
+}
+
+impl FromStr for CsvTable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "imu" => Ok(CsvTable::Imu),
+            "gps" => Ok(CsvTable::Gps),
+            _ => Err(format!("Unknown table '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CsvConfig {
+    pub delimiter: u8,
+    pub time_format: TimeFormat,
+}
+
+impl Default for CsvConfig {
+    fn default() -> Self {
+        CsvConfig { delimiter: b',', time_format: TimeFormat::Millis }
+    }
+}
+
+/// A row that can't be loaded. Nothing from the file is loaded then.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvError {
+    /// 1 is the header
+    pub line: u64,
+    pub message: String,
+}
+
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for CsvError {}
+
+/// The header: lineno, then the table's columns as `create_imu_table` and
+/// `create_gps_table` name them
+pub fn header(table: CsvTable) -> Vec<&'static str> {
+    let columns = match table {
+        CsvTable::Imu => IMU_COLUMNS,
+        CsvTable::Gps => GPS_COLUMNS,
+    };
+    let mut names = vec!["lineno"];
+    names.extend(columns.iter().map(|(name, _)| *name));
+    names
+}
+
+fn time(ms: u64, format: TimeFormat) -> String {
+    match format {
+        TimeFormat::Millis => ms.to_string(),
+        TimeFormat::Iso => iso_time(ms),
+    }
+}
+
+/// NULL is an empty field
+fn opt<T: ToString>(v: Option<T>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn stamp_fields(stamp: Option<Timestamp>) -> [String; 3] {
+    [opt(stamp.map(|t| t.seconds)), opt(stamp.map(|t| t.nanos)), opt(stamp.map(|t| t.timebase))]
+}
+
+fn imu_record(r: &ImuShort, format: TimeFormat) -> Vec<String> {
+    let mut fields = vec![
+        r.line.to_string(),
+        r.uuid.to_string(),
+        time(r.pitime, format),
+        time(r.gps_time, format),
+        r.sequence.to_string(),
+    ];
+    fields.extend(
+        [
+            r.accel_x, r.accel_y, r.accel_z,
+            r.gyro_x, r.gyro_y, r.gyro_z,
+            r.pose_roll, r.pose_pitch, r.pose_yaw,
+            r.pose_heading_accuracy,
+            r.mag_x, r.mag_y, r.mag_z,
+            r.pressure,
+        ]
+        .map(|v| v.to_string()),
+    );
+    fields.extend([
+        opt(r.altitude),
+        r.temperature.to_string(),
+        r.temp_cpu.to_string(),
+        (r.uploaded as u8).to_string(),
+        (r.confirmed as u8).to_string(),
+        opt(r.clock_offset),
+        opt(r.clock_error),
+    ]);
+    fields.extend(stamp_fields(r.stamp));
+    fields
+}
+
+/// Unknown accuracies, 0 in a GpsData, are NULL in the table and so empty
+fn gps_record(line: i64, d: &GpsData, format: TimeFormat) -> Vec<String> {
+    let accuracy = |a: f32| opt(Some(a).filter(|a| *a > 0.0));
+    let mut fields = vec![
+        line.to_string(),
+        d.uuid.to_string(),
+        time(d.pitime, format),
+        time(d.gps_time, format),
+        d.sequence.to_string(),
+        d.lat.to_string(),
+        d.lon.to_string(),
+        d.alt.to_string(),
+        d.speed.to_string(),
+        d.track.to_string(),
+        d.status_nsats_vuc.to_string(),
+        d.hdop.to_string(),
+        accuracy(d.h_acc),
+        accuracy(d.v_acc),
+        accuracy(d.speed_acc),
+        d.time_valid.to_string(),
+    ];
+    fields.extend(stamp_fields(d.pi_stamp));
+    fields.extend(stamp_fields(d.gps_stamp));
+    fields
+}
+
+fn writer<W: Write>(out: W, table: CsvTable, config: &CsvConfig) -> Result<csv::Writer<W>, Box<dyn Error>> {
+    let mut writer = WriterBuilder::new().delimiter(config.delimiter).from_writer(out);
+    writer.write_record(header(table))?;
+    Ok(writer)
+}
+
+/// Write IMU rows as CSV, with a header
+pub fn write_imu_csv<W: Write>(out: W, rows: &[ImuShort], config: &CsvConfig) -> Result<(), Box<dyn Error>> {
+    let mut writer = writer(out, CsvTable::Imu, config)?;
+    for r in rows {
+        writer.write_record(imu_record(r, config.time_format))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write GPS lines as CSV, with a header. GpsData has no lineno, so the
+/// lines are numbered from 1.
+pub fn write_gps_csv<W: Write>(out: W, data: &[GpsData], config: &CsvConfig) -> Result<(), Box<dyn Error>> {
+    let mut writer = writer(out, CsvTable::Gps, config)?;
+    for (i, d) in data.iter().enumerate() {
+        writer.write_record(gps_record(i as i64 + 1, d, config.time_format))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write a table of a device or server database as CSV, in lineno order,
+/// optionally just one device's rows
+pub fn export_csv<W: Write>(conn: &Connection, table: CsvTable, uuid: Option<u64>, out: W, config: &CsvConfig) -> Result<u64, Box<dyn Error>> {
+    let name = match table {
+        CsvTable::Imu => "imu",
+        CsvTable::Gps => "gps",
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT * FROM {} WHERE ?1 IS NULL OR uuid = ?1 ORDER BY lineno",
+        name
+    ))?;
+    let mut rows = stmt.query([uuid.map(|u| u as i64)])?;
+    let mut writer = writer(out, table, config)?;
+    let mut n = 0;
+    while let Some(row) = rows.next()? {
+        let record = match table {
+            CsvTable::Imu => imu_record(&ImuShort::from_row(row)?, config.time_format),
+            CsvTable::Gps => gps_record(row.get("lineno")?, &gps_from_row(row)?, config.time_format),
+        };
+        writer.write_record(record)?;
+        n += 1;
+    }
+    writer.flush()?;
+    Ok(n)
+}
+
+/// One CSV row, with its fields found by header name
+struct Fields<'a> {
+    line: u64,
+    record: &'a StringRecord,
+    columns: &'a HashMap<String, usize>,
+    format: TimeFormat,
+}
+
+impl Fields<'_> {
+    fn error(&self, message: String) -> CsvError {
+        CsvError { line: self.line, message }
+    }
+
+    /// None if the column isn't there or the field is empty
+    fn opt<T: FromStr>(&self, name: &str) -> Result<Option<T>, CsvError> {
+        match self.columns.get(name).and_then(|i| self.record.get(*i)).map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(s) => s.parse().map(Some).map_err(|_| self.error(format!("bad {} '{}'", name, s))),
+        }
+    }
+
+    fn req<T: FromStr>(&self, name: &str) -> Result<T, CsvError> {
+        self.opt(name)?.ok_or_else(|| self.error(format!("no {}", name)))
+    }
+
+    fn float(&self, name: &str) -> Result<f32, CsvError> {
+        let v: f32 = self.req(name)?;
+        if !v.is_finite() {
+            return Err(self.error(format!("bad {} '{}'", name, v)));
+        }
+        Ok(v)
+    }
+
+    /// 0/1 as the tables have them, or true/false
+    fn flag(&self, name: &str) -> Result<bool, CsvError> {
+        match self.req::<String>(name)?.to_ascii_lowercase().as_str() {
+            "1" | "true" => Ok(true),
+            "0" | "false" => Ok(false),
+            s => Err(self.error(format!("bad {} '{}'", name, s))),
+        }
+    }
+
+    /// Either time format is read, whichever is configured
+    fn time(&self, name: &str) -> Result<Option<u64>, CsvError> {
+        let Some(s) = self.opt::<String>(name)? else { return Ok(None) };
+        let ms = match self.format {
+            TimeFormat::Millis => s.parse().ok(),
+            TimeFormat::Iso => parse_iso_time(&s),
+        };
+        ms.filter(|ms| *ms <= i64::MAX as u64).map(Some).ok_or_else(|| self.error(format!("bad {} '{}'", name, s)))
+    }
+
+    fn stamp(&self, prefix: &str) -> Result<Option<Timestamp>, CsvError> {
+        let Some(seconds) = self.opt(&format!("{}_s", prefix))? else { return Ok(None) };
+        let nanos: u32 = self.req(&format!("{}_ns", prefix))?;
+        if nanos >= 1_000_000_000 {
+            return Err(self.error(format!("bad {}_ns '{}'", prefix, nanos)));
+        }
+        Ok(Some(Timestamp { seconds, nanos, timebase: self.req(&format!("{}_base", prefix))? }))
+    }
+}
+
+/// Read a CSV with a header, checking every row before returning any
+fn read_csv<R: Read, T>(input: R, config: &CsvConfig, mut parse: impl FnMut(&Fields) -> Result<T, CsvError>) -> Result<Vec<T>, Box<dyn Error>> {
+    let mut reader = ReaderBuilder::new().delimiter(config.delimiter).from_reader(input);
+    let columns: HashMap<String, usize> =
+        reader.headers()?.iter().enumerate().map(|(i, name)| (name.trim().to_string(), i)).collect();
+    let mut rows = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record?;
+        let fields = Fields { line: i as u64 + 2, record: &record, columns: &columns, format: config.time_format };
+        rows.push(parse(&fields)?);
+    }
+    Ok(rows)
+}
+
+/// Read IMU rows from CSV. Columns are found by header name, so any
+/// order will do, and columns the table allows to be NULL may be left
+/// out. lineno is kept if there is one, but a store numbers rows its own
+/// way.
+pub fn read_imu_csv<R: Read>(input: R, config: &CsvConfig) -> Result<Vec<ImuShort>, Box<dyn Error>> {
+    read_csv(input, config, |f| {
+        let pitime = f.time("pitime")?.ok_or_else(|| f.error("no pitime".into()))?;
+        Ok(ImuShort {
+            line: f.opt("lineno")?.unwrap_or_default(),
+            uuid: f.req("uuid")?,
+            pitime,
+            gps_time: f.time("gps_time")?.unwrap_or_default(),
+            sequence: f.req("sequence")?,
+            accel_x: f.float("x_accel")?,
+            accel_y: f.float("y_accel")?,
+            accel_z: f.float("z_accel")?,
+            gyro_x: f.float("x_gyro")?,
+            gyro_y: f.float("y_gyro")?,
+            gyro_z: f.float("z_gyro")?,
+            pose_roll: f.float("roll_pose")?,
+            pose_pitch: f.float("pitch_pose")?,
+            pose_yaw: f.float("yaw_pose")?,
+            pose_heading_accuracy: f.float("heading_accuracy")?,
+            mag_x: f.float("x_mag")?,
+            mag_y: f.float("y_mag")?,
+            mag_z: f.float("z_mag")?,
+            pressure: f.opt("pressure")?.unwrap_or_default(),
+            altitude: f.opt("altitude")?,
+            temperature: f.opt("temperature")?.unwrap_or_default(),
+            temp_cpu: f.opt("temp_cpu")?.unwrap_or_default(),
+            uploaded: f.flag("uploaded")?,
+            confirmed: f.flag("confirmed")?,
+            clock_offset: f.opt("clock_offset")?,
+            clock_error: f.opt("clock_error")?,
+            stamp: f.stamp("stamp")?,
+        })
+    })
+}
+
+/// Read GPS lines from CSV, as `read_imu_csv`
+pub fn read_gps_csv<R: Read>(input: R, config: &CsvConfig) -> Result<Vec<GpsData>, Box<dyn Error>> {
+    read_csv(input, config, |f| {
+        let time = |name| f.time(name)?.ok_or_else(|| f.error(format!("no {}", name)));
+        let lat = f.float("lat")?;
+        let lon = f.float("lon")?;
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(f.error(format!("no such place {}, {}", lat, lon)));
+        }
+        Ok(GpsData {
+            uuid: f.req("uuid")?,
+            pitime: time("pitime")?,
+            gps_time: time("gps_time")?,
+            sequence: f.req("sequence")?,
+            lat,
+            lon,
+            alt: f.float("alt")?,
+            speed: f.float("speed")?,
+            track: f.float("track")?,
+            status_nsats_vuc: f.req("status_nsats_vuc")?,
+            hdop: f.float("hdop")?,
+            h_acc: f.opt("h_acc")?.unwrap_or_default(),
+            v_acc: f.opt("v_acc")?.unwrap_or_default(),
+            speed_acc: f.opt("speed_acc")?.unwrap_or_default(),
+            time_valid: f.opt("time_valid")?.unwrap_or_default(),
+            pi_stamp: f.stamp("pi_stamp")?,
+            gps_stamp: f.stamp("gps_stamp")?,
+        })
+    })
+}
@@ -1,55 +1,27 @@
-use measurements::Pressure;
-use rusqlite::{ params, Connection, Result}; //ffi::SQLITE_NULL,
-use std::{
-    fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+use rusqlite::{ named_params, params, Connection, Result, Row}; //ffi::SQLITE_NULL,
+use std::fmt;
+
+use crate::data_defs::{
+    self, as_degrees_per_second, as_g, from_degrees_per_second, from_g, generate_imu_data, Angle, Length,
+    MagneticFlux, Pressure, Speed, Temperature,
 };
-mod data_defs;
-use crate::data_defs::{generate_imu_data, ImuData, Inertial, Orientation, Vector3D};
-use sqlite_tests::ThreadPool;
+use crate::fake_gps::{decode_fields, encode_fields};
+use crate::{gps, imu, timestamp};
+use crate::schema::{self, IMU_COLUMNS};
 
 pub fn make_imu(conn: &mut Connection)->Result<()>{
     let _ = conn.execute("DROP TABLE IF EXISTS imu",());
-
-    conn.execute("CREATE TABLE imu 
-                    ( lineno INTEGER PRIMARY KEY, 
-                        uuid BIGINT NOT NULL,
-                        pitime DATETIME(6) NOT NULL,
-                        gps_time DATETIME(3), 
-                        sequence INT NOT NULL, 
-                        x_accel FLOAT NOT NULL,  
-                        y_accel FLOAT NOT NULL,  
-                        z_accel FLOAT NOT NULL,  
-                        x_gyro FLOAT NOT NULL,  
-                        y_gyro FLOAT NOT NULL,  
-                        z_gyro FLOAT NOT NULL,
-                        roll_pose FLOAT NOT NULL,  
-                        pitch_pose FLOAT NOT NULL,  
-                        yaw_pose FLOAT NOT NULL, 
-                        heading_accuracy FLOAT NOT NULL,
-                        x_mag FLOAT NOT NULL, 
-                        y_mag FLOAT NOT NULL,  
-                        z_mag FLOAT NOT NULL,  
-                        altitude FLOAT, 
-                        temperature FLOAT, 
-                        temp_cpu FLOAT, 
-                        uploaded int NOT NULL,  
-                        confirmed int NOT NULL
-                    )",
-                    (),
-                )?;
-                Ok(())
+    schema::create_imu_table(conn)
 }
 
 pub fn make_test(conn: &mut Connection)->Result<()>{
     let _ = conn.execute("DROP TABLE IF EXISTS test",());
 
     conn.execute("CREATE TABLE test
-                    ( lineno INTEGER PRIMARY KEY, 
+                    ( lineno INTEGER PRIMARY KEY,
                         uuid BIGINT NOT NULL,
                         pitime DATETIME(6) NOT NULL,
-                        uploaded int NOT NULL,  
+                        uploaded int NOT NULL,
                         confirmed int NOT NULL
                     )",
                     (),
@@ -62,14 +34,14 @@ pub fn fill_test(conn: &mut Connection, n_rows: usize)->Result<()>{
         println!("{} ",i);
         conn.execute("INSERT INTO test (
             uuid,
-            pitime, 
+            pitime,
             uploaded, confirmed)
             VALUES (
                 ?1,
-                ?2, 
-                ?3, 
+                ?2,
+                ?3,
                 ?4)",
-         params![ 
+         params![
             0x12367ABC,
             1781003456,
             0, 0],)?;
@@ -79,221 +51,455 @@ pub fn fill_test(conn: &mut Connection, n_rows: usize)->Result<()>{
 
 pub fn fill_imu(conn: &mut Connection)->Result<()>{
 
-    let imu = generate_imu_data();
+    let mut short = ImuShort::try_from(generate_imu_data())
+        .expect("generate_imu_data always fills every field");
+    short.uuid = 0x12367ABCABAB;
+    short.pitime = 1781003456;
+    short.gps_time = 1781003457;
 
-    let inertial = imu.inertial.unwrap();
-    let accel = inertial.accel.unwrap();
-    let pose = inertial.pose.unwrap();
-    let gyro = inertial.gyro.unwrap();
-    let mag = inertial.mag.unwrap();
-
-    // for i in 0..n_rows {
-        print!(".");
-        conn.execute("INSERT INTO imu (
-            uuid,
-            pitime, gps_time, sequence, 
-            x_accel, y_accel, z_accel,
-            x_gyro, y_gyro, z_gyro, 
-            roll_pose, pitch_pose, yaw_pose, 
-            heading_accuracy, 
-            x_mag, y_mag, z_mag, 
-            altitude, temperature, temp_cpu, 
-            uploaded, confirmed)
-            VALUES (
-                ?1,
-                ?2, ?3, ?4,
-                ?5, ?6, ?7, 
-                ?8, ?9, ?10, 
-                ?11, ?12, ?13, 
-                ?14,
-                ?15, ?16, ?17, 
-                ?18, ?19, ?20, 
-                ?21, ?22)",
-         params![
-            0x12367ABCABAB as i64,          //sqlite handles this i64 just fine
-            1781003456, 1781003457, 0,
-            accel.x, accel.y, accel.z,
-            gyro.x, gyro.y, gyro.z,
-            pose.roll, pose.pitch, pose.yaw,
-            pose.heading_accuracy,
-            mag.x, mag.y, mag.z,
-            imu.pressure.unwrap().as_pascals(), imu.temperature.unwrap(), imu.temp_cpu.unwrap(),
-            0, 0],)?;
-    // };
+    print!(".");
+    insert_imu_short(conn, &short)?;
     Ok(())
-} 
-
-#[derive(Debug)]
+}
 
+/// One row of the imu table, exactly as stored. Plain numbers in the same
+/// units as the wire: accelerations in g, rotation rates in deg/s, angles
+/// in degrees, magnetic field in µT, pressure in Pa, altitude in m and
+/// temperatures in °C. Go through data_defs::ImuData to get units attached.
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct ImuShort{
-    line: usize,
-    uuid: u64,
-    pitime: u64,
-    gps_time: u64, 
-    sequence: usize,
-
-    acccel_x: f32, 
-    accel_y: f32, 
-    accel_z: f32,
-
-    gyro_x: f32, 
-    gyro_y: f32, 
-    gyro_z: f32, 
-
-    pose_roll: f32, 
-    pose_pitch: f32, 
-    pose_yaw: f32, 
-
-    pose_heading_accuracy: f32, 
-
-    mag_x: f32, 
-    mag_y: f32, 
-    mag_z: f32, 
-
-    altitude: f32, 
-    temperature: f32, 
-    temp_cpu: f32, 
-    uploaded: usize,   
-    confirmed: usize,
-}
-
-pub fn convert_imu_short_to_data(short: ImuShort)-> ImuData {
-
-    ImuData{
-        timestamp: short.pitime,
-        inertial: Ok( Inertial {
-            pose: Some( Orientation{
-                roll: short.pose_roll,
-                pitch: short.pose_pitch,
-                yaw: short.pose_yaw,
-                heading_accuracy: short.pose_heading_accuracy,
-            }),     
-            gyro: Some( Vector3D{
-                x: short.gyro_x,
-                y: short.gyro_y,
-                z: short.gyro_z,
-            }),
-            accel: Some( Vector3D{
-                x: short.acccel_x,
-                y: short.accel_y,
-                z: short.accel_z,
-            }),
-            mag: Some( Vector3D{
-                x: short.mag_x,
-                y: short.mag_y,
-                z: short.mag_z,
-            }),
-        }),
-        pressure: Some(Pressure::from_pascals(short.altitude.into())),
-        temperature: Some(short.temperature),
-        temp_cpu: Some(short.temp_cpu),
+    pub line: i64,
+    pub uuid: u64,
+    pub pitime: u64,
+    pub gps_time: u64,
+    pub sequence: u32,
+
+    pub accel_x: f32,
+    pub accel_y: f32,
+    pub accel_z: f32,
+
+    pub gyro_x: f32,
+    pub gyro_y: f32,
+    pub gyro_z: f32,
+
+    pub pose_roll: f32,
+    pub pose_pitch: f32,
+    pub pose_yaw: f32,
+
+    pub pose_heading_accuracy: f32,
+
+    pub mag_x: f32,
+    pub mag_y: f32,
+    pub mag_z: f32,
+
+    pub pressure: f32,
+    /// Derived from pressure, so not known until something fills it in
+    pub altitude: Option<f32>,
+    pub temperature: f32,
+    pub temp_cpu: f32,
+    pub uploaded: bool,
+    pub confirmed: bool,
+    /// pitime - gps_time used to fill in gps_time, ms, and how far that
+    /// can be trusted. None until timesync has corrected the row.
+    pub clock_offset: Option<f64>,
+    pub clock_error: Option<f64>,
+    /// The full time the reading was taken, when the sender gave one.
+    /// pitime is this rounded down to the ms.
+    pub stamp: Option<timestamp::Timestamp>,
+}
 
+impl ImuShort {
+    /// Read a row of the imu table. Columns are looked up by name, so any
+    /// SELECT that includes them all will do.
+    pub fn from_row(row: &Row) -> Result<ImuShort> {
+        Ok(ImuShort{ line: row.get("lineno")?,
+            uuid: row.get::<_, i64>("uuid")? as u64,
+            pitime: row.get("pitime")?,
+            gps_time: row.get::<_, Option<u64>>("gps_time")?.unwrap_or_default(),
+            sequence: row.get("sequence")?,
+
+            accel_x: row.get("x_accel")?,
+            accel_y: row.get("y_accel")?,
+            accel_z: row.get("z_accel")?,
+
+            gyro_x: row.get("x_gyro")?,
+            gyro_y: row.get("y_gyro")?,
+            gyro_z: row.get("z_gyro")?,
+
+            pose_roll: row.get("roll_pose")?,
+            pose_pitch: row.get("pitch_pose")?,
+            pose_yaw: row.get("yaw_pose")?,
+
+            pose_heading_accuracy: row.get("heading_accuracy")?,
+
+            mag_x: row.get("x_mag")?,
+            mag_y: row.get("y_mag")?,
+            mag_z: row.get("z_mag")?,
+
+            pressure: row.get("pressure")?,
+            altitude: row.get("altitude")?,
+            temperature: row.get("temperature")?,
+            temp_cpu: row.get("temp_cpu")?,
+            uploaded: row.get("uploaded")?,
+            confirmed: row.get("confirmed")?,
+            clock_offset: row.get("clock_offset")?,
+            clock_error: row.get("clock_error")?,
+            stamp: read_stamp(row, "stamp")?,
+        })
     }
-
 }
 
+/// Write one row to the imu table. Returns 0 if a unique index on the
+/// table says the row is already there, 1 otherwise.
+pub fn insert_imu_short(conn: &Connection, short: &ImuShort) -> Result<usize> {
+    insert_imu_short_into(conn, "imu", short)
+}
 
-pub fn get_earliest_n(conn: &mut Connection, n: usize)->Result<()>{//->ImuData{
-
-    let mut stmt = conn.prepare(&format!("SELECT * FROM imu WHERE uploaded = false ORDER BY lineno ASC LIMIT {}", n))?;
-
-    let imu_iter = stmt.query_map([], |row| {
-        Ok(ImuShort{ line: row.get(0)?,
-            uuid: row.get(1)?,
-            pitime: row.get(2)?,
-            gps_time: row.get(3)?,
-            sequence: row.get(4)?,
-        
-            acccel_x: row.get(5)?,
-            accel_y: row.get(6)?,
-            accel_z: row.get(7)?,
-        
-            gyro_x: row.get(8)?,
-            gyro_y: row.get(9)?,
-            gyro_z: row.get(10)?,
-        
-            pose_roll: row.get(11)?,
-            pose_pitch: row.get(12)?,
-            pose_yaw: row.get(13)?,
-        
-            pose_heading_accuracy: row.get(14)?,
-        
-            mag_x: row.get(15)?,
-            mag_y: row.get(16)?,
-            mag_z: row.get(17)?,
-        
-            altitude: row.get(18)?,
-            temperature: row.get(19)?,
-            temp_cpu: row.get(20)?,
-            uploaded: row.get(21)?,
-            confirmed: row.get(22)?,
-        })
-    })?;
+/// As `insert_imu_short`, into another table with the imu table's
+/// columns, e.g. crash_imu
+pub fn insert_imu_short_into(conn: &Connection, table: &str, short: &ImuShort) -> Result<usize> {
+    let mut stmt = conn.prepare_cached(&schema::insert_sql(table, IMU_COLUMNS))?;
+    stmt.execute(named_params!{
+        ":uuid": short.uuid as i64,          //sqlite handles this i64 just fine
+        ":pitime": short.pitime as i64,
+        ":gps_time": short.gps_time as i64,
+        ":sequence": short.sequence,
+        ":x_accel": short.accel_x,
+        ":y_accel": short.accel_y,
+        ":z_accel": short.accel_z,
+        ":x_gyro": short.gyro_x,
+        ":y_gyro": short.gyro_y,
+        ":z_gyro": short.gyro_z,
+        ":roll_pose": short.pose_roll,
+        ":pitch_pose": short.pose_pitch,
+        ":yaw_pose": short.pose_yaw,
+        ":heading_accuracy": short.pose_heading_accuracy,
+        ":x_mag": short.mag_x,
+        ":y_mag": short.mag_y,
+        ":z_mag": short.mag_z,
+        ":pressure": short.pressure,
+        ":altitude": short.altitude,
+        ":temperature": short.temperature,
+        ":temp_cpu": short.temp_cpu,
+        ":uploaded": short.uploaded,
+        ":confirmed": short.confirmed,
+        ":clock_offset": short.clock_offset,
+        ":clock_error": short.clock_error,
+        ":stamp_s": short.stamp.map(|t| t.seconds),
+        ":stamp_ns": short.stamp.map(|t| t.nanos),
+        ":stamp_base": short.stamp.map(|t| t.timebase),
+    })
+}
 
-    for imu in imu_iter {
-        println!("{:?}",imu)
+/// A Timestamp kept in the three columns `<prefix>_s`, `<prefix>_ns` and
+/// `<prefix>_base`. None when the row was stored without one.
+pub fn read_stamp(row: &Row, prefix: &str) -> Result<Option<timestamp::Timestamp>> {
+    let seconds: Option<i64> = row.get(format!("{}_s", prefix).as_str())?;
+    match seconds {
+        Some(seconds) => Ok(Some(timestamp::Timestamp {
+            seconds,
+            nanos: row.get(format!("{}_ns", prefix).as_str())?,
+            timebase: row.get(format!("{}_base", prefix).as_str())?,
+        })),
+        None => Ok(None),
     }
+}
 
-    Ok(())
+/// The full time if there is one, otherwise the ms count
+fn stamp_or_millis(stamp: Option<timestamp::Timestamp>, ms: u64, timebase: data_defs::Timebase) -> data_defs::Timestamp {
+    stamp.map_or(data_defs::Timestamp::from_millis(ms, timebase), Into::into)
 }
 
+/// pitime and friends for a message that may also carry the full time.
+/// A stamp on another clock than the ms count, e.g. a monotonic one
+/// counting from boot, leaves the ms count as it is.
+pub fn millis(stamp: Option<timestamp::Timestamp>, ms: u64, timebase: data_defs::Timebase) -> u64 {
+    stamp.map(data_defs::Timestamp::from).filter(|t| t.timebase == timebase).map_or(ms, |t| t.as_millis())
+}
+
+impl From<timestamp::Timebase> for data_defs::Timebase {
+    fn from(t: timestamp::Timebase) -> Self {
+        match t {
+            timestamp::Timebase::System => data_defs::Timebase::System,
+            timestamp::Timebase::Gps => data_defs::Timebase::Gps,
+            timestamp::Timebase::Monotonic => data_defs::Timebase::Monotonic,
+        }
+    }
+}
+
+impl From<data_defs::Timebase> for timestamp::Timebase {
+    fn from(t: data_defs::Timebase) -> Self {
+        match t {
+            data_defs::Timebase::System => timestamp::Timebase::System,
+            data_defs::Timebase::Gps => timestamp::Timebase::Gps,
+            data_defs::Timebase::Monotonic => timestamp::Timebase::Monotonic,
+        }
+    }
+}
 
+/// A timebase from a newer sender than this code reads as the system
+/// clock, and nanos of a second or more carry into seconds
+impl From<timestamp::Timestamp> for data_defs::Timestamp {
+    fn from(t: timestamp::Timestamp) -> Self {
+        let timebase = timestamp::Timebase::try_from(t.timebase).unwrap_or_default();
+        data_defs::Timestamp::from_nanos(t.seconds as i128 * 1_000_000_000 + t.nanos as i128, timebase.into())
+    }
+}
 
+impl From<data_defs::Timestamp> for timestamp::Timestamp {
+    fn from(t: data_defs::Timestamp) -> Self {
+        timestamp::Timestamp {
+            seconds: t.seconds,
+            nanos: t.nanos,
+            timebase: timestamp::Timebase::from(t.timebase).into(),
+        }
+    }
+}
 
+/// The oldest n rows that haven't been uploaded yet
+pub fn get_earliest_n(conn: &Connection, n: usize)->Result<Vec<ImuShort>>{
 
-fn main()->Result<(), rusqlite::Error> {
-    // let mut conn = Connection::open("/Users/drv201/Code/sqlite_tests/test.db").unwrap();
+    let mut stmt = conn.prepare("SELECT * FROM imu WHERE uploaded = false ORDER BY lineno ASC LIMIT ?1")?;
 
-    //let _ = make_imu(&mut conn);     // Uncomment if you need to make the table
-    // let _ = make_test(&mut conn);    // Uncomment if you need to make the table
+    let imu_iter = stmt.query_map([n as i64], ImuShort::from_row)?;
 
-    // for i in 0..100 {
-    //     let _ = fill_imu(&mut conn);
-    // }
-    // println!("");
+    imu_iter.collect()
+}
 
-    // let _ = get_earliest_n(&mut conn,10);
+/// A field that one representation requires was missing in another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConversionError {
+    Missing(&'static str),
+}
 
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConversionError::Missing(field) => write!(f, "IMU data has no {}", field),
+        }
+    }
+}
 
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+impl std::error::Error for ConversionError {}
+
+/// A row has no uuid, gps_time or lineno, so those are left at zero
+impl TryFrom<imu::ImuData> for ImuShort {
+    type Error = ConversionError;
+
+    fn try_from(d: imu::ImuData) -> std::result::Result<Self, Self::Error> {
+        let inertial = d.inertial.ok_or(ConversionError::Missing("inertial"))?;
+        let pose = inertial.pose.ok_or(ConversionError::Missing("pose"))?;
+        let gyro = inertial.gyro.ok_or(ConversionError::Missing("gyro"))?;
+        let accel = inertial.accel.ok_or(ConversionError::Missing("accel"))?;
+        let mag = inertial.mag.ok_or(ConversionError::Missing("mag"))?;
+
+        Ok(ImuShort {
+            pitime: millis(d.stamp, d.timestamp, data_defs::Timebase::System),
+            stamp: d.stamp,
+            sequence: d.sequence,
+            accel_x: accel.x,
+            accel_y: accel.y,
+            accel_z: accel.z,
+            gyro_x: gyro.x,
+            gyro_y: gyro.y,
+            gyro_z: gyro.z,
+            pose_roll: pose.roll,
+            pose_pitch: pose.pitch,
+            pose_yaw: pose.yaw,
+            pose_heading_accuracy: pose.heading_accuracy,
+            mag_x: mag.x,
+            mag_y: mag.y,
+            mag_z: mag.z,
+            pressure: d.pressure,
+            temperature: d.temperature,
+            temp_cpu: d.temp_cpu,
+            ..Default::default()
+        })
+    }
+}
 
-    let pool = ThreadPool::new(4);
+impl From<ImuShort> for imu::ImuData {
+    fn from(short: ImuShort) -> Self {
+        imu::ImuData {
+            sequence: short.sequence,
+            timestamp: short.pitime,
+            stamp: short.stamp,
+            inertial: Some(imu::Inertial {
+                pose: Some(imu::Orientation {
+                    roll: short.pose_roll,
+                    pitch: short.pose_pitch,
+                    yaw: short.pose_yaw,
+                    heading_accuracy: short.pose_heading_accuracy,
+                }),
+                gyro: Some(imu::Vector3D { x: short.gyro_x, y: short.gyro_y, z: short.gyro_z }),
+                accel: Some(imu::Vector3D { x: short.accel_x, y: short.accel_y, z: short.accel_z }),
+                mag: Some(imu::Vector3D { x: short.mag_x, y: short.mag_y, z: short.mag_z }),
+            }),
+            pressure: short.pressure,
+            temperature: short.temperature,
+            temp_cpu: short.temp_cpu,
+        }
+    }
+}
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+/// Wire angles are in degrees
+impl From<imu::Orientation> for data_defs::Orientation {
+    fn from(o: imu::Orientation) -> Self {
+        data_defs::Orientation {
+            roll: Angle::from_degrees(o.roll.into()),
+            pitch: Angle::from_degrees(o.pitch.into()),
+            yaw: Angle::from_degrees(o.yaw.into()),
+            heading_accuracy: Angle::from_degrees(o.heading_accuracy.into()),
+        }
+    }
+}
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
-        // println!("Connection established!");
-        // handle_connection(stream);
+impl From<data_defs::Orientation> for imu::Orientation {
+    fn from(o: data_defs::Orientation) -> Self {
+        imu::Orientation {
+            roll: o.roll.as_degrees() as f32,
+            pitch: o.pitch.as_degrees() as f32,
+            yaw: o.yaw.as_degrees() as f32,
+            heading_accuracy: o.heading_accuracy.as_degrees() as f32,
+        }
     }
+}
 
+/// A wire vector has no units; which ones apply depends on the field
+impl From<imu::Vector3D> for data_defs::Vector3D<f64> {
+    fn from(v: imu::Vector3D) -> Self {
+        data_defs::Vector3D { x: v.x.into(), y: v.y.into(), z: v.z.into() }
+    }
+}
 
-    Ok(())
+impl From<data_defs::Vector3D<f64>> for imu::Vector3D {
+    fn from(v: data_defs::Vector3D<f64>) -> Self {
+        imu::Vector3D { x: v.x as f32, y: v.y as f32, z: v.z as f32 }
+    }
 }
 
+/// On the wire gyro is in deg/s, accel in g and mag in µT
+impl From<imu::Inertial> for data_defs::Inertial {
+    fn from(i: imu::Inertial) -> Self {
+        data_defs::Inertial {
+            pose: i.pose.map(Into::into),
+            gyro: i.gyro.map(|v| data_defs::Vector3D::from(v).map(from_degrees_per_second)),
+            accel: i.accel.map(|v| data_defs::Vector3D::from(v).map(from_g)),
+            mag: i.mag.map(|v| data_defs::Vector3D::from(v).map(MagneticFlux::from_microteslas)),
+        }
+    }
+}
 
-pub fn handle_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&mut stream);
-    let http_request: Vec<_> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
+impl From<data_defs::Inertial> for imu::Inertial {
+    fn from(i: data_defs::Inertial) -> Self {
+        imu::Inertial {
+            pose: i.pose.map(Into::into),
+            gyro: i.gyro.map(|v| v.map(as_degrees_per_second).into()),
+            accel: i.accel.map(|v| v.map(as_g).into()),
+            mag: i.mag.map(|v| v.map(|m| m.as_microteslas()).into()),
+        }
+    }
+}
 
-    // println!("Reqest: {http_request:#?}");
+/// A message without inertial data means the IMU wasn't ready. Pressure
+/// is in Pa and temperatures in °C.
+impl From<imu::ImuData> for data_defs::ImuData {
+    fn from(d: imu::ImuData) -> Self {
+        data_defs::ImuData {
+            sequence: d.sequence,
+            timestamp: stamp_or_millis(d.stamp, d.timestamp, data_defs::Timebase::System),
+            inertial: d.inertial.map(Into::into).ok_or(data_defs::ImuError::NotReady),
+            pressure: Some(Pressure::from_pascals(d.pressure.into())),
+            temperature: Some(Temperature::from_celsius(d.temperature.into())),
+            temp_cpu: Some(Temperature::from_celsius(d.temp_cpu.into())),
+        }
+    }
+}
 
-    let status_line = "HTTP/1.1 200 OK";
-    let contents = fs::read_to_string("/Users/drv201/Code/sqlite_tests/src/hello.html").unwrap();
-    let length = contents.len();
+/// The message has no way to say a reading is missing, so pressure and
+/// temperatures must all be present
+impl TryFrom<data_defs::ImuData> for imu::ImuData {
+    type Error = ConversionError;
+
+    fn try_from(d: data_defs::ImuData) -> std::result::Result<Self, Self::Error> {
+        let pressure = d.pressure.ok_or(ConversionError::Missing("pressure"))?;
+        let temperature = d.temperature.ok_or(ConversionError::Missing("temperature"))?;
+        let temp_cpu = d.temp_cpu.ok_or(ConversionError::Missing("temp_cpu"))?;
+        Ok(imu::ImuData {
+            sequence: d.sequence,
+            timestamp: d.timestamp.as_millis(),
+            stamp: Some(d.timestamp.into()),
+            inertial: d.inertial.ok().map(Into::into),
+            pressure: pressure.as_pascals() as f32,
+            temperature: temperature.as_celsius() as f32,
+            temp_cpu: temp_cpu.as_celsius() as f32,
+        })
+    }
+}
 
-    let response = 
-        format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+impl From<ImuShort> for data_defs::ImuData {
+    fn from(short: ImuShort) -> Self {
+        imu::ImuData::from(short).into()
+    }
+}
 
-    stream.write_all(response.as_bytes()).unwrap();
+impl TryFrom<data_defs::ImuData> for ImuShort {
+    type Error = ConversionError;
 
+    fn try_from(d: data_defs::ImuData) -> std::result::Result<Self, Self::Error> {
+        ImuShort::try_from(imu::ImuData::try_from(d)?)
+    }
 }
 
+/// Wire and gps table units: degrees, m and m/s. An accuracy of 0 means
+/// the receiver didn't give one.
+impl From<gps::GpsData> for data_defs::GpsData {
+    fn from(d: gps::GpsData) -> Self {
+        let (status, nsats, valid, uploaded, confirmed) = decode_fields(d.status_nsats_vuc);
+        data_defs::GpsData {
+            uuid: d.uuid,
+            pitime: stamp_or_millis(d.pi_stamp, d.pitime, data_defs::Timebase::System),
+            gps_time: stamp_or_millis(d.gps_stamp, d.gps_time, data_defs::Timebase::Gps),
+            sequence: d.sequence,
+            lat: Angle::from_degrees(d.lat.into()),
+            lon: Angle::from_degrees(d.lon.into()),
+            alt: Length::from_meters(d.alt.into()),
+            speed: Speed::from_meters_per_second(d.speed.into()),
+            track: Angle::from_degrees(d.track.into()),
+            status,
+            nsats,
+            valid,
+            uploaded,
+            confirmed,
+            hdop: d.hdop,
+            h_acc: Some(d.h_acc).filter(|a| *a > 0.0).map(|a| Length::from_meters(a.into())),
+            v_acc: Some(d.v_acc).filter(|a| *a > 0.0).map(|a| Length::from_meters(a.into())),
+            speed_acc: Some(d.speed_acc).filter(|a| *a > 0.0).map(|a| Speed::from_meters_per_second(a.into())),
+            time_valid: d.time_valid,
+        }
+    }
+}
 
-    
+impl From<data_defs::GpsData> for gps::GpsData {
+    fn from(d: data_defs::GpsData) -> Self {
+        gps::GpsData {
+            uuid: d.uuid,
+            pitime: d.pitime.as_millis(),
+            gps_time: d.gps_time.as_millis(),
+            sequence: d.sequence,
+            lat: d.lat.as_degrees() as f32,
+            lon: d.lon.as_degrees() as f32,
+            alt: d.alt.as_meters() as f32,
+            speed: d.speed.as_meters_per_second() as f32,
+            track: d.track.as_degrees() as f32,
+            status_nsats_vuc: encode_fields(d.status, d.nsats, d.valid, d.uploaded, d.confirmed),
+            hdop: d.hdop,
+            h_acc: d.h_acc.map_or(0.0, |a| a.as_meters() as f32),
+            v_acc: d.v_acc.map_or(0.0, |a| a.as_meters() as f32),
+            speed_acc: d.speed_acc.map_or(0.0, |a| a.as_meters_per_second() as f32),
+            time_valid: d.time_valid,
+            pi_stamp: Some(d.pitime.into()),
+            gps_stamp: Some(d.gps_time.into()),
+        }
+    }
+}
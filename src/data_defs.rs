@@ -1,76 +1,202 @@
-use measurements::Pressure;
+pub use measurements::{Acceleration, Angle, AngularVelocity, Length, Pressure, Speed, Temperature};
 // use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// Standard gravity, m/s²
+pub const STANDARD_GRAVITY: f64 = 9.80665;
 
-#[derive(Debug)]
+/// Accelerometers report in g
+pub fn from_g(g: f64) -> Acceleration {
+    Acceleration::from_meters_per_second_per_second(g * STANDARD_GRAVITY)
+}
+
+pub fn as_g(a: Acceleration) -> f64 {
+    a.as_meters_per_second_per_second() / STANDARD_GRAVITY
+}
+
+/// Gyros report in deg/s
+pub fn from_degrees_per_second(dps: f64) -> AngularVelocity {
+    AngularVelocity::from_radians_per_second(dps.to_radians())
+}
+
+pub fn as_degrees_per_second(w: AngularVelocity) -> f64 {
+    w.as_radians_per_second().to_degrees()
+}
+
+/// Magnetic flux density. measurements doesn't have one.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct MagneticFlux {
+    microteslas: f64,
+}
+
+impl MagneticFlux {
+    pub fn from_microteslas(microteslas: f64) -> MagneticFlux {
+        MagneticFlux { microteslas }
+    }
+
+    pub fn as_microteslas(&self) -> f64 {
+        self.microteslas
+    }
+}
+
+/// Which clock a `Timestamp` was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Timebase {
+    /// The Pi's wall clock, since the Unix epoch
+    #[default]
+    System,
+    /// UTC from the GPS receiver, since the Unix epoch
+    Gps,
+    /// The Pi's monotonic clock, since boot
+    Monotonic,
+}
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// A time to the nanosecond, and the clock it came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Timestamp {
+    pub seconds: i64,
+    /// Always less than a second
+    pub nanos: u32,
+    pub timebase: Timebase,
+}
+
+impl Timestamp {
+    pub fn from_nanos(nanos: i128, timebase: Timebase) -> Timestamp {
+        Timestamp {
+            seconds: nanos.div_euclid(NANOS_PER_SEC) as i64,
+            nanos: nanos.rem_euclid(NANOS_PER_SEC) as u32,
+            timebase,
+        }
+    }
+
+    /// The ms counts this crate used before Timestamp, e.g. pitime
+    pub fn from_millis(ms: u64, timebase: Timebase) -> Timestamp {
+        Timestamp::from_nanos(ms as i128 * 1_000_000, timebase)
+    }
+
+    /// The system clock now
+    pub fn now() -> Timestamp {
+        let since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Unable to calculate current time in Timestamp::now");
+        Timestamp::from_duration(since_epoch, Timebase::System)
+    }
+
+    pub fn from_duration(d: Duration, timebase: Timebase) -> Timestamp {
+        Timestamp { seconds: d.as_secs() as i64, nanos: d.subsec_nanos(), timebase }
+    }
+
+    pub fn as_nanos(&self) -> i128 {
+        self.seconds as i128 * NANOS_PER_SEC + self.nanos as i128
+    }
+
+    /// Rounded down to the ms. Times before the epoch come out as 0.
+    pub fn as_millis(&self) -> u64 {
+        u64::try_from(self.as_nanos().div_euclid(1_000_000)).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Orientation {
-    pub roll: f32,
-    pub pitch: f32,
-    pub yaw: f32,
-    pub heading_accuracy: f32,
+    pub roll: Angle,
+    pub pitch: Angle,
+    pub yaw: Angle,
+    pub heading_accuracy: Angle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vector3D<T> {
+    pub x: T,
+    pub y: T,
+    pub z: T,
 }
 
-#[derive(Debug)]
-pub struct Vector3D {
-    pub x: f32,
-    pub y: f32,
-    pub z: f32,
+impl<T> Vector3D<T> {
+    /// Apply `f` to each component, e.g. to attach or strip units
+    pub fn map<U>(self, f: impl Fn(T) -> U) -> Vector3D<U> {
+        Vector3D { x: f(self.x), y: f(self.y), z: f(self.z) }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Inertial {
     pub pose: Option<Orientation>,
-    pub gyro: Option<Vector3D>,
-    pub accel: Option<Vector3D>,
-    pub mag: Option<Vector3D>,
+    pub gyro: Option<Vector3D<AngularVelocity>>,
+    pub accel: Option<Vector3D<Acceleration>>,
+    pub mag: Option<Vector3D<MagneticFlux>>,
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ImuError {
     NotReady,
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ImuData {
-    pub timestamp: u64,
+    pub sequence: u32,
+    pub timestamp: Timestamp,
     pub inertial: Result<Inertial, ImuError>,
     pub pressure: Option<Pressure>,
-    pub temperature: Option<f32>,
-    pub temp_cpu: Option<f32>,
+    pub temperature: Option<Temperature>,
+    pub temp_cpu: Option<Temperature>,
     // pub humidity: Option<f64>,
 }
 
+/// One GPS fix, with status_nsats_vuc unpacked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpsData {
+    pub uuid: u64,
+    pub pitime: Timestamp,
+    pub gps_time: Timestamp,
+    pub sequence: u32,
+    pub lat: Angle,
+    pub lon: Angle,
+    pub alt: Length,
+    pub speed: Speed,
+    pub track: Angle,
+    pub status: u8,
+    pub nsats: u8,
+    pub valid: bool,
+    pub uploaded: bool,
+    pub confirmed: bool,
+    pub hdop: f32,
+    /// Accuracy estimates, when the receiver gives them
+    pub h_acc: Option<Length>,
+    pub v_acc: Option<Length>,
+    pub speed_acc: Option<Speed>,
+    /// ubx::TIME_VALID_* bits
+    pub time_valid: u32,
+}
+
 pub fn generate_imu_data()->ImuData{
 
-    let timestamp = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("Unable to calculate current time in imu::cycle")
-        .as_millis() as u64;
-    
+    let timestamp = Timestamp::now();
+
     let orientation = Orientation{
-        roll: 10.4,
-        pitch: 0.0,
-        yaw:188.9,
-        heading_accuracy: 3.2,
+        roll: Angle::from_degrees(10.4),
+        pitch: Angle::from_degrees(0.0),
+        yaw: Angle::from_degrees(188.9),
+        heading_accuracy: Angle::from_degrees(3.2),
     };
 
     let inertial = Ok(Inertial {
         pose: Some(orientation),
-        gyro: Some(Vector3D { x: 0.01, y: 0.03, z: 18.5 }),
-        accel: Some(Vector3D { x: 0.01, y: 0.03, z: 1.005 }),
-        mag: Some(Vector3D { x: 28.3, y: 16.9, z: 11.2 }),
+        gyro: Some(Vector3D { x: 0.01, y: 0.03, z: 18.5 }.map(from_degrees_per_second)),
+        accel: Some(Vector3D { x: 0.01, y: 0.03, z: 1.005 }.map(from_g)),
+        mag: Some(Vector3D { x: 28.3, y: 16.9, z: 11.2 }.map(MagneticFlux::from_microteslas)),
     });
 
-    ImuData { 
+    ImuData {
+        sequence: 0,
         timestamp,
-        inertial, 
-        pressure: Some(Pressure::from_pascals(101320.0)), 
-        temperature: Some(23.0), 
-        temp_cpu: Some(77.3),
+        inertial,
+        pressure: Some(Pressure::from_pascals(101320.0)),
+        temperature: Some(Temperature::from_celsius(23.0)),
+        temp_cpu: Some(Temperature::from_celsius(77.3)),
     }
 
-}
\ No newline at end of file
+}
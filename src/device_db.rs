@@ -0,0 +1,340 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, ErrorCode, Result, Statement};
+
+use crate::data_defs::Timestamp;
+use crate::schema::{self, CRASH_COLUMNS, GPS_COLUMNS, IMU_COLUMNS};
+
+/// SQLite's synchronous levels. In WAL mode Normal can lose the last few
+/// commits on power loss but never corrupts; Full loses nothing that was
+/// committed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+impl FromStr for Synchronous {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Synchronous::Off),
+            "normal" => Ok(Synchronous::Normal),
+            "full" => Ok(Synchronous::Full),
+            "extra" => Ok(Synchronous::Extra),
+            _ => Err(format!("Unknown synchronous level '{}'", s)),
+        }
+    }
+}
+
+/// How hard to look at the database when it's opened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntegrityCheck {
+    Skip,
+    /// PRAGMA quick_check: seconds on a large card rather than minutes
+    Quick,
+    Full,
+}
+
+/// Settings for `open_device_db`
+#[derive(Debug, Clone)]
+pub struct DeviceDbConfig {
+    /// The Pi loses power when the ignition is cut, so the default is Full
+    pub synchronous: Synchronous,
+    pub check: IntegrityCheck,
+    /// WAL pages before a checkpoint copies them into the database. 0
+    /// leaves checkpoints to whoever closes the last connection.
+    pub autocheckpoint: u32,
+    /// How long to wait for the uploader to finish with the database
+    pub busy_timeout: Duration,
+}
+
+impl Default for DeviceDbConfig {
+    fn default() -> Self {
+        DeviceDbConfig {
+            synchronous: Synchronous::Full,
+            check: IntegrityCheck::Quick,
+            autocheckpoint: 1000,
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What `open_device_db` found
+#[derive(Debug, Clone, PartialEq)]
+pub enum DbHealth {
+    Ok,
+    /// The database failed its check and was moved aside to `quarantined`.
+    /// A fresh one took its place, holding the rows that could still be
+    /// read from the old one. `crashes` counts crash records and
+    /// `crash_lines` the IMU lines captured around them. `failures` says
+    /// what couldn't be read.
+    Quarantined {
+        quarantined: PathBuf,
+        problem: String,
+        imu_rows: usize,
+        gps_rows: usize,
+        crashes: usize,
+        crash_lines: usize,
+        failures: Vec<String>,
+    },
+}
+
+fn configure(conn: &Connection, config: &DeviceDbConfig) -> Result<()> {
+    conn.busy_timeout(config.busy_timeout)?;
+    // Only takes effect on a new database; pruning relies on it
+    conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL")?;
+    conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get::<_, String>(0))?;
+    conn.execute_batch(&format!(
+        "PRAGMA synchronous = {}; PRAGMA wal_autocheckpoint = {};",
+        config.synchronous.as_sql(),
+        config.autocheckpoint
+    ))
+}
+
+/// None if the database is fine, otherwise what's wrong with it
+fn check(conn: &Connection, check: IntegrityCheck) -> Result<Option<String>> {
+    let pragma = match check {
+        IntegrityCheck::Skip => "PRAGMA schema_version",
+        IntegrityCheck::Quick => "PRAGMA quick_check",
+        IntegrityCheck::Full => "PRAGMA integrity_check",
+    };
+    match conn.query_row(pragma, [], |row| row.get::<_, Value>(0))? {
+        Value::Text(message) if message != "ok" => Ok(Some(message)),
+        _ => Ok(None),
+    }
+}
+
+/// Why a database couldn't be opened. Only a corrupt one is moved aside;
+/// anything else, e.g. a full card, is left for the caller.
+enum OpenError {
+    Corrupt(String),
+    Sqlite(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for OpenError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e.sqlite_error_code() {
+            Some(ErrorCode::DatabaseCorrupt) | Some(ErrorCode::NotADatabase) => OpenError::Corrupt(e.to_string()),
+            _ => OpenError::Sqlite(e),
+        }
+    }
+}
+
+fn open_checked(path: &Path, config: &DeviceDbConfig) -> std::result::Result<Connection, OpenError> {
+    let conn = Connection::open(path)?;
+    configure(&conn, config)?;
+    if let Some(problem) = check(&conn, config.check)? {
+        return Err(OpenError::Corrupt(problem));
+    }
+    schema::create_imu_table(&conn)?;
+    schema::create_gps_table(&conn)?;
+    schema::create_crash_tables(&conn)?;
+    Ok(conn)
+}
+
+/// `path` with `suffix` added to the file name, e.g. for its WAL
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Move the database and its WAL and shared memory files out of the way
+fn quarantine(path: &Path) -> std::io::Result<PathBuf> {
+    let quarantined = with_suffix(path, &format!(".corrupt-{}", Timestamp::now().as_millis()));
+    fs::rename(path, &quarantined)?;
+    for suffix in ["-wal", "-shm"] {
+        let from = with_suffix(path, suffix);
+        if from.exists() {
+            fs::rename(&from, with_suffix(&quarantined, suffix))?;
+        }
+    }
+    Ok(quarantined)
+}
+
+/// Rows read from the quarantined database before each write
+const SALVAGE_BATCH: i64 = 10_000;
+
+/// What reading one row from a quarantined table gave
+enum Probe {
+    Read,
+    End,
+    Damaged,
+}
+
+/// Read the first row of `select` after `after`, every column of it
+fn probe(select: &mut Statement, width: usize, after: i64) -> Result<Probe> {
+    let mut rows = select.query([after, 1])?;
+    Ok(match rows.next().and_then(|row| match row {
+        Some(row) => (0..width).try_for_each(|i| row.get::<_, Value>(i).map(drop)).map(Some),
+        None => Ok(None),
+    }) {
+        Ok(Some(())) => Probe::Read,
+        Ok(None) => Probe::End,
+        Err(_) => Probe::Damaged,
+    })
+}
+
+/// Past rows that can't be read after `after`: the lineno to carry on
+/// reading after, or None if nothing further can be read. Looks further
+/// and further ahead until a row can be read, then narrows back down so
+/// no more is skipped than was damaged.
+fn skip_damage(select: &mut Statement, width: usize, after: i64) -> Result<Option<i64>> {
+    let mut bad = after;
+    let mut step = 1i64;
+    let good = loop {
+        let at = after.saturating_add(step);
+        match probe(select, width, at)? {
+            Probe::Read => break at,
+            Probe::End => return Ok(None),
+            Probe::Damaged if at == i64::MAX => return Ok(None),
+            Probe::Damaged => {
+                bad = at;
+                step = step.saturating_mul(2);
+            }
+        }
+    };
+    // Reading after `bad` fails, reading after `good` doesn't
+    let (mut bad, mut good) = (bad, good);
+    while good - bad > 1 {
+        let mid = bad + (good - bad) / 2;
+        match probe(select, width, mid)? {
+            Probe::Damaged => bad = mid,
+            _ => good = mid,
+        }
+    }
+    Ok(Some(good))
+}
+
+/// Copy rows out of `bad.<table>` in lineno order. linenos are kept, so
+/// the uploader carries on where it was. Rows that can't be read are
+/// skipped, and each run of them is added to `failures`.
+fn salvage(conn: &Connection, table: &str, columns: &[(&str, &str)], failures: &mut Vec<String>) -> Result<usize> {
+    // An older database won't have the newer tables
+    if !conn.prepare("SELECT 1 FROM bad.sqlite_master WHERE type = 'table' AND name = ?1")?.exists([table])? {
+        return Ok(0);
+    }
+    let mut stmt = conn.prepare(&format!("SELECT name FROM bad.pragma_table_info('{}')", table))?;
+    let present = stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>>>()?;
+    let mut names = vec!["lineno"];
+    names.extend(columns.iter().map(|(name, _)| *name).filter(|name| present.iter().any(|p| p == name)));
+
+    let mut select = conn.prepare(&format!(
+        "SELECT {} FROM bad.{} WHERE lineno > ?1 ORDER BY lineno LIMIT ?2",
+        names.join(", "),
+        table
+    ))?;
+    let params: Vec<&str> = names.iter().map(|_| "?").collect();
+    let insert = format!("INSERT OR IGNORE INTO main.{} ({}) VALUES ({})", table, names.join(", "), params.join(", "));
+
+    let mut copied = 0;
+    let mut last = i64::MIN;
+    loop {
+        // Read first and write after: a damaged page fails the statement
+        // it's read in, which mustn't be the one writing
+        let mut batch: Vec<Vec<Value>> = Vec::new();
+        let mut damaged = false;
+        let mut rows = select.query([last, SALVAGE_BATCH])?;
+        loop {
+            match rows.next().and_then(|row| match row {
+                Some(row) => (0..names.len()).map(|i| row.get::<_, Value>(i)).collect::<Result<Vec<_>>>().map(Some),
+                None => Ok(None),
+            }) {
+                Ok(Some(values)) => batch.push(values),
+                Ok(None) => break,
+                Err(_) => {
+                    damaged = true;
+                    break;
+                }
+            }
+        }
+        drop(rows);
+
+        let tx = conn.unchecked_transaction()?;
+        {
+            let mut insert = tx.prepare_cached(&insert)?;
+            for values in &batch {
+                copied += insert.execute(params_from_iter(values))?;
+            }
+        }
+        tx.commit()?;
+
+        if let Some(Value::Integer(lineno)) = batch.last().map(|values| &values[0]) {
+            last = *lineno;
+        }
+        if damaged {
+            match skip_damage(&mut select, names.len(), last)? {
+                Some(next) => {
+                    failures.push(format!("{}: lines {} to {} unreadable", table, last.saturating_add(1), next));
+                    last = next;
+                }
+                None => {
+                    failures.push(format!("{}: lines after {} unreadable", table, last));
+                    break;
+                }
+            }
+        } else if (batch.len() as i64) < SALVAGE_BATCH {
+            break;
+        }
+    }
+    Ok(copied)
+}
+
+/// Open the device's local database in WAL mode, creating the tables if
+/// needed. SQLite replays a committed WAL by itself; if the database
+/// then fails its integrity check, or can't be read at all, it is
+/// quarantined and replaced by a fresh one with whatever rows could be
+/// salvaged.
+pub fn open_device_db(path: &Path, config: &DeviceDbConfig) -> std::result::Result<(Connection, DbHealth), Box<dyn Error>> {
+    let problem = match open_checked(path, config) {
+        Ok(conn) => return Ok((conn, DbHealth::Ok)),
+        Err(OpenError::Corrupt(problem)) => problem,
+        Err(OpenError::Sqlite(e)) => return Err(e.into()),
+    };
+
+    let quarantined = quarantine(path)?;
+    let conn = match open_checked(path, config) {
+        Ok(conn) => conn,
+        Err(OpenError::Corrupt(problem)) => return Err(problem.into()),
+        Err(OpenError::Sqlite(e)) => return Err(e.into()),
+    };
+    // Whatever can't be read is left in the quarantined file
+    let mut failures = Vec::new();
+    let mut counts = [0; 4];
+    match conn.execute("ATTACH DATABASE ?1 AS bad", [quarantined.to_string_lossy()]) {
+        Ok(_) => {
+            let tables = [("imu", IMU_COLUMNS), ("gps", GPS_COLUMNS), ("crashes", CRASH_COLUMNS), ("crash_imu", IMU_COLUMNS)];
+            for (count, (table, columns)) in counts.iter_mut().zip(tables) {
+                match salvage(&conn, table, columns, &mut failures) {
+                    Ok(n) => *count = n,
+                    Err(e) => failures.push(format!("{}: {}", table, e)),
+                }
+            }
+            conn.execute_batch("DETACH DATABASE bad")?;
+        }
+        Err(e) => failures.push(format!("Couldn't read {}: {}", quarantined.display(), e)),
+    }
+    let [imu_rows, gps_rows, crashes, crash_lines] = counts;
+
+    Ok((conn, DbHealth::Quarantined { quarantined, problem, imu_rows, gps_rows, crashes, crash_lines, failures }))
+}
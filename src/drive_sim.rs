@@ -0,0 +1,77 @@
+use crate::gps::{GpsData, GpsVec};
+use crate::gps_sim::{GpsSimConfig, GpsSimulator, Route};
+use crate::imu::{ImuData, ImuVec};
+use crate::imu_sim::{ImuSimConfig, ImuSimulator, MotionProfile};
+
+/// Runs the IMU and GPS simulators off one clock. The truck's motion comes
+/// from the GPS route follower, so the IMU sees the same accelerations and
+/// turns that the GPS track shows.
+pub struct DriveSimulator {
+    imu: ImuSimulator,
+    gps: GpsSimulator,
+    /// GPS time of the next IMU sample, ms since the epoch
+    clock: u64,
+    next_gps: u64,
+    imu_period: u64,
+    gps_period: u64,
+    uuid: u64,
+}
+
+impl DriveSimulator {
+    /// Both simulators start at `gps_config.start_time`; the IMU timestamp
+    /// reads the same Pi clock, offset and drift, as the GPS `pitime`.
+    pub fn new(
+        imu_config: ImuSimConfig,
+        gps_config: GpsSimConfig,
+        route: Route,
+        profile: MotionProfile,
+    ) -> DriveSimulator {
+        let clock = gps_config.start_time;
+        let uuid = gps_config.uuid;
+        let imu = ImuSimulator::new(imu_config, MotionProfile::new());
+        let gps = GpsSimulator::new(gps_config, route, profile);
+        let imu_period = imu.period_ms();
+        let gps_period = gps.period_ms();
+        DriveSimulator {
+            imu,
+            gps,
+            clock,
+            next_gps: clock,
+            imu_period,
+            gps_period,
+            uuid,
+        }
+    }
+
+    /// Advance one IMU period. A GPS fix is returned whenever one falls due.
+    pub fn tick(&mut self) -> (ImuData, Option<GpsData>) {
+        let dt = self.imu_period as f32 / 1000.0;
+        let t = self.clock;
+
+        self.gps.advance(dt);
+        let pitime = self.gps.pitime(t);
+        let imu = self.imu.sample_from_state(pitime, &self.gps.state(), dt);
+
+        let gps = if t >= self.next_gps {
+            self.next_gps += self.gps_period;
+            Some(self.gps.fix(t))
+        } else {
+            None
+        };
+
+        self.clock += self.imu_period;
+        (imu, gps)
+    }
+
+    /// Run for `n` IMU samples, collecting everything produced
+    pub fn run(&mut self, n: usize) -> (ImuVec, GpsVec) {
+        let mut imu = Vec::with_capacity(n);
+        let mut gps = Vec::new();
+        for _ in 0..n {
+            let (i, g) = self.tick();
+            imu.push(i);
+            gps.extend(g);
+        }
+        (ImuVec { data: imu, uuid: self.uuid }, GpsVec { data: gps })
+    }
+}
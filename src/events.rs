@@ -0,0 +1,252 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::gps::GpsData;
+use crate::imu::{ImuData, Orientation, Vector3D};
+
+/// What kind of harsh driving an event is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    HarshBraking,
+    HarshAcceleration,
+    HarshCornering,
+    /// A jolt too hard for driving alone, e.g. a collision or a kerb
+    Impact,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 4] =
+        [EventKind::HarshBraking, EventKind::HarshAcceleration, EventKind::HarshCornering, EventKind::Impact];
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            EventKind::HarshBraking => "braking",
+            EventKind::HarshAcceleration => "acceleration",
+            EventKind::HarshCornering => "cornering",
+            EventKind::Impact => "impact",
+        })
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EventKind::ALL
+            .into_iter()
+            .find(|kind| kind.to_string() == s.to_ascii_lowercase())
+            .ok_or_else(|| format!("Unknown event kind '{}'", s))
+    }
+}
+
+/// Thresholds for each kind of event. Accelerations are in g with gravity
+/// taken out, rotation rates in deg/s.
+#[derive(Debug, Clone)]
+pub struct EventConfig {
+    /// Deceleration along the truck
+    pub braking_g: f32,
+    /// Acceleration along the truck
+    pub acceleration_g: f32,
+    /// Acceleration across the truck, either way
+    pub cornering_g: f32,
+    /// Rate of turn, either way, that is harsh cornering whatever the
+    /// lateral acceleration
+    pub cornering_dps: f32,
+    /// Acceleration in any direction
+    pub impact_g: f32,
+    /// How long a threshold must be passed for before it counts.
+    /// Impacts count at once.
+    pub min_duration: Duration,
+    /// Samples back under a threshold for less than this don't end the
+    /// event, so one manoeuvre is one event
+    pub release: Duration,
+    /// Furthest a GPS fix may be from an event, by pitime, to give its
+    /// position
+    pub max_fix_age: Duration,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        EventConfig {
+            braking_g: 0.45,
+            acceleration_g: 0.35,
+            cornering_g: 0.4,
+            cornering_dps: 25.0,
+            impact_g: 2.5,
+            min_duration: Duration::from_millis(300),
+            release: Duration::from_secs(1),
+            max_fix_age: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Where the truck was, from the GPS fix nearest an event
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventPosition {
+    /// ms since the epoch
+    pub gps_time: u64,
+    pub lat: f32,
+    pub lon: f32,
+    /// m/s
+    pub speed: f32,
+}
+
+/// One harsh manoeuvre or impact
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DrivingEvent {
+    pub kind: EventKind,
+    /// First and last samples past the threshold, Pi clock, ms since the
+    /// epoch
+    pub start: u64,
+    pub end: u64,
+    /// When the event was at its worst
+    pub peak_time: u64,
+    /// Largest acceleration on the event's axis, g, or for an impact in
+    /// any direction
+    pub peak_g: f32,
+    /// Largest rotation rate about any axis, deg/s
+    pub peak_dps: f32,
+    pub position: Option<EventPosition>,
+}
+
+/// Gravity as the accelerometer sees it when tilted by `pose`, g. A
+/// level IMU reads +1 g on z; see imu_sim for the axes.
+pub fn gravity(pose: &Orientation) -> Vector3D {
+    let (sr, cr) = pose.roll.to_radians().sin_cos();
+    let (sp, cp) = pose.pitch.to_radians().sin_cos();
+    Vector3D { x: -sp, y: sr * cp, z: cr * cp }
+}
+
+/// The acceleration from the truck's motion alone, g, with gravity taken
+/// out using the sample's orientation. A sample without one is taken to
+/// be level.
+pub fn linear_accel(d: &ImuData) -> Option<Vector3D> {
+    let inertial = d.inertial.as_ref()?;
+    let accel = inertial.accel?;
+    let g = gravity(&inertial.pose.unwrap_or_default());
+    Some(Vector3D { x: accel.x - g.x, y: accel.y - g.y, z: accel.z - g.z })
+}
+
+/// A threshold passed and not yet released
+#[derive(Debug, Clone, Copy)]
+struct Episode {
+    start: u64,
+    last: u64,
+    peak_time: u64,
+    peak_g: f32,
+    peak_dps: f32,
+}
+
+/// Finds events in a stream of IMU samples in time order, e.g. as the
+/// logger takes them. Events come out once released, so up to
+/// `release` after they end.
+pub struct EventDetector {
+    config: EventConfig,
+    episodes: [Option<Episode>; 4],
+}
+
+impl EventDetector {
+    pub fn new(config: EventConfig) -> EventDetector {
+        EventDetector { config, episodes: [None; 4] }
+    }
+
+    /// How far past its threshold each kind of event is at a sample: the
+    /// acceleration on its axis, g, or None if it isn't passed
+    fn levels(&self, linear: &Vector3D, gyro: &Vector3D) -> [Option<f32>; 4] {
+        let c = &self.config;
+        let past = |value: f32, threshold: f32| (value >= threshold).then_some(value);
+        let magnitude = (linear.x.powi(2) + linear.y.powi(2) + linear.z.powi(2)).sqrt();
+        let cornering = match past(linear.y.abs(), c.cornering_g) {
+            Some(g) => Some(g),
+            None => (gyro.z.abs() >= c.cornering_dps).then_some(linear.y.abs()),
+        };
+        [past(-linear.x, c.braking_g), past(linear.x, c.acceleration_g), cornering, past(magnitude, c.impact_g)]
+    }
+
+    fn finish_episode(&self, kind: EventKind, e: Episode) -> Option<DrivingEvent> {
+        let min_duration = self.config.min_duration.as_millis() as u64;
+        (kind == EventKind::Impact || e.last - e.start >= min_duration).then_some(DrivingEvent {
+            kind,
+            start: e.start,
+            end: e.last,
+            peak_time: e.peak_time,
+            peak_g: e.peak_g,
+            peak_dps: e.peak_dps,
+            position: None,
+        })
+    }
+
+    /// Take the next sample. Returns any events it ends.
+    pub fn push(&mut self, d: &ImuData) -> Vec<DrivingEvent> {
+        let Some(linear) = linear_accel(d) else {
+            return Vec::new();
+        };
+        let gyro = d.inertial.as_ref().and_then(|i| i.gyro).unwrap_or_default();
+        let dps = gyro.x.abs().max(gyro.y.abs()).max(gyro.z.abs());
+        let release = self.config.release.as_millis() as u64;
+        let t = d.timestamp;
+
+        let mut events = Vec::new();
+        for (i, level) in self.levels(&linear, &gyro).into_iter().enumerate() {
+            let kind = EventKind::ALL[i];
+            if let Some(e) = self.episodes[i].filter(|e| level.is_none() && t.saturating_sub(e.last) > release) {
+                events.extend(self.finish_episode(kind, e));
+                self.episodes[i] = None;
+            }
+            let Some(g) = level else { continue };
+            let e = self.episodes[i].get_or_insert(Episode { start: t, last: t, peak_time: t, peak_g: g, peak_dps: dps });
+            e.last = t;
+            e.peak_dps = e.peak_dps.max(dps);
+            if g > e.peak_g {
+                e.peak_g = g;
+                e.peak_time = t;
+            }
+        }
+        events
+    }
+
+    /// Events still open at the end of the stream
+    pub fn finish(self) -> Vec<DrivingEvent> {
+        let mut events: Vec<DrivingEvent> = EventKind::ALL
+            .into_iter()
+            .zip(self.episodes)
+            .filter_map(|(kind, e)| self.finish_episode(kind, e?))
+            .collect();
+        events.sort_by_key(|e| e.start);
+        events
+    }
+}
+
+/// The GPS fix nearest `pitime`, if one is within `max_age`. `gps` is in
+/// pitime order.
+pub fn nearest_fix(gps: &[GpsData], pitime: u64, max_age: Duration) -> Option<&GpsData> {
+    let i = gps.partition_point(|d| d.pitime < pitime);
+    let before = i.checked_sub(1).and_then(|i| gps.get(i));
+    let nearest = [before, gps.get(i)].into_iter().flatten().min_by_key(|d| d.pitime.abs_diff(pitime))?;
+    (nearest.pitime.abs_diff(pitime) <= max_age.as_millis() as u64).then_some(nearest)
+}
+
+/// All the events in a device's IMU samples, in time order, each placed
+/// by the GPS fix nearest its peak
+pub fn detect_events(imu: &[ImuData], gps: &[GpsData], config: &EventConfig) -> Vec<DrivingEvent> {
+    let mut detector = EventDetector::new(config.clone());
+    let mut events: Vec<DrivingEvent> = imu.iter().flat_map(|d| detector.push(d)).collect();
+    events.extend(detector.finish());
+    events.sort_by_key(|e| (e.start, e.end));
+    // Stored fixes come in gps_time order, which a Pi clock stepped by
+    // NTP needn't agree with
+    let mut gps = gps.to_vec();
+    gps.sort_by_key(|d| (d.pitime, d.sequence));
+    for event in &mut events {
+        event.position = nearest_fix(&gps, event.peak_time, config.max_fix_age).map(|d| EventPosition {
+            gps_time: d.gps_time,
+            lat: d.lat,
+            lon: d.lon,
+            speed: d.speed,
+        });
+    }
+    events
+}
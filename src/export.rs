@@ -0,0 +1,42 @@
+// Writes the imu and gps tables of a device or server database out as
+// Parquet, a directory per device and day, for analysis elsewhere.
+use std::path::PathBuf;
+
+use rusqlite::{Connection, OpenFlags};
+
+use grpc_tests::parquet_export::{export_parquet, ExportConfig};
+
+const USAGE: &str = "usage: export --db <file> --out <dir> [--row-group <rows>]";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut db: Option<PathBuf> = None;
+    let mut out: Option<PathBuf> = None;
+    let mut config = ExportConfig::default();
+
+    let mut it = std::env::args().skip(1);
+    while let Some(flag) = it.next() {
+        let value = it.next().ok_or(USAGE)?;
+        match flag.as_str() {
+            "--db" => db = Some(value.into()),
+            "--out" => out = Some(value.into()),
+            "--row-group" => config.row_group_rows = value.parse()?,
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let (db, out) = (db.ok_or(USAGE)?, out.ok_or(USAGE)?);
+    if config.row_group_rows == 0 {
+        return Err(USAGE.into());
+    }
+
+    // Read only, so a logger or server can carry on writing
+    let conn = Connection::open_with_flags(&db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let stats = export_parquet(&conn, &out, &config)?;
+    println!(
+        "{} IMU rows and {} GPS lines written to {} files under {}",
+        stats.imu_rows,
+        stats.gps_rows,
+        stats.files.len(),
+        out.display()
+    );
+    Ok(())
+}
@@ -1,20 +1,22 @@
 use std::time::SystemTime;
 use crate::gps;
+use crate::imu::ImuVec;
+use crate::drive_sim::DriveSimulator;
+use crate::gps_sim::{GpsSimConfig, Route};
+use crate::imu_sim::{ImuSimConfig, MotionProfile};
 
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-
-use gps::{GpsVec, GpsData};
+use gps::GpsVec;
 
 
 
 /// Encode five database fields into one u32
 pub fn encode_fields(status: u8, nsats: u8, valid: bool, uploaded: bool, confirmed: bool)->u32{
 
-    (status as u32 * 65536 + 
+    status as u32 * 65536 + 
     nsats as u32 * 256 + 
     if valid {4} else {0} + 
     if uploaded {2} else {0} + 
-    if confirmed {1} else {0}).into()
+    if confirmed {1} else {0}
 
 }
 
@@ -32,45 +34,25 @@ pub fn decode_fields(num: u32) -> (u8, u8, bool, bool, bool){
     (status, nsats, uploaded, valid, confirmed)
 }
 
-pub fn generate_gps_data(n : usize)->GpsVec{
-    let mut d: Vec::<GpsData> = Vec::new();
-
-    for i in 0..n as u64 {
-        d.push(generate_gps_line(i.into()));
-    }
-
-    GpsVec{ data: d}
-}
-
-pub fn generate_gps_line(sequence : u32)->GpsData{
+/// Simulate a drive along the default route, starting now, returning n
+/// IMU lines and the GPS fixes that go with them
+pub fn generate_drive_data(n : usize)->(ImuVec, GpsVec){
 
     let timestamp = SystemTime::now()
     .duration_since(SystemTime::UNIX_EPOCH)
-    .expect("Unable to calculate current time in imu::cycle")
+    .expect("Unable to calculate current time in gps::cycle")
     .as_millis() as u64;
-    
-    let uuid = 0x1234567890AB;
-    let pitime = 2000_000_000;
-    let gps_time = 1999_999_999;
-    let lat : f32 = 50.123456;
-    let lon : f32 = -4.9987654;
-    let alt : f32 = 100.45;
-    let speed: f32 = 10.0;
-    let track: f32 = 359.995566;
-    let hdop : f32 = 12.4321;
-    let status_nsats_vuc = encode_fields(1, 12, true, false, false);
-    
-    GpsData { 
-        uuid,
-        pitime,
-        gps_time,
-        sequence,
-        lat,
-        lon,
-        alt,
-        speed,
-        track,
-        status_nsats_vuc,
-        hdop,
-    }
-}
\ No newline at end of file
+
+    let gps_config = GpsSimConfig {
+        start_time: timestamp,
+        ..Default::default()
+    };
+
+    let mut drive = DriveSimulator::new(
+        ImuSimConfig::default(),
+        gps_config,
+        Route::camborne(),
+        MotionProfile::default(),
+    );
+    drive.run(n)
+}
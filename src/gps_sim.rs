@@ -0,0 +1,413 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution, Normal};
+
+use crate::fake_gps::encode_fields;
+use crate::gps::GpsData;
+use crate::imu_sim::{MotionProfile, VehicleState};
+use crate::ubx::TIME_VALID_ALL;
+
+/// Mean radius of the earth, m
+pub const EARTH_RADIUS: f64 = 6_371_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Waypoint {
+    pub lat: f64,
+    pub lon: f64,
+    /// Altitude above mean sea level, m
+    pub alt: f32,
+}
+
+impl Waypoint {
+    pub fn new(lat: f64, lon: f64, alt: f32) -> Waypoint {
+        Waypoint { lat, lon, alt }
+    }
+}
+
+/// Great circle distance between two points, m
+pub fn distance(a: &Waypoint, b: &Waypoint) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.lon - a.lon).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().asin()
+}
+
+/// Initial bearing from a to b, degrees clockwise from north
+pub fn bearing(a: &Waypoint, b: &Waypoint) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let dlon = (b.lon - a.lon).to_radians();
+    let y = dlon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+    y.atan2(x).to_degrees().rem_euclid(360.0)
+}
+
+/// A polyline for the truck to drive along
+#[derive(Debug, Clone)]
+pub struct Route {
+    points: Vec<Waypoint>,
+    /// Distance from the start to each point, m
+    cumulative: Vec<f64>,
+}
+
+impl Route {
+    pub fn new(points: Vec<Waypoint>) -> Route {
+        assert!(!points.is_empty(), "A route needs at least one waypoint");
+        let mut cumulative = vec![0.0];
+        for pair in points.windows(2) {
+            let last = cumulative[cumulative.len() - 1];
+            cumulative.push(last + distance(&pair[0], &pair[1]));
+        }
+        Route { points, cumulative }
+    }
+
+    /// A few km of road around Camborne
+    pub fn camborne() -> Route {
+        Route::new(vec![
+            Waypoint::new(50.123456, -4.998765, 100.4),
+            Waypoint::new(50.126012, -4.990310, 104.0),
+            Waypoint::new(50.129870, -4.985120, 112.5),
+            Waypoint::new(50.132140, -4.975400, 121.0),
+            Waypoint::new(50.130020, -4.962850, 117.2),
+            Waypoint::new(50.124870, -4.955010, 98.6),
+            Waypoint::new(50.119440, -4.958740, 88.3),
+        ])
+    }
+
+    pub fn length(&self) -> f64 {
+        self.cumulative[self.cumulative.len() - 1]
+    }
+
+    /// Position and track `along` metres from the start. Past the end the
+    /// truck sits at the last waypoint.
+    pub fn position(&self, along: f64) -> (Waypoint, f64) {
+        if self.points.len() == 1 {
+            return (self.points[0], 0.0);
+        }
+        let along = along.clamp(0.0, self.length());
+        let i = match self.cumulative.iter().rposition(|&d| d <= along) {
+            Some(i) if i < self.points.len() - 1 => i,
+            _ => self.points.len() - 2,
+        };
+        let (a, b) = (&self.points[i], &self.points[i + 1]);
+        let span = self.cumulative[i + 1] - self.cumulative[i];
+        let f = if span > 0.0 { (along - self.cumulative[i]) / span } else { 0.0 };
+        let point = Waypoint {
+            lat: a.lat + (b.lat - a.lat) * f,
+            lon: a.lon + (b.lon - a.lon) * f,
+            alt: a.alt + (b.alt - a.alt) * f as f32,
+        };
+        (point, bearing(a, b))
+    }
+}
+
+/// A stretch of route, in metres from the start, where there's no sky view
+#[derive(Debug, Clone, Copy)]
+pub struct Tunnel {
+    pub start: f64,
+    pub end: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct GpsSimConfig {
+    pub seed: u64,
+    pub uuid: u64,
+    pub sample_rate_hz: f32,
+    /// GPS time of the first fix, ms since the epoch
+    pub start_time: u64,
+    /// How far the Pi clock is ahead of GPS time, ms
+    pub pi_clock_offset: i64,
+    /// How fast the Pi clock gains on GPS time from start_time, ppm
+    pub pi_clock_drift: f64,
+    pub position_noise: f64,
+    pub alt_noise: f32,
+    pub speed_noise: f32,
+    pub base_hdop: f32,
+    /// Random walk of the HDOP, per √s
+    pub hdop_walk: f32,
+    pub max_sats: u8,
+    pub tunnels: Vec<Tunnel>,
+}
+
+impl Default for GpsSimConfig {
+    fn default() -> Self {
+        GpsSimConfig {
+            seed: 0,
+            uuid: 0x1234567890AB,
+            sample_rate_hz: 1.0,
+            start_time: 1_700_000_000_000,
+            pi_clock_offset: 0,
+            pi_clock_drift: 0.0,
+            position_noise: 2.0,
+            alt_noise: 4.0,
+            speed_noise: 0.1,
+            base_hdop: 0.9,
+            hdop_walk: 0.1,
+            max_sats: 12,
+            tunnels: vec![Tunnel { start: 1500.0, end: 1800.0 }],
+        }
+    }
+}
+
+impl GpsSimConfig {
+    /// What the Pi clock reads at `gps_time`
+    pub fn pitime(&self, gps_time: u64) -> u64 {
+        let drift = (gps_time as i64 - self.start_time as i64) as f64 * self.pi_clock_drift * 1e-6;
+        (gps_time as i64 + self.pi_clock_offset + drift.round() as i64) as u64
+    }
+}
+
+/// Limits on how hard the simulated truck turns, m/s² and deg/s
+const MAX_LATERAL_ACCEL: f32 = 3.0;
+const MAX_YAW_RATE: f32 = 25.0;
+
+/// Follows a `Route` at the speed set by a `MotionProfile` and reports
+/// noisy fixes, losing them inside tunnels.
+pub struct GpsSimulator {
+    config: GpsSimConfig,
+    route: Route,
+    profile: MotionProfile,
+    rng: StdRng,
+    unit: Normal<f64>,
+    state: VehicleState,
+    along: f64,
+    elapsed: f32,
+    hdop: f32,
+    sequence: u32,
+    last_fix: Option<GpsData>,
+}
+
+impl GpsSimulator {
+    pub fn new(config: GpsSimConfig, route: Route, profile: MotionProfile) -> GpsSimulator {
+        let rng = StdRng::seed_from_u64(config.seed);
+        let (start, track) = route.position(0.0);
+        let hdop = config.base_hdop;
+        GpsSimulator {
+            config,
+            route,
+            profile,
+            rng,
+            unit: Normal::new(0.0, 1.0).expect("unit normal is always valid"),
+            state: VehicleState { heading: track as f32, altitude: start.alt, ..Default::default() },
+            along: 0.0,
+            elapsed: 0.0,
+            hdop,
+            sequence: 0,
+            last_fix: None,
+        }
+    }
+
+    pub fn period_ms(&self) -> u64 {
+        (1000.0 / self.config.sample_rate_hz).round() as u64
+    }
+
+    /// What the Pi clock reads at `gps_time`
+    pub fn pitime(&self, gps_time: u64) -> u64 {
+        self.config.pitime(gps_time)
+    }
+
+    /// Current vehicle state, so an IMU simulator can follow the same drive
+    pub fn state(&self) -> VehicleState {
+        self.state
+    }
+
+    fn noise(&mut self, sigma: f64) -> f64 {
+        sigma * self.unit.sample(&mut self.rng)
+    }
+
+    fn in_tunnel(&self) -> bool {
+        self.config
+            .tunnels
+            .iter()
+            .any(|t| self.along >= t.start && self.along < t.end)
+    }
+
+    /// Move the truck on by `dt` seconds
+    pub fn advance(&mut self, dt: f32) {
+        let manoeuvre = self.profile.at(self.elapsed);
+        let mut state = self.state;
+        state.step(manoeuvre, dt);
+        self.along += ((self.state.speed + state.speed) / 2.0 * dt) as f64;
+        if self.along >= self.route.length() {
+            self.along = self.route.length();
+            state.speed = 0.0;
+            state.accel = 0.0;
+        }
+
+        // Heading follows the road, not the profile, but a truck can't
+        // take a corner at more than about MAX_LATERAL_ACCEL
+        let (point, track) = self.route.position(self.along);
+        state.altitude = point.alt;
+        let turn = (track as f32 - self.state.heading + 540.0).rem_euclid(360.0) - 180.0;
+        let max_rate = if state.speed > 0.0 {
+            (MAX_LATERAL_ACCEL / state.speed).to_degrees().min(MAX_YAW_RATE)
+        } else {
+            0.0
+        };
+        let turn = turn.clamp(-max_rate * dt, max_rate * dt);
+        state.heading = (self.state.heading + turn).rem_euclid(360.0);
+        state.yaw_rate = if dt > 0.0 { -turn / dt } else { 0.0 };
+
+        self.state = state;
+        self.elapsed += dt;
+    }
+
+    /// Report a fix for the current position at the given GPS time
+    pub fn fix(&mut self, gps_time: u64) -> GpsData {
+        let dt = 1.0 / self.config.sample_rate_hz;
+        let walk = self.noise((self.config.hdop_walk * dt.sqrt()) as f64) as f32;
+        self.hdop = (self.hdop + walk).clamp(0.5, 5.0);
+
+        let pitime = self.config.pitime(gps_time);
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        if self.in_tunnel() {
+            // No fix: repeat the last known position, flagged as invalid
+            let last = self.last_fix;
+            let (point, track) = self.route.position(self.along);
+            return GpsData {
+                uuid: self.config.uuid,
+                pitime,
+                gps_time,
+                sequence,
+                lat: last.as_ref().map_or(point.lat as f32, |f| f.lat),
+                lon: last.as_ref().map_or(point.lon as f32, |f| f.lon),
+                alt: last.as_ref().map_or(point.alt, |f| f.alt),
+                speed: 0.0,
+                track: last.as_ref().map_or(track as f32, |f| f.track),
+                status_nsats_vuc: encode_fields(0, 0, false, false, false),
+                hdop: 99.99,
+                h_acc: 0.0,
+                v_acc: 0.0,
+                speed_acc: 0.0,
+                // The receiver keeps time without a sky view
+                time_valid: TIME_VALID_ALL,
+                pi_stamp: None,
+                gps_stamp: None,
+            };
+        }
+
+        let (point, _) = self.route.position(self.along);
+        // Noise grows with HDOP
+        let sigma = self.config.position_noise * self.hdop as f64;
+        let north = self.noise(sigma);
+        let east = self.noise(sigma);
+        let lat = point.lat + (north / EARTH_RADIUS).to_degrees();
+        let lon = point.lon + (east / (EARTH_RADIUS * point.lat.to_radians().cos())).to_degrees();
+        let alt = point.alt + self.noise((self.config.alt_noise * self.hdop) as f64) as f32;
+        let speed = (self.state.speed + self.noise(self.config.speed_noise as f64) as f32).max(0.0);
+
+        // Fewer satellites in view when the geometry is poor
+        let nsats = (self.config.max_sats as f32 / self.hdop.max(1.0)).round().max(4.0) as u8;
+
+        let fix = GpsData {
+            uuid: self.config.uuid,
+            pitime,
+            gps_time,
+            sequence,
+            lat: lat as f32,
+            lon: lon as f32,
+            alt,
+            speed,
+            track: self.state.heading,
+            status_nsats_vuc: encode_fields(1, nsats, true, false, false),
+            hdop: self.hdop,
+            h_acc: sigma as f32,
+            v_acc: self.config.alt_noise * self.hdop,
+            speed_acc: self.config.speed_noise,
+            time_valid: TIME_VALID_ALL,
+            pi_stamp: None,
+            gps_stamp: None,
+        };
+        self.last_fix = Some(fix);
+        fix
+    }
+
+    /// Advance one sample period and report a fix
+    pub fn next_sample(&mut self) -> GpsData {
+        let gps_time = self.config.start_time + self.sequence as u64 * self.period_ms();
+        if self.sequence > 0 {
+            self.advance(1.0 / self.config.sample_rate_hz);
+        }
+        self.fix(gps_time)
+    }
+}
+
+impl Iterator for GpsSimulator {
+    type Item = GpsData;
+
+    fn next(&mut self) -> Option<GpsData> {
+        Some(self.next_sample())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_gps::decode_fields;
+    use crate::imu_sim::Manoeuvre;
+
+    /// 5 km due east at 50°N, driven at 20 m/s after a few seconds
+    fn straight() -> (Route, MotionProfile) {
+        let route = Route::new(vec![Waypoint::new(50.0, -5.0, 100.0), Waypoint::new(50.0, -4.93, 100.0)]);
+        let profile = MotionProfile::new()
+            .then(Manoeuvre::Accelerate { rate: 5.0 }, 4.0)
+            .then(Manoeuvre::Cruise, 1000.0);
+        (route, profile)
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_fixes() {
+        let run = |seed| -> Vec<GpsData> {
+            let config = GpsSimConfig { seed, ..Default::default() };
+            GpsSimulator::new(config, Route::camborne(), MotionProfile::urban_loop()).take(300).collect()
+        };
+        assert_eq!(run(3), run(3));
+        assert_ne!(run(3), run(4));
+    }
+
+    #[test]
+    fn fixes_are_lost_in_a_tunnel() {
+        let (route, profile) = straight();
+        let config = GpsSimConfig { tunnels: vec![Tunnel { start: 1000.0, end: 1400.0 }], ..Default::default() };
+        let mut sim = GpsSimulator::new(config, route, profile);
+        let mut last_valid = None;
+        let mut lost = 0;
+        for _ in 0..150 {
+            let fix = sim.next_sample();
+            let (status, nsats, valid, _, _) = decode_fields(fix.status_nsats_vuc);
+            if (1000.0..1400.0).contains(&sim.along) {
+                assert_eq!((status, nsats, valid, fix.hdop, fix.speed), (0, 0, false, 99.99, 0.0));
+                // The last position is held, not the true one
+                let held: GpsData = last_valid.unwrap();
+                assert_eq!((fix.lat, fix.lon), (held.lat, held.lon));
+                lost += 1;
+            } else {
+                assert!(valid && status == 1 && nsats >= 4, "{:?}", fix);
+                last_valid = Some(fix);
+            }
+        }
+        // 400 m at 20 m/s
+        assert_eq!(lost, 20);
+    }
+
+    #[test]
+    fn poor_geometry_means_fewer_satellites_and_more_noise() {
+        let (route, profile) = straight();
+        let config = GpsSimConfig { hdop_walk: 2.0, tunnels: Vec::new(), ..Default::default() };
+        let fixes: Vec<GpsData> = GpsSimulator::new(config.clone(), route.clone(), profile).take(500).collect();
+        assert!(fixes.iter().all(|f| (0.5..=5.0).contains(&f.hdop)));
+        assert!(fixes.iter().any(|f| f.hdop > 3.0) && fixes.iter().any(|f| f.hdop < 1.0));
+        for fix in &fixes {
+            let (_, nsats, _, _, _) = decode_fields(fix.status_nsats_vuc);
+            let expected = (config.max_sats as f32 / fix.hdop.max(1.0)).round().max(4.0) as u8;
+            assert_eq!(nsats, expected);
+            assert!((fix.h_acc - config.position_noise as f32 * fix.hdop).abs() < 1e-4);
+            assert!((fix.v_acc - config.alt_noise * fix.hdop).abs() < 1e-4);
+        }
+        let worst = fixes.iter().max_by(|a, b| a.hdop.total_cmp(&b.hdop)).unwrap();
+        let (_, nsats, _, _, _) = decode_fields(worst.status_nsats_vuc);
+        assert!(nsats < config.max_sats);
+    }
+}
@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::data_defs::Timestamp;
+use crate::gps::GpsData;
+use crate::gps_sim::GpsSimulator;
+use crate::nmea::{self, FixBuilder, NmeaFix};
+use crate::ubx::{self, Decoder, Message, NavDop, NavStatus, UbxError};
+use crate::writer::RowSink;
+
+/// The kinds of GPS a device can have
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GpsType {
+    FakeType,
+    NmeaType,
+    UbxType,
+}
+
+/// Anything that reports GPS fixes
+pub trait GpsSource {
+    fn gps_type(&self) -> GpsType;
+
+    /// Wait for the next fix. None when the source has nothing more to
+    /// give, e.g. at the end of a log or when the receiver goes away.
+    fn next_fix(&mut self) -> Option<GpsData>;
+}
+
+/// A GPS backed by the simulator
+pub struct FakeGps {
+    sim: GpsSimulator,
+}
+
+impl FakeGps {
+    pub fn new(sim: GpsSimulator) -> FakeGps {
+        FakeGps { sim }
+    }
+}
+
+impl GpsSource for FakeGps {
+    fn gps_type(&self) -> GpsType {
+        GpsType::FakeType
+    }
+
+    fn next_fix(&mut self) -> Option<GpsData> {
+        Some(self.sim.next_sample())
+    }
+}
+
+/// Lines read from an NMEA stream and what became of them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NmeaStats {
+    pub lines: u64,
+    pub sentences: u64,
+    /// Sentences this parser doesn't use, e.g. GLL or proprietary ones
+    pub unsupported: u64,
+    /// Lines that failed their checksum or couldn't be parsed
+    pub errors: u64,
+    pub fixes: u64,
+}
+
+/// A receiver talking NMEA 0183 over anything that can be read: a serial
+/// port, a pipe, a pseudo-terminal or a recorded log
+pub struct NmeaGps<R: Read> {
+    reader: BufReader<R>,
+    builder: FixBuilder,
+    uuid: u64,
+    sequence: u32,
+    replaying: bool,
+    done: bool,
+    stats: NmeaStats,
+}
+
+impl NmeaGps<File> {
+    /// Replay a recorded log
+    pub fn open(path: &Path, uuid: u64) -> io::Result<NmeaGps<File>> {
+        Ok(NmeaGps::new(File::open(path)?, uuid).replaying())
+    }
+}
+
+impl<R: Read> NmeaGps<R> {
+    pub fn new(reader: R, uuid: u64) -> NmeaGps<R> {
+        NmeaGps {
+            reader: BufReader::new(reader),
+            builder: FixBuilder::new(),
+            uuid,
+            sequence: 0,
+            replaying: false,
+            done: false,
+            stats: NmeaStats::default(),
+        }
+    }
+
+    /// Stamp each fix with its GPS time instead of the Pi clock, for logs
+    /// recorded earlier
+    pub fn replaying(mut self) -> NmeaGps<R> {
+        self.replaying = true;
+        self
+    }
+
+    pub fn stats(&self) -> &NmeaStats {
+        &self.stats
+    }
+
+    fn stamp(&mut self, fix: NmeaFix) -> GpsData {
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.stats.fixes += 1;
+        stamp_pitime(fix.to_gps_data(self.uuid, 0, sequence), self.replaying)
+    }
+}
+
+/// A live fix is stamped with the Pi clock as it's read. A replayed one
+/// is given its own GPS time, as there's no Pi clock reading to go on.
+fn stamp_pitime(mut fix: GpsData, replaying: bool) -> GpsData {
+    if replaying {
+        fix.pitime = fix.gps_time;
+    } else {
+        let now = Timestamp::now();
+        fix.pitime = now.as_millis();
+        fix.pi_stamp = Some(now.into());
+    }
+    fix
+}
+
+impl<R: Read> GpsSource for NmeaGps<R> {
+    fn gps_type(&self) -> GpsType {
+        GpsType::NmeaType
+    }
+
+    fn next_fix(&mut self) -> Option<GpsData> {
+        let mut line = Vec::new();
+        while !self.done {
+            line.clear();
+            // Receivers start up mid-sentence and serial lines drop bytes,
+            // so a line that isn't UTF-8 is just another bad line
+            match self.reader.read_until(b'\n', &mut line) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(0) | Err(_) => {
+                    self.done = true;
+                    break;
+                }
+                Ok(_) => {}
+            }
+            let text = String::from_utf8_lossy(&line);
+            if text.trim().is_empty() {
+                continue;
+            }
+            self.stats.lines += 1;
+            match nmea::parse(&text) {
+                Ok(sentence) => {
+                    self.stats.sentences += 1;
+                    if let Some(fix) = self.builder.push(sentence) {
+                        return Some(self.stamp(fix));
+                    }
+                }
+                Err(nmea::NmeaError::Unsupported(_)) => self.stats.unsupported += 1,
+                Err(_) => self.stats.errors += 1,
+            }
+        }
+        let fix = self.builder.finish()?;
+        Some(self.stamp(fix))
+    }
+}
+
+/// Frames read from a UBX stream and what became of them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UbxStats {
+    pub frames: u64,
+    /// Messages this parser doesn't use
+    pub unsupported: u64,
+    /// Frames that failed their checksum or had the wrong length
+    pub errors: u64,
+    /// Bytes between frames, e.g. NMEA sent on the same port
+    pub skipped: u64,
+    pub fixes: u64,
+}
+
+/// A u-blox receiver sending UBX over anything that can be read. There is
+/// one fix per NAV-PVT. The receiver sends each epoch's messages in ID
+/// order, so the NAV-STATUS and NAV-DOP for an epoch arrive before its
+/// NAV-PVT, and both go into its fix.
+pub struct UbxGps<R: Read> {
+    reader: R,
+    decoder: Decoder,
+    uuid: u64,
+    sequence: u32,
+    replaying: bool,
+    done: bool,
+    dop: Option<NavDop>,
+    status: Option<NavStatus>,
+    stats: UbxStats,
+}
+
+impl UbxGps<File> {
+    /// Replay a capture of the receiver's output
+    pub fn open(path: &Path, uuid: u64) -> io::Result<UbxGps<File>> {
+        Ok(UbxGps::new(File::open(path)?, uuid).replaying())
+    }
+}
+
+impl<R: Read> UbxGps<R> {
+    pub fn new(reader: R, uuid: u64) -> UbxGps<R> {
+        UbxGps {
+            reader,
+            decoder: Decoder::new(),
+            uuid,
+            sequence: 0,
+            replaying: false,
+            done: false,
+            dop: None,
+            status: None,
+            stats: UbxStats::default(),
+        }
+    }
+
+    /// Stamp each fix with its GPS time instead of the Pi clock, for
+    /// captures recorded earlier
+    pub fn replaying(mut self) -> UbxGps<R> {
+        self.replaying = true;
+        self
+    }
+
+    pub fn stats(&self) -> &UbxStats {
+        &self.stats
+    }
+
+    /// The last NAV-STATUS, for time to first fix and receiver uptime
+    pub fn status(&self) -> Option<&NavStatus> {
+        self.status.as_ref()
+    }
+
+    /// Decode what's already been read. Returns a fix if that includes a
+    /// NAV-PVT.
+    fn decode_buffered(&mut self) -> Option<GpsData> {
+        while let Some(frame) = self.decoder.next_frame() {
+            self.stats.skipped = self.decoder.skipped();
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(_) => {
+                    self.stats.errors += 1;
+                    continue;
+                }
+            };
+            self.stats.frames += 1;
+            match ubx::decode(&frame) {
+                Ok(Message::NavPvt(pvt)) => {
+                    let sequence = self.sequence;
+                    self.sequence = self.sequence.wrapping_add(1);
+                    self.stats.fixes += 1;
+                    let fix = pvt.to_gps_data(self.dop.as_ref(), self.status.as_ref(), self.uuid, 0, sequence);
+                    return Some(stamp_pitime(fix, self.replaying));
+                }
+                Ok(Message::NavDop(dop)) => self.dop = Some(dop),
+                Ok(Message::NavStatus(status)) => self.status = Some(status),
+                Err(UbxError::Unsupported { .. }) => self.stats.unsupported += 1,
+                Err(_) => self.stats.errors += 1,
+            }
+        }
+        self.stats.skipped = self.decoder.skipped();
+        None
+    }
+}
+
+impl<R: Read> GpsSource for UbxGps<R> {
+    fn gps_type(&self) -> GpsType {
+        GpsType::UbxType
+    }
+
+    fn next_fix(&mut self) -> Option<GpsData> {
+        let mut chunk = [0u8; 512];
+        loop {
+            if let Some(fix) = self.decode_buffered() {
+                return Some(fix);
+            }
+            if self.done {
+                return None;
+            }
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.done = true,
+                Ok(n) => self.decoder.feed(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => self.done = true,
+            }
+        }
+    }
+}
+
+/// Put each fix from `source` in `sink` until the source runs out or
+/// `stop` is set. Returns how many were stored.
+pub fn log_gps(source: &mut dyn GpsSource, sink: &dyn RowSink, stop: &AtomicBool) -> Result<u64, rusqlite::Error> {
+    let mut stored = 0;
+    while !stop.load(Ordering::Relaxed) {
+        match source.next_fix() {
+            Some(fix) => stored += sink.put(fix.into())? as u64,
+            None => break,
+        }
+    }
+    Ok(stored)
+}
@@ -0,0 +1,67 @@
+// Lists the harsh braking, acceleration, cornering and impacts in a
+// device's IMU lines, as stored by the server.
+use grpc_tests::calendar::iso_time;
+use grpc_tests::events::{detect_events, EventConfig};
+use grpc_tests::imu::ImuData;
+use grpc_tests::store::{ServerStore, TelemetryStore};
+
+const USAGE: &str = "usage: harsh --uuid <n> [--db <file>] [--from <ms>] [--to <ms>]
+             [--braking <g>] [--acceleration <g>] [--cornering <g>] [--impact <g>]";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut db = String::from("./server_data.db3");
+    let mut uuid: Option<u64> = None;
+    let (mut from, mut to) = (0, u64::MAX);
+    let mut config = EventConfig::default();
+
+    let mut it = std::env::args().skip(1);
+    while let Some(flag) = it.next() {
+        let value = it.next().ok_or(USAGE)?;
+        match flag.as_str() {
+            "--db" => db = value,
+            "--uuid" => uuid = Some(value.parse()?),
+            "--from" => from = value.parse()?,
+            "--to" => to = value.parse()?,
+            "--braking" => config.braking_g = value.parse()?,
+            "--acceleration" => config.acceleration_g = value.parse()?,
+            "--cornering" => config.cornering_g = value.parse()?,
+            "--impact" => config.impact_g = value.parse()?,
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let uuid = uuid.ok_or(USAGE)?;
+
+    let store = ServerStore::open(&db)?;
+    let rows = store.imu_range(uuid, from..to).map_err(|e| e as Box<dyn std::error::Error>)?;
+    // The fixes around those lines, on GPS time where the server has put
+    // the lines on it
+    let times = rows.iter().map(|r| if r.gps_time > 0 { r.gps_time } else { r.pitime });
+    let (start, end) = times.fold((u64::MAX, 0), |(start, end), t| (start.min(t), end.max(t)));
+    let margin = config.max_fix_age.as_millis() as u64;
+    let gps = if rows.is_empty() {
+        Vec::new()
+    } else {
+        store
+            .gps_range(uuid, start.saturating_sub(margin)..end.saturating_add(margin + 1))
+            .map_err(|e| e as Box<dyn std::error::Error>)?
+    };
+    let imu: Vec<ImuData> = rows.into_iter().map(ImuData::from).collect();
+    let events = detect_events(&imu, &gps, &config);
+    for e in &events {
+        let place = match e.position {
+            Some(p) => format!("{},{} at {:.1} m/s", p.lat, p.lon, p.speed),
+            None => "no fix".to_string(),
+        };
+        println!(
+            "{} {:<12} {:.2} g {:.0} deg/s over {} ms, {}",
+            iso_time(e.peak_time),
+            e.kind,
+            e.peak_g,
+            e.peak_dps,
+            e.end - e.start,
+            place
+        );
+    }
+    println!("{} events in {} IMU lines", events.len(), imu.len());
+    Ok(())
+}
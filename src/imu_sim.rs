@@ -0,0 +1,402 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_distr::{Distribution, Normal};
+
+use crate::baro::altitude_to_pressure;
+use crate::data_defs::{Length, Pressure, STANDARD_GRAVITY};
+use crate::imu::{ImuData, Inertial, Orientation, Vector3D};
+
+/// A single driving manoeuvre. Rates are in m/s² and deg/s.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Manoeuvre {
+    Idle,
+    Accelerate { rate: f32 },
+    Cruise,
+    Corner { yaw_rate: f32 },
+    Brake { rate: f32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProfileSegment {
+    pub manoeuvre: Manoeuvre,
+    pub duration_s: f32,
+}
+
+/// A sequence of manoeuvres that the simulated truck follows.
+/// The profile repeats once it reaches the end.
+#[derive(Debug, Clone)]
+pub struct MotionProfile {
+    segments: Vec<ProfileSegment>,
+}
+
+impl MotionProfile {
+    pub fn new() -> MotionProfile {
+        MotionProfile { segments: Vec::new() }
+    }
+
+    /// Append a manoeuvre lasting `duration_s` seconds
+    pub fn then(mut self, manoeuvre: Manoeuvre, duration_s: f32) -> MotionProfile {
+        self.segments.push(ProfileSegment { manoeuvre, duration_s });
+        self
+    }
+
+    /// Pull away, drive round a corner, stop and wait - about two minutes
+    pub fn urban_loop() -> MotionProfile {
+        MotionProfile::new()
+            .then(Manoeuvre::Idle, 10.0)
+            .then(Manoeuvre::Accelerate { rate: 1.2 }, 12.0)
+            .then(Manoeuvre::Cruise, 20.0)
+            .then(Manoeuvre::Corner { yaw_rate: 9.0 }, 10.0)
+            .then(Manoeuvre::Cruise, 15.0)
+            .then(Manoeuvre::Corner { yaw_rate: -12.0 }, 7.5)
+            .then(Manoeuvre::Cruise, 20.0)
+            .then(Manoeuvre::Brake { rate: 2.5 }, 8.0)
+            .then(Manoeuvre::Idle, 20.0)
+    }
+
+    pub fn duration_s(&self) -> f32 {
+        self.segments.iter().map(|s| s.duration_s).sum()
+    }
+
+    /// The manoeuvre in force `t_s` seconds after the start of the profile
+    pub fn at(&self, t_s: f32) -> Manoeuvre {
+        let total = self.duration_s();
+        if self.segments.is_empty() || total <= 0.0 {
+            return Manoeuvre::Idle;
+        }
+        let mut t = t_s.rem_euclid(total);
+        for segment in &self.segments {
+            if t < segment.duration_s {
+                return segment.manoeuvre;
+            }
+            t -= segment.duration_s;
+        }
+        self.segments[self.segments.len() - 1].manoeuvre
+    }
+}
+
+impl Default for MotionProfile {
+    fn default() -> Self {
+        MotionProfile::urban_loop()
+    }
+}
+
+/// Kinematic state of the truck, in the vehicle frame
+/// (x forward, y left, z up).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VehicleState {
+    /// Ground speed, m/s
+    pub speed: f32,
+    /// Heading, degrees clockwise from north
+    pub heading: f32,
+    /// Longitudinal acceleration, m/s²
+    pub accel: f32,
+    /// Rate of turn, deg/s, positive turning left
+    pub yaw_rate: f32,
+    /// Height above sea level, m
+    pub altitude: f32,
+}
+
+impl VehicleState {
+    /// Advance the state by `dt` seconds under the given manoeuvre
+    pub fn step(&mut self, manoeuvre: Manoeuvre, dt: f32) {
+        let (accel, yaw_rate) = match manoeuvre {
+            Manoeuvre::Idle => (0.0, 0.0),
+            Manoeuvre::Accelerate { rate } => (rate, 0.0),
+            Manoeuvre::Cruise => (0.0, 0.0),
+            Manoeuvre::Corner { yaw_rate } => (0.0, yaw_rate),
+            Manoeuvre::Brake { rate } => (-rate, 0.0),
+        };
+        let speed = (self.speed + accel * dt).max(0.0);
+        // Once stopped the truck can't keep decelerating or turn on the spot
+        self.accel = if dt > 0.0 { (speed - self.speed) / dt } else { 0.0 };
+        self.yaw_rate = if speed > 0.0 { yaw_rate } else { 0.0 };
+        self.speed = speed;
+        self.heading = (self.heading - self.yaw_rate * dt).rem_euclid(360.0);
+    }
+
+    /// Lateral (centripetal) acceleration, m/s², positive to the left
+    pub fn lateral_accel(&self) -> f32 {
+        self.speed * self.yaw_rate.to_radians()
+    }
+}
+
+/// Settings for the IMU simulator. Accelerations are in g, rotation rates
+/// in deg/s, angles in degrees, magnetic field in µT and pressure in Pa.
+#[derive(Debug, Clone)]
+pub struct ImuSimConfig {
+    pub seed: u64,
+    pub sample_rate_hz: f32,
+    /// Timestamp of the first sample, ms since the epoch
+    pub start_timestamp: u64,
+    pub accel_noise: f32,
+    pub gyro_noise: f32,
+    pub mag_noise: f32,
+    /// Random walk of the bias, per √s
+    pub accel_bias_walk: f32,
+    pub gyro_bias_walk: f32,
+    /// Extra accelerometer noise from the engine while idling
+    pub idle_vibration: f32,
+    /// Body roll/pitch per m/s² of acceleration, degrees
+    pub body_tilt: f32,
+    /// Pressure at sea level, Pa
+    pub pressure: f32,
+    pub pressure_noise: f32,
+    pub temperature: f32,
+    pub temp_cpu: f32,
+    pub heading_accuracy: f32,
+}
+
+impl Default for ImuSimConfig {
+    fn default() -> Self {
+        ImuSimConfig {
+            seed: 0,
+            sample_rate_hz: 10.0,
+            start_timestamp: 1_700_000_000_000,
+            accel_noise: 0.01,
+            gyro_noise: 0.1,
+            mag_noise: 0.5,
+            accel_bias_walk: 0.0005,
+            gyro_bias_walk: 0.005,
+            idle_vibration: 0.02,
+            body_tilt: 0.8,
+            pressure: 101_320.0,
+            pressure_noise: 2.0,
+            temperature: 23.0,
+            temp_cpu: 55.0,
+            heading_accuracy: 3.2,
+        }
+    }
+}
+
+/// Horizontal and vertical components of the earth's field, µT
+const MAG_HORIZONTAL: f32 = 19.0;
+const MAG_VERTICAL: f32 = -44.0;
+
+/// Produces a plausible stream of IMU samples for a truck following a
+/// `MotionProfile`. The same seed always gives the same stream.
+pub struct ImuSimulator {
+    config: ImuSimConfig,
+    profile: MotionProfile,
+    rng: StdRng,
+    unit: Normal<f32>,
+    state: VehicleState,
+    roll: f32,
+    pitch: f32,
+    accel_bias: [f32; 3],
+    gyro_bias: [f32; 3],
+    sequence: u32,
+    elapsed: f32,
+}
+
+impl ImuSimulator {
+    pub fn new(config: ImuSimConfig, profile: MotionProfile) -> ImuSimulator {
+        let rng = StdRng::seed_from_u64(config.seed);
+        ImuSimulator {
+            config,
+            profile,
+            rng,
+            unit: Normal::new(0.0, 1.0).expect("unit normal is always valid"),
+            state: VehicleState::default(),
+            roll: 0.0,
+            pitch: 0.0,
+            accel_bias: [0.0; 3],
+            gyro_bias: [0.0; 3],
+            sequence: 0,
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn period_ms(&self) -> u64 {
+        (1000.0 / self.config.sample_rate_hz).round() as u64
+    }
+
+    fn noise(&mut self, sigma: f32) -> f32 {
+        sigma * self.unit.sample(&mut self.rng)
+    }
+
+    /// Step the vehicle along its motion profile and return the next sample
+    pub fn next_sample(&mut self) -> ImuData {
+        let dt = 1.0 / self.config.sample_rate_hz;
+        let manoeuvre = self.profile.at(self.elapsed);
+        let mut state = self.state;
+        state.step(manoeuvre, dt);
+        let timestamp = self.config.start_timestamp + self.sequence as u64 * self.period_ms();
+        self.elapsed += dt;
+        self.sample_from_state(timestamp, &state, dt)
+    }
+
+    /// Turn a vehicle state into a sensor reading, adding noise and bias.
+    /// This lets another model (e.g. a route follower) drive the IMU.
+    pub fn sample_from_state(&mut self, timestamp: u64, state: &VehicleState, dt: f32) -> ImuData {
+        let cfg = self.config.clone();
+
+        // Bias drifts as a random walk
+        let walk = dt.max(0.0).sqrt();
+        for i in 0..3 {
+            self.accel_bias[i] += self.noise(cfg.accel_bias_walk * walk);
+            self.gyro_bias[i] += self.noise(cfg.gyro_bias_walk * walk);
+        }
+
+        // Body leans out of corners and pitches nose down under braking
+        let roll = -cfg.body_tilt * state.lateral_accel();
+        let pitch = -cfg.body_tilt * state.accel;
+        let (roll_rate, pitch_rate) = if dt > 0.0 {
+            ((roll - self.roll) / dt, (pitch - self.pitch) / dt)
+        } else {
+            (0.0, 0.0)
+        };
+        self.roll = roll;
+        self.pitch = pitch;
+        self.state = *state;
+
+        // Specific force: gravity resolved into the tilted body frame plus motion
+        let (sr, cr) = roll.to_radians().sin_cos();
+        let (sp, cp) = pitch.to_radians().sin_cos();
+        let vibration = if state.speed == 0.0 { cfg.idle_vibration } else { 0.0 };
+        let accel_sigma = (cfg.accel_noise.powi(2) + vibration.powi(2)).sqrt();
+        let g = STANDARD_GRAVITY as f32;
+        let accel = Vector3D {
+            x: -sp + state.accel / g + self.accel_bias[0] + self.noise(accel_sigma),
+            y: sr * cp + state.lateral_accel() / g + self.accel_bias[1] + self.noise(accel_sigma),
+            z: cr * cp + self.accel_bias[2] + self.noise(accel_sigma),
+        };
+
+        let gyro = Vector3D {
+            x: roll_rate + self.gyro_bias[0] + self.noise(cfg.gyro_noise),
+            y: pitch_rate + self.gyro_bias[1] + self.noise(cfg.gyro_noise),
+            z: state.yaw_rate + self.gyro_bias[2] + self.noise(cfg.gyro_noise),
+        };
+
+        let (sh, ch) = state.heading.to_radians().sin_cos();
+        let mag = Vector3D {
+            x: MAG_HORIZONTAL * ch + self.noise(cfg.mag_noise),
+            y: MAG_HORIZONTAL * sh + self.noise(cfg.mag_noise),
+            z: MAG_VERTICAL + self.noise(cfg.mag_noise),
+        };
+
+        let pose = Orientation {
+            roll,
+            pitch,
+            yaw: state.heading,
+            heading_accuracy: cfg.heading_accuracy,
+        };
+
+        let pressure = altitude_to_pressure(
+            Length::from_meters(state.altitude.into()),
+            Pressure::from_pascals(cfg.pressure.into()),
+        );
+
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        ImuData {
+            sequence,
+            timestamp,
+            inertial: Some(Inertial {
+                pose: Some(pose),
+                gyro: Some(gyro),
+                accel: Some(accel),
+                mag: Some(mag),
+            }),
+            pressure: pressure.as_pascals() as f32 + self.noise(cfg.pressure_noise),
+            temperature: cfg.temperature + self.noise(0.05),
+            temp_cpu: cfg.temp_cpu + self.noise(0.2),
+            stamp: None,
+        }
+    }
+}
+
+impl Iterator for ImuSimulator {
+    type Item = ImuData;
+
+    fn next(&mut self) -> Option<ImuData> {
+        Some(self.next_sample())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accel(d: &ImuData) -> Vector3D {
+        d.inertial.unwrap().accel.unwrap()
+    }
+
+    /// No noise and no bias, so samples are the model alone
+    fn quiet() -> ImuSimConfig {
+        ImuSimConfig {
+            accel_noise: 0.0,
+            gyro_noise: 0.0,
+            mag_noise: 0.0,
+            accel_bias_walk: 0.0,
+            gyro_bias_walk: 0.0,
+            idle_vibration: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_stream() {
+        let run = |seed| -> Vec<ImuData> {
+            ImuSimulator::new(ImuSimConfig { seed, ..Default::default() }, MotionProfile::urban_loop()).take(500).collect()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn gravity_is_on_z_when_level_and_tips_onto_x_under_braking() {
+        let profile = MotionProfile::new().then(Manoeuvre::Idle, 1.0).then(Manoeuvre::Cruise, 1.0);
+        let level = accel(&ImuSimulator::new(quiet(), profile).next_sample());
+        assert_eq!((level.x, level.y, level.z), (0.0, 0.0, 1.0));
+
+        // Braking pitches the body by body_tilt per m/s², which puts some
+        // of gravity on x alongside the deceleration
+        let profile = MotionProfile::new()
+            .then(Manoeuvre::Accelerate { rate: 10.0 }, 2.0)
+            .then(Manoeuvre::Brake { rate: 3.0 }, 2.0);
+        let braking = ImuSimulator::new(quiet(), profile).nth(25).unwrap();
+        let a = accel(&braking);
+        let pitch = braking.inertial.unwrap().pose.unwrap().pitch;
+        assert!((pitch - 0.8 * 3.0).abs() < 1e-4, "{}", pitch);
+        let expected = -pitch.to_radians().sin() - 3.0 / STANDARD_GRAVITY as f32;
+        assert!((a.x - expected).abs() < 1e-5, "{:?}", a);
+        assert!(a.y.abs() < 1e-6 && a.z < 1.0, "{:?}", a);
+
+        // Turning left pushes y positive
+        let profile = MotionProfile::new()
+            .then(Manoeuvre::Accelerate { rate: 10.0 }, 1.0)
+            .then(Manoeuvre::Corner { yaw_rate: 10.0 }, 5.0);
+        let cornering = accel(&ImuSimulator::new(quiet(), profile).nth(20).unwrap());
+        assert!(cornering.y > 0.1, "{:?}", cornering);
+    }
+
+    #[test]
+    fn noise_and_bias_are_as_configured() {
+        let config = ImuSimConfig { accel_bias_walk: 0.0, idle_vibration: 0.0, ..Default::default() };
+        let z: Vec<f32> = ImuSimulator::new(config, MotionProfile::new().then(Manoeuvre::Idle, 1.0))
+            .take(10_000)
+            .map(|d| accel(&d).z)
+            .collect();
+        let mean = z.iter().sum::<f32>() / z.len() as f32;
+        let sigma = (z.iter().map(|z| (z - mean).powi(2)).sum::<f32>() / z.len() as f32).sqrt();
+        assert!((mean - 1.0).abs() < 0.001, "{}", mean);
+        assert!((sigma - 0.01).abs() < 0.001, "{}", sigma);
+
+        // Idling adds engine vibration
+        let config = ImuSimConfig { accel_noise: 0.0, accel_bias_walk: 0.0, ..Default::default() };
+        let shaking = ImuSimulator::new(config, MotionProfile::new().then(Manoeuvre::Idle, 1.0)).take(1000);
+        assert!(shaking.map(|d| accel(&d).x.abs()).fold(0.0, f32::max) > 0.04);
+
+        // Without noise the bias is a random walk away from the true value,
+        // 0.01 per √s or about 0.003 a sample
+        let config = ImuSimConfig { accel_noise: 0.0, idle_vibration: 0.0, accel_bias_walk: 0.01, ..quiet() };
+        let samples: Vec<f32> = ImuSimulator::new(config, MotionProfile::new().then(Manoeuvre::Idle, 1.0))
+            .take(3600)
+            .map(|d| accel(&d).z - 1.0)
+            .collect();
+        assert!(samples.windows(2).all(|w| (w[1] - w[0]).abs() < 0.02));
+        assert!(samples.iter().any(|b| b.abs() > 0.01), "the bias never drifted");
+    }
+}
@@ -0,0 +1,223 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::crash::{CrashConfig, CrashRecorder};
+use crate::data_conv::ImuShort;
+use crate::data_defs::{ImuData, Timestamp};
+use crate::imu;
+use crate::imu_sim::{ImuSimConfig, ImuSimulator, MotionProfile};
+use crate::replay::read_exported;
+use crate::retention::RetentionPolicy;
+use crate::writer::{Record, RowSink};
+
+/// The kinds of IMU a device can have
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImuType {
+    TwoHatType,
+    SenseHatType,
+    FakeType,
+    ReplayType,
+}
+
+/// Anything that can be asked for an IMU reading. The hardware drivers
+/// live with the device code; this crate has the ones that don't need a Pi.
+pub trait ImuSource {
+    fn imu_type(&self) -> ImuType;
+
+    /// Take one reading, stamped with `timestamp`. None when the source
+    /// has nothing more to give, e.g. at the end of a replay file.
+    fn sample(&mut self, timestamp: Timestamp) -> Option<ImuData>;
+}
+
+/// An IMU backed by the simulator
+pub struct FakeImu {
+    sim: ImuSimulator,
+}
+
+impl FakeImu {
+    pub fn new(config: ImuSimConfig, profile: MotionProfile) -> FakeImu {
+        FakeImu { sim: ImuSimulator::new(config, profile) }
+    }
+}
+
+impl Default for FakeImu {
+    fn default() -> Self {
+        FakeImu::new(ImuSimConfig::default(), MotionProfile::default())
+    }
+}
+
+impl ImuSource for FakeImu {
+    fn imu_type(&self) -> ImuType {
+        ImuType::FakeType
+    }
+
+    fn sample(&mut self, timestamp: Timestamp) -> Option<ImuData> {
+        let d = self.sim.next_sample();
+        Some(ImuData { timestamp, ..d.into() })
+    }
+}
+
+/// Plays back recorded readings one per sample, as if they were coming off
+/// the sensor now
+pub struct ReplayImu {
+    data: Vec<imu::ImuData>,
+    next: usize,
+    looped: bool,
+}
+
+impl ReplayImu {
+    pub fn new(data: Vec<imu::ImuData>) -> ReplayImu {
+        ReplayImu { data, next: 0, looped: false }
+    }
+
+    /// Read a protobuf-encoded `ImuVec`, as written by an export
+    pub fn open(path: &Path) -> Result<ReplayImu, Box<dyn Error>> {
+        Ok(ReplayImu::new(read_exported(path, None)?.imu))
+    }
+
+    /// Start again from the beginning instead of running out
+    pub fn looped(mut self) -> ReplayImu {
+        self.looped = true;
+        self
+    }
+}
+
+impl ImuSource for ReplayImu {
+    fn imu_type(&self) -> ImuType {
+        ImuType::ReplayType
+    }
+
+    fn sample(&mut self, timestamp: Timestamp) -> Option<ImuData> {
+        if self.next == self.data.len() && self.looped {
+            self.next = 0;
+        }
+        let d = *self.data.get(self.next)?;
+        self.next += 1;
+        Some(ImuData { timestamp, ..d.into() })
+    }
+}
+
+/// Settings for `run_sampler`
+#[derive(Debug, Clone)]
+pub struct SamplerConfig {
+    pub uuid: u64,
+    pub rate_hz: f32,
+    /// Stop after this many samples. None runs until the source runs out
+    /// or the stop flag is set.
+    pub max_samples: Option<u64>,
+    /// Prune the database this often while sampling
+    pub retention: Option<RetentionPolicy>,
+    /// Store one sample in this many in the imu table. Crash captures
+    /// still get every one.
+    pub downsample: u32,
+    /// Capture the samples around an impact into the crash tables, which
+    /// the database needs to have
+    pub crash: Option<CrashConfig>,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        SamplerConfig {
+            uuid: 0x1234567890AB,
+            rate_hz: 10.0,
+            max_samples: None,
+            retention: None,
+            downsample: 1,
+            crash: None,
+        }
+    }
+}
+
+/// What happened during a run of the sampler
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SamplerStats {
+    pub samples: u64,
+    pub stored: u64,
+    /// Readings that couldn't be stored, e.g. the IMU wasn't ready
+    pub incomplete: u64,
+    /// Sample times missed because the previous sample took too long
+    pub overruns: u64,
+    /// Rows deleted by the retention policy. A `LogWriter` prunes in the
+    /// background and counts them in its own stats instead.
+    pub pruned: u64,
+    /// Impacts that started or stretched a crash capture
+    pub crashes: u64,
+    /// Samples stored in the crash tables
+    pub captured: u64,
+}
+
+/// The time between samples at `rate_hz`, which has to be finite and
+/// positive, and slow enough for the period to be at least a nanosecond
+pub fn sample_period(rate_hz: f32) -> Result<Duration, Box<dyn Error>> {
+    if !(rate_hz.is_finite() && rate_hz > 0.0) {
+        return Err(format!("A sample rate of {} Hz isn't finite and positive", rate_hz).into());
+    }
+    let period = Duration::try_from_secs_f32(1.0 / rate_hz)?;
+    if period.is_zero() {
+        return Err(format!("A sample rate of {} Hz is too fast", rate_hz).into());
+    }
+    Ok(period)
+}
+
+/// Sample `source` at a fixed rate, putting each reading in `sink` for
+/// upload later: a database `Connection`, or a `LogWriter` to keep slow
+/// writes out of the sampling loop. Sample times are kept on a fixed
+/// grid; if one runs late, the missed slots are skipped rather than
+/// bunched up.
+pub fn run_sampler(
+    source: &mut dyn ImuSource,
+    sink: &dyn RowSink,
+    config: &SamplerConfig,
+    stop: &AtomicBool,
+) -> Result<SamplerStats, Box<dyn Error>> {
+    let period = sample_period(config.rate_hz)?;
+    let mut stats = SamplerStats::default();
+    let mut next = Instant::now();
+    let mut last_prune = Instant::now();
+    let mut recorder = config.crash.clone().map(CrashRecorder::new);
+
+    while !stop.load(Ordering::Relaxed) && config.max_samples.is_none_or(|max| stats.samples < max) {
+        let reading = match source.sample(Timestamp::now()) {
+            Some(d) => d,
+            None => break,
+        };
+        let sequence = stats.samples as u32;
+        stats.samples += 1;
+
+        match ImuShort::try_from(ImuData { sequence, ..reading }) {
+            Ok(mut row) => {
+                row.uuid = config.uuid;
+                for record in recorder.as_mut().map(|r| r.push(&row)).unwrap_or_default() {
+                    match record {
+                        Record::Crash(_) => stats.crashes += 1,
+                        _ => stats.captured += 1,
+                    }
+                    sink.put(record)?;
+                }
+                if (sequence as u64).is_multiple_of(config.downsample.max(1) as u64) {
+                    stats.stored += sink.put(row.into())? as u64;
+                }
+            }
+            Err(_) => stats.incomplete += 1,
+        }
+
+        if let Some(policy) = config.retention.as_ref().filter(|p| last_prune.elapsed() >= p.interval) {
+            stats.pruned += sink.prune(policy)?;
+            last_prune = Instant::now();
+        }
+
+        next += period;
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        } else {
+            let behind = ((now - next).as_secs_f64() / period.as_secs_f64()) as u32;
+            stats.overruns += behind as u64;
+            next += period * behind;
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,42 @@
+pub mod timestamp {
+    tonic::include_proto!("timestamp");
+}
+pub mod imu {
+    tonic::include_proto!("imu");
+}
+pub mod gps {
+    tonic::include_proto!("gps");
+}
+pub mod trip {
+    tonic::include_proto!("trip");
+}
+
+pub mod data_defs;
+pub mod calendar;
+pub mod data_conv;
+pub mod schema;
+pub mod retention;
+pub mod device_db;
+pub mod writer;
+pub mod baro;
+pub mod timesync;
+pub mod imu_sim;
+pub mod imu_source;
+pub mod gps_sim;
+pub mod nmea;
+pub mod ubx;
+pub mod gps_source;
+pub mod drive_sim;
+pub mod fake_imu;
+pub mod fake_gps;
+pub mod replay;
+pub mod store;
+pub mod pg_store;
+pub mod parquet_export;
+pub mod tracks;
+pub mod csv_io;
+pub mod trips;
+pub mod events;
+pub mod crash;
+pub mod service;
+pub mod upload;
@@ -0,0 +1,240 @@
+// Load generator: simulates a fleet of trucks all uploading to one server
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::time::{interval, Instant, MissedTickBehavior};
+
+use grpc_tests::drive_sim::DriveSimulator;
+use grpc_tests::gps_sim::{GpsSimConfig, Route};
+use grpc_tests::imu_sim::{ImuSimConfig, MotionProfile};
+use grpc_tests::upload::Uploader;
+
+const USAGE: &str = "usage: loadgen [--server <url>] [--devices <n>] [--seconds <n>]
+               [--imu-hz <hz>] [--gps-hz <hz>] [--batch-secs <s>]";
+
+#[derive(Debug, Clone)]
+struct LoadConfig {
+    server: String,
+    devices: u64,
+    duration: Duration,
+    imu_hz: f32,
+    gps_hz: f32,
+    batch: Duration,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        LoadConfig {
+            server: "http://[::1]:50051".to_string(),
+            devices: 10,
+            duration: Duration::from_secs(30),
+            imu_hz: 10.0,
+            gps_hz: 1.0,
+            batch: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A flag's value in seconds, which may be fractional but not negative
+fn secs(flag: &str, value: &str) -> Result<Duration, String> {
+    let secs: f64 = value.parse().map_err(|e| format!("{} {}: {}", flag, value, e))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("{} {}: {}", flag, value, e))
+}
+
+/// A flag's value as a sample rate. The simulators count time in whole
+/// ms, so it has to be finite, positive and no more than one every ms.
+fn rate(flag: &str, value: &str) -> Result<f32, String> {
+    let hz: f32 = value.parse().map_err(|e| format!("{} {}: {}", flag, value, e))?;
+    if hz.is_finite() && hz > 0.0 && hz <= 1000.0 {
+        Ok(hz)
+    } else {
+        Err(format!("{} {}: must be finite, positive and at most 1000 Hz", flag, value))
+    }
+}
+
+impl LoadConfig {
+    fn from_args(args: &[String]) -> Result<LoadConfig, Box<dyn std::error::Error>> {
+        let mut config = LoadConfig::default();
+        let mut it = args.iter();
+        while let Some(flag) = it.next() {
+            let value = it.next().ok_or(USAGE)?;
+            match flag.as_str() {
+                "--server" => config.server = value.clone(),
+                "--devices" => config.devices = value.parse()?,
+                "--seconds" => config.duration = secs(flag, value)?,
+                "--imu-hz" => config.imu_hz = rate(flag, value)?,
+                "--gps-hz" => config.gps_hz = rate(flag, value)?,
+                "--batch-secs" => config.batch = secs(flag, value)?,
+                _ => return Err(USAGE.into()),
+            }
+        }
+        if config.devices == 0 || config.batch.is_zero() {
+            return Err(USAGE.into());
+        }
+        Ok(config)
+    }
+}
+
+/// What one simulated device saw
+#[derive(Debug, Default)]
+struct DeviceStats {
+    requests: usize,
+    errors: usize,
+    imu_lines: usize,
+    gps_lines: usize,
+    latencies: Vec<Duration>,
+}
+
+impl DeviceStats {
+    fn merge(&mut self, other: DeviceStats) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        self.imu_lines += other.imu_lines;
+        self.gps_lines += other.gps_lines;
+        self.latencies.extend(other.latencies);
+    }
+}
+
+/// Nearest-rank percentile of a sorted slice
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Send one request, counting it and timing it if it succeeds
+async fn timed<T, E>(stats: &mut DeviceStats, send: impl Future<Output = Result<T, E>>) -> bool {
+    let start = Instant::now();
+    let ok = send.await.is_ok();
+    stats.requests += 1;
+    if ok {
+        stats.latencies.push(start.elapsed());
+    } else {
+        stats.errors += 1;
+    }
+    ok
+}
+
+/// Drive one truck for the configured time, uploading a batch every period
+async fn run_device(index: u64, config: LoadConfig) -> DeviceStats {
+    let mut stats = DeviceStats::default();
+
+    let uuid = GpsSimConfig::default().uuid + index;
+    let mut uploader = match Uploader::connect(&config.server, uuid).await {
+        Ok(u) => u,
+        Err(_) => {
+            stats.errors += 1;
+            return stats;
+        }
+    };
+
+    let imu_config = ImuSimConfig {
+        seed: index,
+        sample_rate_hz: config.imu_hz,
+        ..Default::default()
+    };
+    let gps_config = GpsSimConfig {
+        seed: index,
+        uuid,
+        sample_rate_hz: config.gps_hz,
+        ..Default::default()
+    };
+    let mut drive = DriveSimulator::new(imu_config, gps_config, Route::camborne(), MotionProfile::default());
+    let lines_per_batch = ((config.imu_hz as f64 * config.batch.as_secs_f64()).round() as usize).max(1);
+
+    let mut ticker = interval(config.batch);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let end = Instant::now() + config.duration;
+
+    while Instant::now() < end {
+        ticker.tick().await;
+        let (imu_data, gps_data) = drive.run(lines_per_batch);
+
+        let n = imu_data.data.len();
+        if timed(&mut stats, uploader.send_imu(imu_data)).await {
+            stats.imu_lines += n;
+        }
+        if gps_data.data.is_empty() {
+            continue;
+        }
+        let n = gps_data.data.len();
+        if timed(&mut stats, uploader.send_gps(gps_data)).await {
+            stats.gps_lines += n;
+        }
+    }
+
+    stats
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = LoadConfig::from_args(&args)?;
+    println!("{:?}", config);
+
+    let started = Instant::now();
+    let tasks: Vec<_> = (0..config.devices)
+        .map(|i| tokio::spawn(run_device(i, config.clone())))
+        .collect();
+
+    let mut total = DeviceStats::default();
+    for task in tasks {
+        total.merge(task.await?);
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+
+    total.latencies.sort();
+    let lat = &total.latencies;
+    println!("devices:     {}", config.devices);
+    println!("elapsed:     {:.1} s", elapsed);
+    println!("requests:    {} ({:.1}/s)", total.requests, total.requests as f64 / elapsed);
+    println!("imu lines:   {} ({:.1}/s)", total.imu_lines, total.imu_lines as f64 / elapsed);
+    println!("gps lines:   {} ({:.1}/s)", total.gps_lines, total.gps_lines as f64 / elapsed);
+    println!("errors:      {}", total.errors);
+    println!(
+        "latency:     p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+        percentile(lat, 50.0),
+        percentile(lat, 90.0),
+        percentile(lat, 99.0),
+        lat.last().copied().unwrap_or_default(),
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let sorted: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(5));
+        assert_eq!(percentile(&sorted, 90.0), Duration::from_millis(9));
+        assert_eq!(percentile(&sorted, 91.0), Duration::from_millis(10));
+        // The ends are the smallest and largest, and out of range clamps
+        assert_eq!(percentile(&sorted, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&sorted, 100.0), Duration::from_millis(10));
+        assert_eq!(percentile(&sorted, -5.0), Duration::from_millis(1));
+        assert_eq!(percentile(&sorted, 150.0), Duration::from_millis(10));
+
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+        let one = [Duration::from_millis(7)];
+        assert!([0.0, 50.0, 100.0].iter().all(|&p| percentile(&one, p) == one[0]));
+    }
+
+    #[test]
+    fn rates_without_a_whole_ms_period_are_refused() {
+        let args = |flag: &str, value: &str| LoadConfig::from_args(&[flag.to_string(), value.to_string()]);
+        for flag in ["--imu-hz", "--gps-hz"] {
+            for value in ["0", "-1", "nan", "inf", "-inf", "1e30", "2500"] {
+                assert!(args(flag, value).is_err(), "{} {}", flag, value);
+            }
+        }
+        let config = args("--imu-hz", "1000").unwrap();
+        assert_eq!((config.imu_hz, config.gps_hz), (1000.0, 1.0));
+        assert_eq!(args("--gps-hz", "0.5").unwrap().gps_hz, 0.5);
+    }
+}
@@ -0,0 +1,118 @@
+// Device-side logger: samples an IMU source, and optionally replays a GPS
+// log, into the local database ready for the client to upload. A writer
+// thread does the database work. Runs anywhere, no Pi needed.
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use grpc_tests::crash::CrashConfig;
+use grpc_tests::device_db::{open_device_db, DbHealth, DeviceDbConfig};
+use grpc_tests::gps_source::{log_gps, GpsSource, NmeaGps, UbxGps};
+use grpc_tests::imu_source::{run_sampler, sample_period, FakeImu, ImuSource, ReplayImu, SamplerConfig};
+use grpc_tests::retention::{enable_incremental_vacuum, RetentionPolicy};
+use grpc_tests::writer::{LogWriter, WriterConfig};
+
+const USAGE: &str = "usage: logger [--db <file>] [--rate <hz>] [--samples <n>] [--uuid <n>]
+              [--replay <file.pb> [--loop]]
+              [--keep-days <n>] [--max-mb <n>] [--emergency-mb <n>]
+              [--sync off|normal|full|extra]
+              [--gps <file.nmea|file.ubx>]
+              [--queue <n>] [--when-full block|drop-oldest|spill:<dir>]
+              [--downsample <n>] [--crash-g <g>] [--crash-before <s>] [--crash-after <s>]";
+
+const MB: u64 = 1024 * 1024;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut db = PathBuf::from("./my_imu.db3");
+    let mut config = SamplerConfig::default();
+    let mut replay: Option<PathBuf> = None;
+    let mut looped = false;
+    let mut retention = RetentionPolicy::default();
+    let mut db_config = DeviceDbConfig::default();
+    let mut writer_config = WriterConfig::default();
+    let mut gps: Option<PathBuf> = None;
+    let mut crash = CrashConfig::default();
+
+    let mut it = std::env::args().skip(1);
+    while let Some(flag) = it.next() {
+        if flag == "--loop" {
+            looped = true;
+            continue;
+        }
+        let value = it.next().ok_or(USAGE)?;
+        match flag.as_str() {
+            "--db" => db = value.into(),
+            "--rate" => {
+                config.rate_hz = value.parse()?;
+                sample_period(config.rate_hz).map_err(|e| format!("--rate {}: {}", value, e))?;
+            }
+            "--samples" => config.max_samples = Some(value.parse()?),
+            "--uuid" => config.uuid = value.parse()?,
+            "--replay" => replay = Some(value.into()),
+            "--keep-days" => retention.max_age = Some(Duration::from_secs(value.parse::<u64>()? * 24 * 3600)),
+            "--max-mb" => retention.max_bytes = Some(value.parse::<u64>()? * MB),
+            "--emergency-mb" => retention.emergency_bytes = Some(value.parse::<u64>()? * MB),
+            "--sync" => db_config.synchronous = value.parse()?,
+            "--gps" => gps = Some(value.into()),
+            "--queue" => writer_config.capacity = value.parse()?,
+            "--when-full" => writer_config.policy = value.parse()?,
+            "--downsample" => config.downsample = value.parse()?,
+            "--crash-g" => crash.impact_g = value.parse()?,
+            "--crash-before" => {
+                crash.before = Duration::try_from_secs_f64(value.parse()?).map_err(|e| format!("--crash-before {}: {}", value, e))?
+            }
+            "--crash-after" => {
+                crash.after = Duration::try_from_secs_f64(value.parse()?).map_err(|e| format!("--crash-after {}: {}", value, e))?
+            }
+            _ => return Err(USAGE.into()),
+        }
+    }
+    if writer_config.capacity == 0 || config.downsample == 0 {
+        return Err(USAGE.into());
+    }
+
+    let mut source: Box<dyn ImuSource> = match replay {
+        Some(path) if looped => Box::new(ReplayImu::open(&path)?.looped()),
+        Some(path) => Box::new(ReplayImu::open(&path)?),
+        None => Box::new(FakeImu::default()),
+    };
+
+    let mut gps_source: Option<Box<dyn GpsSource + Send>> = match gps {
+        Some(path) if path.extension().is_some_and(|e| e == "ubx") => Some(Box::new(UbxGps::open(&path, config.uuid)?)),
+        Some(path) => Some(Box::new(NmeaGps::open(&path, config.uuid)?)),
+        None => None,
+    };
+
+    config.retention = Some(retention);
+    config.crash = Some(crash);
+
+    let (conn, health) = open_device_db(&db, &db_config)?;
+    if let DbHealth::Quarantined { quarantined, problem, imu_rows, gps_rows, crashes, crash_lines, failures } = health {
+        println!("{} was corrupt ({}), moved to {}", db.display(), problem, quarantined.display());
+        println!("Salvaged {} IMU and {} GPS rows, {} crashes and {} crash IMU lines", imu_rows, gps_rows, crashes, crash_lines);
+        for failure in failures {
+            eprintln!("Not salvaged: {}", failure);
+        }
+    }
+    if enable_incremental_vacuum(&conn)? {
+        println!("Switched {} to incremental vacuum", db.display());
+    }
+
+    println!("Logging {:?} IMU at {} Hz to {}", source.imu_type(), config.rate_hz, db.display());
+    let writer = LogWriter::spawn(conn, writer_config);
+    let stop = AtomicBool::new(false);
+    let (stats, fixes) = thread::scope(|s| {
+        let fixes = gps_source.as_mut().map(|gps| s.spawn(|| log_gps(gps.as_mut(), &writer, &stop)));
+        let stats = run_sampler(source.as_mut(), &writer, &config, &stop);
+        stop.store(true, Ordering::Relaxed);
+        (stats, fixes.map(|t| t.join().unwrap()))
+    });
+    println!("{:?}", stats?);
+    if let Some(fixes) = fixes {
+        println!("{} GPS fixes", fixes?);
+    }
+    println!("{:?}", writer.close());
+
+    Ok(())
+}
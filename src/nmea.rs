@@ -0,0 +1,495 @@
+use std::fmt;
+
+pub use crate::calendar::days_from_civil;
+use crate::calendar::MS_PER_DAY;
+use crate::data_defs::{Timebase, Timestamp};
+use crate::fake_gps::encode_fields;
+use crate::gps::GpsData;
+use crate::ubx::{TIME_VALID_DATE, TIME_VALID_TIME};
+
+const KNOTS_TO_MPS: f32 = 1852.0 / 3600.0;
+
+/// GGA: the fix itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gga {
+    /// ms since midnight UTC
+    pub time: Option<u32>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    /// 0 no fix, 1 GPS, 2 DGPS, 4 RTK fixed, 5 RTK float, 6 dead reckoning
+    pub quality: u8,
+    /// Satellites used in the fix
+    pub nsats: u8,
+    pub hdop: Option<f32>,
+    /// Above mean sea level, m
+    pub alt: Option<f32>,
+}
+
+/// RMC: recommended minimum, the only sentence with the date
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rmc {
+    pub time: Option<u32>,
+    pub valid: bool,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub speed_knots: Option<f32>,
+    /// Degrees true
+    pub track: Option<f32>,
+    /// Days since the epoch
+    pub date: Option<u32>,
+}
+
+/// VTG: course and speed over ground
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vtg {
+    pub track: Option<f32>,
+    pub speed_kph: Option<f32>,
+}
+
+/// GSA: satellites used and dilution of precision
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gsa {
+    /// 1 no fix, 2 2D, 3 3D
+    pub fix_type: u8,
+    pub prns: Vec<u8>,
+    pub pdop: Option<f32>,
+    pub hdop: Option<f32>,
+    pub vdop: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SatInView {
+    pub prn: u8,
+    pub elevation: Option<u8>,
+    pub azimuth: Option<u16>,
+    /// None when the satellite is tracked but not heard
+    pub snr: Option<u8>,
+}
+
+/// GSV: satellites in view, split over several sentences
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gsv {
+    pub sentences: u8,
+    pub number: u8,
+    pub in_view: u8,
+    pub sats: Vec<SatInView>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sentence {
+    Gga(Gga),
+    Rmc(Rmc),
+    Vtg(Vtg),
+    Gsa(Gsa),
+    Gsv(Gsv),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NmeaError {
+    /// Doesn't start with $ or has no sentence type
+    NotNmea,
+    BadChecksum { expected: u8, found: u8 },
+    /// A sentence type this parser doesn't handle
+    Unsupported(String),
+    /// A field that's there but can't be read
+    BadField { sentence: &'static str, field: &'static str },
+}
+
+impl fmt::Display for NmeaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NmeaError::NotNmea => write!(f, "not an NMEA sentence"),
+            NmeaError::BadChecksum { expected, found } => {
+                write!(f, "checksum is {:02X} but the sentence says {:02X}", expected, found)
+            }
+            NmeaError::Unsupported(kind) => write!(f, "unsupported sentence {}", kind),
+            NmeaError::BadField { sentence, field } => write!(f, "bad {} in {}", field, sentence),
+        }
+    }
+}
+
+impl std::error::Error for NmeaError {}
+
+/// XOR of everything between the $ and the *
+pub fn checksum(body: &str) -> u8 {
+    body.bytes().fold(0, |acc, b| acc ^ b)
+}
+
+/// Parse one line, e.g. `$GPGGA,...*47`. Any talker (GP, GN, GL, ...) is
+/// accepted. The checksum is checked when there is one. NMEA is ASCII, so
+/// a line with anything else in it is line noise.
+pub fn parse(line: &str) -> Result<Sentence, NmeaError> {
+    let line = line.trim();
+    if !line.is_ascii() {
+        return Err(NmeaError::NotNmea);
+    }
+    let line = line.strip_prefix('$').ok_or(NmeaError::NotNmea)?;
+    let body = match line.split_once('*') {
+        Some((body, sum)) => {
+            let found = u8::from_str_radix(sum, 16).map_err(|_| NmeaError::NotNmea)?;
+            let expected = checksum(body);
+            if expected != found {
+                return Err(NmeaError::BadChecksum { expected, found });
+            }
+            body
+        }
+        None => line,
+    };
+
+    let mut fields = body.split(',');
+    let address = fields.next().unwrap_or_default();
+    if address.len() < 5 {
+        return Err(NmeaError::NotNmea);
+    }
+    let fields: Vec<&str> = fields.collect();
+    let f = Fields { sentence: "", fields: &fields };
+    match &address[address.len() - 3..] {
+        "GGA" => parse_gga(Fields { sentence: "GGA", ..f }).map(Sentence::Gga),
+        "RMC" => parse_rmc(Fields { sentence: "RMC", ..f }).map(Sentence::Rmc),
+        "VTG" => parse_vtg(Fields { sentence: "VTG", ..f }).map(Sentence::Vtg),
+        "GSA" => parse_gsa(Fields { sentence: "GSA", ..f }).map(Sentence::Gsa),
+        "GSV" => parse_gsv(Fields { sentence: "GSV", ..f }).map(Sentence::Gsv),
+        _ => Err(NmeaError::Unsupported(address.to_string())),
+    }
+}
+
+/// The comma-separated fields after the address. Missing and empty fields
+/// read as None.
+#[derive(Clone, Copy)]
+struct Fields<'a> {
+    sentence: &'static str,
+    fields: &'a [&'a str],
+}
+
+impl Fields<'_> {
+    fn raw(&self, i: usize) -> Option<&str> {
+        self.fields.get(i).copied().filter(|s| !s.is_empty())
+    }
+
+    fn bad(&self, field: &'static str) -> NmeaError {
+        NmeaError::BadField { sentence: self.sentence, field }
+    }
+
+    fn num<T: std::str::FromStr>(&self, i: usize, field: &'static str) -> Result<Option<T>, NmeaError> {
+        self.raw(i).map(|s| s.parse().map_err(|_| self.bad(field))).transpose()
+    }
+
+    /// hhmmss.ss as ms since midnight
+    fn time(&self, i: usize) -> Result<Option<u32>, NmeaError> {
+        let s = match self.raw(i) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let bad = || self.bad("time");
+        if s.len() < 6 || !s.is_ascii() {
+            return Err(bad());
+        }
+        let h: u32 = s[0..2].parse().map_err(|_| bad())?;
+        let m: u32 = s[2..4].parse().map_err(|_| bad())?;
+        let sec: f64 = s[4..].parse().map_err(|_| bad())?;
+        if h > 23 || m > 59 || !(0.0..61.0).contains(&sec) {
+            return Err(bad());
+        }
+        Ok(Some((h * 3600 + m * 60) * 1000 + (sec * 1000.0).round() as u32))
+    }
+
+    /// (d)ddmm.mmmm plus hemisphere as signed degrees
+    fn coord(&self, i: usize, field: &'static str) -> Result<Option<f64>, NmeaError> {
+        let (s, hemi) = match (self.raw(i), self.raw(i + 1)) {
+            (Some(s), Some(h)) => (s, h),
+            _ => return Ok(None),
+        };
+        let value: f64 = s.parse().map_err(|_| self.bad(field))?;
+        let degrees = (value / 100.0).trunc();
+        let minutes = value - degrees * 100.0;
+        if minutes >= 60.0 {
+            return Err(self.bad(field));
+        }
+        let deg = degrees + minutes / 60.0;
+        match hemi {
+            "N" | "E" => Ok(Some(deg)),
+            "S" | "W" => Ok(Some(-deg)),
+            _ => Err(self.bad(field)),
+        }
+    }
+
+    /// ddmmyy as days since the epoch
+    fn date(&self, i: usize) -> Result<Option<u32>, NmeaError> {
+        let s = match self.raw(i) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let bad = || self.bad("date");
+        if s.len() != 6 || !s.is_ascii() {
+            return Err(bad());
+        }
+        let d: u32 = s[0..2].parse().map_err(|_| bad())?;
+        let m: u32 = s[2..4].parse().map_err(|_| bad())?;
+        let y: i64 = s[4..6].parse().map_err(|_| bad())?;
+        if !(1..=31).contains(&d) || !(1..=12).contains(&m) {
+            return Err(bad());
+        }
+        // Two digit years: the receiver can't be older than GPS itself
+        let y = if y < 80 { 2000 + y } else { 1900 + y };
+        Ok(Some(days_from_civil(y, m, d) as u32))
+    }
+}
+
+fn parse_gga(f: Fields) -> Result<Gga, NmeaError> {
+    Ok(Gga {
+        time: f.time(0)?,
+        lat: f.coord(1, "latitude")?,
+        lon: f.coord(3, "longitude")?,
+        quality: f.num(5, "fix quality")?.unwrap_or(0),
+        nsats: f.num(6, "satellites")?.unwrap_or(0),
+        hdop: f.num(7, "HDOP")?,
+        alt: f.num(8, "altitude")?,
+    })
+}
+
+fn parse_rmc(f: Fields) -> Result<Rmc, NmeaError> {
+    Ok(Rmc {
+        time: f.time(0)?,
+        valid: f.raw(1) == Some("A"),
+        lat: f.coord(2, "latitude")?,
+        lon: f.coord(4, "longitude")?,
+        speed_knots: f.num(6, "speed")?,
+        track: f.num(7, "track")?,
+        date: f.date(8)?,
+    })
+}
+
+fn parse_vtg(f: Fields) -> Result<Vtg, NmeaError> {
+    // Old receivers leave out the unit letters: track, track, knots, kph
+    let labelled = f.raw(1) == Some("T");
+    let (track, kph) = if labelled { (0, 6) } else { (0, 3) };
+    Ok(Vtg { track: f.num(track, "track")?, speed_kph: f.num(kph, "speed")? })
+}
+
+fn parse_gsa(f: Fields) -> Result<Gsa, NmeaError> {
+    let mut prns = Vec::new();
+    for i in 2..14 {
+        if let Some(prn) = f.num(i, "PRN")? {
+            prns.push(prn);
+        }
+    }
+    Ok(Gsa {
+        fix_type: f.num(1, "fix type")?.unwrap_or(1),
+        prns,
+        pdop: f.num(14, "PDOP")?,
+        hdop: f.num(15, "HDOP")?,
+        vdop: f.num(16, "VDOP")?,
+    })
+}
+
+fn parse_gsv(f: Fields) -> Result<Gsv, NmeaError> {
+    let mut sats = Vec::new();
+    let mut i = 3;
+    while f.raw(i).is_some() {
+        sats.push(SatInView {
+            prn: f.num(i, "PRN")?.ok_or_else(|| f.bad("PRN"))?,
+            elevation: f.num(i + 1, "elevation")?,
+            azimuth: f.num(i + 2, "azimuth")?,
+            snr: f.num(i + 3, "SNR")?,
+        });
+        i += 4;
+    }
+    Ok(Gsv {
+        sentences: f.num(0, "sentence count")?.ok_or_else(|| f.bad("sentence count"))?,
+        number: f.num(1, "sentence number")?.ok_or_else(|| f.bad("sentence number"))?,
+        in_view: f.num(2, "satellites in view")?.unwrap_or(0),
+        sats,
+    })
+}
+
+/// Everything a receiver said about one instant
+#[derive(Debug, Clone, Default)]
+struct Epoch {
+    time: Option<u32>,
+    date: Option<u32>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    alt: Option<f32>,
+    quality: Option<u8>,
+    nsats: Option<u8>,
+    rmc_valid: Option<bool>,
+    hdop: Option<f32>,
+    speed: Option<f32>,
+    track: Option<f32>,
+    fix_type: Option<u8>,
+    in_view: Option<u8>,
+}
+
+impl Epoch {
+    fn is_empty(&self) -> bool {
+        self.time.is_none() && self.quality.is_none() && self.rmc_valid.is_none()
+    }
+}
+
+/// Collects the sentences a receiver sends each second into `GpsData`.
+/// GGA and RMC carry the time; a new time starts a new fix, and GSA, GSV
+/// and VTG belong to whichever fix is being collected.
+#[derive(Debug, Clone, Default)]
+pub struct FixBuilder {
+    current: Epoch,
+    date: Option<u32>,
+    last_position: Option<(f64, f64, f32)>,
+    /// Satellites in view, from the last complete GSV group
+    in_view: Option<u8>,
+}
+
+/// A fix before the device has stamped it with uuid, pitime and sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NmeaFix {
+    /// ms since the epoch. Until an RMC gives the date this is just the
+    /// time of day.
+    pub gps_time: u64,
+    pub lat: f32,
+    pub lon: f32,
+    pub alt: f32,
+    /// m/s
+    pub speed: f32,
+    pub track: f32,
+    /// GGA fix quality
+    pub status: u8,
+    pub nsats: u8,
+    pub valid: bool,
+    pub hdop: f32,
+    /// GSA fix type: 1 none, 2 2D, 3 3D
+    pub fix_type: u8,
+    pub in_view: u8,
+    /// ubx::TIME_VALID_* bits for whether gps_time has its date and time
+    pub time_valid: u32,
+}
+
+impl NmeaFix {
+    /// As a `GpsData`, not yet uploaded or confirmed
+    pub fn to_gps_data(&self, uuid: u64, pitime: u64, sequence: u32) -> GpsData {
+        GpsData {
+            uuid,
+            pitime,
+            gps_time: self.gps_time,
+            sequence,
+            lat: self.lat,
+            lon: self.lon,
+            alt: self.alt,
+            speed: self.speed,
+            track: self.track,
+            status_nsats_vuc: encode_fields(self.status, self.nsats, self.valid, false, false),
+            hdop: self.hdop,
+            // NMEA has no accuracy estimates without GST
+            h_acc: 0.0,
+            v_acc: 0.0,
+            speed_acc: 0.0,
+            time_valid: self.time_valid,
+            pi_stamp: None,
+            gps_stamp: (self.time_valid & TIME_VALID_DATE != 0)
+                .then(|| Timestamp::from_millis(self.gps_time, Timebase::Gps).into()),
+        }
+    }
+}
+
+impl FixBuilder {
+    pub fn new() -> FixBuilder {
+        FixBuilder::default()
+    }
+
+    /// Add a sentence. Returns the previous fix if this one starts a new one.
+    pub fn push(&mut self, sentence: Sentence) -> Option<NmeaFix> {
+        let time = match &sentence {
+            Sentence::Gga(g) => g.time,
+            Sentence::Rmc(r) => r.time,
+            _ => None,
+        };
+        let done = match (time, self.current.time) {
+            (Some(t), Some(current)) if t != current => self.finish(),
+            _ => None,
+        };
+
+        let e = &mut self.current;
+        e.time = e.time.or(time);
+        match sentence {
+            Sentence::Gga(g) => {
+                e.lat = g.lat.or(e.lat);
+                e.lon = g.lon.or(e.lon);
+                e.alt = g.alt.or(e.alt);
+                e.quality = Some(g.quality);
+                e.nsats = Some(g.nsats);
+                e.hdop = g.hdop.or(e.hdop);
+            }
+            Sentence::Rmc(r) => {
+                e.rmc_valid = Some(r.valid);
+                e.lat = e.lat.or(r.lat);
+                e.lon = e.lon.or(r.lon);
+                e.speed = r.speed_knots.map(|k| k * KNOTS_TO_MPS).or(e.speed);
+                e.track = r.track.or(e.track);
+                if r.date.is_some() {
+                    e.date = r.date;
+                }
+            }
+            Sentence::Vtg(v) => {
+                e.speed = e.speed.or(v.speed_kph.map(|k| k / 3.6));
+                e.track = e.track.or(v.track);
+            }
+            Sentence::Gsa(a) => {
+                e.fix_type = Some(a.fix_type);
+                e.hdop = e.hdop.or(a.hdop);
+            }
+            Sentence::Gsv(v) => {
+                e.in_view = Some(v.in_view);
+            }
+        }
+        done
+    }
+
+    /// Finish the fix being collected, e.g. at the end of a log
+    pub fn finish(&mut self) -> Option<NmeaFix> {
+        let e = std::mem::take(&mut self.current);
+        if e.is_empty() {
+            return None;
+        }
+        if e.date.is_some() {
+            self.date = e.date;
+        }
+        if e.in_view.is_some() {
+            self.in_view = e.in_view;
+        }
+
+        let quality = e.quality.unwrap_or(u8::from(e.rmc_valid == Some(true)));
+        let valid = e.rmc_valid.unwrap_or(quality > 0) && quality > 0 && e.lat.is_some() && e.lon.is_some();
+        let gps_time = self.date.unwrap_or(0) as u64 * MS_PER_DAY + e.time.unwrap_or(0) as u64;
+        let mut time_valid = 0;
+        if self.date.is_some() {
+            time_valid |= TIME_VALID_DATE;
+        }
+        if e.time.is_some() {
+            time_valid |= TIME_VALID_TIME;
+        }
+
+        // Without a fix, report the last known position, as the simulator does
+        let (lat, lon, alt) = match (valid, e.lat, e.lon) {
+            (true, Some(lat), Some(lon)) => {
+                let p = (lat, lon, e.alt.unwrap_or(0.0));
+                self.last_position = Some(p);
+                p
+            }
+            _ => self.last_position.unwrap_or((0.0, 0.0, 0.0)),
+        };
+
+        Some(NmeaFix {
+            gps_time,
+            lat: lat as f32,
+            lon: lon as f32,
+            alt,
+            speed: if valid { e.speed.unwrap_or(0.0) } else { 0.0 },
+            track: e.track.unwrap_or(0.0),
+            status: if valid { quality } else { 0 },
+            nsats: if valid { e.nsats.unwrap_or(0) } else { 0 },
+            valid,
+            hdop: if valid { e.hdop.unwrap_or(99.99) } else { 99.99 },
+            fix_type: e.fix_type.unwrap_or(if valid { 3 } else { 1 }),
+            in_view: e.in_view.or(self.in_view).unwrap_or(0),
+            time_valid,
+        })
+    }
+}
@@ -0,0 +1,382 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::{
+    Float32Type, Float64Type, Int32Type, Int64Type, TimestampMillisecondType, UInt32Type, UInt64Type, UInt8Type,
+};
+use arrow_array::{
+    Array, ArrayRef, ArrowPrimitiveType, BooleanArray, Float32Array, Float64Array, Int32Array, Int64Array,
+    PrimitiveArray, RecordBatch, TimestampMillisecondArray, UInt32Array, UInt64Array, UInt8Array,
+};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use rusqlite::{Connection, Row};
+
+use crate::data_conv::ImuShort;
+use crate::fake_gps::{decode_fields, encode_fields};
+use crate::gps::GpsData;
+use crate::calendar::civil_from_days;
+use crate::schema::{self, GPS_COLUMNS, IMU_COLUMNS};
+use crate::store::gps_from_row;
+use crate::timestamp::Timestamp;
+
+const DAY_MS: u64 = 24 * 3600 * 1000;
+
+/// Rows taken from the database at a time
+const BATCH_ROWS: usize = 8192;
+
+/// Settings for `export_parquet`
+#[derive(Debug, Clone)]
+pub struct ExportConfig {
+    /// Rows to a row group. A day at 100 Hz is 8.6 million IMU rows; a
+    /// million make row groups of about 100 MB before compression.
+    pub row_group_rows: usize,
+    pub compression: Compression,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig { row_group_rows: 1_000_000, compression: Compression::SNAPPY }
+    }
+}
+
+/// What `export_parquet` wrote
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportStats {
+    pub imu_rows: u64,
+    pub gps_rows: u64,
+    pub files: Vec<PathBuf>,
+}
+
+/// Where a device's rows for a day go, e.g.
+/// `imu/uuid=20015998343868/date=2023-11-14/part-0.parquet`. Days are UTC.
+pub fn partition_path(root: &Path, table: &str, uuid: u64, time_ms: u64) -> PathBuf {
+    let (y, m, d) = civil_from_days((time_ms / DAY_MS) as i64);
+    root.join(table)
+        .join(format!("uuid={}", uuid))
+        .join(format!("date={:04}-{:02}-{:02}", y, m, d))
+        .join("part-0.parquet")
+}
+
+type ToBatch<T> = fn(&[T]) -> Result<RecordBatch, Box<dyn Error>>;
+
+/// How one table is read and turned into Arrow
+struct Table<T> {
+    name: &'static str,
+    columns: &'static [(&'static str, &'static str)],
+    /// By the partition's uuid and time, so each is written in turn
+    order: &'static str,
+    from_row: fn(&Row) -> rusqlite::Result<T>,
+    /// uuid and time in ms
+    key: fn(&T) -> (u64, u64),
+    to_batch: ToBatch<T>,
+}
+
+const IMU: Table<ImuShort> = Table {
+    name: "imu",
+    columns: IMU_COLUMNS,
+    order: "uuid, pitime, sequence",
+    from_row: ImuShort::from_row,
+    key: |r| (r.uuid, r.pitime),
+    to_batch: imu_batch,
+};
+
+const GPS: Table<GpsData> = Table {
+    name: "gps",
+    columns: GPS_COLUMNS,
+    order: "uuid, gps_time, sequence",
+    from_row: gps_from_row,
+    key: |d| (d.uuid, d.gps_time),
+    to_batch: gps_batch,
+};
+
+fn ms(values: impl Iterator<Item = u64>) -> ArrayRef {
+    Arc::new(TimestampMillisecondArray::from_iter_values(values.map(|t| t as i64)).with_timezone("UTC"))
+}
+
+fn floats(values: impl Iterator<Item = f32>) -> ArrayRef {
+    Arc::new(Float32Array::from_iter_values(values))
+}
+
+/// A Timestamp's three columns, null when the row has none
+fn stamp_columns(prefix: &str, stamps: Vec<Option<Timestamp>>) -> Vec<(String, ArrayRef, bool)> {
+    vec![
+        (format!("{}_s", prefix), Arc::new(stamps.iter().map(|t| t.map(|t| t.seconds)).collect::<Int64Array>()), true),
+        (format!("{}_ns", prefix), Arc::new(stamps.iter().map(|t| t.map(|t| t.nanos)).collect::<UInt32Array>()), true),
+        (format!("{}_base", prefix), Arc::new(stamps.iter().map(|t| t.map(|t| t.timebase)).collect::<Int32Array>()), true),
+    ]
+}
+
+fn batch(columns: Vec<(&str, ArrayRef, bool)>, stamps: Vec<(String, ArrayRef, bool)>) -> Result<RecordBatch, Box<dyn Error>> {
+    let stamps = stamps.iter().map(|(name, array, nullable)| (name.as_str(), array.clone(), *nullable));
+    Ok(RecordBatch::try_from_iter_with_nullable(columns.into_iter().chain(stamps))?)
+}
+
+fn imu_batch(rows: &[ImuShort]) -> Result<RecordBatch, Box<dyn Error>> {
+    let f = |get: fn(&ImuShort) -> f32| floats(rows.iter().map(get));
+    batch(
+        vec![
+            ("lineno", Arc::new(Int64Array::from_iter_values(rows.iter().map(|r| r.line))), false),
+            ("uuid", Arc::new(UInt64Array::from_iter_values(rows.iter().map(|r| r.uuid))), false),
+            ("pitime", ms(rows.iter().map(|r| r.pitime)), false),
+            ("gps_time", ms(rows.iter().map(|r| r.gps_time)), false),
+            ("sequence", Arc::new(UInt32Array::from_iter_values(rows.iter().map(|r| r.sequence))), false),
+            ("x_accel", f(|r| r.accel_x), false),
+            ("y_accel", f(|r| r.accel_y), false),
+            ("z_accel", f(|r| r.accel_z), false),
+            ("x_gyro", f(|r| r.gyro_x), false),
+            ("y_gyro", f(|r| r.gyro_y), false),
+            ("z_gyro", f(|r| r.gyro_z), false),
+            ("roll_pose", f(|r| r.pose_roll), false),
+            ("pitch_pose", f(|r| r.pose_pitch), false),
+            ("yaw_pose", f(|r| r.pose_yaw), false),
+            ("heading_accuracy", f(|r| r.pose_heading_accuracy), false),
+            ("x_mag", f(|r| r.mag_x), false),
+            ("y_mag", f(|r| r.mag_y), false),
+            ("z_mag", f(|r| r.mag_z), false),
+            ("pressure", f(|r| r.pressure), false),
+            ("altitude", Arc::new(rows.iter().map(|r| r.altitude).collect::<Float32Array>()), true),
+            ("temperature", f(|r| r.temperature), false),
+            ("temp_cpu", f(|r| r.temp_cpu), false),
+            ("uploaded", Arc::new(rows.iter().map(|r| Some(r.uploaded)).collect::<BooleanArray>()), false),
+            ("confirmed", Arc::new(rows.iter().map(|r| Some(r.confirmed)).collect::<BooleanArray>()), false),
+            ("clock_offset", Arc::new(rows.iter().map(|r| r.clock_offset).collect::<Float64Array>()), true),
+            ("clock_error", Arc::new(rows.iter().map(|r| r.clock_error).collect::<Float64Array>()), true),
+        ],
+        stamp_columns("stamp", rows.iter().map(|r| r.stamp).collect()),
+    )
+}
+
+/// status_nsats_vuc is split into its fields, and unknown accuracies,
+/// stored as 0, are null
+fn gps_batch(data: &[GpsData]) -> Result<RecordBatch, Box<dyn Error>> {
+    let f = |get: fn(&GpsData) -> f32| floats(data.iter().map(get));
+    let accuracy = |get: fn(&GpsData) -> f32| -> ArrayRef {
+        Arc::new(data.iter().map(|d| Some(get(d)).filter(|a| *a > 0.0)).collect::<Float32Array>())
+    };
+    let fields: Vec<_> = data.iter().map(|d| decode_fields(d.status_nsats_vuc)).collect();
+    let flag = |get: fn(&(u8, u8, bool, bool, bool)) -> bool| -> ArrayRef {
+        Arc::new(fields.iter().map(|f| Some(get(f))).collect::<BooleanArray>())
+    };
+    let stamps = stamp_columns("pi_stamp", data.iter().map(|d| d.pi_stamp).collect())
+        .into_iter()
+        .chain(stamp_columns("gps_stamp", data.iter().map(|d| d.gps_stamp).collect()))
+        .collect();
+    batch(
+        vec![
+            ("uuid", Arc::new(UInt64Array::from_iter_values(data.iter().map(|d| d.uuid))), false),
+            ("pitime", ms(data.iter().map(|d| d.pitime)), false),
+            ("gps_time", ms(data.iter().map(|d| d.gps_time)), false),
+            ("sequence", Arc::new(UInt32Array::from_iter_values(data.iter().map(|d| d.sequence))), false),
+            ("lat", f(|d| d.lat), false),
+            ("lon", f(|d| d.lon), false),
+            ("alt", f(|d| d.alt), false),
+            ("speed", f(|d| d.speed), false),
+            ("track", f(|d| d.track), false),
+            ("status", Arc::new(UInt8Array::from_iter_values(fields.iter().map(|f| f.0))), false),
+            ("nsats", Arc::new(UInt8Array::from_iter_values(fields.iter().map(|f| f.1))), false),
+            ("valid", flag(|f| f.2), false),
+            ("uploaded", flag(|f| f.3), false),
+            ("confirmed", flag(|f| f.4), false),
+            ("hdop", f(|d| d.hdop), false),
+            ("h_acc", accuracy(|d| d.h_acc), true),
+            ("v_acc", accuracy(|d| d.v_acc), true),
+            ("speed_acc", accuracy(|d| d.speed_acc), true),
+            ("time_valid", Arc::new(UInt32Array::from_iter_values(data.iter().map(|d| d.time_valid))), false),
+        ],
+        stamps,
+    )
+}
+
+/// The partition being written: uuid and day, and its file
+struct Partition {
+    key: (u64, u64),
+    writer: ArrowWriter<File>,
+}
+
+/// Write out what's pending. Returns how many rows that was.
+fn flush<T>(table: &Table<T>, pending: &mut Vec<T>, partition: &mut Partition) -> Result<u64, Box<dyn Error>> {
+    if pending.is_empty() {
+        return Ok(0);
+    }
+    partition.writer.write(&(table.to_batch)(pending)?)?;
+    let n = pending.len() as u64;
+    pending.clear();
+    Ok(n)
+}
+
+/// Export one table. A database from before the table existed has
+/// nothing to export, and one from an older schema is read as replay
+/// reads it.
+fn export_table<T>(conn: &Connection, table: &Table<T>, root: &Path, config: &ExportConfig, files: &mut Vec<PathBuf>) -> Result<u64, Box<dyn Error>> {
+    if !schema::has_table(conn, table.name)? {
+        return Ok(0);
+    }
+    let props = WriterProperties::builder()
+        .set_max_row_group_size(config.row_group_rows)
+        .set_compression(config.compression)
+        .build();
+    let mut current: Option<Partition> = None;
+    let mut pending: Vec<T> = Vec::with_capacity(BATCH_ROWS);
+    let mut exported = 0;
+
+    let columns = schema::select_columns_sql(conn, table.name, table.columns)?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM {} ORDER BY {}", columns, table.name, table.order))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let row = (table.from_row)(row)?;
+        let (uuid, time) = (table.key)(&row);
+        let key = (uuid, time / DAY_MS);
+        if current.as_ref().map(|p| p.key) != Some(key) {
+            if let Some(mut partition) = current.take() {
+                exported += flush(table, &mut pending, &mut partition)?;
+                partition.writer.close()?;
+            }
+            let path = partition_path(root, table.name, uuid, time);
+            fs::create_dir_all(path.parent().ok_or("Partition has no directory")?)?;
+            let schema = (table.to_batch)(std::slice::from_ref(&row))?.schema();
+            let writer = ArrowWriter::try_new(File::create(&path)?, schema, Some(props.clone()))?;
+            files.push(path);
+            current = Some(Partition { key, writer });
+        }
+        pending.push(row);
+        if pending.len() == BATCH_ROWS {
+            exported += flush(table, &mut pending, current.as_mut().ok_or("No partition")?)?;
+        }
+    }
+    if let Some(mut partition) = current {
+        exported += flush(table, &mut pending, &mut partition)?;
+        partition.writer.close()?;
+    }
+    Ok(exported)
+}
+
+/// Write the imu and gps tables of a database as Parquet under `root`, a
+/// file per device and day (see `partition_path`). Times are UTC ms
+/// timestamps, GPS status bits get a column each, and values the
+/// database holds as NULL are null.
+pub fn export_parquet(conn: &Connection, root: &Path, config: &ExportConfig) -> Result<ExportStats, Box<dyn Error>> {
+    let mut stats = ExportStats::default();
+    stats.imu_rows = export_table(conn, &IMU, root, config, &mut stats.files)?;
+    stats.gps_rows = export_table(conn, &GPS, root, config, &mut stats.files)?;
+    Ok(stats)
+}
+
+fn column<'a, T: ArrowPrimitiveType>(batch: &'a RecordBatch, name: &str) -> Result<&'a PrimitiveArray<T>, Box<dyn Error>> {
+    let array = batch.column_by_name(name).ok_or(format!("No column '{}'", name))?;
+    Ok(array.as_primitive_opt::<T>().ok_or(format!("Column '{}' has the wrong type", name))?)
+}
+
+fn flags<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a BooleanArray, Box<dyn Error>> {
+    let array = batch.column_by_name(name).ok_or(format!("No column '{}'", name))?;
+    Ok(array.as_boolean_opt().ok_or(format!("Column '{}' has the wrong type", name))?)
+}
+
+fn nullable<T: ArrowPrimitiveType>(array: &PrimitiveArray<T>, i: usize) -> Option<T::Native> {
+    array.is_valid(i).then(|| array.value(i))
+}
+
+fn read_stamps(batch: &RecordBatch, prefix: &str) -> Result<Vec<Option<Timestamp>>, Box<dyn Error>> {
+    let seconds = column::<Int64Type>(batch, &format!("{}_s", prefix))?;
+    let nanos = column::<UInt32Type>(batch, &format!("{}_ns", prefix))?;
+    let base = column::<Int32Type>(batch, &format!("{}_base", prefix))?;
+    Ok((0..batch.num_rows())
+        .map(|i| nullable(seconds, i).map(|seconds| Timestamp { seconds, nanos: nanos.value(i), timebase: base.value(i) }))
+        .collect())
+}
+
+fn read_batches(path: &Path) -> Result<Vec<RecordBatch>, Box<dyn Error>> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()?;
+    Ok(reader.collect::<Result<_, _>>()?)
+}
+
+/// Read back a file of exported IMU rows
+pub fn read_imu_parquet(path: &Path) -> Result<Vec<ImuShort>, Box<dyn Error>> {
+    let mut rows = Vec::new();
+    for batch in read_batches(path)? {
+        let f = |name| column::<Float32Type>(&batch, name);
+        let (line, uuid, sequence) = (column::<Int64Type>(&batch, "lineno")?, column::<UInt64Type>(&batch, "uuid")?, column::<UInt32Type>(&batch, "sequence")?);
+        let (pitime, gps_time) = (column::<TimestampMillisecondType>(&batch, "pitime")?, column::<TimestampMillisecondType>(&batch, "gps_time")?);
+        let (accel, gyro) = ([f("x_accel")?, f("y_accel")?, f("z_accel")?], [f("x_gyro")?, f("y_gyro")?, f("z_gyro")?]);
+        let (pose, mag) = ([f("roll_pose")?, f("pitch_pose")?, f("yaw_pose")?], [f("x_mag")?, f("y_mag")?, f("z_mag")?]);
+        let (heading_accuracy, pressure, altitude) = (f("heading_accuracy")?, f("pressure")?, f("altitude")?);
+        let (temperature, temp_cpu) = (f("temperature")?, f("temp_cpu")?);
+        let (uploaded, confirmed) = (flags(&batch, "uploaded")?, flags(&batch, "confirmed")?);
+        let (clock_offset, clock_error) = (column::<Float64Type>(&batch, "clock_offset")?, column::<Float64Type>(&batch, "clock_error")?);
+        for (i, stamp) in read_stamps(&batch, "stamp")?.into_iter().enumerate() {
+            rows.push(ImuShort {
+                line: line.value(i),
+                uuid: uuid.value(i),
+                pitime: pitime.value(i) as u64,
+                gps_time: gps_time.value(i) as u64,
+                sequence: sequence.value(i),
+                accel_x: accel[0].value(i),
+                accel_y: accel[1].value(i),
+                accel_z: accel[2].value(i),
+                gyro_x: gyro[0].value(i),
+                gyro_y: gyro[1].value(i),
+                gyro_z: gyro[2].value(i),
+                pose_roll: pose[0].value(i),
+                pose_pitch: pose[1].value(i),
+                pose_yaw: pose[2].value(i),
+                pose_heading_accuracy: heading_accuracy.value(i),
+                mag_x: mag[0].value(i),
+                mag_y: mag[1].value(i),
+                mag_z: mag[2].value(i),
+                pressure: pressure.value(i),
+                altitude: nullable(altitude, i),
+                temperature: temperature.value(i),
+                temp_cpu: temp_cpu.value(i),
+                uploaded: uploaded.value(i),
+                confirmed: confirmed.value(i),
+                clock_offset: nullable(clock_offset, i),
+                clock_error: nullable(clock_error, i),
+                stamp,
+            });
+        }
+    }
+    Ok(rows)
+}
+
+/// Read back a file of exported GPS lines
+pub fn read_gps_parquet(path: &Path) -> Result<Vec<GpsData>, Box<dyn Error>> {
+    let mut data = Vec::new();
+    for batch in read_batches(path)? {
+        let f = |name| column::<Float32Type>(&batch, name);
+        let (uuid, sequence) = (column::<UInt64Type>(&batch, "uuid")?, column::<UInt32Type>(&batch, "sequence")?);
+        let (pitime, gps_time) = (column::<TimestampMillisecondType>(&batch, "pitime")?, column::<TimestampMillisecondType>(&batch, "gps_time")?);
+        let (lat, lon, alt, speed, track, hdop) = (f("lat")?, f("lon")?, f("alt")?, f("speed")?, f("track")?, f("hdop")?);
+        let (status, nsats) = (column::<UInt8Type>(&batch, "status")?, column::<UInt8Type>(&batch, "nsats")?);
+        let (valid, uploaded, confirmed) = (flags(&batch, "valid")?, flags(&batch, "uploaded")?, flags(&batch, "confirmed")?);
+        let (h_acc, v_acc, speed_acc) = (f("h_acc")?, f("v_acc")?, f("speed_acc")?);
+        let time_valid = column::<UInt32Type>(&batch, "time_valid")?;
+        let stamps = read_stamps(&batch, "pi_stamp")?.into_iter().zip(read_stamps(&batch, "gps_stamp")?);
+        for (i, (pi_stamp, gps_stamp)) in stamps.enumerate() {
+            data.push(GpsData {
+                uuid: uuid.value(i),
+                pitime: pitime.value(i) as u64,
+                gps_time: gps_time.value(i) as u64,
+                sequence: sequence.value(i),
+                lat: lat.value(i),
+                lon: lon.value(i),
+                alt: alt.value(i),
+                speed: speed.value(i),
+                track: track.value(i),
+                status_nsats_vuc: encode_fields(status.value(i), nsats.value(i), valid.value(i), uploaded.value(i), confirmed.value(i)),
+                hdop: hdop.value(i),
+                h_acc: nullable(h_acc, i).unwrap_or_default(),
+                v_acc: nullable(v_acc, i).unwrap_or_default(),
+                speed_acc: nullable(speed_acc, i).unwrap_or_default(),
+                time_valid: time_valid.value(i),
+                pi_stamp,
+                gps_stamp,
+            });
+        }
+    }
+    Ok(data)
+}
@@ -0,0 +1,523 @@
+use std::ops::Range;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use postgres::binary_copy::BinaryCopyInWriter;
+use postgres::types::{ToSql, Type};
+use postgres::{Client, NoTls, Row};
+
+use crate::crash::Crash;
+use crate::data_conv::ImuShort;
+use crate::gps::GpsData;
+use crate::schema::{CRASH_COLUMNS, GPS_COLUMNS, IMU_COLUMNS, TRIP_COLUMNS};
+use crate::store::{sql_range, GpsKey, ImuKey, StoreError, TelemetryStore};
+use crate::timestamp::Timestamp;
+use crate::trip::Trip;
+
+/// Integer timestamps are ms, so a day to a chunk
+const CHUNK_MS: i64 = 24 * 3600 * 1000;
+
+/// One column's value, as COPY writes it
+type Cell = Box<dyn ToSql + Sync + Send>;
+
+/// The schema.rs column types as PostgreSQL has them. Every integer is
+/// made a BIGINT, as uuid and the u32 fields don't fit in an INT, and
+/// every FLOAT a DOUBLE PRECISION, which is what FLOAT means there.
+fn pg_type(kind: &str) -> String {
+    match kind.strip_prefix("INT") {
+        Some(rest) => format!("BIGINT{}", rest),
+        None => kind.to_string(),
+    }
+}
+
+fn copy_type(kind: &str) -> Type {
+    if kind.contains("INT") {
+        Type::INT8
+    } else {
+        Type::FLOAT8
+    }
+}
+
+fn names(columns: &[(&str, &str)]) -> String {
+    columns.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")
+}
+
+/// The table and its indexes, and any nullable column an older table is
+/// missing. A TimescaleDB database gets a hypertable on the time column.
+fn create_table(client: &mut Client, table: &str, columns: &[(&str, &str)], time: &str) -> Result<(), postgres::Error> {
+    let typed: Vec<String> = columns.iter().map(|(name, kind)| format!("{} {}", name, pg_type(kind))).collect();
+    let mut sql = format!(
+        "CREATE TABLE IF NOT EXISTS {table} (lineno BIGINT GENERATED BY DEFAULT AS IDENTITY, {});
+         CREATE UNIQUE INDEX IF NOT EXISTS {table}_dedupe ON {table} (uuid, sequence, {time});
+         CREATE INDEX IF NOT EXISTS {table}_time ON {table} (uuid, {time});",
+        typed.join(", ")
+    );
+    for (name, kind) in columns.iter().filter(|(_, kind)| !kind.contains("NOT NULL")) {
+        sql += &format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {};", table, name, pg_type(kind));
+    }
+    client.batch_execute(&sql)?;
+
+    let timescale = client.query_opt("SELECT 1 FROM pg_extension WHERE extname = 'timescaledb'", &[])?.is_some();
+    if timescale {
+        client.execute(
+            // The client can only send these as text, and the interval's
+            // type can't be inferred from create_hypertable's anyelement
+            "SELECT create_hypertable($1::text::regclass, $2::text::name, chunk_time_interval => $3::bigint,
+                 if_not_exists => TRUE, migrate_data => TRUE)",
+            &[&table, &time, &CHUNK_MS],
+        )?;
+    }
+    Ok(())
+}
+
+/// Create the tables if they aren't there. Trips and crashes are few, so
+/// theirs are plain tables.
+fn bootstrap(client: &mut Client) -> Result<(), postgres::Error> {
+    create_table(client, "imu", IMU_COLUMNS, "pitime")?;
+    create_table(client, "gps", GPS_COLUMNS, "gps_time")?;
+    let typed = |columns: &[(&str, &str)]| {
+        columns.iter().map(|(name, kind)| format!("{} {}", name, pg_type(kind))).collect::<Vec<_>>().join(", ")
+    };
+    client.batch_execute(&format!(
+        "CREATE TABLE IF NOT EXISTS trips (lineno BIGINT GENERATED BY DEFAULT AS IDENTITY, {});
+         CREATE INDEX IF NOT EXISTS trips_time ON trips (uuid, start_time);
+         CREATE TABLE IF NOT EXISTS crashes (lineno BIGINT GENERATED BY DEFAULT AS IDENTITY, {});
+         CREATE UNIQUE INDEX IF NOT EXISTS crashes_dedupe ON crashes (uuid, pitime);",
+        typed(TRIP_COLUMNS),
+        typed(CRASH_COLUMNS)
+    ))
+}
+
+fn int(v: impl Into<Option<i64>>) -> Cell {
+    Box::new(v.into())
+}
+
+fn float(v: impl Into<Option<f64>>) -> Cell {
+    Box::new(v.into())
+}
+
+fn stamp_cells(stamp: Option<Timestamp>) -> [Cell; 3] {
+    [
+        int(stamp.map(|t| t.seconds)),
+        int(stamp.map(|t| t.nanos as i64)),
+        int(stamp.map(|t| t.timebase as i64)),
+    ]
+}
+
+/// An imu row in IMU_COLUMNS order
+fn imu_cells(r: &ImuShort) -> Vec<Cell> {
+    let mut cells = vec![
+        int(r.uuid as i64),
+        int(r.pitime as i64),
+        int(r.gps_time as i64),
+        int(r.sequence as i64),
+    ];
+    for v in [
+        r.accel_x, r.accel_y, r.accel_z,
+        r.gyro_x, r.gyro_y, r.gyro_z,
+        r.pose_roll, r.pose_pitch, r.pose_yaw,
+        r.pose_heading_accuracy,
+        r.mag_x, r.mag_y, r.mag_z,
+        r.pressure,
+    ] {
+        cells.push(float(v as f64));
+    }
+    cells.extend([
+        float(r.altitude.map(f64::from)),
+        float(r.temperature as f64),
+        float(r.temp_cpu as f64),
+        int(r.uploaded as i64),
+        int(r.confirmed as i64),
+        float(r.clock_offset),
+        float(r.clock_error),
+    ]);
+    cells.extend(stamp_cells(r.stamp));
+    cells
+}
+
+/// A gps line in GPS_COLUMNS order
+fn gps_cells(d: &GpsData) -> Vec<Cell> {
+    // Unknown accuracies are stored as NULL rather than 0
+    let accuracy = |a: f32| float(Some(a as f64).filter(|a| *a > 0.0));
+    let mut cells = vec![
+        int(d.uuid as i64),
+        int(d.pitime as i64),
+        int(d.gps_time as i64),
+        int(d.sequence as i64),
+        float(d.lat as f64),
+        float(d.lon as f64),
+        float(d.alt as f64),
+        float(d.speed as f64),
+        float(d.track as f64),
+        int(d.status_nsats_vuc as i64),
+        float(d.hdop as f64),
+        accuracy(d.h_acc),
+        accuracy(d.v_acc),
+        accuracy(d.speed_acc),
+        int(d.time_valid as i64),
+    ];
+    cells.extend(stamp_cells(d.pi_stamp));
+    cells.extend(stamp_cells(d.gps_stamp));
+    cells
+}
+
+fn read_stamp(row: &Row, prefix: &str) -> Result<Option<Timestamp>, postgres::Error> {
+    let seconds: Option<i64> = row.try_get(format!("{}_s", prefix).as_str())?;
+    match seconds {
+        Some(seconds) => Ok(Some(Timestamp {
+            seconds,
+            nanos: row.try_get::<_, i64>(format!("{}_ns", prefix).as_str())? as u32,
+            timebase: row.try_get::<_, i64>(format!("{}_base", prefix).as_str())? as i32,
+        })),
+        None => Ok(None),
+    }
+}
+
+fn imu_from_row(row: &Row) -> Result<ImuShort, postgres::Error> {
+    let float = |name: &str| row.try_get::<_, f64>(name).map(|v| v as f32);
+    Ok(ImuShort {
+        line: row.try_get("lineno")?,
+        uuid: row.try_get::<_, i64>("uuid")? as u64,
+        pitime: row.try_get::<_, i64>("pitime")? as u64,
+        gps_time: row.try_get::<_, Option<i64>>("gps_time")?.unwrap_or_default() as u64,
+        sequence: row.try_get::<_, i64>("sequence")? as u32,
+        accel_x: float("x_accel")?,
+        accel_y: float("y_accel")?,
+        accel_z: float("z_accel")?,
+        gyro_x: float("x_gyro")?,
+        gyro_y: float("y_gyro")?,
+        gyro_z: float("z_gyro")?,
+        pose_roll: float("roll_pose")?,
+        pose_pitch: float("pitch_pose")?,
+        pose_yaw: float("yaw_pose")?,
+        pose_heading_accuracy: float("heading_accuracy")?,
+        mag_x: float("x_mag")?,
+        mag_y: float("y_mag")?,
+        mag_z: float("z_mag")?,
+        pressure: float("pressure")?,
+        altitude: row.try_get::<_, Option<f64>>("altitude")?.map(|v| v as f32),
+        temperature: float("temperature")?,
+        temp_cpu: float("temp_cpu")?,
+        uploaded: row.try_get::<_, i64>("uploaded")? != 0,
+        confirmed: row.try_get::<_, i64>("confirmed")? != 0,
+        clock_offset: row.try_get("clock_offset")?,
+        clock_error: row.try_get("clock_error")?,
+        stamp: read_stamp(row, "stamp")?,
+    })
+}
+
+fn gps_from_row(row: &Row) -> Result<GpsData, postgres::Error> {
+    let float = |name: &str| row.try_get::<_, f64>(name).map(|v| v as f32);
+    let accuracy = |name: &str| row.try_get::<_, Option<f64>>(name).map(|v| v.unwrap_or_default() as f32);
+    Ok(GpsData {
+        uuid: row.try_get::<_, i64>("uuid")? as u64,
+        pitime: row.try_get::<_, i64>("pitime")? as u64,
+        gps_time: row.try_get::<_, i64>("gps_time")? as u64,
+        sequence: row.try_get::<_, i64>("sequence")? as u32,
+        lat: float("lat")?,
+        lon: float("lon")?,
+        alt: float("alt")?,
+        speed: float("speed")?,
+        track: float("track")?,
+        status_nsats_vuc: row.try_get::<_, i64>("status_nsats_vuc")? as u32,
+        hdop: float("hdop")?,
+        h_acc: accuracy("h_acc")?,
+        v_acc: accuracy("v_acc")?,
+        speed_acc: accuracy("speed_acc")?,
+        time_valid: row.try_get::<_, Option<i64>>("time_valid")?.unwrap_or_default() as u32,
+        pi_stamp: read_stamp(row, "pi_stamp")?,
+        gps_stamp: read_stamp(row, "gps_stamp")?,
+    })
+}
+
+fn crash_from_row(row: &Row) -> Result<Crash, postgres::Error> {
+    Ok(Crash {
+        uuid: row.try_get::<_, i64>("uuid")? as u64,
+        pitime: row.try_get::<_, i64>("pitime")? as u64,
+        peak_g: row.try_get::<_, f64>("peak_g")? as f32,
+        window_start: row.try_get::<_, i64>("window_start")? as u64,
+        window_end: row.try_get::<_, i64>("window_end")? as u64,
+    })
+}
+
+fn trip_from_row(row: &Row) -> Result<Trip, postgres::Error> {
+    let float = |name: &str| row.try_get::<_, f64>(name).map(|v| v as f32);
+    Ok(Trip {
+        uuid: row.try_get::<_, i64>("uuid")? as u64,
+        start_time: row.try_get::<_, i64>("start_time")? as u64,
+        end_time: row.try_get::<_, i64>("end_time")? as u64,
+        start_lat: float("start_lat")?,
+        start_lon: float("start_lon")?,
+        end_lat: float("end_lat")?,
+        end_lon: float("end_lon")?,
+        distance: float("distance")?,
+        duration: row.try_get::<_, i64>("duration")? as u64,
+        max_speed: float("max_speed")?,
+    })
+}
+
+/// COPY a batch into a temporary table, then move across whatever isn't
+/// already stored. Returns how many were new.
+fn copy_in(client: &mut Client, table: &str, columns: &[(&str, &str)], rows: Vec<Vec<Cell>>) -> Result<usize, postgres::Error> {
+    let names = names(columns);
+    let typed: Vec<String> = columns.iter().map(|(name, kind)| format!("{} {}", name, pg_type(kind))).collect();
+    let types: Vec<Type> = columns.iter().map(|(_, kind)| copy_type(kind)).collect();
+
+    let mut tx = client.transaction()?;
+    tx.batch_execute(&format!(
+        "CREATE TEMP TABLE IF NOT EXISTS {}_incoming ({}) ON COMMIT DELETE ROWS",
+        table,
+        typed.join(", ")
+    ))?;
+    let sink = tx.copy_in(&format!("COPY {}_incoming ({}) FROM STDIN (FORMAT binary)", table, names))?;
+    let mut writer = BinaryCopyInWriter::new(sink, &types);
+    for cells in &rows {
+        let values: Vec<&(dyn ToSql + Sync)> = cells.iter().map(|c| c.as_ref() as &(dyn ToSql + Sync)).collect();
+        writer.write(&values)?;
+    }
+    writer.finish()?;
+    let stored = tx.execute(
+        &format!("INSERT INTO {table} ({names}) SELECT {names} FROM {table}_incoming ON CONFLICT DO NOTHING"),
+        &[],
+    )?;
+    tx.commit()?;
+    Ok(stored as usize)
+}
+
+type Job = Box<dyn FnOnce(&mut Client) + Send>;
+
+/// The PostgreSQL (or TimescaleDB) backend, for a shared analytics
+/// database. The tables match the device's and ServerStore's, and are
+/// created on connecting.
+///
+/// The blocking client can't be used from inside the server's runtime, so
+/// the connection lives on a thread of its own and calls are handed to it.
+pub struct PgStore {
+    jobs: Option<mpsc::Sender<Job>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl PgStore {
+    /// Connect with a libpq style config, e.g.
+    /// "host=localhost user=telemetry dbname=fleet", or a postgresql:// URL
+    pub fn connect(config: &str) -> Result<PgStore, StoreError> {
+        let config = config.to_string();
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (ready, connected) = mpsc::channel();
+        let worker = thread::Builder::new().name("pg-store".into()).spawn(move || {
+            let mut client = match Client::connect(&config, NoTls).and_then(|mut client| {
+                bootstrap(&mut client)?;
+                Ok(client)
+            }) {
+                Ok(client) => client,
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+            for job in queue {
+                job(&mut client);
+            }
+        })?;
+        connected.recv()??;
+        Ok(PgStore { jobs: Some(jobs), worker: Some(worker) })
+    }
+
+    /// Run `f` on the connection's thread and wait for what it returns
+    fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Client) -> Result<T, postgres::Error> + Send + 'static,
+    ) -> Result<T, StoreError> {
+        let (reply, result) = mpsc::channel();
+        let job: Job = Box::new(move |client| {
+            let _ = reply.send(f(client));
+        });
+        let sent = self.jobs.as_ref().is_some_and(|jobs| jobs.send(job).is_ok());
+        if !sent {
+            return Err("The PostgreSQL connection has closed".into());
+        }
+        Ok(result.recv()??)
+    }
+
+    fn exists(&self, sql: &'static str, key: [i64; 3]) -> Result<bool, StoreError> {
+        self.call(move |client| {
+            let row = client.query_one(sql, &[&key[0], &key[1], &key[2]])?;
+            row.try_get(0)
+        })
+    }
+}
+
+impl Drop for PgStore {
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl TelemetryStore for PgStore {
+    fn append_imu(&self, rows: &[ImuShort]) -> Result<usize, StoreError> {
+        let rows: Vec<Vec<Cell>> = rows.iter().map(imu_cells).collect();
+        self.call(move |client| copy_in(client, "imu", IMU_COLUMNS, rows))
+    }
+
+    fn append_gps(&self, data: &[GpsData]) -> Result<usize, StoreError> {
+        let rows: Vec<Vec<Cell>> = data.iter().map(gps_cells).collect();
+        self.call(move |client| copy_in(client, "gps", GPS_COLUMNS, rows))
+    }
+
+    fn imu_range(&self, uuid: u64, range: Range<u64>) -> Result<Vec<ImuShort>, StoreError> {
+        let (start, end) = sql_range(&range);
+        self.call(move |client| {
+            let rows = client.query(
+                "SELECT * FROM imu WHERE uuid = $1 AND pitime >= $2 AND pitime < $3 ORDER BY pitime, sequence",
+                &[&(uuid as i64), &start, &end],
+            )?;
+            rows.iter().map(imu_from_row).collect()
+        })
+    }
+
+    fn gps_range(&self, uuid: u64, range: Range<u64>) -> Result<Vec<GpsData>, StoreError> {
+        let (start, end) = sql_range(&range);
+        self.call(move |client| {
+            let rows = client.query(
+                "SELECT * FROM gps WHERE uuid = $1 AND gps_time >= $2 AND gps_time < $3 ORDER BY gps_time, sequence",
+                &[&(uuid as i64), &start, &end],
+            )?;
+            rows.iter().map(gps_from_row).collect()
+        })
+    }
+
+    fn has_imu(&self, key: ImuKey) -> Result<bool, StoreError> {
+        self.exists(
+            "SELECT EXISTS (SELECT 1 FROM imu WHERE uuid = $1 AND sequence = $2 AND pitime = $3)",
+            [key.uuid as i64, key.sequence as i64, key.pitime as i64],
+        )
+    }
+
+    fn has_gps(&self, key: GpsKey) -> Result<bool, StoreError> {
+        self.exists(
+            "SELECT EXISTS (SELECT 1 FROM gps WHERE uuid = $1 AND sequence = $2 AND gps_time = $3)",
+            [key.uuid as i64, key.sequence as i64, key.gps_time as i64],
+        )
+    }
+
+    fn uncorrected_imu(&self, uuid: u64) -> Result<Vec<ImuShort>, StoreError> {
+        self.call(move |client| {
+            let rows = client.query(
+                "SELECT * FROM imu WHERE uuid = $1 AND clock_offset IS NULL ORDER BY pitime, sequence",
+                &[&(uuid as i64)],
+            )?;
+            rows.iter().map(imu_from_row).collect()
+        })
+    }
+
+    fn update_imu_times(&self, rows: &[ImuShort]) -> Result<usize, StoreError> {
+        let rows = rows.to_vec();
+        self.call(move |client| {
+            let mut tx = client.transaction()?;
+            let update = tx.prepare(
+                "UPDATE imu SET gps_time = $1, clock_offset = $2, clock_error = $3,
+                    stamp_s = $4, stamp_ns = $5, stamp_base = $6
+                 WHERE uuid = $7 AND sequence = $8 AND pitime = $9",
+            )?;
+            let mut updated = 0;
+            for r in &rows {
+                updated += tx.execute(
+                    &update,
+                    &[
+                        &(r.gps_time as i64),
+                        &r.clock_offset,
+                        &r.clock_error,
+                        &r.stamp.map(|t| t.seconds),
+                        &r.stamp.map(|t| t.nanos as i64),
+                        &r.stamp.map(|t| t.timebase as i64),
+                        &(r.uuid as i64),
+                        &(r.sequence as i64),
+                        &(r.pitime as i64),
+                    ],
+                )?;
+            }
+            tx.commit()?;
+            Ok(updated as usize)
+        })
+    }
+
+    fn replace_trips(&self, uuid: u64, range: Range<u64>, trips: &[Trip]) -> Result<(), StoreError> {
+        let (start, end) = sql_range(&range);
+        let trips = trips.to_vec();
+        self.call(move |client| {
+            let mut tx = client.transaction()?;
+            tx.execute(
+                "DELETE FROM trips WHERE uuid = $1 AND start_time < $3 AND end_time >= $2",
+                &[&(uuid as i64), &start, &end],
+            )?;
+            let params: Vec<String> = (1..=TRIP_COLUMNS.len()).map(|i| format!("${}", i)).collect();
+            let insert = tx.prepare(&format!("INSERT INTO trips ({}) VALUES ({})", names(TRIP_COLUMNS), params.join(", ")))?;
+            for t in &trips {
+                tx.execute(
+                    &insert,
+                    &[
+                        &(t.uuid as i64),
+                        &(t.start_time as i64),
+                        &(t.end_time as i64),
+                        &(t.start_lat as f64),
+                        &(t.start_lon as f64),
+                        &(t.end_lat as f64),
+                        &(t.end_lon as f64),
+                        &(t.distance as f64),
+                        &(t.duration as i64),
+                        &(t.max_speed as f64),
+                    ],
+                )?;
+            }
+            tx.commit()
+        })
+    }
+
+    fn trips(&self, uuid: u64, range: Range<u64>) -> Result<Vec<Trip>, StoreError> {
+        let (start, end) = sql_range(&range);
+        self.call(move |client| {
+            let rows = client.query(
+                "SELECT * FROM trips WHERE uuid = $1 AND start_time < $3 AND end_time >= $2 ORDER BY start_time",
+                &[&(uuid as i64), &start, &end],
+            )?;
+            rows.iter().map(trip_from_row).collect()
+        })
+    }
+
+    fn append_crashes(&self, crashes: &[Crash]) -> Result<usize, StoreError> {
+        let crashes = crashes.to_vec();
+        self.call(move |client| {
+            let mut tx = client.transaction()?;
+            let insert = tx.prepare(
+                "INSERT INTO crashes (uuid, pitime, peak_g, window_start, window_end) VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT DO NOTHING",
+            )?;
+            let mut stored = 0;
+            for c in &crashes {
+                stored += tx.execute(
+                    &insert,
+                    &[
+                        &(c.uuid as i64),
+                        &(c.pitime as i64),
+                        &(c.peak_g as f64),
+                        &(c.window_start as i64),
+                        &(c.window_end as i64),
+                    ],
+                )?;
+            }
+            tx.commit()?;
+            Ok(stored as usize)
+        })
+    }
+
+    fn crashes(&self, uuid: u64) -> Result<Vec<Crash>, StoreError> {
+        self.call(move |client| {
+            let rows = client.query("SELECT * FROM crashes WHERE uuid = $1 ORDER BY pitime", &[&(uuid as i64)])?;
+            rows.iter().map(crash_from_row).collect()
+        })
+    }
+}
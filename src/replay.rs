@@ -0,0 +1,282 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use prost::Message;
+use rusqlite::Connection;
+use tokio::time::{sleep_until, Instant};
+
+use crate::gps::{GpsData, GpsVec};
+use crate::imu::{ImuData, ImuVec};
+use crate::data_conv::ImuShort;
+use crate::schema;
+use crate::store::gps_from_row;
+use crate::upload::Uploader;
+
+/// How fast to play a recording back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    RealTime,
+    /// Play back n times faster than recorded
+    Accelerated(f64),
+    /// Send everything as fast as the server will take it
+    Max,
+}
+
+impl ReplaySpeed {
+    /// When a sample `offset` into the recording should be sent, measured
+    /// from the start of the replay. None means don't wait.
+    pub fn delay(&self, offset: Duration) -> Option<Duration> {
+        match self {
+            ReplaySpeed::RealTime => Some(offset),
+            ReplaySpeed::Accelerated(factor) => Some(offset.div_f64(*factor)),
+            ReplaySpeed::Max => None,
+        }
+    }
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = String;
+
+    /// Accepts "realtime", "max" or a speed-up factor such as "10" or "10x"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "realtime" | "1" | "1x" => Ok(ReplaySpeed::RealTime),
+            "max" => Ok(ReplaySpeed::Max),
+            _ => match s.trim_end_matches('x').parse::<f64>() {
+                Ok(f) if f > 0.0 && f.is_finite() => Ok(ReplaySpeed::Accelerated(f)),
+                _ => Err(format!("Unknown replay speed '{}'", s)),
+            },
+        }
+    }
+}
+
+/// A recorded drive, each stream sorted by Pi time
+#[derive(Debug, Default)]
+pub struct Recording {
+    /// Device the drive came from, 0 if the recording doesn't say
+    pub uuid: u64,
+    pub imu: Vec<ImuData>,
+    pub gps: Vec<GpsData>,
+}
+
+/// Read the `imu` and `gps` tables of a recorded SQLite database,
+/// optionally for one device only. A missing table is treated as empty.
+/// Recordings from older versions of the schema are read as they are, so
+/// the connection may be read-only.
+pub fn read_recording(conn: &Connection, uuid: Option<u64>) -> Result<Recording, rusqlite::Error> {
+    let filter = match uuid {
+        Some(u) => format!("WHERE uuid = {}", u as i64),
+        None => String::new(),
+    };
+
+    let imu = if schema::has_table(conn, "imu")? {
+        let columns = schema::select_columns_sql(conn, "imu", schema::IMU_COLUMNS)?;
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM imu {} ORDER BY pitime, lineno", columns, filter))?;
+        let rows = stmt.query_map([], |row| ImuShort::from_row(row).map(ImuData::from))?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    } else {
+        Vec::new()
+    };
+
+    let uuid = match uuid {
+        Some(u) => u,
+        None => conn
+            .query_row("SELECT uuid FROM imu ORDER BY lineno LIMIT 1", [], |row| row.get::<_, i64>(0))
+            .map(|u| u as u64)
+            .unwrap_or(0),
+    };
+
+    let gps = if schema::has_table(conn, "gps")? {
+        let columns = schema::select_columns_sql(conn, "gps", schema::GPS_COLUMNS)?;
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM gps {} ORDER BY pitime, lineno", columns, filter))?;
+        let rows = stmt.query_map([], gps_from_row)?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    } else {
+        Vec::new()
+    };
+
+    Ok(Recording { uuid, imu, gps })
+}
+
+/// Read a recording exported as protobuf-encoded `ImuVec` and `GpsVec` files,
+/// i.e. exactly what goes over the wire
+pub fn read_exported(imu_path: &Path, gps_path: Option<&Path>) -> Result<Recording, Box<dyn Error>> {
+    let imu_vec = ImuVec::decode(fs::read(imu_path)?.as_slice())?;
+    let uuid = imu_vec.uuid;
+    let mut imu = imu_vec.data;
+    let mut gps = match gps_path {
+        Some(path) => GpsVec::decode(fs::read(path)?.as_slice())?.data,
+        None => Vec::new(),
+    };
+    imu.sort_by_key(|d| d.timestamp);
+    gps.sort_by_key(|d| d.pitime);
+    Ok(Recording { uuid, imu, gps })
+}
+
+/// A slice of the recording to be sent in one go
+#[derive(Debug)]
+pub struct Batch {
+    /// Time of the last sample in the batch, relative to the first sample
+    /// of the recording
+    pub offset: Duration,
+    pub imu: ImuVec,
+    pub gps: GpsVec,
+}
+
+/// Cut a recording into batches each spanning at most `window` of recorded
+/// time. Sending each batch at its offset keeps the original timing to
+/// within the window.
+pub fn make_batches(recording: Recording, window: Duration) -> Vec<Batch> {
+    let start = recording
+        .imu
+        .first()
+        .map(|d| d.timestamp)
+        .into_iter()
+        .chain(recording.gps.first().map(|d| d.pitime))
+        .min()
+        .unwrap_or(0);
+    let window = window.as_millis() as u64;
+
+    let mut batches: Vec<Batch> = Vec::new();
+    let mut imu = recording.imu.into_iter().peekable();
+    let mut gps = recording.gps.into_iter().peekable();
+
+    loop {
+        let first = match (imu.peek(), gps.peek()) {
+            (Some(i), Some(g)) => i.timestamp.min(g.pitime),
+            (Some(i), None) => i.timestamp,
+            (None, Some(g)) => g.pitime,
+            (None, None) => break,
+        };
+        let end = first.saturating_add(window.max(1));
+
+        let mut batch = Batch {
+            offset: Duration::ZERO,
+            imu: ImuVec::default(),
+            gps: GpsVec::default(),
+        };
+        let mut last = first;
+        while let Some(d) = imu.next_if(|d| d.timestamp < end) {
+            last = last.max(d.timestamp);
+            batch.imu.data.push(d);
+        }
+        while let Some(d) = gps.next_if(|d| d.pitime < end) {
+            last = last.max(d.pitime);
+            batch.gps.data.push(d);
+        }
+        batch.offset = Duration::from_millis(last - start);
+        batches.push(batch);
+    }
+    batches
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayStats {
+    pub batches: usize,
+    pub imu_lines: usize,
+    pub gps_lines: usize,
+    /// How far behind schedule the slowest batch went out
+    pub max_lag: Duration,
+}
+
+/// Send the batches to the server, pacing them to the requested speed
+pub async fn replay(
+    uploader: &mut Uploader,
+    batches: Vec<Batch>,
+    speed: ReplaySpeed,
+) -> Result<ReplayStats, Box<dyn Error>> {
+    let mut stats = ReplayStats::default();
+    let start = Instant::now();
+
+    for batch in batches {
+        if let Some(delay) = speed.delay(batch.offset) {
+            let due = start + delay;
+            sleep_until(due).await;
+            stats.max_lag = stats.max_lag.max(Instant::now().duration_since(due));
+        }
+
+        stats.batches += 1;
+        if !batch.imu.data.is_empty() {
+            stats.imu_lines += batch.imu.data.len();
+            uploader.send_imu(batch.imu).await?;
+        }
+        if !batch.gps.data.is_empty() {
+            stats.gps_lines += batch.gps.data.len();
+            uploader.send_gps(batch.gps).await?;
+        }
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn imu(timestamp: u64) -> ImuData {
+        ImuData { timestamp, ..Default::default() }
+    }
+
+    fn gps(pitime: u64) -> GpsData {
+        GpsData { pitime, ..Default::default() }
+    }
+
+    #[test]
+    fn speeds_parse() {
+        assert_eq!("realtime".parse(), Ok(ReplaySpeed::RealTime));
+        assert_eq!("1x".parse(), Ok(ReplaySpeed::RealTime));
+        assert_eq!("max".parse(), Ok(ReplaySpeed::Max));
+        assert_eq!("10".parse(), Ok(ReplaySpeed::Accelerated(10.0)));
+        assert_eq!("2.5x".parse(), Ok(ReplaySpeed::Accelerated(2.5)));
+        for bad in ["", "x", "fast", "0", "-2", "inf", "NaN", "10y"] {
+            assert_eq!(bad.parse::<ReplaySpeed>(), Err(format!("Unknown replay speed '{}'", bad)));
+        }
+    }
+
+    #[test]
+    fn delays_scale_with_speed() {
+        let offset = Duration::from_millis(4500);
+        assert_eq!(ReplaySpeed::RealTime.delay(offset), Some(offset));
+        assert_eq!(ReplaySpeed::Accelerated(10.0).delay(offset), Some(Duration::from_millis(450)));
+        assert_eq!(ReplaySpeed::Accelerated(0.5).delay(offset), Some(Duration::from_secs(9)));
+        assert_eq!(ReplaySpeed::Max.delay(offset), None);
+    }
+
+    #[test]
+    fn batches_cover_a_window_from_their_first_sample() {
+        let recording = Recording {
+            uuid: 1,
+            imu: [1000, 1100, 1999, 2000, 2500, 6000].into_iter().map(imu).collect(),
+            gps: [900, 1950, 4000].into_iter().map(gps).collect(),
+        };
+        let batches = make_batches(recording, Duration::from_secs(1));
+        let times: Vec<(Vec<u64>, Vec<u64>, u64)> = batches
+            .iter()
+            .map(|b| {
+                let imu = b.imu.data.iter().map(|d| d.timestamp).collect();
+                let gps = b.gps.data.iter().map(|d| d.pitime).collect();
+                (imu, gps, b.offset.as_millis() as u64)
+            })
+            .collect();
+        // Windows start at the first unsent sample, so the gap before 4000
+        // has no empty batches, and offsets are from the first GPS fix
+        assert_eq!(
+            times,
+            [
+                (vec![1000, 1100], vec![900], 200),
+                (vec![1999, 2000, 2500], vec![1950], 1600),
+                (vec![], vec![4000], 3100),
+                (vec![6000], vec![], 5100),
+            ]
+        );
+
+        // A zero window still makes progress, one timestamp at a time
+        let recording = Recording { imu: [5, 5, 6].into_iter().map(imu).collect(), ..Default::default() };
+        let sizes: Vec<usize> = make_batches(recording, Duration::ZERO).iter().map(|b| b.imu.data.len()).collect();
+        assert_eq!(sizes, [2, 1]);
+        assert!(make_batches(Recording::default(), Duration::from_secs(1)).is_empty());
+    }
+}
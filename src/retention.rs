@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use rusqlite::{Connection, OptionalExtension, Result};
+
+/// Tables that get pruned, with the condition for a row the server has
+/// confirmed. GPS keeps the flag in status_nsats_vuc, see
+/// fake_gps::encode_fields. The crash tables aren't here: a capture is
+/// kept however full the card gets.
+const TABLES: &[(&str, &str)] = &[("imu", "confirmed != 0"), ("gps", "status_nsats_vuc & 1 = 1")];
+
+/// How much the device keeps. Confirmed rows are safe on the server, so
+/// they go first; unconfirmed rows only go once the emergency threshold
+/// is passed, as losing the oldest readings beats the logger failing.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Confirmed rows older than this, by pitime, are deleted
+    pub max_age: Option<Duration>,
+    /// Live database size to keep under, bytes. The oldest confirmed rows
+    /// are evicted until it fits.
+    pub max_bytes: Option<u64>,
+    /// Past this size the oldest rows are evicted whether confirmed or
+    /// not, until back under it
+    pub emergency_bytes: Option<u64>,
+    /// Free pages handed back to the filesystem per prune. 0 hands back
+    /// all of them.
+    pub vacuum_pages: u32,
+    /// Rows deleted per statement while evicting
+    pub batch: usize,
+    /// How often the logger prunes
+    pub interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_age: Some(Duration::from_secs(7 * 24 * 3600)),
+            max_bytes: None,
+            emergency_bytes: None,
+            vacuum_pages: 1024,
+            batch: 1000,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What a prune did
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PruneStats {
+    /// Confirmed rows past max_age
+    pub expired: u64,
+    /// Confirmed rows evicted to get under max_bytes
+    pub evicted: u64,
+    /// Rows evicted past emergency_bytes, confirmed or not
+    pub emergency: u64,
+    /// Pages handed back by the incremental vacuum
+    pub vacuumed_pages: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl PruneStats {
+    pub fn deleted(&self) -> u64 {
+        self.expired + self.evicted + self.emergency
+    }
+}
+
+fn pragma(conn: &Connection, name: &str) -> Result<u64> {
+    conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get::<_, i64>(0))
+        .map(|n| n as u64)
+}
+
+/// Bytes in pages holding data. Deleted rows leave free pages that still
+/// take up the file until vacuumed, so these aren't counted.
+pub fn used_bytes(conn: &Connection) -> Result<u64> {
+    Ok((pragma(conn, "page_count")? - pragma(conn, "freelist_count")?) * pragma(conn, "page_size")?)
+}
+
+/// Bytes the database file takes up, free pages and all
+pub fn file_bytes(conn: &Connection) -> Result<u64> {
+    Ok(pragma(conn, "page_count")? * pragma(conn, "page_size")?)
+}
+
+/// Switch the database to incremental auto-vacuum, so pruning can give
+/// space back without a full VACUUM. An existing database has to be
+/// rebuilt once to switch; returns true if that was done.
+pub fn enable_incremental_vacuum(conn: &Connection) -> Result<bool> {
+    if pragma(conn, "auto_vacuum")? == 2 {
+        return Ok(false);
+    }
+    conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+    Ok(true)
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|n| n > 0)
+}
+
+fn tables(conn: &Connection) -> Result<Vec<(&'static str, &'static str)>> {
+    let mut found = Vec::new();
+    for &(table, confirmed) in TABLES {
+        if table_exists(conn, table)? {
+            found.push((table, confirmed));
+        }
+    }
+    Ok(found)
+}
+
+/// Delete the oldest rows matching `condition`, `batch` at a time and
+/// oldest table first, until the live data fits in `limit`
+fn evict(conn: &Connection, condition: impl Fn(&str) -> &str, limit: u64, batch: usize) -> Result<u64> {
+    let tables = tables(conn)?;
+    let mut deleted = 0;
+    while used_bytes(conn)? > limit {
+        let mut oldest: Option<(i64, &str, &str)> = None;
+        for &(table, confirmed) in &tables {
+            let sql = format!("SELECT pitime FROM {} WHERE {} ORDER BY lineno LIMIT 1", table, condition(confirmed));
+            let pitime: Option<i64> = conn.query_row(&sql, [], |row| row.get(0)).optional()?;
+            if let Some(pitime) = pitime {
+                if oldest.is_none_or(|(t, _, _)| pitime < t) {
+                    oldest = Some((pitime, table, condition(confirmed)));
+                }
+            }
+        }
+        let (_, table, condition) = match oldest {
+            Some(oldest) => oldest,
+            None => break,
+        };
+        deleted += conn.execute(
+            &format!(
+                "DELETE FROM {0} WHERE lineno IN (SELECT lineno FROM {0} WHERE {1} ORDER BY lineno LIMIT ?1)",
+                table, condition
+            ),
+            [batch as i64],
+        )? as u64;
+    }
+    Ok(deleted)
+}
+
+/// Apply `policy` to the device database. `now` is the Pi clock in ms
+/// since the epoch, the same clock as pitime.
+pub fn prune(conn: &Connection, policy: &RetentionPolicy, now: u64) -> Result<PruneStats> {
+    let mut stats = PruneStats { bytes_before: file_bytes(conn)?, ..Default::default() };
+
+    if let Some(max_age) = policy.max_age {
+        let cutoff = now.saturating_sub(max_age.as_millis() as u64) as i64;
+        for (table, confirmed) in tables(conn)? {
+            let sql = format!("DELETE FROM {} WHERE {} AND pitime < ?1", table, confirmed);
+            stats.expired += conn.execute(&sql, [cutoff])? as u64;
+        }
+    }
+    if let Some(max_bytes) = policy.max_bytes {
+        stats.evicted = evict(conn, |confirmed| confirmed, max_bytes, policy.batch)?;
+    }
+    if let Some(emergency_bytes) = policy.emergency_bytes {
+        stats.emergency = evict(conn, |_| "1", emergency_bytes, policy.batch)?;
+    }
+
+    if pragma(conn, "auto_vacuum")? == 2 {
+        let free = pragma(conn, "freelist_count")?;
+        // Each step of the pragma frees one page
+        let mut stmt = conn.prepare(&format!("PRAGMA incremental_vacuum({})", policy.vacuum_pages))?;
+        let mut rows = stmt.query([])?;
+        while rows.next()?.is_some() {}
+        stats.vacuumed_pages = free - pragma(conn, "freelist_count")?;
+    }
+    stats.bytes_after = file_bytes(conn)?;
+    Ok(stats)
+}
@@ -0,0 +1,215 @@
+use rusqlite::{Connection, Result};
+
+/// Columns of the imu table after lineno. Every CREATE and INSERT for the
+/// table is built from this list, and rows are read back by these names.
+/// pitime and gps_time are ms since the epoch; a Timestamp with its full
+/// precision is kept in the `_s`, `_ns` and `_base` columns.
+pub const IMU_COLUMNS: &[(&str, &str)] = &[
+    ("uuid", "BIGINT NOT NULL"),
+    ("pitime", "BIGINT NOT NULL"),
+    ("gps_time", "BIGINT"),
+    ("sequence", "INT NOT NULL"),
+    ("x_accel", "FLOAT NOT NULL"),
+    ("y_accel", "FLOAT NOT NULL"),
+    ("z_accel", "FLOAT NOT NULL"),
+    ("x_gyro", "FLOAT NOT NULL"),
+    ("y_gyro", "FLOAT NOT NULL"),
+    ("z_gyro", "FLOAT NOT NULL"),
+    ("roll_pose", "FLOAT NOT NULL"),
+    ("pitch_pose", "FLOAT NOT NULL"),
+    ("yaw_pose", "FLOAT NOT NULL"),
+    ("heading_accuracy", "FLOAT NOT NULL"),
+    ("x_mag", "FLOAT NOT NULL"),
+    ("y_mag", "FLOAT NOT NULL"),
+    ("z_mag", "FLOAT NOT NULL"),
+    ("pressure", "FLOAT"),
+    ("altitude", "FLOAT"),
+    ("temperature", "FLOAT"),
+    ("temp_cpu", "FLOAT"),
+    ("uploaded", "INT NOT NULL"),
+    ("confirmed", "INT NOT NULL"),
+    ("clock_offset", "FLOAT"),
+    ("clock_error", "FLOAT"),
+    ("stamp_s", "BIGINT"),
+    ("stamp_ns", "INT"),
+    ("stamp_base", "INT"),
+];
+
+/// Columns of the gps table after lineno. uploaded and confirmed live in
+/// the bottom bits of status_nsats_vuc, see fake_gps::encode_fields
+pub const GPS_COLUMNS: &[(&str, &str)] = &[
+    ("uuid", "BIGINT NOT NULL"),
+    ("pitime", "BIGINT NOT NULL"),
+    ("gps_time", "BIGINT NOT NULL"),
+    ("sequence", "INT NOT NULL"),
+    ("lat", "FLOAT NOT NULL"),
+    ("lon", "FLOAT NOT NULL"),
+    ("alt", "FLOAT NOT NULL"),
+    ("speed", "FLOAT NOT NULL"),
+    ("track", "FLOAT NOT NULL"),
+    ("status_nsats_vuc", "INT NOT NULL"),
+    ("hdop", "FLOAT NOT NULL"),
+    ("h_acc", "FLOAT"),
+    ("v_acc", "FLOAT"),
+    ("speed_acc", "FLOAT"),
+    ("time_valid", "INT"),
+    ("pi_stamp_s", "BIGINT"),
+    ("pi_stamp_ns", "INT"),
+    ("pi_stamp_base", "INT"),
+    ("gps_stamp_s", "BIGINT"),
+    ("gps_stamp_ns", "INT"),
+    ("gps_stamp_base", "INT"),
+];
+
+/// Columns of the device's crashes table after lineno, one row per
+/// impact, see crash.rs. The IMU lines captured around it are in
+/// crash_imu, which has IMU_COLUMNS; both are left alone by retention.
+/// The server keeps the crashes it is sent in a table of its own.
+pub const CRASH_COLUMNS: &[(&str, &str)] = &[
+    ("uuid", "BIGINT NOT NULL"),
+    ("pitime", "BIGINT NOT NULL"),
+    ("peak_g", "FLOAT NOT NULL"),
+    ("window_start", "BIGINT NOT NULL"),
+    ("window_end", "BIGINT NOT NULL"),
+    ("uploaded", "INT"),
+];
+
+/// Columns of the server's trips table after lineno, see trips.rs. Times
+/// are GPS time, ms since the epoch.
+pub const TRIP_COLUMNS: &[(&str, &str)] = &[
+    ("uuid", "BIGINT NOT NULL"),
+    ("start_time", "BIGINT NOT NULL"),
+    ("end_time", "BIGINT NOT NULL"),
+    ("start_lat", "FLOAT NOT NULL"),
+    ("start_lon", "FLOAT NOT NULL"),
+    ("end_lat", "FLOAT NOT NULL"),
+    ("end_lon", "FLOAT NOT NULL"),
+    ("distance", "FLOAT NOT NULL"),
+    ("duration", "BIGINT NOT NULL"),
+    ("max_speed", "FLOAT NOT NULL"),
+];
+
+/// CREATE TABLE for one of the column lists above.
+/// lineno needs to be exactly INTEGER PRIMARY KEY to act as a ROWID
+pub fn create_table_sql(table: &str, columns: &[(&str, &str)]) -> String {
+    let columns: Vec<String> = columns
+        .iter()
+        .map(|(name, kind)| format!("{} {}", name, kind))
+        .collect();
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} (lineno INTEGER PRIMARY KEY NULL, {})",
+        table,
+        columns.join(", ")
+    )
+}
+
+/// INSERT with a named parameter per column, e.g. :x_accel. Rows that hit
+/// a unique index are skipped rather than failing the whole statement.
+pub fn insert_sql(table: &str, columns: &[(&str, &str)]) -> String {
+    let names: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+    let params: Vec<String> = names.iter().map(|name| format!(":{}", name)).collect();
+    format!(
+        "INSERT OR IGNORE INTO {} ({}) VALUES ({})",
+        table,
+        names.join(", "),
+        params.join(", ")
+    )
+}
+
+fn column_names(conn: &Connection, table: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))?;
+    let names = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    names.collect()
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    Ok(column_names(conn, table)?.iter().any(|name| name == column))
+}
+
+pub fn has_table(conn: &Connection, table: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get(0),
+    )
+}
+
+/// SELECT list that reads a table made by an older version of the schema
+/// as if it had `columns`, without changing it: nullable columns it lacks
+/// read as NULL, and an imu table from before the pressure column has its
+/// raw pressure read out of altitude.
+pub fn select_columns_sql(conn: &Connection, table: &str, columns: &[(&str, &str)]) -> Result<String> {
+    let present = column_names(conn, table)?;
+    let has = |name: &str| present.iter().any(|p| p == name);
+    let old_pressure = columns.iter().any(|(name, _)| *name == "pressure") && !has("pressure");
+    let mut select = vec!["lineno".to_string()];
+    for (name, kind) in columns {
+        select.push(match *name {
+            "pressure" if old_pressure => "altitude AS pressure".to_string(),
+            "altitude" if old_pressure => "NULL AS altitude".to_string(),
+            name if !has(name) && !kind.contains("NOT NULL") => format!("NULL AS {}", name),
+            name => name.to_string(),
+        });
+    }
+    Ok(select.join(", "))
+}
+
+/// Add any nullable column in `columns` that an older table doesn't have.
+/// Rows already there get NULL, which reads back as "not known".
+fn add_missing_columns(conn: &Connection, table: &str, columns: &[(&str, &str)]) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for (name, kind) in columns.iter().filter(|(_, kind)| !kind.contains("NOT NULL")) {
+        if !has_column(&tx, table, name)? {
+            tx.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, name, kind), ())?;
+        }
+    }
+    tx.commit()
+}
+
+/// Create the imu table if it isn't there, and bring an older one up to
+/// date. Tables from before the pressure column existed kept the raw
+/// pressure in altitude, so that is moved over.
+pub fn create_imu_table(conn: &Connection) -> Result<()> {
+    conn.execute(&create_table_sql("imu", IMU_COLUMNS), ())?;
+    if !has_column(conn, "imu", "pressure")? {
+        conn.execute_batch(
+            "BEGIN;
+             ALTER TABLE imu ADD COLUMN pressure FLOAT;
+             UPDATE imu SET pressure = altitude, altitude = NULL;
+             COMMIT;",
+        )?;
+    }
+    add_missing_columns(conn, "imu", IMU_COLUMNS)
+}
+
+/// Create the gps table if it isn't there, and bring an older one up to
+/// date
+pub fn create_gps_table(conn: &Connection) -> Result<()> {
+    conn.execute(&create_table_sql("gps", GPS_COLUMNS), ())?;
+    add_missing_columns(conn, "gps", GPS_COLUMNS)
+}
+
+/// Create the trips table if it isn't there
+pub fn create_trip_table(conn: &Connection) -> Result<()> {
+    conn.execute(&create_table_sql("trips", TRIP_COLUMNS), ())?;
+    conn.execute("CREATE INDEX IF NOT EXISTS trips_time ON trips (uuid, start_time)", ())?;
+    add_missing_columns(conn, "trips", TRIP_COLUMNS)
+}
+
+/// Create the crashes and crash_imu tables if they aren't there. Lines
+/// captured for two crashes whose windows overlap are stored once.
+pub fn create_crash_tables(conn: &Connection) -> Result<()> {
+    create_crash_table(conn)?;
+    conn.execute(&create_table_sql("crash_imu", IMU_COLUMNS), ())?;
+    conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS crash_imu_dedupe ON crash_imu (uuid, sequence, pitime)", ())?;
+    add_missing_columns(conn, "crash_imu", IMU_COLUMNS)
+}
+
+/// Create the crashes table if it isn't there, and bring an older one up
+/// to date. A crash is known by its device and the line that triggered
+/// it, so one sent twice is stored once.
+pub fn create_crash_table(conn: &Connection) -> Result<()> {
+    conn.execute(&create_table_sql("crashes", CRASH_COLUMNS), ())?;
+    conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS crashes_dedupe ON crashes (uuid, pitime)", ())?;
+    add_missing_columns(conn, "crashes", CRASH_COLUMNS)
+}
@@ -1,73 +1,39 @@
-use std::thread;
-use tonic::{transport::Server, Request, Response, Status};
+use std::sync::Arc;
 
-use imu::imu_data_server_server::{ImuDataServer, ImuDataServerServer}     ;
-use gps::gps_data_server_server::{GpsDataServer, GpsDataServerServer}     ;
-use imu::{ImuVec, ImuReply};
-use gps::{GpsVec, GpsReply};
+use grpc_tests::baro::{BaroConfig, Barometers};
+use grpc_tests::data_defs::Pressure;
+use grpc_tests::service::spawn_server_with;
+use grpc_tests::pg_store::PgStore;
+use grpc_tests::store::{ServerStore, TelemetryStore};
+use grpc_tests::timesync::ClockSyncs;
 
-
-pub mod imu {
-    tonic::include_proto!("imu");
-}
-pub mod gps {
-    tonic::include_proto!("gps");
-}
-
-
-#[derive(Debug, Default)]
-pub struct ImuDataSource {}
-
-#[tonic::async_trait]
-impl ImuDataServer for ImuDataSource {
-    async fn send_imu(
-        &self,
-        request: Request<ImuVec>,
-    ) -> Result<Response<ImuReply>, Status> {
-        // println!("Got a request: {:?}", request);
-
-        let n_lines = request.into_inner().data.len();
-
-        let reply = ImuReply {
-            message: format!("{} IMU lines received!", n_lines), //, request.into_inner().name),
-        };
-
-        Ok(Response::new(reply))
-    }
-}
-
-#[derive(Debug, Default)]
-pub struct GpsDataSource {}
-
-#[tonic::async_trait]
-impl GpsDataServer for GpsDataSource {
-    async fn send_gps(
-        &self,
-        request: Request<GpsVec>,
-    ) -> Result<Response<GpsReply>, Status> {
-
-        let n_lines = request.into_inner().data.len();
-
-        let reply = GpsReply {
-            message: format!("{} GPS lines received!", n_lines), 
-        };
-
-        Ok(Response::new(reply))
-    }
-}
+const USAGE: &str = "usage: server [--p0 <sea level pressure, Pa>] [--no-gps-calibration] [--postgres <config>]";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::1]:50051".parse()?;
-    let greeter = ImuDataSource::default();
-    let gps_data = GpsDataSource::default();
+    let mut baro = BaroConfig::default();
+    let mut postgres = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--p0" => baro.p0 = Pressure::from_pascals(args.next().ok_or(USAGE)?.parse()?),
+            "--no-gps-calibration" => baro.gps_calibration = false,
+            "--postgres" => postgres = Some(args.next().ok_or(USAGE)?),
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    let store: Arc<dyn TelemetryStore> = match postgres {
+        Some(config) => Arc::new(PgStore::connect(&config).map_err(|e| e as Box<dyn std::error::Error>)?),
+        None => Arc::new(ServerStore::open("./server_data.db3")?),
+    };
 
+    let clocks = Arc::new(ClockSyncs::default());
+    let server = spawn_server_with(store, Arc::new(Barometers::new(baro)), clocks, "[::1]:50051").await?;
+    println!("Listening on {}", server.addr);
 
-    Server::builder()
-        .add_service(ImuDataServerServer::new(greeter))
-        .add_service(GpsDataServerServer::new(gps_data))
-        .serve(addr)
-        .await?;
+    tokio::signal::ctrl_c().await?;
+    server.stop().await?;
 
     Ok(())
 
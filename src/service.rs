@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::baro::Barometers;
+use crate::data_conv::ImuShort;
+use crate::gps::gps_data_server_server::{GpsDataServer, GpsDataServerServer};
+use crate::gps::{GpsReply, GpsVec};
+use crate::imu::imu_data_server_server::{ImuDataServer, ImuDataServerServer};
+use crate::imu::{CrashVec, ImuReply, ImuVec};
+use crate::store::TelemetryStore;
+use crate::timesync::{backfill, ClockSyncs};
+use crate::trip::trip_server_server::{TripServer, TripServerServer};
+use crate::trip::{TripList, TripQuery};
+use crate::trips::{refresh_trips, TripConfig};
+
+/// How long trip detection waits after lines arrive, so a burst of
+/// batches is handled in one go
+const TRIP_DELAY: Duration = Duration::from_secs(2);
+
+/// Trips to detect again for lines just stored, done in the background so
+/// uploads don't wait on it. A device's ranges are merged until they're
+/// got to.
+pub struct TripUpdates {
+    store: Arc<dyn TelemetryStore>,
+    config: TripConfig,
+    /// GPS time, ms, per device
+    pending: Mutex<HashMap<u64, Range<u64>>>,
+    /// Held while trips are being detected, so a reader waits for them
+    refreshing: Mutex<()>,
+    work: Notify,
+}
+
+impl TripUpdates {
+    pub fn new(store: Arc<dyn TelemetryStore>, config: TripConfig) -> TripUpdates {
+        TripUpdates { store, config, pending: Mutex::new(HashMap::new()), refreshing: Mutex::new(()), work: Notify::new() }
+    }
+
+    /// Note lines stored for `uuid` at `times`, GPS time, ms
+    pub fn mark(&self, uuid: u64, times: impl Iterator<Item = u64>) {
+        let (start, end) = times.fold((u64::MAX, 0), |(start, end), t| (start.min(t), end.max(t)));
+        if start <= end {
+            self.add(uuid, start..end + 1);
+            self.work.notify_one();
+        }
+    }
+
+    fn add(&self, uuid: u64, range: Range<u64>) {
+        let mut pending = self.pending.lock().unwrap();
+        let merged = pending.entry(uuid).or_insert(range.clone());
+        *merged = merged.start.min(range.start)..merged.end.max(range.end);
+    }
+
+    /// Detect the trips pending for `uuid`, or for every device. Failures
+    /// are logged rather than failing an upload, whose lines are stored
+    /// by then, and are tried again with the device's next lines.
+    pub fn flush(&self, uuid: Option<u64>) {
+        let _refreshing = self.refreshing.lock().unwrap();
+        let ranges: Vec<(u64, Range<u64>)> = {
+            let mut pending = self.pending.lock().unwrap();
+            match uuid {
+                Some(uuid) => pending.remove(&uuid).map(|range| (uuid, range)).into_iter().collect(),
+                None => pending.drain().collect(),
+            }
+        };
+        for (uuid, range) in ranges {
+            if let Err(e) = refresh_trips(self.store.as_ref(), uuid, range.clone(), &self.config) {
+                eprintln!("Failed to update device {:x}'s trips: {}", uuid, e);
+                self.add(uuid, range);
+            }
+        }
+    }
+
+    /// `flush` off the async runtime
+    async fn flush_blocking(self: &Arc<Self>, uuid: Option<u64>) {
+        let updates = self.clone();
+        let _ = tokio::task::spawn_blocking(move || updates.flush(uuid)).await;
+    }
+
+    /// Flush whatever's pending a little after it's marked, until the
+    /// task is aborted
+    async fn run(self: Arc<Self>) {
+        loop {
+            self.work.notified().await;
+            tokio::time::sleep(TRIP_DELAY).await;
+            self.flush_blocking(None).await;
+        }
+    }
+}
+
+pub struct ImuDataSource {
+    store: Arc<dyn TelemetryStore>,
+    barometers: Arc<Barometers>,
+    clocks: Arc<ClockSyncs>,
+    trips: Arc<TripUpdates>,
+}
+
+impl ImuDataSource {
+    /// Altitude is worked out from pressure as lines arrive, and GPS time
+    /// from the Pi clock, using the barometers and clock models that the
+    /// GPS service keeps calibrated
+    pub fn new(
+        store: Arc<dyn TelemetryStore>,
+        barometers: Arc<Barometers>,
+        clocks: Arc<ClockSyncs>,
+        trips: Arc<TripUpdates>,
+    ) -> ImuDataSource {
+        ImuDataSource { store, barometers, clocks, trips }
+    }
+}
+
+#[tonic::async_trait]
+impl ImuDataServer for ImuDataSource {
+    async fn send_imu(
+        &self,
+        request: Request<ImuVec>,
+    ) -> Result<Response<ImuReply>, Status> {
+        let imu = request.into_inner();
+
+        if imu.uuid == 0 {
+            return Err(Status::invalid_argument("IMU batch has no device uuid"));
+        }
+        if imu.data.is_empty() {
+            return Err(Status::invalid_argument("IMU batch is empty"));
+        }
+
+        let n_lines = imu.data.len();
+        let mut rows = Vec::with_capacity(n_lines);
+        for d in imu.data {
+            let sequence = d.sequence;
+            let mut row = ImuShort::try_from(d).map_err(|e| {
+                Status::invalid_argument(format!("IMU line {}: {}", sequence, e))
+            })?;
+            row.uuid = imu.uuid;
+            row.uploaded = true;
+            row.confirmed = true;
+            rows.push(row);
+        }
+        self.clocks.apply(imu.uuid, &mut rows);
+        self.barometers.apply(imu.uuid, &mut rows);
+
+        let stored = self
+            .store
+            .append_imu(&rows)
+            .map_err(|e| Status::internal(format!("Failed to store IMU lines: {}", e)))?;
+        // The engine vibration may settle where a trip ended
+        if stored > 0 {
+            let times = rows.iter().map(|r| if r.gps_time > 0 { r.gps_time } else { r.pitime });
+            self.trips.mark(imu.uuid, times);
+        }
+
+        let reply = ImuReply {
+            message: format!("{} IMU lines received!", n_lines),
+            stored: stored as u32,
+            duplicates: (n_lines - stored) as u32,
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn send_crashes(
+        &self,
+        request: Request<CrashVec>,
+    ) -> Result<Response<ImuReply>, Status> {
+        let crashes = request.into_inner().crashes;
+
+        if crashes.is_empty() {
+            return Err(Status::invalid_argument("Crash batch is empty"));
+        }
+        if let Some(crash) = crashes.iter().find(|c| c.uuid == 0) {
+            return Err(Status::invalid_argument(format!("Crash at {} has no device uuid", crash.pitime)));
+        }
+
+        let stored = self
+            .store
+            .append_crashes(&crashes)
+            .map_err(|e| Status::internal(format!("Failed to store crashes: {}", e)))?;
+
+        let reply = ImuReply {
+            message: format!("{} crashes received!", crashes.len()),
+            stored: stored as u32,
+            duplicates: (crashes.len() - stored) as u32,
+        };
+
+        Ok(Response::new(reply))
+    }
+}
+
+pub struct GpsDataSource {
+    store: Arc<dyn TelemetryStore>,
+    barometers: Arc<Barometers>,
+    clocks: Arc<ClockSyncs>,
+    trips: Arc<TripUpdates>,
+}
+
+impl GpsDataSource {
+    /// Trips are detected again around each batch that has new lines
+    pub fn new(
+        store: Arc<dyn TelemetryStore>,
+        barometers: Arc<Barometers>,
+        clocks: Arc<ClockSyncs>,
+        trips: Arc<TripUpdates>,
+    ) -> GpsDataSource {
+        GpsDataSource { store, barometers, clocks, trips }
+    }
+}
+
+#[tonic::async_trait]
+impl GpsDataServer for GpsDataSource {
+    async fn send_gps(
+        &self,
+        request: Request<GpsVec>,
+    ) -> Result<Response<GpsReply>, Status> {
+        let gps = request.into_inner();
+
+        if gps.data.is_empty() {
+            return Err(Status::invalid_argument("GPS batch is empty"));
+        }
+        if let Some(d) = gps.data.iter().find(|d| d.uuid == 0) {
+            return Err(Status::invalid_argument(format!(
+                "GPS line {} has no device uuid",
+                d.sequence
+            )));
+        }
+
+        let n_lines = gps.data.len();
+        let stored = self
+            .store
+            .append_gps(&gps.data)
+            .map_err(|e| Status::internal(format!("Failed to store GPS lines: {}", e)))?;
+        self.barometers.calibrate(&gps.data);
+        let mut uuids: Vec<u64> = gps.data.iter().map(|d| d.uuid).collect();
+        uuids.sort_unstable();
+        uuids.dedup();
+        let unsynced: Vec<u64> = uuids.iter().copied().filter(|uuid| !self.clocks.has_model(*uuid)).collect();
+        self.clocks.observe(&gps.data);
+        // IMU lines stored before the device's first clock model go on GPS
+        // time now. They're stored either way, so a failure isn't the
+        // sender's problem.
+        for uuid in unsynced.into_iter().filter(|uuid| self.clocks.has_model(*uuid)) {
+            if let Err(e) = backfill(self.store.as_ref(), &self.clocks, uuid) {
+                eprintln!("Failed to put device {:x}'s earlier IMU lines on GPS time: {}", uuid, e);
+            }
+        }
+        if stored > 0 {
+            for uuid in uuids {
+                let times = gps.data.iter().filter(|d| d.uuid == uuid).map(|d| d.gps_time);
+                self.trips.mark(uuid, times);
+            }
+        }
+
+        let reply = GpsReply {
+            message: format!("{} GPS lines received!", n_lines),
+            stored: stored as u32,
+            duplicates: (n_lines - stored) as u32,
+        };
+
+        Ok(Response::new(reply))
+    }
+}
+
+pub struct TripSource {
+    store: Arc<dyn TelemetryStore>,
+    updates: Arc<TripUpdates>,
+}
+
+impl TripSource {
+    /// Trips still pending in `updates` are detected before they're listed
+    pub fn new(store: Arc<dyn TelemetryStore>, updates: Arc<TripUpdates>) -> TripSource {
+        TripSource { store, updates }
+    }
+}
+
+impl TripQuery {
+    fn range(&self) -> std::ops::Range<u64> {
+        self.from..if self.to == 0 { u64::MAX } else { self.to }
+    }
+}
+
+#[tonic::async_trait]
+impl TripServer for TripSource {
+    async fn list_trips(&self, request: Request<TripQuery>) -> Result<Response<TripList>, Status> {
+        let query = request.into_inner();
+        if query.uuid == 0 {
+            return Err(Status::invalid_argument("Trip query has no device uuid"));
+        }
+        self.updates.flush_blocking(Some(query.uuid)).await;
+        let trips = self
+            .store
+            .trips(query.uuid, query.range())
+            .map_err(|e| Status::internal(format!("Failed to read trips: {}", e)))?;
+        Ok(Response::new(TripList { trips }))
+    }
+
+    /// Detect the trips again from the lines stored, e.g. after importing
+    /// a device's history, and return them
+    async fn detect_trips(&self, request: Request<TripQuery>) -> Result<Response<TripList>, Status> {
+        let query = request.into_inner();
+        if query.uuid == 0 {
+            return Err(Status::invalid_argument("Trip query has no device uuid"));
+        }
+        let range = query.range();
+        let (store, updates) = (self.store.clone(), self.updates.clone());
+        tokio::task::spawn_blocking(move || {
+            let _refreshing = updates.refreshing.lock().unwrap();
+            refresh_trips(store.as_ref(), query.uuid, range.clone(), &updates.config)
+                .and_then(|_| store.trips(query.uuid, range))
+        })
+        .await
+        .map_err(|e| Status::internal(format!("Failed to detect trips: {}", e)))?
+        .map(|trips| Response::new(TripList { trips }))
+        .map_err(|e| Status::internal(format!("Failed to detect trips: {}", e)))
+    }
+}
+
+/// A server running in the background, e.g. inside a test
+pub struct ServerHandle {
+    pub addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<(), tonic::transport::Error>>,
+    trips: Arc<TripUpdates>,
+    trip_task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// URL for a client to connect to
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Stop accepting requests and wait for the server to finish,
+    /// trip detection included
+    pub async fn stop(self) -> Result<(), Box<dyn std::error::Error>> {
+        let _ = self.shutdown.send(());
+        self.task.await??;
+        self.trip_task.abort();
+        self.trips.flush_blocking(None).await;
+        Ok(())
+    }
+}
+
+/// Start the IMU, GPS and trip services on `addr` in the background. Use port 0
+/// to get an ephemeral port; the one chosen is in the returned handle.
+pub async fn spawn_server(store: Arc<dyn TelemetryStore>, addr: &str) -> std::io::Result<ServerHandle> {
+    spawn_server_with(store, Arc::new(Barometers::default()), Arc::new(ClockSyncs::default()), addr).await
+}
+
+/// As `spawn_server`, with the barometers to turn pressure into altitude
+/// and the clock models to put IMU lines on GPS time
+pub async fn spawn_server_with(
+    store: Arc<dyn TelemetryStore>,
+    barometers: Arc<Barometers>,
+    clocks: Arc<ClockSyncs>,
+    addr: &str,
+) -> std::io::Result<ServerHandle> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    let (shutdown, rx) = oneshot::channel::<()>();
+    let trips = Arc::new(TripUpdates::new(store.clone(), TripConfig::default()));
+    let trip_task = tokio::spawn(trips.clone().run());
+
+    let task = tokio::spawn(
+        Server::builder()
+            .add_service(ImuDataServerServer::new(ImuDataSource::new(
+                store.clone(),
+                barometers.clone(),
+                clocks.clone(),
+                trips.clone(),
+            )))
+            .add_service(GpsDataServerServer::new(GpsDataSource::new(store.clone(), barometers, clocks, trips.clone())))
+            .add_service(TripServerServer::new(TripSource::new(store, trips.clone())))
+            .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                let _ = rx.await;
+            }),
+    );
+
+    Ok(ServerHandle { addr, shutdown, task, trips, trip_task })
+}
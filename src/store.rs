@@ -0,0 +1,509 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Mutex;
+
+use rusqlite::{named_params, Connection, Row};
+
+use crate::crash::{crash_from_row, insert_crash, Crash};
+use crate::data_conv::{insert_imu_short, read_stamp, ImuShort};
+use crate::gps::GpsData;
+use crate::imu::ImuData;
+use crate::schema::{self, GPS_COLUMNS, TRIP_COLUMNS};
+use crate::trip::Trip;
+
+/// Anything a backend can fail with
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// What makes an IMU line a resend of one already stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImuKey {
+    pub uuid: u64,
+    pub sequence: u32,
+    pub pitime: u64,
+}
+
+impl From<&ImuShort> for ImuKey {
+    fn from(row: &ImuShort) -> Self {
+        ImuKey { uuid: row.uuid, sequence: row.sequence, pitime: row.pitime }
+    }
+}
+
+/// What makes a GPS line a resend of one already stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GpsKey {
+    pub uuid: u64,
+    pub sequence: u32,
+    pub gps_time: u64,
+}
+
+impl From<&GpsData> for GpsKey {
+    fn from(d: &GpsData) -> Self {
+        GpsKey { uuid: d.uuid, sequence: d.sequence, gps_time: d.gps_time }
+    }
+}
+
+/// Where the server keeps what the trucks send it. The RPC handlers only
+/// see this, so a new backend is a new implementation and nothing else.
+/// Lines already stored, by their key, are skipped rather than stored
+/// twice.
+pub trait TelemetryStore: Send + Sync {
+    /// Store a batch of IMU rows. Returns how many were new.
+    fn append_imu(&self, rows: &[ImuShort]) -> Result<usize, StoreError>;
+
+    /// Store a batch of GPS lines. Returns how many were new.
+    fn append_gps(&self, data: &[GpsData]) -> Result<usize, StoreError>;
+
+    /// A device's IMU rows with pitime in `range`, ms, in time order
+    fn imu_range(&self, uuid: u64, range: Range<u64>) -> Result<Vec<ImuShort>, StoreError>;
+
+    /// A device's GPS lines with gps_time in `range`, ms, in time order
+    fn gps_range(&self, uuid: u64, range: Range<u64>) -> Result<Vec<GpsData>, StoreError>;
+
+    fn has_imu(&self, key: ImuKey) -> Result<bool, StoreError>;
+
+    fn has_gps(&self, key: GpsKey) -> Result<bool, StoreError>;
+
+    /// A device's IMU rows not yet put on GPS time, i.e. without a
+    /// clock_offset, in time order
+    fn uncorrected_imu(&self, uuid: u64) -> Result<Vec<ImuShort>, StoreError>;
+
+    /// Write the gps_time, stamp and clock correction of IMU rows already
+    /// stored, found by their key. Returns how many were updated.
+    fn update_imu_times(&self, rows: &[ImuShort]) -> Result<usize, StoreError>;
+
+    /// Replace a device's trips that overlap `range`, GPS time, ms, with
+    /// `trips`, e.g. after detecting them again
+    fn replace_trips(&self, uuid: u64, range: Range<u64>, trips: &[Trip]) -> Result<(), StoreError>;
+
+    /// A device's trips that overlap `range`, GPS time, ms, in time order
+    fn trips(&self, uuid: u64, range: Range<u64>) -> Result<Vec<Trip>, StoreError>;
+
+    /// Store the crashes devices captured. Returns how many were new; a
+    /// crash is known by its uuid and pitime.
+    fn append_crashes(&self, crashes: &[Crash]) -> Result<usize, StoreError>;
+
+    /// A device's crashes, in pitime order
+    fn crashes(&self, uuid: u64) -> Result<Vec<Crash>, StoreError>;
+}
+
+/// Whether a trip overlaps a range of GPS time
+pub fn overlaps(trip: &Trip, range: &Range<u64>) -> bool {
+    trip.start_time < range.end && trip.end_time >= range.start
+}
+
+/// SQLite and PostgreSQL integers are signed
+pub(crate) fn sql_range(range: &Range<u64>) -> (i64, i64) {
+    let clamp = |t: u64| t.min(i64::MAX as u64) as i64;
+    (clamp(range.start), clamp(range.end))
+}
+
+/// The unique indexes let a device resend a batch without it being
+/// stored twice. The tables themselves come from schema.rs.
+const DEDUPE: &str = "
+    CREATE UNIQUE INDEX IF NOT EXISTS imu_dedupe ON imu (uuid, sequence, pitime);
+    CREATE UNIQUE INDEX IF NOT EXISTS gps_dedupe ON gps (uuid, sequence, gps_time);
+";
+
+/// Build a GpsData from a row of the gps table
+pub fn gps_from_row(row: &Row) -> Result<GpsData, rusqlite::Error> {
+    Ok(GpsData {
+        uuid: row.get::<_, i64>("uuid")? as u64,
+        pitime: row.get("pitime")?,
+        gps_time: row.get("gps_time")?,
+        sequence: row.get("sequence")?,
+        lat: row.get("lat")?,
+        lon: row.get("lon")?,
+        alt: row.get("alt")?,
+        speed: row.get("speed")?,
+        track: row.get("track")?,
+        status_nsats_vuc: row.get("status_nsats_vuc")?,
+        hdop: row.get("hdop")?,
+        h_acc: row.get::<_, Option<f32>>("h_acc")?.unwrap_or_default(),
+        v_acc: row.get::<_, Option<f32>>("v_acc")?.unwrap_or_default(),
+        speed_acc: row.get::<_, Option<f32>>("speed_acc")?.unwrap_or_default(),
+        time_valid: row.get::<_, Option<u32>>("time_valid")?.unwrap_or_default(),
+        pi_stamp: read_stamp(row, "pi_stamp")?,
+        gps_stamp: read_stamp(row, "gps_stamp")?,
+    })
+}
+
+/// Build a Trip from a row of the trips table
+pub fn trip_from_row(row: &Row) -> Result<Trip, rusqlite::Error> {
+    Ok(Trip {
+        uuid: row.get::<_, i64>("uuid")? as u64,
+        start_time: row.get("start_time")?,
+        end_time: row.get("end_time")?,
+        start_lat: row.get("start_lat")?,
+        start_lon: row.get("start_lon")?,
+        end_lat: row.get("end_lat")?,
+        end_lon: row.get("end_lon")?,
+        distance: row.get("distance")?,
+        duration: row.get("duration")?,
+        max_speed: row.get("max_speed")?,
+    })
+}
+
+/// Insert one GPS line into the gps table. Returns 0 if a unique index
+/// says it's already there.
+pub fn insert_gps_data(conn: &Connection, d: &GpsData) -> Result<usize, rusqlite::Error> {
+    let mut stmt = conn.prepare_cached(&schema::insert_sql("gps", GPS_COLUMNS))?;
+    stmt.execute(named_params! {
+        ":uuid": d.uuid as i64,
+        ":pitime": d.pitime as i64,
+        ":gps_time": d.gps_time as i64,
+        ":sequence": d.sequence,
+        ":lat": d.lat,
+        ":lon": d.lon,
+        ":alt": d.alt,
+        ":speed": d.speed,
+        ":track": d.track,
+        ":status_nsats_vuc": d.status_nsats_vuc,
+        ":hdop": d.hdop,
+        // Unknown accuracies are stored as NULL rather than 0
+        ":h_acc": Some(d.h_acc).filter(|a| *a > 0.0),
+        ":v_acc": Some(d.v_acc).filter(|a| *a > 0.0),
+        ":speed_acc": Some(d.speed_acc).filter(|a| *a > 0.0),
+        ":time_valid": d.time_valid,
+        ":pi_stamp_s": d.pi_stamp.map(|t| t.seconds),
+        ":pi_stamp_ns": d.pi_stamp.map(|t| t.nanos),
+        ":pi_stamp_base": d.pi_stamp.map(|t| t.timebase),
+        ":gps_stamp_s": d.gps_stamp.map(|t| t.seconds),
+        ":gps_stamp_ns": d.gps_stamp.map(|t| t.nanos),
+        ":gps_stamp_base": d.gps_stamp.map(|t| t.timebase),
+    })
+}
+
+/// The SQLite backend, for small deployments
+pub struct ServerStore {
+    conn: Mutex<Connection>,
+}
+
+impl ServerStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ServerStore, rusqlite::Error> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<ServerStore, rusqlite::Error> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: Connection) -> Result<ServerStore, rusqlite::Error> {
+        schema::create_imu_table(&conn)?;
+        schema::create_gps_table(&conn)?;
+        schema::create_trip_table(&conn)?;
+        schema::create_crash_table(&conn)?;
+        conn.execute_batch(DEDUPE)?;
+        Ok(ServerStore { conn: Mutex::new(conn) })
+    }
+
+    /// Store a batch of IMU rows in a single transaction. Returns how many
+    /// were new; the rest were already stored.
+    pub fn insert_imu(&self, rows: &[ImuShort]) -> Result<usize, rusqlite::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut stored = 0;
+        for row in rows {
+            stored += insert_imu_short(&tx, row)?;
+        }
+        tx.commit()?;
+        Ok(stored)
+    }
+
+    /// Store a batch of GPS lines. Returns how many were new.
+    pub fn insert_gps(&self, data: &[GpsData]) -> Result<usize, rusqlite::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut stored = 0;
+        for d in data {
+            stored += insert_gps_data(&tx, d)?;
+        }
+        tx.commit()?;
+        Ok(stored)
+    }
+
+    /// All IMU lines stored for a device, in time order
+    pub fn read_imu(&self, uuid: u64) -> Result<Vec<ImuData>, rusqlite::Error> {
+        Ok(self.read_imu_rows(uuid)?.into_iter().map(ImuData::from).collect())
+    }
+
+    /// As `read_imu`, but the rows as stored, altitude included
+    pub fn read_imu_rows(&self, uuid: u64) -> Result<Vec<ImuShort>, rusqlite::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT * FROM imu WHERE uuid = ?1 ORDER BY pitime, sequence")?;
+        let rows = stmt.query_map([uuid as i64], ImuShort::from_row)?;
+        rows.collect()
+    }
+
+    /// All GPS lines stored for a device, in time order
+    pub fn read_gps(&self, uuid: u64) -> Result<Vec<GpsData>, rusqlite::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT * FROM gps WHERE uuid = ?1 ORDER BY gps_time, sequence")?;
+        let rows = stmt.query_map([uuid as i64], gps_from_row)?;
+        rows.collect()
+    }
+}
+
+impl TelemetryStore for ServerStore {
+    fn append_imu(&self, rows: &[ImuShort]) -> Result<usize, StoreError> {
+        Ok(self.insert_imu(rows)?)
+    }
+
+    fn append_gps(&self, data: &[GpsData]) -> Result<usize, StoreError> {
+        Ok(self.insert_gps(data)?)
+    }
+
+    fn imu_range(&self, uuid: u64, range: Range<u64>) -> Result<Vec<ImuShort>, StoreError> {
+        let (start, end) = sql_range(&range);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT * FROM imu WHERE uuid = ?1 AND pitime >= ?2 AND pitime < ?3 ORDER BY pitime, sequence",
+        )?;
+        let rows = stmt.query_map((uuid as i64, start, end), ImuShort::from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn gps_range(&self, uuid: u64, range: Range<u64>) -> Result<Vec<GpsData>, StoreError> {
+        let (start, end) = sql_range(&range);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT * FROM gps WHERE uuid = ?1 AND gps_time >= ?2 AND gps_time < ?3 ORDER BY gps_time, sequence",
+        )?;
+        let rows = stmt.query_map((uuid as i64, start, end), gps_from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn has_imu(&self, key: ImuKey) -> Result<bool, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached("SELECT 1 FROM imu WHERE uuid = ?1 AND sequence = ?2 AND pitime = ?3")?;
+        Ok(stmt.exists((key.uuid as i64, key.sequence, key.pitime as i64))?)
+    }
+
+    fn has_gps(&self, key: GpsKey) -> Result<bool, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached("SELECT 1 FROM gps WHERE uuid = ?1 AND sequence = ?2 AND gps_time = ?3")?;
+        Ok(stmt.exists((key.uuid as i64, key.sequence, key.gps_time as i64))?)
+    }
+
+    fn uncorrected_imu(&self, uuid: u64) -> Result<Vec<ImuShort>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT * FROM imu WHERE uuid = ?1 AND clock_offset IS NULL ORDER BY pitime, sequence",
+        )?;
+        let rows = stmt.query_map([uuid as i64], ImuShort::from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn update_imu_times(&self, rows: &[ImuShort]) -> Result<usize, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut updated = 0;
+        {
+            let mut stmt = tx.prepare_cached(
+                "UPDATE imu SET gps_time = :gps_time, clock_offset = :clock_offset, clock_error = :clock_error,
+                    stamp_s = :stamp_s, stamp_ns = :stamp_ns, stamp_base = :stamp_base
+                 WHERE uuid = :uuid AND sequence = :sequence AND pitime = :pitime",
+            )?;
+            for row in rows {
+                updated += stmt.execute(named_params! {
+                    ":gps_time": row.gps_time as i64,
+                    ":clock_offset": row.clock_offset,
+                    ":clock_error": row.clock_error,
+                    ":stamp_s": row.stamp.map(|t| t.seconds),
+                    ":stamp_ns": row.stamp.map(|t| t.nanos),
+                    ":stamp_base": row.stamp.map(|t| t.timebase),
+                    ":uuid": row.uuid as i64,
+                    ":sequence": row.sequence,
+                    ":pitime": row.pitime as i64,
+                })?;
+            }
+        }
+        tx.commit()?;
+        Ok(updated)
+    }
+
+    fn replace_trips(&self, uuid: u64, range: Range<u64>, trips: &[Trip]) -> Result<(), StoreError> {
+        let (start, end) = sql_range(&range);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM trips WHERE uuid = ?1 AND start_time < ?3 AND end_time >= ?2", (uuid as i64, start, end))?;
+        {
+            let mut stmt = tx.prepare_cached(&schema::insert_sql("trips", TRIP_COLUMNS))?;
+            for t in trips {
+                stmt.execute(named_params! {
+                    ":uuid": t.uuid as i64,
+                    ":start_time": t.start_time as i64,
+                    ":end_time": t.end_time as i64,
+                    ":start_lat": t.start_lat,
+                    ":start_lon": t.start_lon,
+                    ":end_lat": t.end_lat,
+                    ":end_lon": t.end_lon,
+                    ":distance": t.distance,
+                    ":duration": t.duration as i64,
+                    ":max_speed": t.max_speed,
+                })?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn trips(&self, uuid: u64, range: Range<u64>) -> Result<Vec<Trip>, StoreError> {
+        let (start, end) = sql_range(&range);
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT * FROM trips WHERE uuid = ?1 AND start_time < ?3 AND end_time >= ?2 ORDER BY start_time",
+        )?;
+        let rows = stmt.query_map((uuid as i64, start, end), trip_from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn append_crashes(&self, crashes: &[Crash]) -> Result<usize, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut stored = 0;
+        for crash in crashes {
+            stored += insert_crash(&tx, crash)?;
+        }
+        tx.commit()?;
+        Ok(stored)
+    }
+
+    fn crashes(&self, uuid: u64) -> Result<Vec<Crash>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached("SELECT * FROM crashes WHERE uuid = ?1 ORDER BY pitime")?;
+        let rows = stmt.query_map([uuid as i64], crash_from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+#[derive(Default)]
+struct Tables {
+    imu: Vec<ImuShort>,
+    gps: Vec<GpsData>,
+    trips: Vec<Trip>,
+    crashes: Vec<Crash>,
+    imu_keys: HashSet<ImuKey>,
+    gps_keys: HashSet<GpsKey>,
+}
+
+/// Keeps everything in memory, for tests and trying things out
+#[derive(Default)]
+pub struct MemoryStore {
+    tables: Mutex<Tables>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl TelemetryStore for MemoryStore {
+    fn append_imu(&self, rows: &[ImuShort]) -> Result<usize, StoreError> {
+        let mut tables = self.tables.lock().unwrap();
+        let mut stored = 0;
+        for row in rows {
+            if tables.imu_keys.insert(row.into()) {
+                // As SQLite numbers its rows
+                let line = tables.imu.len() as i64 + 1;
+                tables.imu.push(ImuShort { line, ..row.clone() });
+                stored += 1;
+            }
+        }
+        Ok(stored)
+    }
+
+    fn append_gps(&self, data: &[GpsData]) -> Result<usize, StoreError> {
+        let mut tables = self.tables.lock().unwrap();
+        let mut stored = 0;
+        for d in data {
+            if tables.gps_keys.insert(d.into()) {
+                tables.gps.push(*d);
+                stored += 1;
+            }
+        }
+        Ok(stored)
+    }
+
+    fn imu_range(&self, uuid: u64, range: Range<u64>) -> Result<Vec<ImuShort>, StoreError> {
+        let tables = self.tables.lock().unwrap();
+        let mut rows: Vec<ImuShort> =
+            tables.imu.iter().filter(|r| r.uuid == uuid && range.contains(&r.pitime)).cloned().collect();
+        rows.sort_by_key(|r| (r.pitime, r.sequence));
+        Ok(rows)
+    }
+
+    fn gps_range(&self, uuid: u64, range: Range<u64>) -> Result<Vec<GpsData>, StoreError> {
+        let tables = self.tables.lock().unwrap();
+        let mut data: Vec<GpsData> =
+            tables.gps.iter().filter(|d| d.uuid == uuid && range.contains(&d.gps_time)).copied().collect();
+        data.sort_by_key(|d| (d.gps_time, d.sequence));
+        Ok(data)
+    }
+
+    fn has_imu(&self, key: ImuKey) -> Result<bool, StoreError> {
+        Ok(self.tables.lock().unwrap().imu_keys.contains(&key))
+    }
+
+    fn has_gps(&self, key: GpsKey) -> Result<bool, StoreError> {
+        Ok(self.tables.lock().unwrap().gps_keys.contains(&key))
+    }
+
+    fn uncorrected_imu(&self, uuid: u64) -> Result<Vec<ImuShort>, StoreError> {
+        let tables = self.tables.lock().unwrap();
+        let mut rows: Vec<ImuShort> =
+            tables.imu.iter().filter(|r| r.uuid == uuid && r.clock_offset.is_none()).cloned().collect();
+        rows.sort_by_key(|r| (r.pitime, r.sequence));
+        Ok(rows)
+    }
+
+    fn update_imu_times(&self, rows: &[ImuShort]) -> Result<usize, StoreError> {
+        let mut tables = self.tables.lock().unwrap();
+        let times: HashMap<ImuKey, &ImuShort> = rows.iter().map(|r| (r.into(), r)).collect();
+        let mut updated = 0;
+        for stored in tables.imu.iter_mut() {
+            if let Some(row) = times.get(&ImuKey::from(&*stored)) {
+                stored.gps_time = row.gps_time;
+                stored.stamp = row.stamp;
+                stored.clock_offset = row.clock_offset;
+                stored.clock_error = row.clock_error;
+                updated += 1;
+            }
+        }
+        Ok(updated)
+    }
+
+    fn replace_trips(&self, uuid: u64, range: Range<u64>, trips: &[Trip]) -> Result<(), StoreError> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.trips.retain(|t| t.uuid != uuid || !overlaps(t, &range));
+        tables.trips.extend_from_slice(trips);
+        Ok(())
+    }
+
+    fn trips(&self, uuid: u64, range: Range<u64>) -> Result<Vec<Trip>, StoreError> {
+        let tables = self.tables.lock().unwrap();
+        let mut trips: Vec<Trip> = tables.trips.iter().filter(|t| t.uuid == uuid && overlaps(t, &range)).copied().collect();
+        trips.sort_by_key(|t| t.start_time);
+        Ok(trips)
+    }
+
+    fn append_crashes(&self, crashes: &[Crash]) -> Result<usize, StoreError> {
+        let mut tables = self.tables.lock().unwrap();
+        let mut stored = 0;
+        for crash in crashes {
+            if !tables.crashes.iter().any(|c| (c.uuid, c.pitime) == (crash.uuid, crash.pitime)) {
+                tables.crashes.push(*crash);
+                stored += 1;
+            }
+        }
+        Ok(stored)
+    }
+
+    fn crashes(&self, uuid: u64) -> Result<Vec<Crash>, StoreError> {
+        let tables = self.tables.lock().unwrap();
+        let mut crashes: Vec<Crash> = tables.crashes.iter().filter(|c| c.uuid == uuid).copied().collect();
+        crashes.sort_by_key(|c| c.pitime);
+        Ok(crashes)
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::data_conv::ImuShort;
+use crate::data_defs::{self, Timebase};
+use crate::fake_gps::decode_fields;
+use crate::gps::GpsData;
+use crate::store::{StoreError, TelemetryStore};
+use crate::ubx::{TIME_VALID_DATE, TIME_VALID_TIME};
+
+/// Settings for a `ClockSync`
+#[derive(Debug, Clone)]
+pub struct SyncConfig {
+    /// How many fixes to fit the clock model to. At 1 Hz the default is
+    /// ten minutes, long enough to see drift but short enough to follow
+    /// the Pi's crystal as it warms up.
+    pub window: usize,
+    /// Fixes needed before corrections are given
+    pub min_fixes: usize,
+    /// Fixes further than this from the fitted line are left out of it,
+    /// ms. Serial latency now and then makes a fix arrive late.
+    pub max_residual: f64,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig { window: 600, min_fixes: 5, max_residual: 50.0 }
+    }
+}
+
+/// How the Pi clock relates to GPS time at some pitime. This is what gets
+/// recorded against each corrected IMU row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockCorrection {
+    /// pitime - gps_time, ms
+    pub offset: f64,
+    /// How fast the Pi clock gains on GPS time, parts per million
+    pub drift_ppm: f64,
+    /// RMS of the fixes about the fitted line, ms. Roughly how far any one
+    /// corrected time can be trusted.
+    pub rms: f64,
+    /// Fixes the fit used
+    pub fixes: usize,
+}
+
+/// offset = intercept + slope * (pitime - reference)
+#[derive(Debug, Clone, Copy)]
+struct Fit {
+    reference: u64,
+    intercept: f64,
+    slope: f64,
+    rms: f64,
+    fixes: usize,
+}
+
+/// Least squares line through (pitime - reference, pitime - gps_time)
+fn fit_line<'a>(pairs: impl Iterator<Item = &'a (u64, u64)> + Clone, reference: u64) -> Option<Fit> {
+    let points = pairs.map(|(pi, gps)| ((*pi as i64 - reference as i64) as f64, (*pi as i64 - *gps as i64) as f64));
+    let n = points.clone().count();
+    if n == 0 {
+        return None;
+    }
+    let (sx, sy) = points.clone().fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    let (mx, my) = (sx / n as f64, sy / n as f64);
+    let (sxx, sxy) = points
+        .clone()
+        .fold((0.0, 0.0), |(sxx, sxy), (x, y)| (sxx + (x - mx) * (x - mx), sxy + (x - mx) * (y - my)));
+    // All at one pitime: no drift to be seen
+    let slope = if sxx > 0.0 { sxy / sxx } else { 0.0 };
+    let intercept = my - slope * mx;
+    let ss: f64 = points.map(|(x, y)| (y - intercept - slope * x).powi(2)).sum();
+    Some(Fit { reference, intercept, slope, rms: (ss / n as f64).sqrt(), fixes: n })
+}
+
+/// Tracks one device's Pi clock against the GPS time in its fixes
+#[derive(Debug, Clone)]
+pub struct ClockSync {
+    config: SyncConfig,
+    /// (pitime, gps_time) of usable fixes, in pitime order
+    pairs: VecDeque<(u64, u64)>,
+    fit: Option<Fit>,
+}
+
+impl ClockSync {
+    pub fn new(config: SyncConfig) -> ClockSync {
+        ClockSync { config, pairs: VecDeque::new(), fit: None }
+    }
+
+    /// The fix knows the date and time. Senders from before time_valid
+    /// existed leave it at 0, and their valid fixes are taken on trust.
+    fn usable_fix(fix: &GpsData) -> bool {
+        let (_, _, valid, _, _) = decode_fields(fix.status_nsats_vuc);
+        let both = TIME_VALID_DATE | TIME_VALID_TIME;
+        let time_ok = fix.time_valid & both == both || (fix.time_valid == 0 && valid);
+        time_ok && fix.gps_time > 0 && fix.pitime > 0
+    }
+
+    /// Add a fix to the model. Returns false if it can't be used.
+    pub fn observe(&mut self, fix: &GpsData) -> bool {
+        if !Self::usable_fix(fix) {
+            return false;
+        }
+        let pair = (fix.pitime, fix.gps_time);
+        let at = self.pairs.partition_point(|p| p.0 < pair.0);
+        if self.pairs.get(at) == Some(&pair) {
+            return false;
+        }
+        self.pairs.insert(at, pair);
+        while self.pairs.len() > self.config.window {
+            self.pairs.pop_front();
+        }
+        self.refit();
+        true
+    }
+
+    fn refit(&mut self) {
+        self.fit = None;
+        if self.pairs.len() < self.config.min_fixes {
+            return;
+        }
+        let reference = self.pairs[self.pairs.len() - 1].0;
+        let first = match fit_line(self.pairs.iter(), reference) {
+            Some(fit) => fit,
+            None => return,
+        };
+        let residual = |(pi, gps): &(u64, u64)| {
+            let x = (*pi as i64 - reference as i64) as f64;
+            ((*pi as i64 - *gps as i64) as f64 - first.intercept - first.slope * x).abs()
+        };
+        let kept = self.pairs.iter().filter(|p| residual(p) <= self.config.max_residual);
+        self.fit = match fit_line(kept.clone(), reference) {
+            Some(fit) if fit.fixes >= self.config.min_fixes => Some(fit),
+            // Too scattered to say which fixes are the outliers
+            _ => Some(first),
+        };
+    }
+
+    /// The correction at `pitime`, once enough fixes have been seen.
+    /// Before the first fix or after the last, the fitted line is carried
+    /// on.
+    pub fn correction(&self, pitime: u64) -> Option<ClockCorrection> {
+        let fit = self.fit?;
+        let x = (pitime as i64 - fit.reference as i64) as f64;
+        Some(ClockCorrection {
+            offset: fit.intercept + fit.slope * x,
+            drift_ppm: fit.slope * 1e6,
+            rms: fit.rms,
+            fixes: fit.fixes,
+        })
+    }
+
+    /// GPS time at `pitime`, ms since the epoch
+    pub fn gps_time(&self, pitime: u64) -> Option<u64> {
+        let c = self.correction(pitime)?;
+        u64::try_from(pitime as i64 - c.offset.round() as i64).ok()
+    }
+
+    /// Fill in the row's gps_time from its pitime and move its stamp onto
+    /// GPS time, recording the correction used. The Pi's own stamp is the
+    /// new one plus clock_offset. Rows are left alone until there's a
+    /// model.
+    pub fn apply(&self, row: &mut ImuShort) -> bool {
+        let (c, gps_time) = match (self.correction(row.pitime), self.gps_time(row.pitime)) {
+            (Some(c), Some(t)) => (c, t),
+            _ => return false,
+        };
+        let pi_nanos = match row.stamp.map(data_defs::Timestamp::from) {
+            Some(t) if t.timebase == Timebase::System => t.as_nanos(),
+            _ => row.pitime as i128 * 1_000_000,
+        };
+        let offset_nanos = (c.offset * 1e6).round() as i128;
+        row.stamp = Some(data_defs::Timestamp::from_nanos(pi_nanos - offset_nanos, Timebase::Gps).into());
+        row.gps_time = gps_time;
+        row.clock_offset = Some(c.offset);
+        row.clock_error = Some(c.rms);
+        true
+    }
+}
+
+/// A `ClockSync` per device
+#[derive(Debug, Default)]
+pub struct ClockSyncs {
+    config: SyncConfig,
+    devices: Mutex<HashMap<u64, ClockSync>>,
+}
+
+impl ClockSyncs {
+    pub fn new(config: SyncConfig) -> ClockSyncs {
+        ClockSyncs { config, devices: Mutex::new(HashMap::new()) }
+    }
+
+    /// Correct each row's time using its device's clock model. Returns
+    /// how many rows were corrected.
+    pub fn apply(&self, uuid: u64, rows: &mut [ImuShort]) -> usize {
+        let devices = self.devices.lock().unwrap();
+        match devices.get(&uuid) {
+            Some(sync) => rows.iter_mut().map(|row| sync.apply(row)).filter(|applied| *applied).count(),
+            None => 0,
+        }
+    }
+
+    /// Add each fix to its device's model. Returns how many were used.
+    pub fn observe(&self, fixes: &[GpsData]) -> usize {
+        let mut devices = self.devices.lock().unwrap();
+        let mut used = 0;
+        for fix in fixes {
+            let sync = devices.entry(fix.uuid).or_insert_with(|| ClockSync::new(self.config.clone()));
+            if sync.observe(fix) {
+                used += 1;
+            }
+        }
+        used
+    }
+
+    pub fn correction(&self, uuid: u64, pitime: u64) -> Option<ClockCorrection> {
+        self.devices.lock().unwrap().get(&uuid)?.correction(pitime)
+    }
+
+    /// Whether the device has enough fixes for a clock model yet
+    pub fn has_model(&self, uuid: u64) -> bool {
+        self.correction(uuid, 0).is_some()
+    }
+}
+
+/// Put a device's IMU rows that were stored before it had a clock model
+/// on GPS time. Returns how many were corrected.
+pub fn backfill(store: &dyn TelemetryStore, clocks: &ClockSyncs, uuid: u64) -> Result<usize, StoreError> {
+    let mut rows = store.uncorrected_imu(uuid)?;
+    if clocks.apply(uuid, &mut rows) == 0 {
+        return Ok(0);
+    }
+    rows.retain(|row| row.clock_offset.is_some());
+    store.update_imu_times(&rows)
+}
@@ -0,0 +1,44 @@
+// Writes a device's drive, as stored by the server, as a GPX, KML or
+// GeoJSON track to look at on a map.
+use std::time::Duration;
+
+use grpc_tests::store::{ServerStore, TelemetryStore};
+use grpc_tests::tracks::{export_track, SegmentConfig, TrackFormat};
+
+const USAGE: &str = "usage: track --uuid <n> [--db <file>] [--from <ms>] [--to <ms>]
+             [--format gpx|kml|geojson] [--gap <s>] [--out <file>]";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut db = String::from("./server_data.db3");
+    let mut uuid: Option<u64> = None;
+    let (mut from, mut to) = (0, u64::MAX);
+    let mut format = TrackFormat::Gpx;
+    let mut config = SegmentConfig::default();
+    let mut out: Option<String> = None;
+
+    let mut it = std::env::args().skip(1);
+    while let Some(flag) = it.next() {
+        let value = it.next().ok_or(USAGE)?;
+        match flag.as_str() {
+            "--db" => db = value,
+            "--uuid" => uuid = Some(value.parse()?),
+            "--from" => from = value.parse()?,
+            "--to" => to = value.parse()?,
+            "--format" => format = value.parse()?,
+            "--gap" => {
+                config.max_gap = Duration::try_from_secs_f64(value.parse()?).map_err(|e| format!("--gap {}: {}", value, e))?
+            }
+            "--out" => out = Some(value),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let uuid = uuid.ok_or(USAGE)?;
+
+    let store = ServerStore::open(&db)?;
+    let data = store.gps_range(uuid, from..to).map_err(|e| e as Box<dyn std::error::Error>)?;
+    let track = export_track(&format!("Device {}", uuid), &data, format, &config);
+    let out = out.unwrap_or(format!("{}.{}", uuid, format.extension()));
+    std::fs::write(&out, track)?;
+    println!("{} GPS lines written to {}", data.len(), out);
+    Ok(())
+}
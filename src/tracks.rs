@@ -0,0 +1,199 @@
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub use crate::calendar::iso_time;
+use crate::fake_gps::decode_fields;
+use crate::gps::GpsData;
+
+/// The map formats a track can be written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackFormat {
+    Gpx,
+    Kml,
+    GeoJson,
+}
+
+impl TrackFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            TrackFormat::Gpx => "gpx",
+            TrackFormat::Kml => "kml",
+            TrackFormat::GeoJson => "geojson",
+        }
+    }
+}
+
+impl FromStr for TrackFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gpx" => Ok(TrackFormat::Gpx),
+            "kml" => Ok(TrackFormat::Kml),
+            "geojson" | "json" => Ok(TrackFormat::GeoJson),
+            _ => Err(format!("Unknown track format '{}'", s)),
+        }
+    }
+}
+
+/// When a drive is broken into segments
+#[derive(Debug, Clone)]
+pub struct SegmentConfig {
+    /// A longer gap between fixes starts a new segment, e.g. where the
+    /// truck was parked with the ignition off
+    pub max_gap: Duration,
+    /// Segments with fewer points are dropped. A line needs two.
+    pub min_points: usize,
+}
+
+impl Default for SegmentConfig {
+    fn default() -> Self {
+        SegmentConfig { max_gap: Duration::from_secs(10), min_points: 2 }
+    }
+}
+
+/// One sample of a track
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackPoint {
+    /// gps_time, ms since the epoch
+    pub time: u64,
+    pub lat: f32,
+    pub lon: f32,
+    pub alt: f32,
+    pub speed: f32,
+    pub track: f32,
+}
+
+impl From<&GpsData> for TrackPoint {
+    fn from(d: &GpsData) -> Self {
+        TrackPoint { time: d.gps_time, lat: d.lat, lon: d.lon, alt: d.alt, speed: d.speed, track: d.track }
+    }
+}
+
+/// An unbroken run of fixes
+pub type Segment = Vec<TrackPoint>;
+
+/// Split a device's GPS lines, in time order, into segments. Lines
+/// without a valid fix are left out and end the segment they fall in, as
+/// does a gap of more than `max_gap`.
+pub fn segments(data: &[GpsData], config: &SegmentConfig) -> Vec<Segment> {
+    let max_gap = config.max_gap.as_millis() as u64;
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut current: Segment = Vec::new();
+    for d in data {
+        let (_, _, valid, _, _) = decode_fields(d.status_nsats_vuc);
+        let gap = current.last().is_some_and(|last| d.gps_time.saturating_sub(last.time) > max_gap);
+        if !valid || gap {
+            segments.push(std::mem::take(&mut current));
+        }
+        if valid {
+            current.push(d.into());
+        }
+    }
+    segments.push(current);
+    segments.retain(|s| !s.is_empty() && s.len() >= config.min_points);
+    segments
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A GPX 1.1 track with a trkseg per segment
+pub fn to_gpx(name: &str, segments: &[Segment]) -> String {
+    let mut gpx = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <gpx version=\"1.1\" creator=\"grpc_tests\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+    );
+    let _ = writeln!(gpx, "  <trk>\n    <name>{}</name>", xml_escape(name));
+    for segment in segments {
+        gpx += "    <trkseg>\n";
+        for p in segment {
+            let _ = writeln!(
+                gpx,
+                "      <trkpt lat=\"{}\" lon=\"{}\"><ele>{}</ele><time>{}</time></trkpt>",
+                p.lat,
+                p.lon,
+                p.alt,
+                iso_time(p.time)
+            );
+        }
+        gpx += "    </trkseg>\n";
+    }
+    gpx += "  </trk>\n</gpx>\n";
+    gpx
+}
+
+/// A KML document with a placemark per segment, each a LineString with
+/// the time it covers
+pub fn to_kml(name: &str, segments: &[Segment]) -> String {
+    let mut kml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <kml xmlns=\"http://www.opengis.net/kml/2.2\">\n",
+    );
+    let _ = writeln!(kml, "  <Document>\n    <name>{}</name>", xml_escape(name));
+    for (i, segment) in segments.iter().enumerate() {
+        let (first, last) = (segment[0], segment[segment.len() - 1]);
+        let _ = writeln!(kml, "    <Placemark>\n      <name>Segment {}</name>", i + 1);
+        let _ = writeln!(
+            kml,
+            "      <TimeSpan><begin>{}</begin><end>{}</end></TimeSpan>",
+            iso_time(first.time),
+            iso_time(last.time)
+        );
+        kml += "      <LineString>\n        <altitudeMode>absolute</altitudeMode>\n        <coordinates>";
+        let coordinates: Vec<String> = segment.iter().map(|p| format!("{},{},{}", p.lon, p.lat, p.alt)).collect();
+        kml += &coordinates.join(" ");
+        kml += "</coordinates>\n      </LineString>\n    </Placemark>\n";
+    }
+    kml += "  </Document>\n</kml>\n";
+    kml
+}
+
+/// A GeoJSON FeatureCollection with a LineString feature per segment.
+/// Positions are [lon, lat, alt], as GeoJSON has them.
+pub fn to_geojson(name: &str, segments: &[Segment]) -> String {
+    let features: Vec<String> = segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| {
+            let coordinates: Vec<String> = segment.iter().map(|p| format!("[{},{},{}]", p.lon, p.lat, p.alt)).collect();
+            let times: Vec<String> = segment.iter().map(|p| format!("\"{}\"", iso_time(p.time))).collect();
+            let speeds: Vec<String> = segment.iter().map(|p| p.speed.to_string()).collect();
+            format!(
+                "{{\"type\":\"Feature\",\"properties\":{{\"name\":\"{}\",\"segment\":{},\"times\":[{}],\"speeds\":[{}]}},\
+                 \"geometry\":{{\"type\":\"LineString\",\"coordinates\":[{}]}}}}",
+                json_escape(name),
+                i + 1,
+                times.join(","),
+                speeds.join(","),
+                coordinates.join(",")
+            )
+        })
+        .collect();
+    format!("{{\"type\":\"FeatureCollection\",\"features\":[\n{}\n]}}\n", features.join(",\n"))
+}
+
+/// A device's GPS lines, in time order, as a map track named `name`
+pub fn export_track(name: &str, data: &[GpsData], format: TrackFormat, config: &SegmentConfig) -> String {
+    let segments = segments(data, config);
+    match format {
+        TrackFormat::Gpx => to_gpx(name, &segments),
+        TrackFormat::Kml => to_kml(name, &segments),
+        TrackFormat::GeoJson => to_geojson(name, &segments),
+    }
+}
@@ -0,0 +1,208 @@
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::data_conv::ImuShort;
+use crate::fake_gps::decode_fields;
+use crate::gps::GpsData;
+use crate::gps_sim::{distance, Waypoint};
+use crate::store::{StoreError, TelemetryStore};
+use crate::trip::Trip;
+
+/// IMU lines either side of a fix that its vibration is measured over, ms
+const VIBRATION_WINDOW: u64 = 1000;
+/// Fewer IMU lines than this around a fix says nothing about the engine
+const MIN_VIBRATION_LINES: usize = 5;
+
+/// How a device's history is cut into trips
+#[derive(Debug, Clone)]
+pub struct TripConfig {
+    /// A fix at this speed or more is the truck moving, m/s
+    pub moving_speed: f32,
+    /// Standard deviation of the accel magnitude, g, above which the
+    /// engine is taken to be running
+    pub engine_vibration: f32,
+    /// Stopped this long with the engine off ends a trip
+    pub engine_off: Duration,
+    /// Stopped this long with the engine running, or with no IMU lines to
+    /// tell, ends a trip, e.g. idling through a delivery
+    pub max_stop: Duration,
+    /// A longer gap between fixes ends a trip, as the logger was off
+    pub max_gap: Duration,
+    /// Shorter trips are GPS wander or shunting around the yard, and are
+    /// dropped, m
+    pub min_distance: f32,
+}
+
+impl Default for TripConfig {
+    fn default() -> Self {
+        TripConfig {
+            moving_speed: 2.0,
+            engine_vibration: 0.01,
+            engine_off: Duration::from_secs(60),
+            max_stop: Duration::from_secs(300),
+            max_gap: Duration::from_secs(120),
+            min_distance: 200.0,
+        }
+    }
+}
+
+fn waypoint(d: &GpsData) -> Waypoint {
+    Waypoint::new(d.lat as f64, d.lon as f64, d.alt)
+}
+
+/// Whether the engine was running at a fix, from how much the accel
+/// magnitude shakes in the IMU lines around it. None without enough lines.
+fn engine_running(d: &GpsData, imu: &[ImuShort], config: &TripConfig) -> Option<bool> {
+    let start = imu.partition_point(|r| r.pitime < d.pitime.saturating_sub(VIBRATION_WINDOW));
+    let end = imu.partition_point(|r| r.pitime <= d.pitime + VIBRATION_WINDOW);
+    let lines = &imu[start..end];
+    if lines.len() < MIN_VIBRATION_LINES {
+        return None;
+    }
+    let magnitudes: Vec<f64> = lines
+        .iter()
+        .map(|r| ((r.accel_x as f64).powi(2) + (r.accel_y as f64).powi(2) + (r.accel_z as f64).powi(2)).sqrt())
+        .collect();
+    let mean = magnitudes.iter().sum::<f64>() / magnitudes.len() as f64;
+    let variance = magnitudes.iter().map(|m| (m - mean).powi(2)).sum::<f64>() / magnitudes.len() as f64;
+    Some(variance.sqrt() > config.engine_vibration as f64)
+}
+
+/// A trip found so far, up to the last fix the truck was moving at
+struct OpenTrip {
+    first: GpsData,
+    last_moving: GpsData,
+    /// Along the track from the first fix, m
+    travelled: f64,
+    distance: f64,
+    max_speed: f32,
+    /// GPS time the truck stopped, if it has
+    stopped: Option<u64>,
+}
+
+impl OpenTrip {
+    fn close(self, config: &TripConfig) -> Option<Trip> {
+        let (first, last) = (self.first, self.last_moving);
+        (self.distance >= config.min_distance as f64).then_some(Trip {
+            uuid: first.uuid,
+            start_time: first.gps_time,
+            end_time: last.gps_time,
+            start_lat: first.lat,
+            start_lon: first.lon,
+            end_lat: last.lat,
+            end_lon: last.lon,
+            distance: self.distance as f32,
+            duration: last.gps_time - first.gps_time,
+            max_speed: self.max_speed,
+        })
+    }
+}
+
+/// Cut a device's GPS lines, in time order, into trips. A trip starts at
+/// the first fix the truck is moving at and ends at the last before it
+/// stops for good: for longer than `engine_off` once the IMU lines, in
+/// pitime order, show the engine has been switched off, for longer than
+/// `max_stop` otherwise, or at a gap of more than `max_gap`. Lines without
+/// a valid fix are ignored.
+pub fn detect_trips(gps: &[GpsData], imu: &[ImuShort], config: &TripConfig) -> Vec<Trip> {
+    let (engine_off, max_stop) = (config.engine_off.as_millis() as u64, config.max_stop.as_millis() as u64);
+    let max_gap = config.max_gap.as_millis() as u64;
+    let mut trips = Vec::new();
+    let mut open: Option<OpenTrip> = None;
+    let mut previous: Option<&GpsData> = None;
+
+    for d in gps {
+        let (_, _, valid, _, _) = decode_fields(d.status_nsats_vuc);
+        if !valid {
+            continue;
+        }
+        if previous.is_some_and(|p| d.gps_time.saturating_sub(p.gps_time) > max_gap) {
+            trips.extend(open.take().and_then(|t| t.close(config)));
+        }
+        if let (Some(trip), Some(p)) = (open.as_mut(), previous) {
+            trip.travelled += distance(&waypoint(p), &waypoint(d));
+        }
+        previous = Some(d);
+
+        if d.speed >= config.moving_speed {
+            let trip = open.get_or_insert(OpenTrip {
+                first: *d,
+                last_moving: *d,
+                travelled: 0.0,
+                distance: 0.0,
+                max_speed: 0.0,
+                stopped: None,
+            });
+            trip.last_moving = *d;
+            trip.distance = trip.travelled;
+            trip.max_speed = trip.max_speed.max(d.speed);
+            trip.stopped = None;
+        } else if let Some(trip) = open.as_mut() {
+            let stopped = *trip.stopped.get_or_insert(d.gps_time);
+            let limit = match engine_running(d, imu, config) {
+                Some(false) => engine_off,
+                _ => max_stop,
+            };
+            if d.gps_time - stopped > limit {
+                trips.extend(open.take().and_then(|t| t.close(config)));
+            }
+        }
+    }
+    trips.extend(open.and_then(|t| t.close(config)));
+    trips
+}
+
+/// The IMU lines `detect_trips` looks at for `gps`: those around the fixes
+/// the truck was stopped at. On the move there's nothing to read, so a
+/// long drive isn't read again in full each time it's refreshed.
+fn stopped_imu(store: &dyn TelemetryStore, uuid: u64, gps: &[GpsData], config: &TripConfig) -> Result<Vec<ImuShort>, StoreError> {
+    let mut around: Vec<Range<u64>> = gps
+        .iter()
+        .filter(|d| d.speed < config.moving_speed)
+        .map(|d| d.pitime.saturating_sub(VIBRATION_WINDOW)..d.pitime.saturating_add(VIBRATION_WINDOW + 1))
+        .collect();
+    around.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for r in around {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    let mut imu = Vec::new();
+    for r in merged {
+        imu.extend(store.imu_range(uuid, r)?);
+    }
+    Ok(imu)
+}
+
+/// Detect a device's trips again after lines in `range`, GPS time, ms,
+/// have arrived, and store them in place of those that were there. The
+/// range is widened to take in any stored trip it touches, and far enough
+/// either side to find where a trip running into it started or ended, so
+/// trips that ended before that are left alone. Returns the trips now
+/// stored for the widened range.
+pub fn refresh_trips(
+    store: &dyn TelemetryStore,
+    uuid: u64,
+    range: Range<u64>,
+    config: &TripConfig,
+) -> Result<Vec<Trip>, StoreError> {
+    let pad = config.max_gap.max(config.max_stop).as_millis() as u64;
+    let mut window = range.start.saturating_sub(pad)..range.end.saturating_add(pad);
+    loop {
+        let wider = store.trips(uuid, window.clone())?.iter().fold(window.clone(), |w, t| {
+            w.start.min(t.start_time)..w.end.max(t.end_time + 1)
+        });
+        if wider == window {
+            break;
+        }
+        window = wider;
+    }
+
+    let gps = store.gps_range(uuid, window.clone())?;
+    let imu = stopped_imu(store, uuid, &gps, config)?;
+    let trips = detect_trips(&gps, &imu, config);
+    store.replace_trips(uuid, window, &trips)?;
+    Ok(trips)
+}
@@ -0,0 +1,397 @@
+use std::fmt;
+
+use crate::data_defs::{Timebase, Timestamp};
+use crate::fake_gps::encode_fields;
+use crate::gps::GpsData;
+use crate::calendar::days_from_civil;
+
+pub const SYNC: [u8; 2] = [0xB5, 0x62];
+pub const CLASS_NAV: u8 = 0x01;
+pub const NAV_STATUS: u8 = 0x03;
+pub const NAV_DOP: u8 = 0x04;
+pub const NAV_PVT: u8 = 0x07;
+
+/// Longer than any message we ask the receiver for; anything bigger is a
+/// corrupt length field, not a frame worth waiting for
+pub const MAX_PAYLOAD: usize = 1024;
+
+/// `GpsData::time_valid` bits, the same as the NAV-PVT valid field
+pub const TIME_VALID_DATE: u32 = 0x01;
+pub const TIME_VALID_TIME: u32 = 0x02;
+/// No second-level ambiguity left in the time
+pub const TIME_FULLY_RESOLVED: u32 = 0x04;
+pub const TIME_VALID_ALL: u32 = TIME_VALID_DATE | TIME_VALID_TIME | TIME_FULLY_RESOLVED;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UbxError {
+    BadChecksum { class: u8, id: u8 },
+    /// The length field is over MAX_PAYLOAD
+    TooLong(usize),
+    /// A message we decode, but with the wrong payload length
+    WrongLength { class: u8, id: u8, len: usize },
+    /// A message this parser doesn't decode
+    Unsupported { class: u8, id: u8 },
+}
+
+impl fmt::Display for UbxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UbxError::BadChecksum { class, id } => write!(f, "bad checksum on {:02X}-{:02X}", class, id),
+            UbxError::TooLong(len) => write!(f, "payload of {} bytes is too long", len),
+            UbxError::WrongLength { class, id, len } => {
+                write!(f, "{:02X}-{:02X} can't be {} bytes long", class, id, len)
+            }
+            UbxError::Unsupported { class, id } => write!(f, "unsupported message {:02X}-{:02X}", class, id),
+        }
+    }
+}
+
+impl std::error::Error for UbxError {}
+
+/// 8-bit Fletcher checksum over class, id, length and payload
+pub fn checksum(bytes: &[u8]) -> [u8; 2] {
+    let (mut a, mut b) = (0u8, 0u8);
+    for byte in bytes {
+        a = a.wrapping_add(*byte);
+        b = b.wrapping_add(a);
+    }
+    [a, b]
+}
+
+/// One message off the wire, checksum already checked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub class: u8,
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(class: u8, id: u8, payload: Vec<u8>) -> Frame {
+        Frame { class, id, payload }
+    }
+
+    /// The bytes as a receiver would send them
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.payload.len() + 8);
+        out.extend_from_slice(&SYNC);
+        out.extend_from_slice(&[self.class, self.id]);
+        out.extend_from_slice(&(self.payload.len() as u16).to_le_bytes());
+        out.extend_from_slice(&self.payload);
+        let ck = checksum(&out[2..]);
+        out.extend_from_slice(&ck);
+        out
+    }
+}
+
+/// Splits a byte stream into frames. Bytes are fed in as they arrive, in
+/// chunks of any size; anything between frames (NMEA the receiver is also
+/// sending, or line noise) is skipped.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    skipped: u64,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Bytes thrown away looking for the start of a frame
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// The next complete frame, if there is one yet. After an error the
+    /// decoder looks for a frame starting just past the bad sync bytes, so
+    /// a sync pair inside a payload can't lose the frames that follow.
+    pub fn next_frame(&mut self) -> Option<Result<Frame, UbxError>> {
+        let start = self.buf.windows(2).position(|w| w == SYNC);
+        let start = match start {
+            Some(start) => start,
+            None => {
+                // Keep a trailing 0xB5, it may be half a sync pair
+                let keep = usize::from(self.buf.last() == Some(&SYNC[0]));
+                self.discard(self.buf.len() - keep);
+                return None;
+            }
+        };
+        self.discard(start);
+        if self.buf.len() < 6 {
+            return None;
+        }
+
+        let (class, id) = (self.buf[2], self.buf[3]);
+        let len = u16::from_le_bytes([self.buf[4], self.buf[5]]) as usize;
+        if len > MAX_PAYLOAD {
+            self.discard(2);
+            return Some(Err(UbxError::TooLong(len)));
+        }
+        if self.buf.len() < len + 8 {
+            return None;
+        }
+        if checksum(&self.buf[2..6 + len]) != [self.buf[6 + len], self.buf[7 + len]] {
+            self.discard(2);
+            return Some(Err(UbxError::BadChecksum { class, id }));
+        }
+        let payload = self.buf[6..6 + len].to_vec();
+        self.buf.drain(..len + 8);
+        Some(Ok(Frame { class, id, payload }))
+    }
+
+    fn discard(&mut self, n: usize) {
+        self.skipped += n as u64;
+        self.buf.drain(..n);
+    }
+}
+
+/// Little-endian reads from a payload whose length has been checked
+struct Payload<'a>(&'a [u8]);
+
+impl Payload<'_> {
+    fn u8(&self, at: usize) -> u8 {
+        self.0[at]
+    }
+    fn u16(&self, at: usize) -> u16 {
+        u16::from_le_bytes([self.0[at], self.0[at + 1]])
+    }
+    fn u32(&self, at: usize) -> u32 {
+        u32::from_le_bytes([self.0[at], self.0[at + 1], self.0[at + 2], self.0[at + 3]])
+    }
+    fn i32(&self, at: usize) -> i32 {
+        self.u32(at) as i32
+    }
+}
+
+/// NAV-PVT: position, velocity and time, all from one navigation epoch.
+/// Units are as the receiver sends them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavPvt {
+    /// GPS time of week of the epoch, ms
+    pub itow: u32,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub min: u8,
+    pub sec: u8,
+    /// TIME_VALID_* bits
+    pub valid: u8,
+    /// Time accuracy, ns
+    pub t_acc: u32,
+    /// Fraction of the second, ns, -1e9..1e9
+    pub nano: i32,
+    /// 0 none, 1 dead reckoning, 2 2D, 3 3D, 4 GNSS + dead reckoning, 5 time only
+    pub fix_type: u8,
+    /// Bit 0 is gnssFixOK
+    pub flags: u8,
+    pub num_sv: u8,
+    /// 1e-7 degrees
+    pub lon: i32,
+    pub lat: i32,
+    /// Above the ellipsoid and above mean sea level, mm
+    pub height: i32,
+    pub h_msl: i32,
+    /// mm
+    pub h_acc: u32,
+    pub v_acc: u32,
+    /// mm/s
+    pub vel_n: i32,
+    pub vel_e: i32,
+    pub vel_d: i32,
+    pub g_speed: i32,
+    /// Heading of motion, 1e-5 degrees
+    pub head_mot: i32,
+    /// mm/s
+    pub s_acc: u32,
+    /// 1e-5 degrees
+    pub head_acc: u32,
+    /// 0.01
+    pub p_dop: u16,
+}
+
+/// NAV-STATUS: receiver navigation status
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavStatus {
+    pub itow: u32,
+    /// Same values as NavPvt::fix_type
+    pub gps_fix: u8,
+    /// Bit 0 gpsFixOk, 1 diffSoln, 2 wknSet, 3 towSet
+    pub flags: u8,
+    /// Time to first fix, ms
+    pub ttff: u32,
+    /// Time since the receiver started, ms
+    pub msss: u32,
+}
+
+/// NAV-DOP: dilution of precision, in units of 0.01
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NavDop {
+    pub itow: u32,
+    pub g_dop: u16,
+    pub p_dop: u16,
+    pub t_dop: u16,
+    pub v_dop: u16,
+    pub h_dop: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    NavPvt(NavPvt),
+    NavStatus(NavStatus),
+    NavDop(NavDop),
+}
+
+fn expect_len(frame: &Frame, len: usize) -> Result<Payload<'_>, UbxError> {
+    if frame.payload.len() != len {
+        return Err(UbxError::WrongLength { class: frame.class, id: frame.id, len: frame.payload.len() });
+    }
+    Ok(Payload(&frame.payload))
+}
+
+/// Decode the messages we use
+pub fn decode(frame: &Frame) -> Result<Message, UbxError> {
+    match (frame.class, frame.id) {
+        (CLASS_NAV, NAV_PVT) => {
+            let p = expect_len(frame, 92)?;
+            Ok(Message::NavPvt(NavPvt {
+                itow: p.u32(0),
+                year: p.u16(4),
+                month: p.u8(6),
+                day: p.u8(7),
+                hour: p.u8(8),
+                min: p.u8(9),
+                sec: p.u8(10),
+                valid: p.u8(11),
+                t_acc: p.u32(12),
+                nano: p.i32(16),
+                fix_type: p.u8(20),
+                flags: p.u8(21),
+                num_sv: p.u8(23),
+                lon: p.i32(24),
+                lat: p.i32(28),
+                height: p.i32(32),
+                h_msl: p.i32(36),
+                h_acc: p.u32(40),
+                v_acc: p.u32(44),
+                vel_n: p.i32(48),
+                vel_e: p.i32(52),
+                vel_d: p.i32(56),
+                g_speed: p.i32(60),
+                head_mot: p.i32(64),
+                s_acc: p.u32(68),
+                head_acc: p.u32(72),
+                p_dop: p.u16(76),
+            }))
+        }
+        (CLASS_NAV, NAV_STATUS) => {
+            let p = expect_len(frame, 16)?;
+            Ok(Message::NavStatus(NavStatus {
+                itow: p.u32(0),
+                gps_fix: p.u8(4),
+                flags: p.u8(5),
+                ttff: p.u32(8),
+                msss: p.u32(12),
+            }))
+        }
+        (CLASS_NAV, NAV_DOP) => {
+            let p = expect_len(frame, 18)?;
+            Ok(Message::NavDop(NavDop {
+                itow: p.u32(0),
+                g_dop: p.u16(4),
+                p_dop: p.u16(6),
+                t_dop: p.u16(8),
+                v_dop: p.u16(10),
+                h_dop: p.u16(12),
+            }))
+        }
+        (class, id) => Err(UbxError::Unsupported { class, id }),
+    }
+}
+
+impl NavPvt {
+    /// A 2D or 3D fix, with or without dead reckoning, that the receiver
+    /// says is within its accuracy limits
+    pub fn fix_ok(&self) -> bool {
+        self.flags & 0x01 != 0 && (2..=4).contains(&self.fix_type)
+    }
+
+    /// UTC of the epoch to the nanosecond, if the receiver knows the date
+    /// and time
+    pub fn utc(&self) -> Option<Timestamp> {
+        let valid = self.valid as u32;
+        if valid & TIME_VALID_DATE == 0 || valid & TIME_VALID_TIME == 0 {
+            return None;
+        }
+        let days = days_from_civil(self.year as i64, self.month as u32, self.day as u32);
+        let secs = days * 86_400 + self.hour as i64 * 3600 + self.min as i64 * 60 + self.sec as i64;
+        // nano can be negative: the second field is rounded to the nearest
+        Some(Timestamp::from_nanos(secs as i128 * 1_000_000_000 + self.nano as i128, Timebase::Gps))
+    }
+
+    /// utc() rounded down to the ms
+    pub fn utc_ms(&self) -> Option<u64> {
+        self.utc().map(|t| t.as_millis())
+    }
+
+    /// As a `GpsData`, not yet uploaded or confirmed. GpsData has no PDOP,
+    /// so without a NAV-DOP HDOP is taken as PDOP, which is never smaller.
+    /// The epoch's NAV-STATUS, if there is one, has the last word on the
+    /// fix, and without a week number and time of week the time isn't
+    /// fully resolved.
+    pub fn to_gps_data(
+        &self,
+        dop: Option<&NavDop>,
+        status: Option<&NavStatus>,
+        uuid: u64,
+        pitime: u64,
+        sequence: u32,
+    ) -> GpsData {
+        let status = status.filter(|s| s.itow == self.itow);
+        let valid = self.fix_ok() && status.is_none_or(|s| s.fix_ok());
+        let fix_type = status.map_or(self.fix_type, |s| s.gps_fix);
+        let hdop = match dop {
+            Some(d) if d.itow == self.itow => d.h_dop,
+            _ => self.p_dop,
+        };
+        let mut time_valid = self.valid as u32 & TIME_VALID_ALL;
+        if status.is_some_and(|s| !s.time_set()) {
+            time_valid &= !TIME_FULLY_RESOLVED;
+        }
+        GpsData {
+            uuid,
+            pitime,
+            gps_time: self.utc_ms().unwrap_or_default(),
+            sequence,
+            lat: (self.lat as f64 * 1e-7) as f32,
+            lon: (self.lon as f64 * 1e-7) as f32,
+            alt: self.h_msl as f32 / 1000.0,
+            speed: self.g_speed as f32 / 1000.0,
+            track: (self.head_mot as f64 * 1e-5) as f32,
+            status_nsats_vuc: encode_fields(if valid { fix_type } else { 0 }, self.num_sv, valid, false, false),
+            hdop: if valid { hdop as f32 / 100.0 } else { 99.99 },
+            h_acc: self.h_acc as f32 / 1000.0,
+            v_acc: self.v_acc as f32 / 1000.0,
+            speed_acc: self.s_acc as f32 / 1000.0,
+            time_valid,
+            pi_stamp: None,
+            gps_stamp: self.utc().map(Into::into),
+        }
+    }
+}
+
+impl NavStatus {
+    pub fn fix_ok(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    /// Week number and time of week are both known, so GPS time is
+    pub fn time_set(&self) -> bool {
+        self.flags & 0x0C == 0x0C
+    }
+}
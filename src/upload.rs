@@ -0,0 +1,92 @@
+use std::error::Error;
+
+use rusqlite::Connection;
+use tonic::transport::{Channel, Endpoint};
+
+use crate::crash::{mark_confirmed, mark_crashes_sent, next_upload, unsent_crashes, CRASH_TABLE};
+use crate::gps::gps_data_server_client::GpsDataServerClient;
+use crate::gps::{GpsReply, GpsVec};
+use crate::imu::imu_data_server_client::ImuDataServerClient;
+use crate::imu::{Crash, CrashVec, ImuData, ImuReply, ImuVec};
+
+/// What `upload_backlog` sent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BacklogStats {
+    pub crashes: u64,
+    /// Lines from crash captures
+    pub crash_lines: u64,
+    pub lines: u64,
+    pub batches: u64,
+}
+
+/// The device end of the link: sends one truck's data to the server
+pub struct Uploader {
+    uuid: u64,
+    imu_client: ImuDataServerClient<Channel>,
+    gps_client: GpsDataServerClient<Channel>,
+}
+
+impl Uploader {
+    pub async fn connect(url: &str, uuid: u64) -> Result<Uploader, tonic::transport::Error> {
+        let channel = Endpoint::new(url.to_string())?.connect().await?;
+        Ok(Uploader {
+            uuid,
+            imu_client: ImuDataServerClient::new(channel.clone()),
+            gps_client: GpsDataServerClient::new(channel),
+        })
+    }
+
+    pub fn uuid(&self) -> u64 {
+        self.uuid
+    }
+
+    /// Send a batch of IMU lines, stamped with this device's uuid
+    pub async fn send_imu(&mut self, mut imu: ImuVec) -> Result<ImuReply, tonic::Status> {
+        imu.uuid = self.uuid;
+        let response = self.imu_client.send_imu(tonic::Request::new(imu)).await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn send_gps(&mut self, gps: GpsVec) -> Result<GpsReply, tonic::Status> {
+        let response = self.gps_client.send_gps(tonic::Request::new(gps)).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Send crashes the device captured, stamped with this device's uuid
+    pub async fn send_crashes(&mut self, crashes: &[Crash]) -> Result<ImuReply, tonic::Status> {
+        let crashes = crashes.iter().map(|c| Crash { uuid: self.uuid, ..*c }).collect();
+        let response = self.imu_client.send_crashes(tonic::Request::new(CrashVec { crashes })).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Send every crash and IMU line in the device database the server
+    /// doesn't have yet, lines up to `batch` at a time, flagging each
+    /// batch confirmed once it is acknowledged. Crashes and their captures
+    /// go first, so a crash reaches the server however far behind the rest
+    /// of the backlog is.
+    pub async fn upload_backlog(&mut self, conn: &Connection, batch: usize) -> Result<BacklogStats, Box<dyn Error>> {
+        let mut stats = BacklogStats::default();
+        let crashes = unsent_crashes(conn)?;
+        if !crashes.is_empty() {
+            self.send_crashes(&crashes).await?;
+            mark_crashes_sent(conn, &crashes)?;
+            stats.crashes = crashes.len() as u64;
+            stats.batches += 1;
+        }
+        loop {
+            let (table, rows) = next_upload(conn, batch.max(1))?;
+            if rows.is_empty() {
+                return Ok(stats);
+            }
+            let data = rows.iter().cloned().map(ImuData::from).collect();
+            self.send_imu(ImuVec { data, uuid: self.uuid }).await?;
+            mark_confirmed(conn, table, &rows)?;
+            if table == CRASH_TABLE {
+                stats.crash_lines += rows.len() as u64;
+            } else {
+                stats.lines += rows.len() as u64;
+            }
+            stats.batches += 1;
+        }
+    }
+}
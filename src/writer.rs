@@ -0,0 +1,533 @@
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use prost::Message;
+use rusqlite::Connection;
+
+use crate::crash::{insert_crash, Crash, CRASH_TABLE};
+use crate::data_conv::{insert_imu_short, insert_imu_short_into, ImuShort};
+use crate::data_defs::Timestamp;
+use crate::gps::GpsData;
+use crate::imu::{ImuData, ImuVec};
+use crate::retention::{prune, RetentionPolicy};
+use crate::store::insert_gps_data;
+
+/// A line for the device database
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Imu(ImuShort),
+    Gps(GpsData),
+    /// An impact, and the lines captured around it, see crash.rs
+    Crash(Crash),
+    CrashImu(ImuShort),
+}
+
+impl Record {
+    /// Crash captures are never dropped or spilled to make room
+    pub fn is_protected(&self) -> bool {
+        matches!(self, Record::Crash(_) | Record::CrashImu(_))
+    }
+}
+
+impl From<ImuShort> for Record {
+    fn from(row: ImuShort) -> Self {
+        Record::Imu(row)
+    }
+}
+
+impl From<GpsData> for Record {
+    fn from(d: GpsData) -> Self {
+        Record::Gps(d)
+    }
+}
+
+/// Somewhere sensor loops can put what they read: straight into a
+/// database, or onto a `LogWriter`'s queue
+pub trait RowSink {
+    /// Returns how many rows were stored or queued
+    fn put(&self, record: Record) -> Result<usize, rusqlite::Error>;
+
+    /// Apply a retention policy. Returns how many rows it deleted, if
+    /// that's known yet.
+    fn prune(&self, policy: &RetentionPolicy) -> Result<u64, rusqlite::Error>;
+}
+
+fn insert(conn: &Connection, record: &Record) -> Result<usize, rusqlite::Error> {
+    match record {
+        Record::Imu(row) => insert_imu_short(conn, row),
+        Record::Gps(d) => insert_gps_data(conn, d),
+        Record::Crash(crash) => insert_crash(conn, crash),
+        Record::CrashImu(row) => insert_imu_short_into(conn, CRASH_TABLE, row),
+    }
+}
+
+impl RowSink for Connection {
+    fn put(&self, record: Record) -> Result<usize, rusqlite::Error> {
+        insert(self, &record)
+    }
+
+    fn prune(&self, policy: &RetentionPolicy) -> Result<u64, rusqlite::Error> {
+        Ok(prune(self, policy, Timestamp::now().as_millis())?.deleted())
+    }
+}
+
+/// What to do with a record when the queue is full. Crash captures are
+/// queued whatever the policy.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FullPolicy {
+    /// Wait for the writer to make room. Nothing is lost, but the sensor
+    /// loop misses its sample times.
+    Block,
+    /// Throw away the oldest queued record to make room
+    DropOldest,
+    /// Append the record to a file in this directory; the writer stores
+    /// it once it has caught up. Spilled IMU rows keep their uuid but not
+    /// gps_time or the clock correction, which the device doesn't have.
+    /// Batches the writer still can't store after `MAX_ATTEMPTS` go here
+    /// too.
+    Spill(PathBuf),
+}
+
+impl FromStr for FullPolicy {
+    type Err = String;
+
+    /// Accepts "block", "drop-oldest" or "spill:<dir>"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(FullPolicy::Block),
+            "drop-oldest" => Ok(FullPolicy::DropOldest),
+            _ => match s.strip_prefix("spill:") {
+                Some(dir) if !dir.is_empty() => Ok(FullPolicy::Spill(dir.into())),
+                _ => Err(format!("Unknown queue policy '{}'", s)),
+            },
+        }
+    }
+}
+
+/// Settings for a `LogWriter`
+#[derive(Debug, Clone)]
+pub struct WriterConfig {
+    /// Records the queue holds before `policy` applies
+    pub capacity: usize,
+    /// Most records written in one transaction
+    pub batch: usize,
+    pub policy: FullPolicy,
+}
+
+impl Default for WriterConfig {
+    fn default() -> Self {
+        WriterConfig { capacity: 1000, batch: 500, policy: FullPolicy::Block }
+    }
+}
+
+/// How the queue and the writer are doing
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriterStats {
+    pub pushed: u64,
+    /// Records in the queue now
+    pub depth: usize,
+    pub max_depth: usize,
+    /// Pushes that had to wait for room
+    pub blocked: u64,
+    /// Records thrown away: by DropOldest, pushed after close, or in a
+    /// spill that couldn't be written
+    pub dropped: u64,
+    pub spilled: u64,
+    /// Spilled records read back and stored
+    pub unspilled: u64,
+    /// Rows written, less any a unique index turned away
+    pub written: u64,
+    pub batches: u64,
+    /// Batches written again after their transaction failed, e.g. with
+    /// the database busy
+    pub retried: u64,
+    /// Records given up on after `MAX_ATTEMPTS` failed transactions that
+    /// couldn't be spilled instead. Crash captures are only given up on
+    /// like this when the writer is closing.
+    pub failed: u64,
+    /// Rows deleted by retention policies
+    pub pruned: u64,
+}
+
+struct Queue {
+    records: VecDeque<Record>,
+    closed: bool,
+    /// Set when something is in the spill files
+    spill_pending: bool,
+    prune: Option<RetentionPolicy>,
+    stats: WriterStats,
+}
+
+struct Shared {
+    config: WriterConfig,
+    queue: Mutex<Queue>,
+    /// Signalled when there's work for the writer
+    work: Condvar,
+    /// Signalled when the writer has taken records off the queue
+    room: Condvar,
+    /// Held while the spill files are written or read
+    spill: Mutex<()>,
+}
+
+const IMU_SPILL: &str = "imu.pb";
+const GPS_SPILL: &str = "gps.pb";
+/// Crash captures are only spilled once the database has failed them
+/// `MAX_ATTEMPTS` times
+const CRASH_SPILL: &str = "crashes.pb";
+const CRASH_IMU_SPILL: &str = "crash_imu.pb";
+const SPILL_FILES: [&str; 4] = [IMU_SPILL, GPS_SPILL, CRASH_SPILL, CRASH_IMU_SPILL];
+/// Added to a spill file's name while the writer stores what's in it
+const TAKEN: &str = ".taken";
+
+/// A failed transaction is tried again after this, doubling each time
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+/// Transactions tried for a batch before it's spilled or given up on
+const MAX_ATTEMPTS: u32 = 5;
+
+fn taken(dir: &Path, file: &str) -> PathBuf {
+    dir.join(format!("{}{}", file, TAKEN))
+}
+
+fn spill_files_exist(dir: &Path) -> bool {
+    SPILL_FILES
+        .iter()
+        .flat_map(|f| [dir.join(f), taken(dir, f)])
+        .any(|path| fs::metadata(path).is_ok_and(|m| m.len() > 0))
+}
+
+/// Append a record to its spill file, length-delimited
+fn append_spill(dir: &Path, record: &Record) -> io::Result<()> {
+    let imu = |row: &ImuShort| ImuVec { uuid: row.uuid, data: vec![ImuData::from(row.clone())] }.encode_length_delimited_to_vec();
+    let (file, bytes) = match record {
+        Record::Imu(row) => (IMU_SPILL, imu(row)),
+        Record::Gps(d) => (GPS_SPILL, d.encode_length_delimited_to_vec()),
+        Record::Crash(crash) => (CRASH_SPILL, crash.encode_length_delimited_to_vec()),
+        Record::CrashImu(row) => (CRASH_IMU_SPILL, imu(row)),
+    };
+    fs::create_dir_all(dir)?;
+    OpenOptions::new().create(true).append(true).open(dir.join(file))?.write_all(&bytes)
+}
+
+/// Everything in the spill files, which are moved aside so new spills
+/// start afresh. They're only removed by `clear_spill` once what was read
+/// from them is committed; until then they're read again, ahead of any
+/// newer spill. A record torn by a crash mid-append ends its file.
+fn take_spill(dir: &Path) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for file in SPILL_FILES {
+        let path = taken(dir, file);
+        if !path.exists() {
+            match fs::rename(dir.join(file), &path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let mut buf = bytes.as_slice();
+        while !buf.is_empty() {
+            let mut imu = || {
+                ImuVec::decode_length_delimited(&mut buf).ok().and_then(|vec| {
+                    let row = ImuShort::try_from(*vec.data.first()?).ok()?;
+                    Some(ImuShort { uuid: vec.uuid, ..row })
+                })
+            };
+            let record = match file {
+                IMU_SPILL => imu().map(Record::Imu),
+                CRASH_IMU_SPILL => imu().map(Record::CrashImu),
+                CRASH_SPILL => Crash::decode_length_delimited(&mut buf).ok().map(Record::Crash),
+                _ => GpsData::decode_length_delimited(&mut buf).ok().map(Record::Gps),
+            };
+            match record {
+                Some(record) => records.push(record),
+                None => break,
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Remove the spill files `take_spill` read
+fn clear_spill(dir: &Path) -> io::Result<()> {
+    for file in SPILL_FILES {
+        match fs::remove_file(taken(dir, file)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Takes records from sensor loops and writes them to the device database
+/// on a thread of its own, batching them into transactions so a slow card
+/// doesn't hold up sampling
+pub struct LogWriter {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LogWriter {
+    /// Start the writer thread, which owns `conn` from now on
+    pub fn spawn(conn: Connection, config: WriterConfig) -> LogWriter {
+        let spill_pending = match &config.policy {
+            FullPolicy::Spill(dir) => spill_files_exist(dir),
+            _ => false,
+        };
+        let shared = Arc::new(Shared {
+            config,
+            queue: Mutex::new(Queue {
+                records: VecDeque::new(),
+                closed: false,
+                spill_pending,
+                prune: None,
+                stats: WriterStats::default(),
+            }),
+            work: Condvar::new(),
+            room: Condvar::new(),
+            spill: Mutex::new(()),
+        });
+        let writer = shared.clone();
+        let thread = thread::spawn(move || write_loop(conn, &writer));
+        LogWriter { shared, thread: Some(thread) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.shared.queue.lock().unwrap()
+    }
+
+    /// Queue a record for writing. Returns false if it was dropped.
+    pub fn push(&self, record: impl Into<Record>) -> bool {
+        let record = record.into();
+        let mut queue = self.lock();
+        queue.stats.pushed += 1;
+        if queue.records.len() >= self.shared.config.capacity && !queue.closed && !record.is_protected() {
+            match &self.shared.config.policy {
+                FullPolicy::Block => {
+                    queue.stats.blocked += 1;
+                    while queue.records.len() >= self.shared.config.capacity && !queue.closed {
+                        queue = self.shared.room.wait(queue).unwrap();
+                    }
+                }
+                FullPolicy::DropOldest => {
+                    if let Some(oldest) = queue.records.iter().position(|r| !r.is_protected()) {
+                        queue.records.remove(oldest);
+                        queue.stats.dropped += 1;
+                    }
+                }
+                FullPolicy::Spill(dir) => {
+                    drop(queue);
+                    return self.spill(dir, &record);
+                }
+            }
+        }
+        if queue.closed {
+            queue.stats.dropped += 1;
+            return false;
+        }
+        queue.records.push_back(record);
+        queue.stats.max_depth = queue.stats.max_depth.max(queue.records.len());
+        self.shared.work.notify_one();
+        true
+    }
+
+    fn spill(&self, dir: &Path, record: &Record) -> bool {
+        let _spill = self.shared.spill.lock().unwrap();
+        let written = append_spill(dir, record).is_ok();
+        let mut queue = self.lock();
+        if written {
+            queue.stats.spilled += 1;
+            queue.spill_pending = true;
+        } else {
+            queue.stats.dropped += 1;
+        }
+        written
+    }
+
+    pub fn stats(&self) -> WriterStats {
+        let queue = self.lock();
+        WriterStats { depth: queue.records.len(), ..queue.stats.clone() }
+    }
+
+    /// Write everything queued or spilled, then stop the writer thread
+    pub fn close(mut self) -> WriterStats {
+        self.shutdown();
+        self.stats()
+    }
+
+    fn shutdown(&mut self) {
+        self.lock().closed = true;
+        self.shared.work.notify_all();
+        self.shared.room.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl RowSink for LogWriter {
+    fn put(&self, record: Record) -> Result<usize, rusqlite::Error> {
+        Ok(self.push(record) as usize)
+    }
+
+    /// Done by the writer between batches; the rows deleted show up in
+    /// `stats().pruned`
+    fn prune(&self, policy: &RetentionPolicy) -> Result<u64, rusqlite::Error> {
+        self.lock().prune = Some(policy.clone());
+        self.shared.work.notify_one();
+        Ok(0)
+    }
+}
+
+/// Write `batch` in one transaction. Returns how many rows were new.
+fn write_batch(conn: &mut Connection, batch: &[Record]) -> Result<usize, rusqlite::Error> {
+    let tx = conn.transaction()?;
+    let mut written = 0;
+    for record in batch {
+        written += insert(&tx, record)?;
+    }
+    tx.commit()?;
+    Ok(written)
+}
+
+fn write_loop(mut conn: Connection, shared: &Shared) {
+    let spill_dir = match &shared.config.policy {
+        FullPolicy::Spill(dir) => Some(dir.as_path()),
+        _ => None,
+    };
+    // Failed transactions in a row, for the batch at the front of the
+    // queue and for the spill files
+    let mut attempts = 0;
+    let mut unspill_failures = 0;
+    loop {
+        let failures = attempts.max(unspill_failures).min(MAX_ATTEMPTS);
+        if failures > 0 {
+            thread::sleep(RETRY_DELAY * 2u32.pow(failures - 1));
+        }
+        let (batch, policy, unspill, closed) = {
+            let mut queue = shared.queue.lock().unwrap();
+            while queue.records.is_empty() && queue.prune.is_none() && !queue.spill_pending && !queue.closed {
+                queue = shared.work.wait(queue).unwrap();
+            }
+            let n = queue.records.len().min(shared.config.batch.max(1));
+            let batch: Vec<Record> = queue.records.drain(..n).collect();
+            shared.room.notify_all();
+            // Spilled records wait until the queue has been caught up on
+            let unspill = queue.spill_pending && queue.records.is_empty();
+            (batch, queue.prune.take(), unspill, queue.closed)
+        };
+
+        let mut stats = WriterStats::default();
+        // Records to go back on the front of the queue
+        let mut retry = Vec::new();
+        let mut spilled = false;
+        if !batch.is_empty() {
+            stats.batches += 1;
+            match write_batch(&mut conn, &batch) {
+                Ok(written) => {
+                    stats.written += written as u64;
+                    attempts = 0;
+                }
+                Err(_) if attempts + 1 < MAX_ATTEMPTS => {
+                    attempts += 1;
+                    stats.retried += 1;
+                    retry = batch;
+                }
+                Err(_) => {
+                    attempts = 0;
+                    let _spill = shared.spill.lock().unwrap();
+                    for record in batch {
+                        match spill_dir.map(|dir| append_spill(dir, &record)) {
+                            Some(Ok(())) => {
+                                stats.spilled += 1;
+                                spilled = true;
+                            }
+                            // With nowhere to spill them, crash captures
+                            // are kept and tried for as long as the
+                            // writer runs
+                            _ if record.is_protected() && !closed => retry.push(record),
+                            _ => stats.failed += 1,
+                        }
+                    }
+                    if !retry.is_empty() {
+                        attempts = MAX_ATTEMPTS - 1;
+                        stats.retried += 1;
+                    }
+                }
+            }
+        }
+
+        if let Some(policy) = policy {
+            stats.pruned += prune(&conn, &policy, Timestamp::now().as_millis()).map_or(0, |p| p.deleted());
+        }
+
+        // Left pending on failure, unless closing: the files stay on the
+        // card for the next writer either way
+        let mut unspilled = false;
+        if let (true, Some(dir)) = (unspill, spill_dir) {
+            let records = {
+                let _spill = shared.spill.lock().unwrap();
+                shared.queue.lock().unwrap().spill_pending = false;
+                take_spill(dir)
+            };
+            let mut read_back = 0;
+            let stored = records.is_ok_and(|records| {
+                records.chunks(shared.config.batch.max(1)).all(|chunk| {
+                    stats.batches += 1;
+                    match write_batch(&mut conn, chunk) {
+                        Ok(written) => {
+                            stats.written += written as u64;
+                            read_back += chunk.len() as u64;
+                            true
+                        }
+                        Err(_) => false,
+                    }
+                })
+            });
+            if stored && clear_spill(dir).is_ok() {
+                stats.unspilled += read_back;
+                unspill_failures = 0;
+                unspilled = true;
+            } else {
+                unspill_failures += 1;
+                stats.retried += 1;
+                spilled |= !closed;
+            }
+        }
+
+        let mut queue = shared.queue.lock().unwrap();
+        for record in retry.into_iter().rev() {
+            queue.records.push_front(record);
+        }
+        queue.spill_pending |= spilled;
+        if let (true, Some(dir)) = (unspilled, spill_dir) {
+            // Spilled while the taken files were being stored
+            queue.spill_pending |= spill_files_exist(dir);
+        }
+        queue.stats.written += stats.written;
+        queue.stats.batches += stats.batches;
+        queue.stats.retried += stats.retried;
+        queue.stats.failed += stats.failed;
+        queue.stats.spilled += stats.spilled;
+        queue.stats.pruned += stats.pruned;
+        queue.stats.unspilled += stats.unspilled;
+        if closed && queue.records.is_empty() && !queue.spill_pending {
+            break;
+        }
+    }
+}
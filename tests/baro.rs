@@ -0,0 +1,139 @@
+use grpc_tests::baro::{
+    altitude_to_pressure, pressure_to_altitude, reference_pressure, BaroConfig, Barometer, STANDARD_P0,
+};
+use grpc_tests::data_defs::{Length, Pressure};
+use grpc_tests::drive_sim::DriveSimulator;
+use grpc_tests::fake_gps::encode_fields;
+use grpc_tests::gps::GpsData;
+use grpc_tests::gps_sim::{GpsSimConfig, Route};
+use grpc_tests::imu_sim::{ImuSimConfig, MotionProfile};
+
+fn pa(pascals: f64) -> Pressure {
+    Pressure::from_pascals(pascals)
+}
+
+fn m(meters: f64) -> Length {
+    Length::from_meters(meters)
+}
+
+fn fix(pitime: u64, alt: f32) -> GpsData {
+    GpsData {
+        uuid: 1,
+        pitime,
+        gps_time: pitime,
+        alt,
+        status_nsats_vuc: encode_fields(1, 9, true, false, false),
+        hdop: 0.9,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn matches_the_standard_atmosphere() {
+    let p0 = pa(STANDARD_P0);
+    assert_eq!(pressure_to_altitude(p0, p0).as_meters(), 0.0);
+    // ISA: 89 874.6 Pa at 1000 m, 79 495.2 Pa at 2000 m
+    assert!((pressure_to_altitude(pa(89_874.6), p0).as_meters() - 1000.0).abs() < 1.0);
+    assert!((pressure_to_altitude(pa(79_495.2), p0).as_meters() - 2000.0).abs() < 2.0);
+}
+
+#[test]
+fn conversions_invert_each_other() {
+    for alt in [-50.0, 0.0, 120.0, 1500.0] {
+        let p = altitude_to_pressure(m(alt), pa(100_900.0));
+        assert!((pressure_to_altitude(p, pa(100_900.0)).as_meters() - alt).abs() < 1e-6);
+        assert!((reference_pressure(p, m(alt)).as_pascals() - 100_900.0).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn configured_p0_is_used_until_calibrated() {
+    let baro = Barometer::new(BaroConfig { p0: pa(100_000.0), ..Default::default() });
+    assert!(!baro.is_calibrated());
+    let p = altitude_to_pressure(m(300.0), pa(100_000.0));
+    assert!((baro.altitude(p).as_meters() - 300.0).abs() < 1e-6);
+
+    let mut fixed = Barometer::new(BaroConfig { p0: pa(100_000.0), gps_calibration: false, ..Default::default() });
+    fixed.observe(1000, p);
+    assert!(!fixed.calibrate(&fix(1000, 120.0)));
+    assert_eq!(fixed.p0(), pa(100_000.0));
+}
+
+#[test]
+fn first_good_fix_sets_p0() {
+    let mut baro = Barometer::new(BaroConfig::default());
+    let p = altitude_to_pressure(m(112.5), pa(100_600.0));
+    baro.observe(10_000, p);
+
+    assert!(baro.calibrate(&fix(10_050, 112.5)));
+    assert!(baro.is_calibrated());
+    assert!((baro.p0().as_pascals() - 100_600.0).abs() < 0.01);
+    assert!((baro.altitude(p).as_meters() - 112.5).abs() < 0.01);
+}
+
+#[test]
+fn poor_fixes_are_ignored() {
+    let mut baro = Barometer::new(BaroConfig::default());
+    baro.observe(10_000, pa(100_000.0));
+
+    let mut invalid = fix(10_000, 100.0);
+    invalid.status_nsats_vuc = encode_fields(0, 0, false, false, false);
+    let mut few_sats = fix(10_000, 100.0);
+    few_sats.status_nsats_vuc = encode_fields(1, 4, true, false, false);
+    let mut high_hdop = fix(10_000, 100.0);
+    high_hdop.hdop = 4.5;
+    let no_pressure_nearby = fix(20_000, 100.0);
+
+    for f in [invalid, few_sats, high_hdop, no_pressure_nearby] {
+        assert!(!baro.calibrate(&f));
+    }
+    assert!(!baro.is_calibrated());
+    assert_eq!(baro.p0(), pa(STANDARD_P0));
+}
+
+#[test]
+fn later_fixes_only_nudge_p0() {
+    let mut baro = Barometer::new(BaroConfig { time_constant: 100.0, ..Default::default() });
+    let p = altitude_to_pressure(m(100.0), pa(101_000.0));
+    baro.observe(0, p);
+    baro.calibrate(&fix(0, 100.0));
+
+    // A 10 m jump a second later moves p0 about 1% of the way
+    baro.observe(1000, p);
+    assert!(baro.calibrate(&fix(1000, 110.0)));
+    let target = reference_pressure(p, m(110.0)).as_pascals();
+    let moved = (baro.p0().as_pascals() - 101_000.0) / (target - 101_000.0);
+    assert!((moved - 1.0 / 101.0).abs() < 1e-6);
+
+    // An older fix arriving late is not used
+    assert!(!baro.calibrate(&fix(500, 200.0)));
+}
+
+#[test]
+fn calibrated_altitude_follows_the_gps() {
+    // A low-pressure day; the barometer starts out assuming the standard
+    let imu_config = ImuSimConfig { pressure: 100_400.0, ..Default::default() };
+    let mut sim = DriveSimulator::new(imu_config, GpsSimConfig::default(), Route::camborne(), MotionProfile::default());
+    let mut baro = Barometer::new(BaroConfig::default());
+
+    let mut errors = Vec::new();
+    for _ in 0..6000 {
+        let (imu, gps) = sim.tick();
+        let altitude = baro.update(&imu);
+        if let Some(g) = gps {
+            baro.calibrate(&g);
+            if baro.is_calibrated() {
+                errors.push(altitude.as_meters() - f64::from(g.alt));
+            }
+        }
+    }
+
+    // Uncalibrated, the truck would look about 77 m too high
+    assert!(baro.is_calibrated());
+    let p0 = baro.p0().as_pascals();
+    assert!((p0 - 100_400.0).abs() < 60.0, "p0 {}", p0);
+    let late = &errors[errors.len() - 100..];
+    let mean = late.iter().sum::<f64>() / late.len() as f64;
+    assert!(mean.abs() < 5.0, "mean error {} m", mean);
+}
+
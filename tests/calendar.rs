@@ -0,0 +1,11 @@
+use grpc_tests::calendar::{civil_from_days, days_from_civil};
+
+#[test]
+fn days_and_dates_convert_both_ways() {
+    for days in -800_000..800_000 {
+        let (y, m, d) = civil_from_days(days);
+        assert_eq!(days_from_civil(y, m, d), days);
+    }
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(days_from_civil(2024, 2, 29)), (2024, 2, 29));
+}
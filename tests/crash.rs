@@ -0,0 +1,253 @@
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rusqlite::Connection;
+
+use grpc_tests::crash::{
+    mark_confirmed, next_upload, read_crashes, unsent_crashes, CrashConfig, CrashRecorder, CRASH_TABLE,
+};
+use grpc_tests::data_conv::{insert_imu_short, ImuShort};
+use grpc_tests::device_db::{open_device_db, DeviceDbConfig};
+use grpc_tests::imu::{ImuData, Inertial, Orientation, Vector3D};
+use grpc_tests::imu_source::{run_sampler, ReplayImu, SamplerConfig};
+use grpc_tests::retention::{prune, RetentionPolicy};
+use grpc_tests::service::spawn_server;
+use grpc_tests::store::{MemoryStore, TelemetryStore};
+use grpc_tests::upload::Uploader;
+use grpc_tests::writer::{FullPolicy, LogWriter, Record, RowSink, WriterConfig};
+
+const UUID: u64 = 0x1234567890AB;
+const T0: u64 = 1_700_000_000_000;
+
+fn device_db(dir: &Path) -> Connection {
+    open_device_db(&dir.join("device.db3"), &DeviceDbConfig::default()).unwrap().0
+}
+
+/// A line 100 ms after the one before, level and still unless `jolt`
+fn row(sequence: u32, jolt: f32) -> ImuShort {
+    ImuShort {
+        uuid: UUID,
+        pitime: T0 + sequence as u64 * 100,
+        sequence,
+        accel_x: -jolt,
+        accel_z: 1.0,
+        ..Default::default()
+    }
+}
+
+fn sequences(conn: &Connection, table: &str) -> Vec<u32> {
+    let mut stmt = conn.prepare(&format!("SELECT sequence FROM {} ORDER BY lineno", table)).unwrap();
+    stmt.query_map([], |r| r.get(0)).unwrap().collect::<Result<_, _>>().unwrap()
+}
+
+fn captured(records: &[Record]) -> Vec<u32> {
+    records
+        .iter()
+        .filter_map(|r| match r {
+            Record::CrashImu(row) => Some(row.sequence),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn an_impact_keeps_the_lines_either_side() {
+    let mut recorder = CrashRecorder::new(CrashConfig::default());
+    let mut records = Vec::new();
+    for i in 0..600 {
+        let jolt = if i == 300 { 4.0 } else { 0.2 };
+        records.extend(recorder.push(&row(i, jolt)));
+    }
+    assert!(!recorder.is_capturing());
+
+    let Record::Crash(crash) = records[0] else { panic!("{:?}", records[0]) };
+    assert_eq!((crash.uuid, crash.pitime), (UUID, T0 + 30_000));
+    assert!((crash.peak_g - 4.0).abs() < 1e-6);
+    assert_eq!((crash.window_start, crash.window_end), (T0 + 20_000, T0 + 35_000));
+    // 10 s before and 5 s after, every line
+    assert_eq!(captured(&records), (200..=350).collect::<Vec<_>>());
+    assert_eq!(records.len(), 152);
+}
+
+#[test]
+fn a_second_impact_stretches_the_capture() {
+    let config = CrashConfig { before: Duration::from_secs(1), after: Duration::from_secs(2), ..Default::default() };
+    let mut recorder = CrashRecorder::new(config);
+    let mut records = Vec::new();
+    for i in 0..200 {
+        let jolt = if i == 50 || i == 65 { 3.0 } else { 0.0 };
+        records.extend(recorder.push(&row(i, jolt)));
+    }
+    let crashes: Vec<u64> = records
+        .iter()
+        .filter_map(|r| match r {
+            Record::Crash(c) => Some(c.pitime),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(crashes, [T0 + 5000, T0 + 6500]);
+    assert_eq!(captured(&records), (40..=85).collect::<Vec<_>>());
+}
+
+#[test]
+fn a_long_jolt_is_one_crash() {
+    let config = CrashConfig { before: Duration::from_secs(1), after: Duration::from_secs(2), ..Default::default() };
+    let mut recorder = CrashRecorder::new(config);
+    let mut records = Vec::new();
+    for i in 0..200 {
+        // Past the threshold for half a second, dipping under it once,
+        // then again well after it is released
+        let jolt = match i {
+            50..=52 | 54 | 55 => 3.0 + i as f32 / 10.0,
+            53 => 1.0,
+            80 => 4.0,
+            _ => 0.0,
+        };
+        records.extend(recorder.push(&row(i, jolt)));
+    }
+    let crashes: Vec<_> = records
+        .iter()
+        .filter_map(|r| match r {
+            Record::Crash(c) => Some(*c),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(crashes.len(), 2, "{:?}", crashes);
+    assert_eq!((crashes[0].pitime, crashes[0].window_start), (T0 + 5000, T0 + 4000));
+    assert!((crashes[0].peak_g - 8.0).abs() < 1e-6);
+    assert_eq!(crashes[1].pitime, T0 + 8000);
+    // The capture runs on from the last line past the threshold
+    assert_eq!(captured(&records), (40..=100).collect::<Vec<_>>());
+}
+
+/// Level readings, with a jolt at one of them
+fn readings(n: u32, impact: u32) -> Vec<ImuData> {
+    (0..n)
+        .map(|i| ImuData {
+            sequence: i,
+            inertial: Some(Inertial {
+                pose: Some(Orientation::default()),
+                gyro: Some(Vector3D::default()),
+                accel: Some(Vector3D { x: if i == impact { -5.0 } else { 0.0 }, y: 0.0, z: 1.0 }),
+                mag: Some(Vector3D::default()),
+            }),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[test]
+fn the_sampler_captures_full_rate_while_downsampling() {
+    let dir = tempfile::tempdir().unwrap();
+    let conn = device_db(dir.path());
+    let config = SamplerConfig {
+        uuid: UUID,
+        rate_hz: 1000.0,
+        max_samples: Some(400),
+        downsample: 10,
+        // Long enough after for the capture to be written, which is
+        // synchronous here, before the next line is sampled
+        crash: Some(CrashConfig { before: Duration::from_millis(50), after: Duration::from_millis(200), ..Default::default() }),
+        ..Default::default()
+    };
+    let mut source = ReplayImu::new(readings(400, 200));
+    let stats = run_sampler(&mut source, &conn, &config, &AtomicBool::new(false)).unwrap();
+    assert_eq!((stats.samples, stats.stored, stats.crashes), (400, 40, 1));
+
+    assert_eq!(sequences(&conn, "imu"), (0..40).map(|i| i * 10).collect::<Vec<_>>());
+    let crash_lines = sequences(&conn, CRASH_TABLE);
+    assert_eq!(crash_lines.len() as u64, stats.captured);
+    assert!(crash_lines.windows(2).all(|w| w[1] == w[0] + 1), "{:?}", crash_lines);
+    assert!(crash_lines.contains(&199) && crash_lines.contains(&201));
+
+    let crashes = read_crashes(&conn).unwrap();
+    assert_eq!(crashes.len(), 1);
+    let (pitime, first): (u64, u64) = conn
+        .query_row(
+            "SELECT (SELECT pitime FROM crash_imu WHERE sequence = 200), (SELECT min(pitime) FROM crash_imu)",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!((crashes[0].pitime, crashes[0].window_start), (pitime, first));
+}
+
+#[test]
+fn retention_leaves_crash_captures_alone() {
+    let dir = tempfile::tempdir().unwrap();
+    let conn = device_db(dir.path());
+    for i in 0..2000 {
+        insert_imu_short(&conn, &ImuShort { confirmed: i % 2 == 0, ..row(i, 0.0) }).unwrap();
+    }
+    let mut recorder = CrashRecorder::new(CrashConfig::default());
+    for i in 0..200 {
+        for record in recorder.push(&row(i, if i == 150 { 3.0 } else { 0.0 })) {
+            conn.put(record).unwrap();
+        }
+    }
+    let crash_lines = sequences(&conn, CRASH_TABLE);
+    mark_confirmed(&conn, CRASH_TABLE, &next_upload(&conn, 1000).unwrap().1).unwrap();
+
+    let policy = RetentionPolicy {
+        max_age: Some(Duration::from_secs(1)),
+        emergency_bytes: Some(0),
+        ..Default::default()
+    };
+    let stats = prune(&conn, &policy, T0 + 3_600_000).unwrap();
+    assert_eq!(stats.deleted(), 2000);
+    assert!(sequences(&conn, "imu").is_empty());
+    assert_eq!(sequences(&conn, CRASH_TABLE), crash_lines);
+    assert_eq!(read_crashes(&conn).unwrap().len(), 1);
+}
+
+#[test]
+fn crash_captures_are_never_dropped_or_spilled() {
+    let dir = tempfile::tempdir().unwrap();
+    let spill = dir.path().join("spill");
+    let config = WriterConfig { capacity: 1, batch: 1, policy: FullPolicy::Spill(spill) };
+    let writer = LogWriter::spawn(device_db(dir.path()), config);
+    for i in 0..300 {
+        assert!(writer.push(Record::CrashImu(row(i, 0.0))));
+    }
+    let stats = writer.close();
+    assert_eq!((stats.spilled, stats.dropped, stats.written), (0, 0, 300));
+}
+
+#[tokio::test]
+async fn crash_captures_upload_ahead_of_the_backlog() {
+    let dir = tempfile::tempdir().unwrap();
+    let conn = device_db(dir.path());
+    for i in 0..500 {
+        insert_imu_short(&conn, &row(i, 0.0)).unwrap();
+    }
+    let mut recorder = CrashRecorder::new(CrashConfig::default());
+    for i in 1000..1200 {
+        for record in recorder.push(&row(i, if i == 1100 { 3.0 } else { 0.0 })) {
+            conn.put(record).unwrap();
+        }
+    }
+    assert_eq!(next_upload(&conn, 10).unwrap().0, CRASH_TABLE);
+
+    let store = Arc::new(MemoryStore::new());
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let mut uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+    let stats = uploader.upload_backlog(&conn, 100).await.unwrap();
+    assert_eq!((stats.crashes, stats.crash_lines, stats.lines, stats.batches), (1, 151, 500, 8));
+    assert!(next_upload(&conn, 10).unwrap().1.is_empty());
+    assert!(unsent_crashes(&conn).unwrap().is_empty());
+    assert_eq!(store.crashes(UUID).unwrap(), read_crashes(&conn).unwrap());
+
+    // Nothing is sent twice
+    let stats = uploader.upload_backlog(&conn, 100).await.unwrap();
+    assert_eq!(stats, Default::default());
+
+    // The server numbers lines as they arrive
+    let stored = store.imu_range(UUID, 0..u64::MAX).unwrap();
+    assert_eq!(stored.len(), 651);
+    let crash = stored.iter().filter(|r| r.sequence >= 1000);
+    assert!(crash.clone().all(|r| r.line <= 151));
+    assert_eq!(crash.count(), 151);
+    server.stop().await.unwrap();
+}
@@ -0,0 +1,167 @@
+use rusqlite::Connection;
+
+use grpc_tests::csv_io::{export_csv, header, read_gps_csv, read_imu_csv, write_gps_csv, CsvConfig, CsvError, CsvTable, TimeFormat};
+use grpc_tests::data_conv::ImuShort;
+use grpc_tests::fake_gps::encode_fields;
+use grpc_tests::gps::GpsData;
+use grpc_tests::calendar::{iso_time, parse_iso_time};
+use grpc_tests::schema::IMU_COLUMNS;
+use grpc_tests::store::{ServerStore, TelemetryStore};
+use grpc_tests::timestamp::{Timebase, Timestamp};
+
+const UUID: u64 = 0x1234567890AB;
+const T0: u64 = 1_709_251_199_000;
+
+fn imu(i: u32) -> ImuShort {
+    let pitime = T0 + i as u64 * 10;
+    ImuShort {
+        uuid: UUID,
+        pitime,
+        gps_time: pitime - 2,
+        sequence: i,
+        accel_x: 0.1 * i as f32,
+        accel_z: 9.81,
+        gyro_z: -1.5e-3,
+        mag_y: 18_000.5,
+        pressure: 101_325.25,
+        altitude: (!i.is_multiple_of(2)).then_some(42.125),
+        temperature: 21.5,
+        uploaded: true,
+        confirmed: i != 3,
+        clock_offset: Some(-1.75),
+        clock_error: Some(0.5),
+        stamp: (i != 0).then_some(Timestamp { seconds: (pitime / 1000) as i64, nanos: 123_456_789, timebase: Timebase::Gps as i32 }),
+        ..Default::default()
+    }
+}
+
+fn fix(i: u32) -> GpsData {
+    GpsData {
+        uuid: UUID,
+        pitime: T0 + i as u64 * 1000 + 3,
+        gps_time: T0 + i as u64 * 1000,
+        sequence: i,
+        lat: 50.25,
+        lon: -5.125,
+        alt: 80.5,
+        speed: 13.4,
+        track: 271.25,
+        status_nsats_vuc: encode_fields(1, 9, true, false, false),
+        hdop: 0.9,
+        h_acc: if i == 0 { 0.0 } else { 2.5 },
+        time_valid: 7,
+        ..Default::default()
+    }
+}
+
+fn store() -> ServerStore {
+    let store = ServerStore::open_in_memory().unwrap();
+    store.insert_imu(&(0..5).map(imu).collect::<Vec<_>>()).unwrap();
+    store.insert_gps(&(0..3).map(fix).collect::<Vec<_>>()).unwrap();
+    store
+}
+
+fn csv_error(e: Box<dyn std::error::Error>) -> CsvError {
+    e.downcast_ref::<CsvError>().cloned().unwrap_or_else(|| panic!("{}", e))
+}
+
+#[test]
+fn the_header_is_the_tables_column_names() {
+    let names = header(CsvTable::Imu);
+    assert_eq!(names[0], "lineno");
+    assert_eq!(names[1..], IMU_COLUMNS.iter().map(|(name, _)| *name).collect::<Vec<_>>()[..]);
+    assert_eq!(header(CsvTable::Gps)[1..4], ["uuid", "pitime", "gps_time"]);
+}
+
+#[test]
+fn a_table_exported_and_read_back_is_unchanged() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("server.db3");
+    let stored = {
+        let store = ServerStore::open(&path).unwrap();
+        store.insert_imu(&(0..5).map(imu).collect::<Vec<_>>()).unwrap();
+        store.insert_gps(&(0..3).map(fix).collect::<Vec<_>>()).unwrap();
+        (store.read_imu_rows(UUID).unwrap(), store.read_gps(UUID).unwrap())
+    };
+    let conn = Connection::open(&path).unwrap();
+
+    for config in [
+        CsvConfig::default(),
+        CsvConfig { delimiter: b';', time_format: TimeFormat::Iso },
+        CsvConfig { delimiter: b'\t', time_format: TimeFormat::Millis },
+    ] {
+        let mut out = Vec::new();
+        assert_eq!(export_csv(&conn, CsvTable::Imu, Some(UUID), &mut out, &config).unwrap(), 5);
+        assert_eq!(read_imu_csv(&out[..], &config).unwrap(), stored.0);
+
+        let mut out = Vec::new();
+        assert_eq!(export_csv(&conn, CsvTable::Gps, None, &mut out, &config).unwrap(), 3);
+        assert_eq!(read_gps_csv(&out[..], &config).unwrap(), stored.1);
+    }
+
+    let config = CsvConfig { delimiter: b';', time_format: TimeFormat::Iso };
+    let mut out = Vec::new();
+    export_csv(&conn, CsvTable::Imu, Some(UUID), &mut out, &config).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    assert!(text.starts_with("lineno;uuid;pitime;gps_time;sequence;x_accel;"));
+    assert!(text.lines().nth(1).unwrap().starts_with("1;20015998341291;2024-02-29T23:59:59.000Z;2024-02-29T23:59:58.998Z;0;0;"));
+}
+
+#[test]
+fn a_hand_written_fixture_loads_into_the_server_store() {
+    // Any column order, and the nullable columns left out
+    let fixture = "\
+sequence,uuid,pitime,x_accel,y_accel,z_accel,x_gyro,y_gyro,z_gyro,roll_pose,pitch_pose,yaw_pose,heading_accuracy,x_mag,y_mag,z_mag,uploaded,confirmed
+0,7,1700000000000,0,0,9.81,0,0,0,0,0,0,5,0,0,0,1,1
+1,7,1700000000010,0.5,0,9.81,0,0,0,0,0,0,5,0,0,0,true,false
+";
+    let rows = read_imu_csv(fixture.as_bytes(), &CsvConfig::default()).unwrap();
+    assert_eq!(rows[1].accel_x, 0.5);
+    assert_eq!((rows[1].uploaded, rows[1].confirmed, rows[1].altitude, rows[1].stamp), (true, false, None, None));
+
+    let store = store();
+    assert_eq!(store.append_imu(&rows).unwrap(), 2);
+    assert_eq!(store.append_imu(&rows).unwrap(), 0);
+    assert_eq!(store.imu_range(7, 0..u64::MAX).unwrap().len(), 2);
+}
+
+#[test]
+fn bad_rows_are_reported_by_line() {
+    let mut out = Vec::new();
+    write_gps_csv(&mut out, &(0..3).map(fix).collect::<Vec<_>>(), &CsvConfig::default()).unwrap();
+    let good = String::from_utf8(out).unwrap();
+    assert_eq!(read_gps_csv(good.as_bytes(), &CsvConfig::default()).unwrap().len(), 3);
+    // Unknown accuracies come back as 0
+    assert_eq!(read_gps_csv(good.as_bytes(), &CsvConfig::default()).unwrap()[0].h_acc, 0.0);
+
+    let bad = good.replacen("50.25", "95.25", 2);
+    let e = csv_error(read_gps_csv(bad.as_bytes(), &CsvConfig::default()).unwrap_err());
+    assert_eq!(e.line, 2);
+    assert!(e.message.contains("no such place"), "{}", e);
+
+    let lines: Vec<&str> = good.lines().collect();
+    let bad = format!("{}\n{}\n{}\n", lines[0], lines[1], lines[2].replacen("-5.125", "west", 1));
+    let e = csv_error(read_gps_csv(bad.as_bytes(), &CsvConfig::default()).unwrap_err());
+    assert_eq!(e, CsvError { line: 3, message: "bad lon 'west'".into() });
+
+    // Millis where ISO is expected
+    let config = CsvConfig { time_format: TimeFormat::Iso, ..Default::default() };
+    let e = csv_error(read_gps_csv(good.as_bytes(), &config).unwrap_err());
+    assert!(e.message.starts_with("bad pitime"), "{}", e);
+
+    let missing = "uuid,pitime,sequence,lat,lon\n7,1700000000000,0,50,-5\n";
+    let e = csv_error(read_gps_csv(missing.as_bytes(), &CsvConfig::default()).unwrap_err());
+    assert_eq!(e.message, "no gps_time");
+}
+
+#[test]
+fn iso_times_parse_back() {
+    for ms in [0, 951_782_400_000, T0, T0 + 999, 4_102_444_799_999] {
+        assert_eq!(parse_iso_time(&iso_time(ms)), Some(ms));
+    }
+    assert_eq!(parse_iso_time("2024-02-29T23:59:59Z"), Some(T0));
+    assert_eq!(parse_iso_time("2024-02-29T23:59:59.5Z"), Some(T0 + 500));
+    assert_eq!(parse_iso_time("2024-02-29T23:59:59.000"), None);
+    assert_eq!(parse_iso_time("2024-13-01T00:00:00Z"), None);
+    assert_eq!(parse_iso_time("yesterday"), None);
+}
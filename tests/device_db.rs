@@ -0,0 +1,239 @@
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use rusqlite::Connection;
+
+use grpc_tests::crash::{insert_crash, read_crashes, Crash};
+use grpc_tests::data_conv::{insert_imu_short, insert_imu_short_into, ImuShort};
+use grpc_tests::device_db::{open_device_db, DbHealth, DeviceDbConfig, Synchronous};
+
+const ROWS_PER_COMMIT: u64 = 10;
+const WRITER: &str = "DEVICE_DB_WRITER";
+
+/// One transaction of rows, numbered on from `first`
+fn commit_rows(conn: &Connection, first: u64) {
+    let tx = conn.unchecked_transaction().unwrap();
+    for i in first..first + ROWS_PER_COMMIT {
+        let row = ImuShort { uuid: 7, pitime: 1_700_000_000_000 + i, sequence: i as u32, ..Default::default() };
+        insert_imu_short(&tx, &row).unwrap();
+    }
+    tx.commit().unwrap();
+}
+
+fn count(conn: &Connection) -> u64 {
+    conn.query_row("SELECT count(*) FROM imu", [], |row| row.get::<_, i64>(0)).unwrap() as u64
+}
+
+/// The rows are numbered 0, 1, 2... with none missing from the middle
+fn assert_contiguous(conn: &Connection) -> u64 {
+    let (n, max): (i64, Option<i64>) =
+        conn.query_row("SELECT count(*), max(sequence) FROM imu", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+    assert_eq!(max.map_or(0, |m| m + 1), n);
+    n as u64
+}
+
+/// No commit is missing, or there in part
+fn assert_whole(conn: &Connection) {
+    assert_eq!(assert_contiguous(conn) % ROWS_PER_COMMIT, 0);
+}
+
+fn journal_mode(conn: &Connection) -> String {
+    conn.query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap()
+}
+
+#[test]
+fn the_database_is_opened_in_wal_mode() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("imu.db3");
+    let config = DeviceDbConfig { synchronous: Synchronous::Normal, ..Default::default() };
+    let (conn, health) = open_device_db(&path, &config).unwrap();
+    assert_eq!(health, DbHealth::Ok);
+    assert_eq!(journal_mode(&conn), "wal");
+    let sync: i64 = conn.query_row("PRAGMA synchronous", [], |row| row.get(0)).unwrap();
+    assert_eq!(sync, 1);
+    let auto_vacuum: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0)).unwrap();
+    assert_eq!(auto_vacuum, 2);
+
+    commit_rows(&conn, 0);
+    drop(conn);
+    // Reopening finds it as it was left
+    let (conn, health) = open_device_db(&path, &DeviceDbConfig::default()).unwrap();
+    assert_eq!(health, DbHealth::Ok);
+    assert_eq!(count(&conn), ROWS_PER_COMMIT);
+    assert_eq!("full".parse(), Ok(Synchronous::Full));
+}
+
+/// Run as a child process by `a_killed_writer_loses_no_committed_rows`:
+/// commits rows until killed, saying how many after each commit
+fn write_until_killed(path: &Path) {
+    let (conn, _) = open_device_db(path, &DeviceDbConfig::default()).unwrap();
+    let mut stdout = std::io::stdout();
+    for batch in 0..100_000 {
+        commit_rows(&conn, batch * ROWS_PER_COMMIT);
+        writeln!(stdout, "committed {}", (batch + 1) * ROWS_PER_COMMIT).unwrap();
+        stdout.flush().unwrap();
+    }
+}
+
+#[test]
+fn a_killed_writer_loses_no_committed_rows() {
+    if let Some(path) = std::env::var_os(WRITER) {
+        write_until_killed(Path::new(&path));
+        return;
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("imu.db3");
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "a_killed_writer_loses_no_committed_rows", "--nocapture", "--test-threads=1"])
+        .env(WRITER, &path)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut committed = 0;
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        if let Some(n) = line.unwrap().strip_prefix("committed ") {
+            committed = n.parse().unwrap();
+        }
+        if committed >= 500 {
+            // Mid-write, as when the ignition is cut
+            child.kill().unwrap();
+            break;
+        }
+    }
+    child.wait().unwrap();
+    assert!(committed >= 500);
+    // The WAL wasn't checkpointed by a clean close
+    assert!(path.with_file_name("imu.db3-wal").exists());
+
+    let (conn, health) = open_device_db(&path, &DeviceDbConfig::default()).unwrap();
+    assert_eq!(health, DbHealth::Ok);
+    assert!(count(&conn) >= committed, "{} rows, {} committed", count(&conn), committed);
+    assert_whole(&conn);
+}
+
+#[test]
+fn a_torn_wal_write_loses_only_its_own_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("imu.db3");
+    let config = DeviceDbConfig { autocheckpoint: 0, ..Default::default() };
+    let (conn, _) = open_device_db(&path, &config).unwrap();
+    for batch in 0..5 {
+        commit_rows(&conn, batch * ROWS_PER_COMMIT);
+    }
+
+    // What the card holds if the power goes halfway through writing the
+    // last frame of the WAL
+    let copy = dir.path().join("copy.db3");
+    fs::copy(&path, &copy).unwrap();
+    let wal = fs::read(path.with_file_name("imu.db3-wal")).unwrap();
+    fs::write(copy.with_file_name("copy.db3-wal"), &wal[..wal.len() - 100]).unwrap();
+    drop(conn);
+
+    let (conn, health) = open_device_db(&copy, &config).unwrap();
+    assert_eq!(health, DbHealth::Ok);
+    assert_eq!(count(&conn), 4 * ROWS_PER_COMMIT);
+    assert_whole(&conn);
+}
+
+#[test]
+fn a_corrupt_database_is_quarantined_and_salvaged() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("imu.db3");
+    let (conn, _) = open_device_db(&path, &DeviceDbConfig::default()).unwrap();
+    let crash = Crash { uuid: 7, pitime: 1_700_000_000_001, peak_g: 3.5, window_start: 1_700_000_000_000, window_end: 1_700_000_000_002 };
+    insert_crash(&conn, &crash).unwrap();
+    for i in 0..3 {
+        let row = ImuShort { uuid: 7, pitime: 1_700_000_000_000 + i, sequence: i as u32, ..Default::default() };
+        insert_imu_short_into(&conn, "crash_imu", &row).unwrap();
+    }
+    for batch in 0..200 {
+        commit_rows(&conn, batch * ROWS_PER_COMMIT);
+    }
+    let (page_size, pages): (u64, u64) = conn
+        .query_row("SELECT page_size, page_count FROM pragma_page_size, pragma_page_count", [], |row| {
+            Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64))
+        })
+        .unwrap();
+    drop(conn);
+
+    // Scribble over the last page, which holds the newest rows
+    let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start((pages - 1) * page_size)).unwrap();
+    file.write_all(&vec![0xA5; page_size as usize]).unwrap();
+    drop(file);
+
+    let (conn, health) = open_device_db(&path, &DeviceDbConfig::default()).unwrap();
+    let (quarantined, imu_rows, crashes, crash_lines) = match health {
+        DbHealth::Quarantined { quarantined, imu_rows, crashes, crash_lines, .. } => {
+            (quarantined, imu_rows, crashes, crash_lines)
+        }
+        DbHealth::Ok => panic!("corruption not found"),
+    };
+    assert!(quarantined.exists());
+    assert!(imu_rows > 0 && imu_rows < 2000, "{} salvaged", imu_rows);
+    // The crash, on an early page, comes through with its lines
+    assert_eq!((crashes, crash_lines), (1, 3));
+    assert_eq!(read_crashes(&conn).unwrap(), vec![crash]);
+    // The new database has what could be saved, and takes new rows
+    assert_eq!(assert_contiguous(&conn), imu_rows as u64);
+    assert_eq!(journal_mode(&conn), "wal");
+    commit_rows(&conn, imu_rows as u64);
+}
+
+#[test]
+fn salvage_carries_on_past_damaged_rows() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("imu.db3");
+    let (conn, _) = open_device_db(&path, &DeviceDbConfig::default()).unwrap();
+    for batch in 0..200 {
+        commit_rows(&conn, batch * ROWS_PER_COMMIT);
+    }
+    // The page holding a row from the middle of the table
+    let page: i64 = conn
+        .query_row("SELECT pageno FROM dbstat WHERE name = 'imu' AND pagetype = 'leaf' ORDER BY pageno LIMIT 1 OFFSET 10", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    let page_size: i64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0)).unwrap();
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)").unwrap();
+    drop(conn);
+
+    let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(((page - 1) * page_size) as u64)).unwrap();
+    file.write_all(&vec![0xA5; page_size as usize]).unwrap();
+    drop(file);
+
+    let (conn, health) = open_device_db(&path, &DeviceDbConfig::default()).unwrap();
+    let (imu_rows, failures) = match health {
+        DbHealth::Quarantined { imu_rows, failures, .. } => (imu_rows, failures),
+        DbHealth::Ok => panic!("corruption not found"),
+    };
+    assert!(imu_rows > 1000 && imu_rows < 2000, "{} salvaged", imu_rows);
+    assert_eq!(count(&conn), imu_rows as u64);
+    // The rows after the damage are there too
+    let newest: i64 = conn.query_row("SELECT max(sequence) FROM imu", [], |row| row.get(0)).unwrap();
+    assert_eq!(newest, 1999);
+    assert_eq!(failures.len(), 1, "{:?}", failures);
+    assert!(failures[0].starts_with("imu: lines "), "{:?}", failures);
+}
+
+#[test]
+fn a_file_that_isnt_a_database_is_replaced() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("imu.db3");
+    fs::write(&path, vec![0x5A; 8192]).unwrap();
+
+    let (conn, health) = open_device_db(&path, &DeviceDbConfig::default()).unwrap();
+    match health {
+        DbHealth::Quarantined { quarantined, imu_rows, gps_rows, .. } => {
+            assert_eq!(fs::read(quarantined).unwrap(), vec![0x5A; 8192]);
+            assert_eq!((imu_rows, gps_rows), (0, 0));
+        }
+        DbHealth::Ok => panic!("garbage accepted"),
+    }
+    assert_eq!(count(&conn), 0);
+}
@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use grpc_tests::fake_gps::generate_drive_data;
+use grpc_tests::fake_imu::generate_imu_data;
+use grpc_tests::gps::GpsVec;
+use grpc_tests::imu::{ImuData, ImuVec};
+use grpc_tests::timestamp::Timebase;
+use grpc_tests::service::{spawn_server, ServerHandle};
+use grpc_tests::store::ServerStore;
+use grpc_tests::upload::Uploader;
+
+const UUID: u64 = 0x1234567890AB;
+
+async fn start() -> (Arc<ServerStore>, ServerHandle, Uploader) {
+    let store = Arc::new(ServerStore::open_in_memory().unwrap());
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+    (store, server, uploader)
+}
+
+#[tokio::test]
+async fn upload_is_acknowledged() {
+    let (_store, server, mut uploader) = start().await;
+    let (imu, gps) = generate_drive_data(100);
+    let n_gps = gps.data.len();
+
+    let reply = uploader.send_imu(imu).await.unwrap();
+    assert_eq!(reply.message, "100 IMU lines received!");
+    assert_eq!(reply.stored, 100);
+    assert_eq!(reply.duplicates, 0);
+
+    let reply = uploader.send_gps(gps).await.unwrap();
+    assert_eq!(reply.stored as usize, n_gps);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn uploaded_lines_are_stored_unchanged() {
+    let (store, server, mut uploader) = start().await;
+    let (imu, gps) = generate_drive_data(50);
+
+    uploader.send_imu(imu.clone()).await.unwrap();
+    uploader.send_gps(gps.clone()).await.unwrap();
+
+    // Once the GPS lines give a clock model the IMU stamps move onto GPS
+    // time; everything else is as sent
+    let stored = store.read_imu(UUID).unwrap();
+    assert!(stored.iter().all(|d| d.stamp.is_some_and(|t| t.timebase == Timebase::Gps as i32)));
+    let unstamped = |d: &ImuData| ImuData { stamp: None, ..*d };
+    assert_eq!(stored.iter().map(unstamped).collect::<Vec<_>>(), imu.data.iter().map(unstamped).collect::<Vec<_>>());
+    assert_eq!(store.read_gps(UUID).unwrap(), gps.data);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn data_survives_a_server_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("server.db3");
+    let (imu, gps) = generate_drive_data(30);
+
+    let store = Arc::new(ServerStore::open(&path).unwrap());
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let mut uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+    uploader.send_imu(imu.clone()).await.unwrap();
+    uploader.send_gps(gps.clone()).await.unwrap();
+    server.stop().await.unwrap();
+    drop(store);
+
+    let store = ServerStore::open(&path).unwrap();
+    assert_eq!(store.read_imu(UUID).unwrap(), imu.data);
+    assert_eq!(store.read_gps(UUID).unwrap(), gps.data);
+}
+
+#[tokio::test]
+async fn resent_batches_are_not_stored_twice() {
+    let (store, server, mut uploader) = start().await;
+    let (imu, gps) = generate_drive_data(40);
+    let n_gps = gps.data.len() as u32;
+
+    uploader.send_imu(imu.clone()).await.unwrap();
+    let reply = uploader.send_imu(imu.clone()).await.unwrap();
+    assert_eq!(reply.stored, 0);
+    assert_eq!(reply.duplicates, 40);
+
+    uploader.send_gps(gps.clone()).await.unwrap();
+    let reply = uploader.send_gps(gps).await.unwrap();
+    assert_eq!(reply.stored, 0);
+    assert_eq!(reply.duplicates, n_gps);
+
+    // A batch overlapping the last one only stores the new lines
+    let (more, _) = generate_drive_data(60);
+    let mut overlap = ImuVec { data: imu.data[20..].to_vec(), ..Default::default() };
+    overlap.data.extend(more.data.into_iter().skip(40).map(|mut d| {
+        d.timestamp += 1_000_000;
+        d
+    }));
+    let reply = uploader.send_imu(overlap).await.unwrap();
+    assert_eq!(reply.stored, 20);
+    assert_eq!(reply.duplicates, 20);
+    assert_eq!(store.read_imu(UUID).unwrap().len(), 60);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn devices_are_kept_apart() {
+    let (store, server, mut first) = start().await;
+    let mut second = Uploader::connect(&server.url(), UUID + 1).await.unwrap();
+
+    // Identical data from two trucks isn't a duplicate
+    let imu = generate_imu_data(10);
+    assert_eq!(first.send_imu(imu.clone()).await.unwrap().stored, 10);
+    assert_eq!(second.send_imu(imu).await.unwrap().stored, 10);
+    assert_eq!(store.read_imu(UUID + 1).unwrap().len(), 10);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn empty_batches_are_rejected() {
+    let (_store, server, mut uploader) = start().await;
+
+    let status = uploader.send_imu(ImuVec::default()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    let status = uploader.send_gps(GpsVec::default()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn malformed_lines_reject_the_whole_batch() {
+    let (store, server, mut uploader) = start().await;
+
+    let mut imu = generate_imu_data(5);
+    imu.data[3].inertial = None;
+    let status = uploader.send_imu(imu).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert!(store.read_imu(UUID).unwrap().is_empty());
+
+    let (_, mut gps) = generate_drive_data(20);
+    gps.data[1].uuid = 0;
+    let status = uploader.send_gps(gps).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert!(store.read_gps(UUID).unwrap().is_empty());
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn imu_without_a_device_is_rejected() {
+    let (_store, server, _) = start().await;
+    let mut anonymous = Uploader::connect(&server.url(), 0).await.unwrap();
+
+    let status = anonymous.send_imu(generate_imu_data(5)).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn requests_fail_once_the_server_is_gone() {
+    let (_store, server, mut uploader) = start().await;
+    let url = server.url();
+    server.stop().await.unwrap();
+
+    assert!(uploader.send_imu(generate_imu_data(5)).await.is_err());
+    assert!(Uploader::connect(&url, UUID).await.is_err());
+}
+
+#[tokio::test]
+async fn altitude_is_stored_alongside_pressure() {
+    let (store, server, mut uploader) = start().await;
+    let (imu, gps) = generate_drive_data(600);
+    let (first, second) = imu.data.split_at(300);
+
+    uploader.send_imu(ImuVec { data: first.to_vec(), ..Default::default() }).await.unwrap();
+    uploader.send_gps(gps.clone()).await.unwrap();
+    uploader.send_imu(ImuVec { data: second.to_vec(), ..Default::default() }).await.unwrap();
+
+    let rows = store.read_imu_rows(UUID).unwrap();
+    assert_eq!(rows.len(), 600);
+    for (row, sent) in rows.iter().zip(&imu.data) {
+        assert_eq!(row.pressure, sent.pressure);
+        assert!(row.altitude.is_some());
+    }
+
+    // Once the GPS has calibrated the barometer, altitude agrees with it
+    let last_fix = gps.data.last().unwrap();
+    let last_row = rows.last().unwrap();
+    assert!((last_row.altitude.unwrap() - last_fix.alt).abs() < 15.0);
+
+    server.stop().await.unwrap();
+}
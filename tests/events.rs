@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use grpc_tests::events::{detect_events, linear_accel, EventConfig, EventDetector, EventKind, EventPosition};
+use grpc_tests::gps::GpsData;
+use grpc_tests::imu::{ImuData, Inertial, Orientation, Vector3D};
+use grpc_tests::imu_sim::{ImuSimConfig, ImuSimulator, Manoeuvre, MotionProfile};
+
+const T0: u64 = 1_700_000_000_000;
+
+/// A sample at `t` ms after T0 reading `accel`, g, tilted by `pitch` if
+/// the orientation is known
+fn sample(t: u64, accel: (f32, f32, f32), pitch: Option<f32>) -> ImuData {
+    ImuData {
+        sequence: (t / 100) as u32,
+        timestamp: T0 + t,
+        inertial: Some(Inertial {
+            pose: pitch.map(|pitch| Orientation { pitch, ..Default::default() }),
+            accel: Some(Vector3D { x: accel.0, y: accel.1, z: accel.2 }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// 10 Hz of a level truck at a steady speed, with `x` g along it from
+/// `from` to `to` ms
+fn pulse(n: u64, x: f32, from: u64, to: u64) -> Vec<ImuData> {
+    (0..n).map(|i| i * 100).map(|t| sample(t, (if (from..to).contains(&t) { x } else { 0.0 }, 0.0, 1.0), Some(0.0))).collect()
+}
+
+fn simulate(profile: MotionProfile, seconds: u64) -> Vec<ImuData> {
+    ImuSimulator::new(ImuSimConfig::default(), profile).take(seconds as usize * 10).collect()
+}
+
+#[test]
+fn harsh_manoeuvres_are_found_in_a_simulated_drive() {
+    let profile = MotionProfile::new()
+        .then(Manoeuvre::Idle, 5.0)
+        .then(Manoeuvre::Accelerate { rate: 4.0 }, 5.0)
+        .then(Manoeuvre::Cruise, 10.0)
+        // 0.53 g at 20 m/s
+        .then(Manoeuvre::Corner { yaw_rate: 15.0 }, 5.0)
+        .then(Manoeuvre::Cruise, 10.0)
+        .then(Manoeuvre::Brake { rate: 5.5 }, 3.0)
+        .then(Manoeuvre::Cruise, 10.0);
+    let events = detect_events(&simulate(profile, 48), &[], &EventConfig::default());
+
+    let kinds: Vec<EventKind> = events.iter().map(|e| e.kind).collect();
+    assert_eq!(kinds, [EventKind::HarshAcceleration, EventKind::HarshCornering, EventKind::HarshBraking]);
+    let start = ImuSimConfig::default().start_timestamp;
+    for (event, (from, g)) in events.iter().zip([(5_000, 0.41), (20_000, 0.53), (35_000, 0.56)]) {
+        assert!(event.start.abs_diff(start + from) <= 200, "{:?}", event);
+        assert!((event.peak_g - g).abs() < 0.06, "{:?}", event);
+        assert!(event.position.is_none());
+    }
+    assert!((events[2].end - events[2].start).abs_diff(3_000) <= 200);
+
+    // Everyday driving is none of these
+    assert!(detect_events(&simulate(MotionProfile::urban_loop(), 240), &[], &EventConfig::default()).is_empty());
+}
+
+#[test]
+fn gravity_is_taken_out_using_the_orientation() {
+    // Parked nose down on a ramp
+    let (s, c) = 30f32.to_radians().sin_cos();
+    let tilted: Vec<ImuData> = (0..50).map(|i| sample(i * 100, (s, 0.0, c), Some(-30.0))).collect();
+    let linear = linear_accel(&tilted[0]).unwrap();
+    assert!(linear.x.abs() < 1e-6 && linear.z.abs() < 1e-6, "{:?}", linear);
+    assert!(detect_events(&tilted, &[], &EventConfig::default()).is_empty());
+
+    // Taken to be level, the same samples look like hard acceleration
+    let unknown: Vec<ImuData> = (0..50).map(|i| sample(i * 100, (s, 0.0, c), None)).collect();
+    let events = detect_events(&unknown, &[], &EventConfig::default());
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].kind, EventKind::HarshAcceleration);
+}
+
+#[test]
+fn short_spikes_are_debounced_and_dips_bridged() {
+    let config = EventConfig::default();
+    // 200 ms of braking is a bump in the road
+    assert!(detect_events(&pulse(50, -0.6, 1000, 1200), &[], &config).is_empty());
+
+    // A brake, a let-off for half a second and more brake is one event
+    let mut imu = pulse(80, -0.6, 1000, 2000);
+    for d in &mut imu[25..40] {
+        d.inertial.as_mut().unwrap().accel.as_mut().unwrap().x = -0.7;
+    }
+    let events = detect_events(&imu, &[], &config);
+    assert_eq!(events.len(), 1);
+    assert_eq!((events[0].start, events[0].end), (T0 + 1000, T0 + 3900));
+    assert_eq!((events[0].peak_g, events[0].peak_time), (0.7, T0 + 2500));
+
+    // Released for longer, it's two
+    let mut imu = pulse(80, -0.6, 1000, 2000);
+    imu.extend(pulse(80, -0.6, 0, 1000).into_iter().map(|mut d| {
+        d.timestamp += 5000;
+        d
+    }));
+    assert_eq!(detect_events(&imu, &[], &config).len(), 2);
+}
+
+#[test]
+fn an_impact_counts_at_once_and_is_placed_by_gps() {
+    let mut imu = pulse(100, 0.0, 0, 0);
+    imu[42] = sample(4200, (-3.5, 1.0, 1.0), Some(0.0));
+    imu[42].inertial.as_mut().unwrap().gyro = Some(Vector3D { x: 0.0, y: 0.0, z: -80.0 });
+    let fix = |t: u64| GpsData { pitime: T0 + t, gps_time: T0 + t - 5, lat: 50.2, lon: -5.3, speed: 13.0, ..Default::default() };
+
+    let events = detect_events(&imu, &[fix(3000), fix(4000), fix(5000)], &EventConfig::default());
+    let impact = events.iter().find(|e| e.kind == EventKind::Impact).unwrap();
+    assert_eq!((impact.start, impact.end, impact.peak_time), (T0 + 4200, T0 + 4200, T0 + 4200));
+    assert!((impact.peak_g - 3.64).abs() < 0.01, "{}", impact.peak_g);
+    assert_eq!(impact.peak_dps, 80.0);
+    let position = EventPosition { gps_time: T0 + 3995, lat: 50.2, lon: -5.3, speed: 13.0 };
+    assert_eq!(impact.position, Some(position));
+    // One sample is too short to be braking or cornering too
+    assert_eq!(events.len(), 1);
+    // Fixes out of pitime order are found all the same
+    let events = detect_events(&imu, &[fix(5000), fix(3000), fix(4000)], &EventConfig::default());
+    assert_eq!(events[0].position, Some(position));
+
+    // A fix too far away gives no position
+    let config = EventConfig { max_fix_age: Duration::from_millis(500), ..Default::default() };
+    assert_eq!(detect_events(&imu, &[fix(3000)], &config)[0].position, None);
+}
+
+#[test]
+fn the_detector_hands_events_over_as_they_end() {
+    let mut detector = EventDetector::new(EventConfig::default());
+    let imu = pulse(100, 0.5, 1000, 3000);
+    let ended: Vec<usize> = imu.iter().enumerate().filter(|(_, d)| !detector.push(d).is_empty()).map(|(i, _)| i).collect();
+    // Once released, a second after the last sample past the threshold
+    assert_eq!(ended, [40]);
+    assert!(detector.finish().is_empty());
+
+    let mut detector = EventDetector::new(EventConfig::default());
+    for d in &imu[..25] {
+        assert!(detector.push(d).is_empty());
+    }
+    let open = detector.finish();
+    assert_eq!(open.len(), 1);
+    assert_eq!((open[0].kind, open[0].end), (EventKind::HarshAcceleration, T0 + 2400));
+}
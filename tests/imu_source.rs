@@ -0,0 +1,176 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use prost::Message;
+use rusqlite::Connection;
+
+use grpc_tests::data_conv::{get_earliest_n, ImuShort};
+use grpc_tests::data_defs::{ImuData, ImuError, Timestamp};
+use grpc_tests::fake_imu::generate_imu_data;
+use grpc_tests::imu;
+use grpc_tests::imu_source::{run_sampler, sample_period, FakeImu, ImuSource, ImuType, ReplayImu, SamplerConfig};
+use grpc_tests::replay::{make_batches, read_recording, replay, ReplaySpeed};
+use grpc_tests::schema;
+use grpc_tests::service::spawn_server;
+use grpc_tests::store::ServerStore;
+use grpc_tests::upload::Uploader;
+
+const UUID: u64 = 0x1234567890AB;
+
+fn local_db() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    schema::create_imu_table(&conn).unwrap();
+    conn
+}
+
+fn config(rate_hz: f32, max_samples: Option<u64>) -> SamplerConfig {
+    SamplerConfig { uuid: UUID, rate_hz, max_samples, ..Default::default() }
+}
+
+/// An IMU that isn't ready every other reading
+struct Flaky {
+    inner: FakeImu,
+    calls: u32,
+}
+
+impl ImuSource for Flaky {
+    fn imu_type(&self) -> ImuType {
+        ImuType::FakeType
+    }
+
+    fn sample(&mut self, timestamp: Timestamp) -> Option<ImuData> {
+        self.calls += 1;
+        let d = self.inner.sample(timestamp)?;
+        if self.calls.is_multiple_of(2) {
+            Some(ImuData { inertial: Err(ImuError::NotReady), ..d })
+        } else {
+            Some(d)
+        }
+    }
+}
+
+#[test]
+fn fake_imu_fills_the_database() {
+    let conn = local_db();
+    let mut source = FakeImu::default();
+    let stats = run_sampler(&mut source, &conn, &config(200.0, Some(20)), &AtomicBool::new(false)).unwrap();
+    assert_eq!(stats.samples, 20);
+    assert_eq!(stats.stored, 20);
+
+    let rows = get_earliest_n(&conn, 100).unwrap();
+    assert_eq!(rows.len(), 20);
+    for (i, row) in rows.iter().enumerate() {
+        assert_eq!(row.uuid, UUID);
+        assert_eq!(row.sequence, i as u32);
+        assert!(!row.uploaded);
+    }
+    assert!(rows.windows(2).all(|w| w[0].pitime <= w[1].pitime));
+}
+
+#[test]
+fn samples_are_taken_at_the_requested_rate() {
+    let conn = local_db();
+    let start = Instant::now();
+    let stats = run_sampler(&mut FakeImu::default(), &conn, &config(50.0, Some(11)), &AtomicBool::new(false)).unwrap();
+    let elapsed = start.elapsed();
+
+    // 11 samples at 50 Hz span 200 ms, and the loop waits out the last period
+    assert!(elapsed >= Duration::from_millis(215), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+    let rows = get_earliest_n(&conn, 100).unwrap();
+    let span = rows.last().unwrap().pitime - rows[0].pitime;
+    assert!((195..=260).contains(&span), "{} ms", span);
+    assert_eq!(stats.overruns, 0);
+}
+
+#[test]
+fn replay_plays_back_recorded_readings() {
+    let recorded = generate_imu_data(30);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("imu.pb");
+    std::fs::write(&path, recorded.encode_to_vec()).unwrap();
+
+    let conn = local_db();
+    let mut source = ReplayImu::open(&path).unwrap();
+    assert_eq!(source.imu_type(), ImuType::ReplayType);
+    // No limit: the file running out ends the run
+    let stats = run_sampler(&mut source, &conn, &config(1000.0, None), &AtomicBool::new(false)).unwrap();
+    assert_eq!(stats.samples, 30);
+
+    let rows = get_earliest_n(&conn, 100).unwrap();
+    for (row, sent) in rows.into_iter().zip(recorded.data) {
+        let expected = ImuShort::try_from(sent).unwrap();
+        // Only the time and place of the reading are new
+        assert_eq!(ImuShort { line: 0, uuid: 0, pitime: 0, stamp: None, ..row }, ImuShort { pitime: 0, ..expected });
+    }
+}
+
+#[test]
+fn looped_replay_keeps_going() {
+    let recorded = generate_imu_data(5);
+    let conn = local_db();
+    let mut source = ReplayImu::new(recorded.data.clone()).looped();
+    let stats = run_sampler(&mut source, &conn, &config(1000.0, Some(12)), &AtomicBool::new(false)).unwrap();
+    assert_eq!(stats.stored, 12);
+
+    let rows = get_earliest_n(&conn, 100).unwrap();
+    assert_eq!(rows[10].accel_x, rows[0].accel_x);
+    assert_eq!(rows[11].accel_x, rows[1].accel_x);
+}
+
+#[test]
+fn readings_that_are_not_ready_are_skipped() {
+    let conn = local_db();
+    let mut source = Flaky { inner: FakeImu::default(), calls: 0 };
+    let stats = run_sampler(&mut source, &conn, &config(1000.0, Some(10)), &AtomicBool::new(false)).unwrap();
+    assert_eq!(stats.samples, 10);
+    assert_eq!(stats.stored, 5);
+    assert_eq!(stats.incomplete, 5);
+    assert_eq!(get_earliest_n(&conn, 100).unwrap().len(), 5);
+}
+
+#[test]
+fn a_set_stop_flag_ends_the_run() {
+    let conn = local_db();
+    let stats = run_sampler(&mut FakeImu::default(), &conn, &config(1000.0, None), &AtomicBool::new(true)).unwrap();
+    assert_eq!(stats.samples, 0);
+}
+
+#[test]
+fn rates_without_a_period_are_refused() {
+    let conn = local_db();
+    for rate in [0.0, -10.0, f32::NAN, f32::INFINITY, 1e30] {
+        let run = run_sampler(&mut FakeImu::default(), &conn, &config(rate, Some(5)), &AtomicBool::new(false));
+        assert!(run.is_err(), "{} Hz", rate);
+        assert!(sample_period(rate).is_err(), "{} Hz", rate);
+    }
+    assert!(get_earliest_n(&conn, 10).unwrap().is_empty());
+    assert_eq!(sample_period(1000.0).unwrap(), Duration::from_millis(1));
+}
+
+#[tokio::test]
+async fn sampled_data_reaches_the_server() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("device.db3");
+    {
+        let conn = Connection::open(&path).unwrap();
+        schema::create_imu_table(&conn).unwrap();
+        run_sampler(&mut FakeImu::default(), &conn, &config(1000.0, Some(50)), &AtomicBool::new(false)).unwrap();
+    }
+
+    let store = Arc::new(ServerStore::open_in_memory().unwrap());
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let mut uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+
+    let recording = read_recording(&Connection::open(&path).unwrap(), None).unwrap();
+    assert_eq!(recording.uuid, UUID);
+    let sent = recording.imu.clone();
+    let batches = make_batches(recording, Duration::from_millis(10));
+    replay(&mut uploader, batches, ReplaySpeed::Max).await.unwrap();
+
+    let stored: Vec<imu::ImuData> = store.read_imu(UUID).unwrap();
+    assert_eq!(stored, sent);
+
+    server.stop().await.unwrap();
+}
@@ -0,0 +1,305 @@
+use rusqlite::{Connection, OpenFlags};
+
+use grpc_tests::data_conv::{get_earliest_n, insert_imu_short, make_imu, ImuShort};
+use grpc_tests::gps::GpsData;
+use grpc_tests::replay::read_recording;
+use grpc_tests::imu::{ImuData, Inertial, Orientation, Vector3D};
+use grpc_tests::schema::{self, GPS_COLUMNS, IMU_COLUMNS};
+use grpc_tests::timestamp::{Timebase, Timestamp};
+use grpc_tests::{data_defs, store::ServerStore};
+
+/// Every field holds a different value, so a field written to the wrong
+/// column can't come back looking right
+fn distinct_row() -> ImuShort {
+    ImuShort {
+        line: 0,
+        uuid: 0x1234567890AB,
+        pitime: 1_781_003_456_789,
+        gps_time: 1_781_003_456_001,
+        sequence: 42,
+        accel_x: 1.0,
+        accel_y: 2.0,
+        accel_z: 3.0,
+        gyro_x: 4.0,
+        gyro_y: 5.0,
+        gyro_z: 6.0,
+        pose_roll: 7.0,
+        pose_pitch: 8.0,
+        pose_yaw: 9.0,
+        pose_heading_accuracy: 10.0,
+        mag_x: 11.0,
+        mag_y: 12.0,
+        mag_z: 13.0,
+        pressure: 101325.0,
+        altitude: Some(14.0),
+        temperature: 15.0,
+        temp_cpu: 16.0,
+        uploaded: false,
+        confirmed: true,
+        clock_offset: Some(17.0),
+        clock_error: Some(18.0),
+        stamp: Some(Timestamp { seconds: 19, nanos: 20, timebase: Timebase::Monotonic.into() }),
+    }
+}
+
+/// What each column of the imu table should hold for distinct_row()
+fn expected_imu_column(name: &str) -> f64 {
+    match name {
+        "uuid" => 0x1234567890AB_u64 as f64,
+        "pitime" => 1_781_003_456_789.0,
+        "gps_time" => 1_781_003_456_001.0,
+        "sequence" => 42.0,
+        "x_accel" => 1.0,
+        "y_accel" => 2.0,
+        "z_accel" => 3.0,
+        "x_gyro" => 4.0,
+        "y_gyro" => 5.0,
+        "z_gyro" => 6.0,
+        "roll_pose" => 7.0,
+        "pitch_pose" => 8.0,
+        "yaw_pose" => 9.0,
+        "heading_accuracy" => 10.0,
+        "x_mag" => 11.0,
+        "y_mag" => 12.0,
+        "z_mag" => 13.0,
+        "pressure" => 101325.0,
+        "altitude" => 14.0,
+        "temperature" => 15.0,
+        "temp_cpu" => 16.0,
+        "uploaded" => 0.0,
+        "confirmed" => 1.0,
+        "clock_offset" => 17.0,
+        "clock_error" => 18.0,
+        "stamp_s" => 19.0,
+        "stamp_ns" => 20.0,
+        "stamp_base" => 2.0,
+        _ => panic!("no expected value for imu column {}", name),
+    }
+}
+
+fn imu_table() -> Connection {
+    let mut conn = Connection::open_in_memory().unwrap();
+    make_imu(&mut conn).unwrap();
+    conn
+}
+
+#[test]
+fn every_field_lands_in_its_named_column() {
+    let conn = imu_table();
+    insert_imu_short(&conn, &distinct_row()).unwrap();
+
+    for (name, _) in IMU_COLUMNS {
+        let stored: f64 = conn
+            .query_row(&format!("SELECT {} FROM imu", name), [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, expected_imu_column(name), "column {}", name);
+    }
+}
+
+#[test]
+fn every_field_reads_back_into_the_same_field() {
+    let conn = imu_table();
+    insert_imu_short(&conn, &distinct_row()).unwrap();
+
+    let rows = get_earliest_n(&conn, 1).unwrap();
+    assert_eq!(rows, vec![ImuShort { line: 1, ..distinct_row() }]);
+}
+
+#[test]
+fn every_message_field_reads_back_into_the_same_field() {
+    let sent = ImuData {
+        sequence: 42,
+        timestamp: 1_781_003_456_789,
+        inertial: Some(Inertial {
+            pose: Some(Orientation { roll: 7.0, pitch: 8.0, yaw: 9.0, heading_accuracy: 10.0 }),
+            gyro: Some(Vector3D { x: 4.0, y: 5.0, z: 6.0 }),
+            accel: Some(Vector3D { x: 1.0, y: 2.0, z: 3.0 }),
+            mag: Some(Vector3D { x: 11.0, y: 12.0, z: 13.0 }),
+        }),
+        pressure: 101325.0,
+        temperature: 15.0,
+        temp_cpu: 16.0,
+        stamp: Some(Timestamp { seconds: 1_781_003_456, nanos: 789_012_345, timebase: Timebase::System.into() }),
+    };
+    let conn = imu_table();
+    insert_imu_short(&conn, &ImuShort::try_from(sent).unwrap()).unwrap();
+
+    let row = get_earliest_n(&conn, 1).unwrap().remove(0);
+    assert_eq!(ImuData::from(row.clone()), sent);
+
+    let domain = data_defs::ImuData::from(row);
+    assert_eq!(domain.pressure.unwrap().as_pascals(), 101325.0);
+    assert_eq!(domain.temperature.unwrap().as_celsius() as f32, 15.0);
+    assert_eq!(domain.temp_cpu.unwrap().as_celsius() as f32, 16.0);
+}
+
+#[test]
+fn rows_are_read_by_name_not_position() {
+    // Same columns as the schema, but in the opposite order
+    let reversed: Vec<(&str, &str)> = IMU_COLUMNS.iter().rev().copied().collect();
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute(&schema::create_table_sql("imu", &reversed), ()).unwrap();
+
+    insert_imu_short(&conn, &distinct_row()).unwrap();
+    let rows = get_earliest_n(&conn, 1).unwrap();
+    assert_eq!(rows[0], ImuShort { line: 1, ..distinct_row() });
+}
+
+#[test]
+fn old_tables_move_pressure_out_of_altitude() {
+    let conn = Connection::open_in_memory().unwrap();
+    let old: Vec<(&str, &str)> = IMU_COLUMNS.iter().copied().filter(|(name, _)| *name != "pressure").collect();
+    conn.execute(&schema::create_table_sql("imu", &old), ()).unwrap();
+    let names: Vec<&str> = old.iter().map(|(name, _)| *name).collect();
+    let values: Vec<String> = names
+        .iter()
+        .map(|name| match *name {
+            "altitude" => "101325.0".to_string(),
+            name => expected_imu_column(name).to_string(),
+        })
+        .collect();
+    conn.execute(&format!("INSERT INTO imu ({}) VALUES ({})", names.join(", "), values.join(", ")), ())
+        .unwrap();
+
+    schema::create_imu_table(&conn).unwrap();
+
+    let row = get_earliest_n(&conn, 1).unwrap().remove(0);
+    assert_eq!(row.pressure, 101325.0);
+    assert_eq!(row.altitude, None);
+
+    // Running it again leaves the upgraded table alone
+    schema::create_imu_table(&conn).unwrap();
+    assert_eq!(get_earliest_n(&conn, 1).unwrap()[0], row);
+}
+
+#[test]
+fn old_recordings_replay_without_being_upgraded() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("recording.db3");
+    {
+        let conn = Connection::open(&path).unwrap();
+        let old: Vec<(&str, &str)> = IMU_COLUMNS
+            .iter()
+            .copied()
+            .filter(|(name, _)| !["pressure", "clock_offset", "clock_error"].contains(name) && !name.starts_with("stamp"))
+            .collect();
+        conn.execute(&schema::create_table_sql("imu", &old), ()).unwrap();
+        let names: Vec<&str> = old.iter().map(|(name, _)| *name).collect();
+        let values: Vec<String> = names
+            .iter()
+            .map(|name| match *name {
+                "altitude" => "101325.0".to_string(),
+                name => expected_imu_column(name).to_string(),
+            })
+            .collect();
+        conn.execute(&format!("INSERT INTO imu ({}) VALUES ({})", names.join(", "), values.join(", ")), ())
+            .unwrap();
+    }
+    let columns = || -> Vec<String> {
+        let conn = Connection::open(&path).unwrap();
+        let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('imu')").unwrap();
+        let names = stmt.query_map([], |row| row.get(0)).unwrap();
+        names.collect::<Result<_, _>>().unwrap()
+    };
+    let before = columns();
+
+    let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+    let recording = read_recording(&conn, None).unwrap();
+    assert_eq!(recording.uuid, expected_imu_column("uuid") as u64);
+    assert_eq!(recording.imu.len(), 1);
+    assert!(recording.gps.is_empty());
+    assert_eq!((recording.imu[0].pressure, recording.imu[0].stamp), (101325.0, None));
+    drop(conn);
+
+    assert_eq!(columns(), before);
+}
+
+#[test]
+fn every_gps_field_lands_in_its_named_column() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("server.db3");
+    let sent = GpsData {
+        uuid: 0x1234567890AB,
+        pitime: 1_781_003_456_789,
+        gps_time: 1_781_003_456_001,
+        sequence: 42,
+        lat: 50.21,
+        lon: -5.3,
+        alt: 3.0,
+        speed: 4.0,
+        track: 5.0,
+        status_nsats_vuc: 6,
+        hdop: 7.0,
+        h_acc: 8.0,
+        v_acc: 9.0,
+        speed_acc: 10.0,
+        time_valid: 11,
+        pi_stamp: Some(Timestamp { seconds: 12, nanos: 13, timebase: Timebase::Monotonic.into() }),
+        gps_stamp: Some(Timestamp { seconds: 14, nanos: 15, timebase: Timebase::Gps.into() }),
+    };
+    let store = ServerStore::open(&path).unwrap();
+    store.insert_gps(&[sent]).unwrap();
+    assert_eq!(store.read_gps(sent.uuid).unwrap(), vec![sent]);
+    drop(store);
+
+    let expected = |name: &str| -> f64 {
+        match name {
+            "uuid" => sent.uuid as f64,
+            "pitime" => sent.pitime as f64,
+            "gps_time" => sent.gps_time as f64,
+            "sequence" => sent.sequence.into(),
+            "lat" => sent.lat.into(),
+            "lon" => sent.lon.into(),
+            "alt" => sent.alt.into(),
+            "speed" => sent.speed.into(),
+            "track" => sent.track.into(),
+            "status_nsats_vuc" => sent.status_nsats_vuc.into(),
+            "hdop" => sent.hdop.into(),
+            "h_acc" => sent.h_acc.into(),
+            "v_acc" => sent.v_acc.into(),
+            "speed_acc" => sent.speed_acc.into(),
+            "time_valid" => sent.time_valid.into(),
+            "pi_stamp_s" => 12.0,
+            "pi_stamp_ns" => 13.0,
+            "pi_stamp_base" => 2.0,
+            "gps_stamp_s" => 14.0,
+            "gps_stamp_ns" => 15.0,
+            "gps_stamp_base" => 1.0,
+            _ => panic!("no expected value for gps column {}", name),
+        }
+    };
+    let conn = Connection::open(&path).unwrap();
+    for (name, _) in GPS_COLUMNS {
+        let stored: f64 = conn
+            .query_row(&format!("SELECT {} FROM gps", name), [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, expected(name), "column {}", name);
+    }
+}
+
+#[test]
+fn old_gps_tables_get_the_accuracy_columns() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("server.db3");
+    {
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE gps (lineno INTEGER PRIMARY KEY NULL, uuid BIGINT NOT NULL,
+                pitime BIGINT NOT NULL, gps_time BIGINT NOT NULL, sequence INT NOT NULL,
+                lat FLOAT NOT NULL, lon FLOAT NOT NULL, alt FLOAT NOT NULL, speed FLOAT NOT NULL,
+                track FLOAT NOT NULL, status_nsats_vuc INT NOT NULL, hdop FLOAT NOT NULL);
+             INSERT INTO gps VALUES (NULL, 7, 1000, 999, 0, 50.0, -5.0, 100.0, 3.0, 90.0, 65796, 0.9);",
+        )
+        .unwrap();
+    }
+
+    let store = ServerStore::open(&path).unwrap();
+    let old = store.read_gps(7).unwrap();
+    assert_eq!(old.len(), 1);
+    assert_eq!((old[0].h_acc, old[0].v_acc, old[0].speed_acc, old[0].time_valid), (0.0, 0.0, 0.0, 0));
+    assert_eq!((old[0].pi_stamp, old[0].gps_stamp), (None, None));
+
+    let new = GpsData { uuid: 7, sequence: 1, h_acc: 1.5, time_valid: 7, ..old[0] };
+    assert_eq!(store.insert_gps(&[new]).unwrap(), 1);
+    assert_eq!(store.read_gps(7).unwrap(), vec![old[0], new]);
+}
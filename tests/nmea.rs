@@ -0,0 +1,197 @@
+use std::io::Cursor;
+use std::path::Path;
+
+use grpc_tests::fake_gps::decode_fields;
+use grpc_tests::gps::GpsData;
+use grpc_tests::gps_sim::{GpsSimConfig, GpsSimulator, Route};
+use grpc_tests::gps_source::{FakeGps, GpsSource, GpsType, NmeaGps};
+use grpc_tests::imu_sim::MotionProfile;
+use grpc_tests::nmea::{days_from_civil, parse, FixBuilder, NmeaError, Sentence};
+
+const UUID: u64 = 0x1234567890AB;
+
+const GGA: &str = "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47";
+const RMC: &str = "$GPRMC,123519,A,4807.038,N,01131.000,E,022.4,084.4,230394,003.1,W*6A";
+const GSA: &str = "$GPGSA,A,3,04,05,,09,12,,,24,,,,,2.5,1.3,2.1*39";
+const GSV: &str = "$GPGSV,2,1,08,01,40,083,46,02,17,308,41,12,07,344,39,14,22,228,45*75";
+const VTG: &str = "$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*48";
+
+fn close(a: f64, b: f64, tol: f64) -> bool {
+    (a - b).abs() < tol
+}
+
+fn recorded() -> Vec<GpsData> {
+    let mut source = NmeaGps::open(Path::new("tests/data/camborne.nmea"), UUID).unwrap();
+    std::iter::from_fn(|| source.next_fix()).collect()
+}
+
+#[test]
+fn gga_gives_position_quality_and_hdop() {
+    let g = match parse(GGA).unwrap() {
+        Sentence::Gga(g) => g,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(g.time, Some((12 * 3600 + 35 * 60 + 19) * 1000));
+    assert!(close(g.lat.unwrap(), 48.0 + 7.038 / 60.0, 1e-9));
+    assert!(close(g.lon.unwrap(), 11.0 + 31.0 / 60.0, 1e-9));
+    assert_eq!((g.quality, g.nsats), (1, 8));
+    assert_eq!(g.hdop, Some(0.9));
+    assert_eq!(g.alt, Some(545.4));
+}
+
+#[test]
+fn rmc_vtg_gsa_and_gsv_parse() {
+    match parse(RMC).unwrap() {
+        Sentence::Rmc(r) => {
+            assert!(r.valid);
+            assert_eq!(r.speed_knots, Some(22.4));
+            assert_eq!(r.track, Some(84.4));
+            assert_eq!(r.date, Some(days_from_civil(1994, 3, 23) as u32));
+        }
+        other => panic!("{:?}", other),
+    }
+    match parse(VTG).unwrap() {
+        Sentence::Vtg(v) => assert_eq!((v.track, v.speed_kph), (Some(54.7), Some(10.2))),
+        other => panic!("{:?}", other),
+    }
+    match parse(GSA).unwrap() {
+        Sentence::Gsa(a) => {
+            assert_eq!(a.fix_type, 3);
+            assert_eq!(a.prns, vec![4, 5, 9, 12, 24]);
+            assert_eq!((a.pdop, a.hdop, a.vdop), (Some(2.5), Some(1.3), Some(2.1)));
+        }
+        other => panic!("{:?}", other),
+    }
+    match parse(GSV).unwrap() {
+        Sentence::Gsv(v) => {
+            assert_eq!((v.sentences, v.number, v.in_view), (2, 1, 8));
+            assert_eq!(v.sats.len(), 4);
+            assert_eq!(v.sats[3].prn, 14);
+            assert_eq!(v.sats[3].snr, Some(45));
+        }
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn other_talkers_and_missing_checksums_are_accepted() {
+    let gn = "$GNGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,";
+    assert!(matches!(parse(gn), Ok(Sentence::Gga(_))));
+}
+
+#[test]
+fn bad_lines_are_rejected() {
+    let corrupted = GGA.replace("545.4", "545.5");
+    assert_eq!(parse(&corrupted), Err(NmeaError::BadChecksum { expected: 0x46, found: 0x47 }));
+    assert_eq!(parse("GPGGA,123519"), Err(NmeaError::NotNmea));
+    assert!(matches!(parse("$GPGLL,4916.45,N,12311.12,W,225444,A"), Err(NmeaError::Unsupported(_))));
+    assert!(matches!(
+        parse("$GPGGA,12x519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,"),
+        Err(NmeaError::BadField { field: "time", .. })
+    ));
+}
+
+#[test]
+fn a_new_time_completes_the_fix() {
+    let mut builder = FixBuilder::new();
+    for line in [RMC, VTG, GGA, GSA, GSV] {
+        assert_eq!(builder.push(parse(line).unwrap()), None);
+    }
+    let next = GGA.replace("123519", "123520").replace("*47", "");
+    let fix = builder.push(parse(&next).unwrap()).unwrap();
+
+    let day_ms = days_from_civil(1994, 3, 23) as u64 * 86_400_000;
+    assert_eq!(fix.gps_time, day_ms + (12 * 3600 + 35 * 60 + 19) * 1000);
+    assert!(fix.valid);
+    assert_eq!((fix.status, fix.nsats, fix.fix_type, fix.in_view), (1, 8, 3, 8));
+    // GGA's HDOP wins over GSA's; RMC's speed and track over VTG's
+    assert_eq!(fix.hdop, 0.9);
+    assert!(close(fix.speed as f64, 22.4 * 1852.0 / 3600.0, 1e-4));
+    assert_eq!(fix.track, 84.4);
+    assert_eq!(fix.alt, 545.4);
+}
+
+#[test]
+fn days_from_civil_matches_known_dates() {
+    assert_eq!(days_from_civil(1970, 1, 1), 0);
+    assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+    assert_eq!(days_from_civil(2023, 11, 14) * 86_400, 1_699_920_000);
+}
+
+#[test]
+fn a_recorded_log_becomes_fixes() {
+    let mut source = NmeaGps::open(Path::new("tests/data/camborne.nmea"), UUID).unwrap();
+    assert_eq!(source.gps_type(), GpsType::NmeaType);
+    let fixes: Vec<GpsData> = std::iter::from_fn(|| source.next_fix()).collect();
+    assert_eq!(fixes.len(), 10);
+
+    // The partial first line, the corrupted GSA and the GLL
+    let stats = source.stats();
+    assert_eq!(stats.errors, 2);
+    assert_eq!(stats.unsupported, 1);
+    assert_eq!(stats.fixes, 10);
+
+    for (i, fix) in fixes.iter().enumerate() {
+        assert_eq!(fix.uuid, UUID);
+        assert_eq!(fix.sequence, i as u32);
+        // Recorded logs are stamped with GPS time
+        assert_eq!(fix.pitime, fix.gps_time);
+        assert_eq!(fix.gps_time, 1_700_000_000_000 + i as u64 * 1000);
+    }
+
+    let first = fixes[0];
+    assert_eq!(decode_fields(first.status_nsats_vuc), (1, 9, true, false, false));
+    assert_eq!(first.hdop, 1.1);
+    assert_eq!(first.alt, 100.4);
+    assert!(close(first.lat as f64, 50.123456, 1e-5));
+    assert!(close(first.lon as f64, -4.998765, 1e-5));
+    assert!(close(first.speed as f64, 20.0 * 1852.0 / 3600.0, 1e-4));
+}
+
+#[test]
+fn fix_loss_repeats_the_last_position() {
+    let fixes = recorded();
+    for fix in &fixes[6..8] {
+        assert_eq!(decode_fields(fix.status_nsats_vuc), (0, 0, false, false, false));
+        assert_eq!(fix.hdop, 99.99);
+        assert_eq!(fix.speed, 0.0);
+        assert_eq!((fix.lat, fix.lon, fix.alt), (fixes[5].lat, fixes[5].lon, fixes[5].alt));
+    }
+    assert!(decode_fields(fixes[8].status_nsats_vuc).2);
+}
+
+#[test]
+fn nmea_can_be_read_from_any_reader() {
+    let text = format!("{}\n{}\n{}\n", RMC, GGA, GSA);
+    let mut source = NmeaGps::new(Cursor::new(text.into_bytes()), UUID);
+    let fix = source.next_fix().unwrap();
+    assert_eq!(fix.lat, (48.0 + 7.038 / 60.0) as f32);
+    // Live sources are stamped with the Pi clock
+    assert!(fix.pitime > fix.gps_time);
+    assert_eq!(source.next_fix(), None);
+}
+
+#[test]
+fn garbage_bytes_are_bad_lines() {
+    // No checksum to catch it, and the lossy decode puts a three byte
+    // character where the sentence type starts
+    let noise = "$GP\u{fffd}GG,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,";
+    assert_eq!(parse(noise), Err(NmeaError::NotNmea));
+    assert_eq!(parse("$\u{fffd}\u{fffd}"), Err(NmeaError::NotNmea));
+
+    let mut bytes = b"$GP\xffGG,123519,4807.038,N\n\xfe\xff\x00$GPR\xc3\n".to_vec();
+    bytes.extend(format!("{}\n{}\n{}\n", RMC, GGA, GSA).into_bytes());
+    let mut source = NmeaGps::new(Cursor::new(bytes), UUID);
+    assert_eq!(source.next_fix().unwrap().lat, (48.0 + 7.038 / 60.0) as f32);
+    assert_eq!(source.next_fix(), None);
+    assert_eq!((source.stats().errors, source.stats().fixes), (2, 1));
+}
+
+#[test]
+fn fake_gps_follows_the_simulator() {
+    let sim = || GpsSimulator::new(GpsSimConfig::default(), Route::camborne(), MotionProfile::default());
+    let mut source = FakeGps::new(sim());
+    assert_eq!(source.gps_type(), GpsType::FakeType);
+    let fixes: Vec<GpsData> = std::iter::from_fn(|| source.next_fix()).take(5).collect();
+    assert_eq!(fixes, sim().take(5).collect::<Vec<_>>());
+}
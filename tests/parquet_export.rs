@@ -0,0 +1,212 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use arrow_array::Array;
+use arrow_schema::{DataType, TimeUnit};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use rusqlite::Connection;
+
+use grpc_tests::data_conv::{insert_imu_short, ImuShort};
+use grpc_tests::fake_gps::encode_fields;
+use grpc_tests::gps::GpsData;
+use grpc_tests::nmea::days_from_civil;
+use grpc_tests::parquet_export::{export_parquet, partition_path, read_gps_parquet, read_imu_parquet, ExportConfig};
+use grpc_tests::schema;
+use grpc_tests::store::{gps_from_row, ServerStore};
+use grpc_tests::timestamp::{Timebase, Timestamp};
+
+const A: u64 = 0x1234567890AB;
+const B: u64 = 0x1234567890AC;
+const DAY: u64 = 24 * 3600 * 1000;
+
+/// A second before midnight going into the 1st of March 2024
+fn t0() -> u64 {
+    days_from_civil(2024, 3, 1) as u64 * DAY - 1000
+}
+
+fn imu(uuid: u64, i: u32) -> ImuShort {
+    let pitime = t0() + i as u64 * 10;
+    ImuShort {
+        uuid,
+        pitime,
+        gps_time: pitime - 2,
+        sequence: i,
+        accel_x: i as f32 * 0.5,
+        accel_z: 9.81,
+        gyro_y: -0.25,
+        pressure: 101_325.0 - i as f32,
+        // Some of what the server fills in, some not
+        altitude: (!i.is_multiple_of(3)).then_some(12.5 + i as f32),
+        clock_offset: i.is_multiple_of(2).then_some(2.0),
+        clock_error: i.is_multiple_of(2).then_some(0.125),
+        stamp: (!i.is_multiple_of(5)).then_some(Timestamp {
+            seconds: (pitime / 1000) as i64,
+            nanos: (pitime % 1000) as u32 * 1_000_000 + 123,
+            timebase: Timebase::Gps as i32,
+        }),
+        uploaded: true,
+        confirmed: !i.is_multiple_of(7),
+        ..Default::default()
+    }
+}
+
+fn fix(i: u32) -> GpsData {
+    let gps_time = t0() + i as u64 * 1000;
+    GpsData {
+        uuid: A,
+        pitime: gps_time + 3,
+        gps_time,
+        sequence: i,
+        lat: 50.2 + i as f32 * 1e-4,
+        lon: -5.3,
+        alt: 80.0,
+        speed: 13.4,
+        track: 270.0,
+        status_nsats_vuc: encode_fields(3, 9, i != 2, true, i.is_multiple_of(2)),
+        hdop: 0.9,
+        h_acc: if i == 1 { 0.0 } else { 2.5 },
+        time_valid: 7,
+        gps_stamp: Some(Timestamp { seconds: (gps_time / 1000) as i64, nanos: 5, timebase: Timebase::Gps as i32 }),
+        ..Default::default()
+    }
+}
+
+/// A server database holding two devices, one of them logging across
+/// midnight
+fn server_db(dir: &Path) -> Connection {
+    let path = dir.join("server.db3");
+    let store = ServerStore::open(&path).unwrap();
+    store.insert_imu(&(0..250).map(|i| imu(A, i)).collect::<Vec<_>>()).unwrap();
+    store.insert_imu(&(0..30).map(|i| imu(B, i)).collect::<Vec<_>>()).unwrap();
+    store.insert_gps(&(0..5).map(fix).collect::<Vec<_>>()).unwrap();
+    Connection::open(&path).unwrap()
+}
+
+fn stored_imu(conn: &Connection) -> Vec<ImuShort> {
+    let mut stmt = conn.prepare("SELECT * FROM imu ORDER BY uuid, pitime, sequence").unwrap();
+    let rows = stmt.query_map([], ImuShort::from_row).unwrap();
+    rows.map(|r| r.unwrap()).collect()
+}
+
+fn stored_gps(conn: &Connection) -> Vec<GpsData> {
+    let mut stmt = conn.prepare("SELECT * FROM gps ORDER BY uuid, gps_time, sequence").unwrap();
+    let rows = stmt.query_map([], gps_from_row).unwrap();
+    rows.map(|r| r.unwrap()).collect()
+}
+
+fn row_groups(path: &Path) -> Vec<i64> {
+    let reader = SerializedFileReader::new(File::open(path).unwrap()).unwrap();
+    reader.metadata().row_groups().iter().map(|g| g.num_rows()).collect()
+}
+
+#[test]
+fn files_are_partitioned_by_device_and_day() {
+    let dir = tempfile::tempdir().unwrap();
+    let conn = server_db(dir.path());
+    let out = dir.path().join("parquet");
+    let stats = export_parquet(&conn, &out, &ExportConfig { row_group_rows: 64, ..Default::default() }).unwrap();
+    assert_eq!((stats.imu_rows, stats.gps_rows), (280, 5));
+
+    let expected: Vec<PathBuf> = [
+        format!("imu/uuid={}/date=2024-02-29/part-0.parquet", A),
+        format!("imu/uuid={}/date=2024-03-01/part-0.parquet", A),
+        format!("imu/uuid={}/date=2024-02-29/part-0.parquet", B),
+        format!("gps/uuid={}/date=2024-02-29/part-0.parquet", A),
+        format!("gps/uuid={}/date=2024-03-01/part-0.parquet", A),
+    ]
+    .iter()
+    .map(|p| out.join(p))
+    .collect();
+    assert_eq!(stats.files, expected);
+    assert_eq!(partition_path(&out, "imu", A, t0() + 1000), expected[1]);
+
+    // 100 rows before midnight and 150 after, 64 to a row group
+    assert_eq!(row_groups(&expected[0]), vec![64, 36]);
+    assert_eq!(row_groups(&expected[1]), vec![64, 64, 22]);
+    assert_eq!(row_groups(&expected[2]), vec![30]);
+}
+
+#[test]
+fn exported_rows_read_back_unchanged() {
+    let dir = tempfile::tempdir().unwrap();
+    let conn = server_db(dir.path());
+    let out = dir.path().join("parquet");
+    let stats = export_parquet(&conn, &out, &ExportConfig::default()).unwrap();
+
+    let (imu_files, gps_files) = stats.files.split_at(3);
+    let imu: Vec<ImuShort> = imu_files.iter().flat_map(|f| read_imu_parquet(f).unwrap()).collect();
+    let gps: Vec<GpsData> = gps_files.iter().flat_map(|f| read_gps_parquet(f).unwrap()).collect();
+    assert_eq!(imu, stored_imu(&conn));
+    assert_eq!(gps, stored_gps(&conn));
+    assert!(imu.iter().any(|r| r.altitude.is_none() && r.stamp.is_none()));
+}
+
+#[test]
+fn old_databases_export_as_they_replay() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("old.db3");
+    let conn = Connection::open(&path).unwrap();
+    schema::create_imu_table(&conn).unwrap();
+    for i in 0..20 {
+        insert_imu_short(&conn, &imu(A, i)).unwrap();
+    }
+    // A logger's database from before the pressure column, which kept the
+    // raw pressure in altitude, the clock correction and the stamps. It
+    // has no gps table.
+    conn.execute_batch(
+        "UPDATE imu SET altitude = pressure;
+         ALTER TABLE imu DROP COLUMN pressure;
+         ALTER TABLE imu DROP COLUMN clock_offset;
+         ALTER TABLE imu DROP COLUMN clock_error;
+         ALTER TABLE imu DROP COLUMN stamp_s;
+         ALTER TABLE imu DROP COLUMN stamp_ns;
+         ALTER TABLE imu DROP COLUMN stamp_base;",
+    )
+    .unwrap();
+
+    let out = dir.path().join("parquet");
+    let stats = export_parquet(&conn, &out, &ExportConfig::default()).unwrap();
+    assert_eq!((stats.imu_rows, stats.gps_rows), (20, 0));
+    let exported: Vec<ImuShort> = stats.files.iter().flat_map(|f| read_imu_parquet(f).unwrap()).collect();
+    let expected: Vec<ImuShort> = (0..20)
+        .map(|i| ImuShort {
+            line: i as i64 + 1,
+            altitude: None,
+            clock_offset: None,
+            clock_error: None,
+            stamp: None,
+            ..imu(A, i)
+        })
+        .collect();
+    assert_eq!(exported, expected);
+}
+
+#[test]
+fn columns_have_their_proper_types() {
+    let dir = tempfile::tempdir().unwrap();
+    let conn = server_db(dir.path());
+    let stats = export_parquet(&conn, &dir.path().join("parquet"), &ExportConfig::default()).unwrap();
+
+    let schema = |path: &Path| ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap()).unwrap().schema().clone();
+    let imu = schema(&stats.files[0]);
+    let field = |name: &str| imu.field_with_name(name).unwrap().clone();
+    assert_eq!(field("pitime").data_type(), &DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())));
+    assert_eq!(field("uuid").data_type(), &DataType::UInt64);
+    assert_eq!(field("x_accel").data_type(), &DataType::Float32);
+    assert!(!field("x_accel").is_nullable());
+    assert!(field("altitude").is_nullable());
+    assert_eq!(field("confirmed").data_type(), &DataType::Boolean);
+    assert_eq!(field("clock_offset").data_type(), &DataType::Float64);
+
+    let gps = schema(&stats.files[3]);
+    assert_eq!(gps.field_with_name("nsats").unwrap().data_type(), &DataType::UInt8);
+    assert_eq!(gps.field_with_name("valid").unwrap().data_type(), &DataType::Boolean);
+    assert!(gps.field_with_name("status_nsats_vuc").is_err());
+    // Unknown accuracies are null rather than 0. Fix 1 is the first after
+    // midnight.
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&stats.files[4]).unwrap()).unwrap().build().unwrap();
+    let batch = reader.into_iter().next().unwrap().unwrap();
+    let h_acc = batch.column_by_name("h_acc").unwrap();
+    assert!(h_acc.is_null(0) && h_acc.is_valid(1));
+}
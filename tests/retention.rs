@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use rusqlite::Connection;
+
+use grpc_tests::data_conv::{insert_imu_short, ImuShort};
+use grpc_tests::fake_gps::encode_fields;
+use grpc_tests::retention::{enable_incremental_vacuum, file_bytes, prune, used_bytes, RetentionPolicy};
+use grpc_tests::schema;
+
+const T0: u64 = 1_700_000_000_000;
+const DAY: u64 = 24 * 3600 * 1000;
+
+fn device_db() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    schema::create_imu_table(&conn).unwrap();
+    schema::create_gps_table(&conn).unwrap();
+    conn
+}
+
+/// `n` rows a second apart from `start`
+fn log_imu(conn: &Connection, start: u64, n: u64, confirmed: bool) {
+    let tx = conn.unchecked_transaction().unwrap();
+    for i in 0..n {
+        let row = ImuShort { uuid: 7, pitime: start + i * 1000, sequence: i as u32, confirmed, uploaded: confirmed, ..Default::default() };
+        insert_imu_short(&tx, &row).unwrap();
+    }
+    tx.commit().unwrap();
+}
+
+fn pitimes(conn: &Connection, table: &str) -> Vec<u64> {
+    let mut stmt = conn.prepare(&format!("SELECT pitime FROM {} ORDER BY lineno", table)).unwrap();
+    let rows = stmt.query_map([], |row| row.get::<_, i64>(0)).unwrap();
+    rows.map(|t| t.unwrap() as u64).collect()
+}
+
+fn count(conn: &Connection, sql: &str) -> u64 {
+    conn.query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap() as u64
+}
+
+fn only(policy: RetentionPolicy) -> RetentionPolicy {
+    RetentionPolicy { max_age: None, ..policy }
+}
+
+#[test]
+fn old_confirmed_rows_expire() {
+    let conn = device_db();
+    log_imu(&conn, T0, 10, true);
+    log_imu(&conn, T0 + 10_000, 10, false);
+    log_imu(&conn, T0 + 8 * DAY, 10, true);
+
+    let stats = prune(&conn, &RetentionPolicy::default(), T0 + 9 * DAY).unwrap();
+    assert_eq!(stats.expired, 10);
+    assert_eq!(stats.deleted(), 10);
+    // Unconfirmed rows are kept however old
+    let left = pitimes(&conn, "imu");
+    assert_eq!(left.len(), 20);
+    assert_eq!(left[0], T0 + 10_000);
+}
+
+#[test]
+fn gps_rows_expire_by_their_confirmed_bit() {
+    let conn = device_db();
+    for (i, confirmed) in [true, false, true].into_iter().enumerate() {
+        conn.execute(
+            "INSERT INTO gps (uuid, pitime, gps_time, sequence, lat, lon, alt, speed, track, status_nsats_vuc, hdop)
+             VALUES (7, ?1, ?1, ?2, 50.0, -5.0, 100.0, 0.0, 0.0, ?3, 0.9)",
+            (T0 as i64 + i as i64, i as u32, encode_fields(1, 8, true, confirmed, confirmed)),
+        )
+        .unwrap();
+    }
+    let stats = prune(&conn, &RetentionPolicy::default(), T0 + 30 * DAY).unwrap();
+    assert_eq!(stats.expired, 2);
+    assert_eq!(pitimes(&conn, "gps"), vec![T0 + 1]);
+}
+
+#[test]
+fn the_oldest_confirmed_rows_make_room() {
+    let conn = device_db();
+    log_imu(&conn, T0, 1000, false);
+    log_imu(&conn, T0 + 1_000_000, 6000, true);
+    log_imu(&conn, T0 + 7_000_000, 1000, false);
+    let full = used_bytes(&conn).unwrap();
+
+    let policy = only(RetentionPolicy { max_bytes: Some(full / 2), batch: 100, ..Default::default() });
+    let stats = prune(&conn, &policy, T0).unwrap();
+    assert!(stats.evicted > 0 && stats.evicted < 6000, "{:?}", stats);
+    assert!(used_bytes(&conn).unwrap() <= full / 2);
+    // What's left of the confirmed rows is the newest of them
+    let oldest_confirmed = count(&conn, "SELECT min(pitime) FROM imu WHERE confirmed");
+    assert_eq!(oldest_confirmed, T0 + 1_000_000 + stats.evicted * 1000);
+    assert_eq!(count(&conn, "SELECT count(*) FROM imu WHERE NOT confirmed"), 2000);
+}
+
+#[test]
+fn unconfirmed_rows_wait_for_the_emergency_threshold() {
+    let conn = device_db();
+    log_imu(&conn, T0, 1000, true);
+    log_imu(&conn, T0 + 1_000_000, 4000, false);
+    let full = used_bytes(&conn).unwrap();
+
+    // Over max_bytes but under the emergency threshold: only confirmed go
+    let policy = only(RetentionPolicy { max_bytes: Some(full / 4), emergency_bytes: Some(full), ..Default::default() });
+    let stats = prune(&conn, &policy, T0).unwrap();
+    assert_eq!((stats.evicted, stats.emergency), (1000, 0));
+    assert_eq!(count(&conn, "SELECT count(*) FROM imu"), 4000);
+
+    let policy = only(RetentionPolicy { emergency_bytes: Some(full / 2), batch: 100, ..Default::default() });
+    let stats = prune(&conn, &policy, T0).unwrap();
+    assert!(stats.emergency > 0, "{:?}", stats);
+    assert!(used_bytes(&conn).unwrap() <= full / 2);
+    // The oldest go first
+    let left = pitimes(&conn, "imu");
+    assert_eq!(left[0], T0 + 1_000_000 + stats.emergency * 1000);
+    assert_eq!(*left.last().unwrap(), T0 + 1_000_000 + 3999 * 1000);
+}
+
+#[test]
+fn pruning_gives_space_back_a_bit_at_a_time() {
+    let dir = tempfile::tempdir().unwrap();
+    let conn = Connection::open(dir.path().join("imu.db3")).unwrap();
+    schema::create_imu_table(&conn).unwrap();
+    log_imu(&conn, T0, 5000, true);
+
+    // An existing database is rebuilt once to switch over
+    assert!(enable_incremental_vacuum(&conn).unwrap());
+    assert!(!enable_incremental_vacuum(&conn).unwrap());
+    let before = file_bytes(&conn).unwrap();
+
+    let policy = RetentionPolicy { max_age: Some(Duration::ZERO), vacuum_pages: 10, ..Default::default() };
+    let stats = prune(&conn, &policy, T0 + DAY).unwrap();
+    assert_eq!(stats.expired, 5000);
+    assert_eq!(stats.vacuumed_pages, 10);
+    assert!(stats.bytes_after < before);
+
+    let stats = prune(&conn, &RetentionPolicy { vacuum_pages: 0, ..policy }, T0 + DAY).unwrap();
+    assert!(stats.vacuumed_pages > 0);
+    assert_eq!(count(&conn, "PRAGMA freelist_count"), 0);
+    assert_eq!(file_bytes(&conn).unwrap(), used_bytes(&conn).unwrap());
+}
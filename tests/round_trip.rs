@@ -0,0 +1,240 @@
+use measurements::Pressure;
+use proptest::prelude::*;
+use rusqlite::Connection;
+
+use grpc_tests::data_conv::{get_earliest_n, insert_imu_short, make_imu, ConversionError, ImuShort};
+use grpc_tests::data_defs;
+use grpc_tests::gps::GpsData;
+use grpc_tests::imu::{ImuData, Inertial, Orientation, Vector3D};
+use grpc_tests::timestamp::{Timebase, Timestamp};
+
+/// Anything SQLite can store in a FLOAT column and give back unchanged
+fn finite() -> impl Strategy<Value = f32> {
+    prop::num::f32::NORMAL | prop::num::f32::SUBNORMAL | prop::num::f32::ZERO
+}
+
+fn vector() -> impl Strategy<Value = Vector3D> {
+    (finite(), finite(), finite()).prop_map(|(x, y, z)| Vector3D { x, y, z })
+}
+
+fn orientation() -> impl Strategy<Value = Orientation> {
+    (finite(), finite(), finite(), finite()).prop_map(|(roll, pitch, yaw, heading_accuracy)| {
+        Orientation { roll, pitch, yaw, heading_accuracy }
+    })
+}
+
+/// A sensor temperature, to the 0.01 °C the sensors report. The domain
+/// keeps temperatures in kelvin, which can't hold arbitrarily tiny Celsius
+/// values exactly.
+fn temperature() -> impl Strategy<Value = f32> {
+    (-27315..=20000i32).prop_map(|c| c as f32 / 100.0)
+}
+
+/// A time whose ms count fits SQLite's signed 64 bits
+fn stamp() -> impl Strategy<Value = Timestamp> {
+    let timebase = prop_oneof![Just(Timebase::System), Just(Timebase::Gps), Just(Timebase::Monotonic)];
+    (0..i64::MAX / 1000, 0..1_000_000_000u32, timebase)
+        .prop_map(|(seconds, nanos, timebase)| Timestamp { seconds, nanos, timebase: timebase.into() })
+}
+
+/// The ms count a sender puts alongside `stamp`
+fn ms(stamp: Timestamp) -> u64 {
+    stamp.seconds as u64 * 1000 + stamp.nanos as u64 / 1_000_000
+}
+
+/// A complete IMU message
+fn imu_data() -> impl Strategy<Value = ImuData> {
+    (
+        any::<u32>(),
+        stamp(),
+        (orientation(), vector(), vector(), vector()),
+        (finite(), temperature(), temperature()),
+    )
+        .prop_map(|(sequence, stamp, (pose, gyro, accel, mag), (pressure, temperature, temp_cpu))| {
+            ImuData {
+                sequence,
+                timestamp: ms(stamp),
+                inertial: Some(Inertial {
+                    pose: Some(pose),
+                    gyro: Some(gyro),
+                    accel: Some(accel),
+                    mag: Some(mag),
+                }),
+                pressure,
+                temperature,
+                temp_cpu,
+                stamp: Some(stamp),
+            }
+        })
+}
+
+/// An accuracy estimate, or 0 for none
+fn accuracy() -> impl Strategy<Value = f32> {
+    prop_oneof![Just(0.0f32), 0.001f32..1000.0]
+}
+
+/// A GPS line with a status word encode_fields could have produced
+fn gps_data() -> impl Strategy<Value = GpsData> {
+    (
+        (any::<u64>(), stamp(), stamp(), any::<u32>()),
+        (finite(), finite(), finite(), finite(), finite(), finite()),
+        (any::<u8>(), any::<u8>(), 0..8u32),
+        (accuracy(), accuracy(), accuracy(), any::<u32>()),
+    )
+        .prop_map(|((uuid, pi_stamp, gps_stamp, sequence), (lat, lon, alt, speed, track, hdop), (status, nsats, vuc), (h_acc, v_acc, speed_acc, time_valid))| {
+            GpsData {
+                uuid,
+                pitime: ms(pi_stamp),
+                gps_time: ms(gps_stamp),
+                sequence,
+                lat,
+                lon,
+                alt,
+                speed,
+                track,
+                status_nsats_vuc: (status as u32) << 16 | (nsats as u32) << 8 | vuc,
+                hdop,
+                h_acc,
+                v_acc,
+                speed_acc,
+                time_valid,
+                pi_stamp: Some(pi_stamp),
+                gps_stamp: Some(gps_stamp),
+            }
+        })
+}
+
+fn imu_table() -> Connection {
+    let mut conn = Connection::open_in_memory().unwrap();
+    make_imu(&mut conn).unwrap();
+    conn
+}
+
+proptest! {
+    #[test]
+    fn proto_to_row_and_back(d in imu_data()) {
+        let row = ImuShort::try_from(d).unwrap();
+        prop_assert_eq!(ImuData::from(row), d);
+    }
+
+    #[test]
+    fn proto_through_the_database_and_back(batch in prop::collection::vec(imu_data(), 1..20)) {
+        let conn = imu_table();
+        for d in &batch {
+            insert_imu_short(&conn, &ImuShort::try_from(*d).unwrap()).unwrap();
+        }
+
+        let rows = get_earliest_n(&conn, batch.len()).unwrap();
+        let back: Vec<ImuData> = rows.into_iter().map(ImuData::from).collect();
+        prop_assert_eq!(back, batch);
+    }
+
+    #[test]
+    fn row_through_the_database_is_unchanged(d in imu_data(), uuid in 0..=i64::MAX as u64, gps_time in 0..=i64::MAX as u64) {
+        let conn = imu_table();
+        let mut row = ImuShort::try_from(d).unwrap();
+        row.uuid = uuid;
+        row.gps_time = gps_time;
+        insert_imu_short(&conn, &row).unwrap();
+
+        let mut back = get_earliest_n(&conn, 1).unwrap().remove(0);
+        prop_assert_eq!(back.line, 1);
+        back.line = 0;
+        prop_assert_eq!(back, row);
+    }
+
+    #[test]
+    fn old_senders_round_trip_without_a_stamp(mut d in imu_data()) {
+        d.stamp = None;
+        let conn = imu_table();
+        insert_imu_short(&conn, &ImuShort::try_from(d).unwrap()).unwrap();
+        let back = get_earliest_n(&conn, 1).unwrap().remove(0);
+        prop_assert_eq!(back.stamp, None);
+        prop_assert_eq!(ImuData::from(back), d);
+    }
+
+    #[test]
+    fn proto_to_domain_and_back(d in imu_data()) {
+        let domain = data_defs::ImuData::from(d);
+        prop_assert_eq!(domain.pressure, Some(Pressure::from_pascals(d.pressure.into())));
+        prop_assert_eq!(ImuData::try_from(domain).unwrap(), d);
+    }
+
+    #[test]
+    fn domain_to_row_and_back(d in imu_data()) {
+        let domain = data_defs::ImuData::from(d);
+        let row = ImuShort::try_from(domain.clone()).unwrap();
+        prop_assert_eq!(data_defs::ImuData::from(row), domain);
+    }
+
+    #[test]
+    fn imu_not_ready_survives_the_round_trip(mut d in imu_data()) {
+        d.inertial = None;
+        let domain = data_defs::ImuData::from(d);
+        prop_assert_eq!(&domain.inertial, &Err(data_defs::ImuError::NotReady));
+        prop_assert_eq!(ImuData::try_from(domain).unwrap(), d);
+    }
+
+    #[test]
+    fn gps_to_domain_and_back(d in gps_data()) {
+        let domain = data_defs::GpsData::from(d);
+        prop_assert_eq!(GpsData::from(domain), d);
+    }
+}
+
+#[test]
+fn incomplete_messages_cannot_become_rows() {
+    let d = ImuData::default();
+    assert_eq!(ImuShort::try_from(d), Err(ConversionError::Missing("inertial")));
+
+    let d = ImuData {
+        inertial: Some(Inertial { gyro: None, ..imu_data_inertial() }),
+        ..ImuData::default()
+    };
+    assert_eq!(ImuShort::try_from(d), Err(ConversionError::Missing("gyro")));
+}
+
+#[test]
+fn missing_readings_cannot_become_messages() {
+    let mut domain = data_defs::generate_imu_data();
+    domain.pressure = None;
+    assert_eq!(ImuData::try_from(domain), Err(ConversionError::Missing("pressure")));
+}
+
+fn imu_data_inertial() -> Inertial {
+    Inertial {
+        pose: Some(Orientation::default()),
+        gyro: Some(Vector3D::default()),
+        accel: Some(Vector3D::default()),
+        mag: Some(Vector3D::default()),
+    }
+}
+
+#[test]
+fn wire_units_become_domain_units() {
+    let d = ImuData {
+        inertial: Some(Inertial {
+            pose: Some(Orientation { roll: 90.0, ..Default::default() }),
+            gyro: Some(Vector3D { z: 180.0, ..Default::default() }),
+            accel: Some(Vector3D { z: 1.0, ..Default::default() }),
+            mag: Some(Vector3D { x: 19.0, ..Default::default() }),
+        }),
+        pressure: 101325.0,
+        temperature: 0.0,
+        ..Default::default()
+    };
+    let domain = data_defs::ImuData::from(d);
+    let inertial = domain.inertial.unwrap();
+
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+    assert!(close(inertial.pose.unwrap().roll.as_radians(), std::f64::consts::FRAC_PI_2));
+    assert!(close(inertial.gyro.unwrap().z.as_radians_per_second(), std::f64::consts::PI));
+    assert!(close(inertial.accel.unwrap().z.as_meters_per_second_per_second(), data_defs::STANDARD_GRAVITY));
+    assert!(close(inertial.mag.unwrap().x.as_microteslas(), 19.0));
+    assert!(close(domain.pressure.unwrap().as_hectopascals(), 1013.25));
+    assert!(close(domain.temperature.unwrap().as_kelvin(), 273.15));
+
+    let fix = data_defs::GpsData::from(GpsData { alt: 100.0, speed: 10.0, ..Default::default() });
+    assert!(close(fix.alt.as_feet(), 328.083_989_501_312_3));
+    assert!(close(fix.speed.as_kilometers_per_hour(), 36.0));
+}
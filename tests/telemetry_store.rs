@@ -0,0 +1,259 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use grpc_tests::crash::Crash;
+use grpc_tests::data_conv::ImuShort;
+use grpc_tests::fake_gps::generate_drive_data;
+use grpc_tests::gps::GpsData;
+use grpc_tests::pg_store::PgStore;
+use grpc_tests::service::spawn_server;
+use grpc_tests::store::{GpsKey, ImuKey, MemoryStore, ServerStore, TelemetryStore};
+use grpc_tests::timestamp::{Timebase, Timestamp};
+use grpc_tests::trip::Trip;
+use grpc_tests::upload::Uploader;
+
+const UUID: u64 = 0x1234567890AB;
+const T0: u64 = 1_700_000_000_000;
+/// A libpq config for a PostgreSQL server the tests may create databases
+/// on, e.g. "host=127.0.0.1 user=postgres". The PostgreSQL tests are
+/// ignored unless asked for, with `cargo test -- --ignored`, and fail
+/// without it.
+const PG_TEST: &str = "PG_TEST_CONFIG";
+
+/// A database of its own for one test, dropped after it
+struct TestDb {
+    admin: String,
+    name: String,
+}
+
+impl TestDb {
+    fn create() -> TestDb {
+        let admin = std::env::var(PG_TEST).unwrap_or_else(|_| panic!("{} isn't set", PG_TEST));
+        static N: AtomicU32 = AtomicU32::new(0);
+        let name = format!("telemetry_test_{}_{}", std::process::id(), N.fetch_add(1, Ordering::Relaxed));
+        let db = TestDb { admin, name };
+        db.admin(&format!("CREATE DATABASE {}", db.name));
+        db
+    }
+
+    fn config(&self) -> String {
+        format!("{} dbname={}", self.admin, self.name)
+    }
+
+    /// The blocking client won't run inside the test's runtime
+    fn admin(&self, sql: &str) {
+        let (config, sql) = (self.admin.clone(), sql.to_string());
+        thread::spawn(move || postgres::Client::connect(&config, postgres::NoTls).unwrap().batch_execute(&sql).unwrap())
+            .join()
+            .unwrap();
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        self.admin(&format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", self.name));
+    }
+}
+
+fn row(uuid: u64, sequence: u32, pitime: u64) -> ImuShort {
+    ImuShort { uuid, pitime, gps_time: pitime, sequence, accel_z: 9.81, uploaded: true, confirmed: true, ..Default::default() }
+}
+
+fn fix(uuid: u64, sequence: u32, gps_time: u64) -> GpsData {
+    GpsData { uuid, pitime: gps_time + 3, gps_time, sequence, lat: 50.2, lon: -5.3, ..Default::default() }
+}
+
+/// What every backend must do, whatever it keeps its rows in
+fn exercise(store: &dyn TelemetryStore) {
+    // Sent out of order, as a resend after a reconnect would be
+    let rows: Vec<ImuShort> = (0..10).rev().map(|i| row(UUID, i, T0 + i as u64 * 10)).collect();
+    assert_eq!(store.append_imu(&rows).unwrap(), 10);
+    assert_eq!(store.append_imu(&rows[..4]).unwrap(), 0);
+    assert_eq!(store.append_imu(&[row(UUID, 10, T0 + 100), row(7, 0, T0)]).unwrap(), 2);
+
+    let found = store.imu_range(UUID, T0 + 20..T0 + 50).unwrap();
+    let sequences: Vec<u32> = found.iter().map(|r| r.sequence).collect();
+    assert_eq!(sequences, vec![2, 3, 4]);
+    assert_eq!(found[0].accel_z, 9.81);
+    assert_eq!(store.imu_range(UUID, 0..u64::MAX).unwrap().len(), 11);
+    assert_eq!(store.imu_range(7, 0..u64::MAX).unwrap().len(), 1);
+    assert!(store.imu_range(8, 0..u64::MAX).unwrap().is_empty());
+
+    assert!(store.has_imu(ImuKey { uuid: UUID, sequence: 3, pitime: T0 + 30 }).unwrap());
+    // A restarted device counts its sequence from 0 again
+    assert!(!store.has_imu(ImuKey { uuid: UUID, sequence: 3, pitime: T0 + 31 }).unwrap());
+
+    // Rows stored before the device's clock model, put on GPS time later
+    let mut late = store.uncorrected_imu(UUID).unwrap();
+    assert_eq!(late.len(), 11);
+    for r in &mut late[..3] {
+        r.gps_time = r.pitime - 5;
+        r.clock_offset = Some(5.0);
+        r.clock_error = Some(0.5);
+        r.stamp = Some(Timestamp { seconds: 1_700_000_000, nanos: 7, timebase: Timebase::Gps.into() });
+    }
+    assert_eq!(store.update_imu_times(&late[..3]).unwrap(), 3);
+    assert_eq!(store.uncorrected_imu(UUID).unwrap(), late[3..].to_vec());
+    let corrected = store.imu_range(UUID, T0..T0 + 30).unwrap();
+    assert_eq!(corrected, late[..3].to_vec());
+    assert_eq!(store.uncorrected_imu(7).unwrap().len(), 1);
+
+    let data: Vec<GpsData> = (0..5).map(|i| fix(UUID, i, T0 + i as u64 * 1000)).collect();
+    assert_eq!(store.append_gps(&data).unwrap(), 5);
+    assert_eq!(store.append_gps(&data).unwrap(), 0);
+    assert_eq!(store.gps_range(UUID, T0 + 1000..T0 + 3000).unwrap(), data[1..3].to_vec());
+    assert!(store.has_gps(GpsKey::from(&data[4])).unwrap());
+    assert!(!store.has_gps(GpsKey { uuid: 7, ..GpsKey::from(&data[4]) }).unwrap());
+
+    let trip = |start: u64, end: u64| Trip {
+        uuid: UUID,
+        start_time: start,
+        end_time: end,
+        end_lon: -5.3,
+        distance: 850.5,
+        duration: end - start,
+        max_speed: 22.25,
+        ..Default::default()
+    };
+    let (first, second) = (trip(T0, T0 + 60_000), trip(T0 + 120_000, T0 + 200_000));
+    store.replace_trips(UUID, 0..u64::MAX, &[second, first]).unwrap();
+    assert_eq!(store.trips(UUID, 0..u64::MAX).unwrap(), vec![first, second]);
+    assert_eq!(store.trips(UUID, T0 + 60_000..T0 + 120_000).unwrap(), vec![first]);
+    // Detected again, the second trip turns out to have started earlier
+    let longer = trip(T0 + 100_000, T0 + 200_000);
+    store.replace_trips(UUID, T0 + 150_000..T0 + 160_000, &[longer]).unwrap();
+    assert_eq!(store.trips(UUID, 0..u64::MAX).unwrap(), vec![first, longer]);
+    assert!(store.trips(7, 0..u64::MAX).unwrap().is_empty());
+
+    let crash = |pitime: u64| Crash {
+        uuid: UUID,
+        pitime,
+        peak_g: 3.5,
+        window_start: pitime - 10_000,
+        window_end: pitime + 5_000,
+    };
+    let (early, late) = (crash(T0 + 20_000), crash(T0 + 90_000));
+    assert_eq!(store.append_crashes(&[late, early]).unwrap(), 2);
+    assert_eq!(store.append_crashes(&[early]).unwrap(), 0);
+    assert_eq!(store.crashes(UUID).unwrap(), vec![early, late]);
+    assert!(store.crashes(7).unwrap().is_empty());
+}
+
+#[test]
+fn the_memory_store_behaves_as_a_store() {
+    exercise(&MemoryStore::new());
+}
+
+#[test]
+fn the_sqlite_store_behaves_as_a_store() {
+    exercise(&ServerStore::open_in_memory().unwrap());
+}
+
+#[test]
+#[ignore = "needs a PostgreSQL server, see PG_TEST_CONFIG"]
+fn the_postgres_store_behaves_as_a_store() {
+    let db = TestDb::create();
+    exercise(&PgStore::connect(&db.config()).unwrap());
+}
+
+#[test]
+#[ignore = "needs a PostgreSQL server, see PG_TEST_CONFIG"]
+fn the_postgres_schema_is_created_once() {
+    let db = TestDb::create();
+    let store = PgStore::connect(&db.config()).unwrap();
+    assert_eq!(store.append_imu(&[row(UUID, 0, T0)]).unwrap(), 1);
+    drop(store);
+
+    // Reconnecting finds the tables, and what's in them, as they were
+    let store = PgStore::connect(&db.config()).unwrap();
+    assert!(store.has_imu(ImuKey { uuid: UUID, sequence: 0, pitime: T0 }).unwrap());
+    assert_eq!(store.append_imu(&[row(UUID, 0, T0)]).unwrap(), 0);
+}
+
+#[test]
+#[ignore = "needs a PostgreSQL server, see PG_TEST_CONFIG"]
+fn timescale_tables_are_hypertables() {
+    let db = TestDb::create();
+    let config = db.config();
+    let available = thread::spawn(move || {
+        let mut client = postgres::Client::connect(&config, postgres::NoTls).unwrap();
+        let available =
+            client.query_opt("SELECT 1 FROM pg_available_extensions WHERE name = 'timescaledb'", &[]).unwrap().is_some();
+        if available {
+            client.batch_execute("CREATE EXTENSION IF NOT EXISTS timescaledb").unwrap();
+        }
+        available
+    })
+    .join()
+    .unwrap();
+    if !available {
+        eprintln!("TimescaleDB isn't installed on the test server, skipping");
+        return;
+    }
+
+    exercise(&PgStore::connect(&db.config()).unwrap());
+    // And connecting again finds them already converted
+    drop(PgStore::connect(&db.config()).unwrap());
+    let config = db.config();
+    let hypertables: Vec<String> = thread::spawn(move || {
+        let mut client = postgres::Client::connect(&config, postgres::NoTls).unwrap();
+        let rows = client
+            .query("SELECT hypertable_name::text FROM timescaledb_information.hypertables ORDER BY 1", &[])
+            .unwrap();
+        rows.iter().map(|r| r.get(0)).collect()
+    })
+    .join()
+    .unwrap();
+    assert_eq!(hypertables, ["gps", "imu"]);
+}
+
+#[test]
+fn both_stores_return_the_same_rows() {
+    let memory = MemoryStore::new();
+    let sqlite = ServerStore::open_in_memory().unwrap();
+    let rows: Vec<ImuShort> = (0..50).map(|i| row(UUID, i % 20, T0 + (i as u64 * 7) % 100)).collect();
+    for store in [&memory as &dyn TelemetryStore, &sqlite] {
+        store.append_imu(&rows).unwrap();
+    }
+    let range = T0 + 10..T0 + 90;
+    assert_eq!(memory.imu_range(UUID, range.clone()).unwrap(), sqlite.imu_range(UUID, range).unwrap());
+}
+
+#[tokio::test]
+#[ignore = "needs a PostgreSQL server, see PG_TEST_CONFIG"]
+async fn uploads_are_copied_into_postgres() {
+    let db = TestDb::create();
+    let store = Arc::new(PgStore::connect(&db.config()).unwrap());
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let mut uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+    let (imu, gps) = generate_drive_data(500);
+
+    assert_eq!(uploader.send_imu(imu.clone()).await.unwrap().stored, 500);
+    assert_eq!(uploader.send_imu(imu).await.unwrap().duplicates, 500);
+    uploader.send_gps(gps.clone()).await.unwrap();
+    server.stop().await.unwrap();
+
+    // As the SQLite store would have them
+    let sqlite = ServerStore::open_in_memory().unwrap();
+    sqlite.append_gps(&gps.data).unwrap();
+    assert_eq!(store.gps_range(UUID, 0..u64::MAX).unwrap(), sqlite.gps_range(UUID, 0..u64::MAX).unwrap());
+    assert_eq!(store.imu_range(UUID, 0..u64::MAX).unwrap().len(), 500);
+}
+
+#[tokio::test]
+async fn the_server_runs_on_any_store() {
+    let store = Arc::new(MemoryStore::new());
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let mut uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+    let (imu, gps) = generate_drive_data(40);
+    let n_gps = gps.data.len();
+
+    assert_eq!(uploader.send_imu(imu.clone()).await.unwrap().stored, 40);
+    assert_eq!(uploader.send_imu(imu).await.unwrap().duplicates, 40);
+    assert_eq!(uploader.send_gps(gps).await.unwrap().stored as usize, n_gps);
+
+    assert_eq!(store.imu_range(UUID, 0..u64::MAX).unwrap().len(), 40);
+    assert_eq!(store.gps_range(UUID, 0..u64::MAX).unwrap().len(), n_gps);
+    server.stop().await.unwrap();
+}
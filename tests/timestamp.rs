@@ -0,0 +1,102 @@
+use grpc_tests::data_conv::ImuShort;
+use grpc_tests::data_defs::{self, Timebase};
+use grpc_tests::gps::GpsData;
+use grpc_tests::imu::{ImuData, Inertial, Orientation, Vector3D};
+use grpc_tests::store::ServerStore;
+use grpc_tests::timestamp::{self, Timestamp};
+
+const UUID: u64 = 0x1234567890AB;
+
+fn stamp(seconds: i64, nanos: u32, timebase: timestamp::Timebase) -> Option<Timestamp> {
+    Some(Timestamp { seconds, nanos, timebase: timebase.into() })
+}
+
+#[test]
+fn nanos_split_into_seconds_and_nanos() {
+    let t = data_defs::Timestamp::from_nanos(1_700_000_000_123_456_789, Timebase::Gps);
+    assert_eq!((t.seconds, t.nanos), (1_700_000_000, 123_456_789));
+    assert_eq!(t.as_millis(), 1_700_000_000_123);
+    assert_eq!(t.as_nanos(), 1_700_000_000_123_456_789);
+
+    // Before the epoch the nanos still count forwards
+    let t = data_defs::Timestamp::from_nanos(-1, Timebase::Monotonic);
+    assert_eq!((t.seconds, t.nanos), (-1, 999_999_999));
+    assert_eq!(t.as_millis(), 0);
+
+    assert_eq!(data_defs::Timestamp::from_millis(1500, Timebase::System).as_nanos(), 1_500_000_000);
+}
+
+#[test]
+fn the_stamp_wins_over_the_ms_count() {
+    let d = GpsData {
+        pitime: 1,
+        gps_time: 2,
+        pi_stamp: stamp(1_700_000_000, 5, timestamp::Timebase::Monotonic),
+        gps_stamp: None,
+        ..Default::default()
+    };
+    let domain = data_defs::GpsData::from(d);
+    assert_eq!(domain.pitime.as_nanos(), 1_700_000_000_000_000_005);
+    assert_eq!(domain.pitime.timebase, Timebase::Monotonic);
+    // Old senders' ms counts keep their meaning
+    assert_eq!(domain.gps_time, data_defs::Timestamp::from_millis(2, Timebase::Gps));
+
+    let back = GpsData::from(domain);
+    assert_eq!((back.pitime, back.gps_time), (1_700_000_000_000, 2));
+    assert_eq!(back.pi_stamp, d.pi_stamp);
+}
+
+#[test]
+fn only_a_system_stamp_gives_the_pitime() {
+    let reading = |stamp| ImuData {
+        timestamp: 1_700_000_000_000,
+        stamp,
+        inertial: Some(Inertial {
+            pose: Some(Orientation::default()),
+            gyro: Some(Vector3D::default()),
+            accel: Some(Vector3D::default()),
+            mag: Some(Vector3D::default()),
+        }),
+        ..Default::default()
+    };
+    let row = ImuShort::try_from(reading(stamp(1_700_000_000, 250_000_000, timestamp::Timebase::System))).unwrap();
+    assert_eq!(row.pitime, 1_700_000_000_250);
+    // Seconds since boot aren't wall-clock time
+    for timebase in [timestamp::Timebase::Monotonic, timestamp::Timebase::Gps] {
+        let row = ImuShort::try_from(reading(stamp(42, 0, timebase))).unwrap();
+        assert_eq!(row.pitime, 1_700_000_000_000);
+        assert_eq!(row.stamp, stamp(42, 0, timebase));
+    }
+}
+
+#[test]
+fn unknown_timebases_are_system_time() {
+    let t = data_defs::Timestamp::from(Timestamp { seconds: 3, nanos: 4, timebase: 99 });
+    assert_eq!(t.timebase, Timebase::System);
+}
+
+#[test]
+fn the_server_stores_stamps_to_the_nanosecond() {
+    let store = ServerStore::open_in_memory().unwrap();
+    let fix = GpsData {
+        uuid: UUID,
+        pitime: 1_700_000_000_001,
+        gps_time: 1_700_000_000_000,
+        pi_stamp: stamp(1_700_000_000, 1_999_999, timestamp::Timebase::System),
+        gps_stamp: stamp(1_700_000_000, 123_456_789, timestamp::Timebase::Gps),
+        ..Default::default()
+    };
+    store.insert_gps(&[fix]).unwrap();
+    assert_eq!(store.read_gps(UUID).unwrap(), vec![fix]);
+
+    let row = ImuShort {
+        uuid: UUID,
+        pitime: 1_700_000_000_000,
+        stamp: stamp(1_700_000_000, 999_999, timestamp::Timebase::Monotonic),
+        ..Default::default()
+    };
+    store.insert_imu(std::slice::from_ref(&row)).unwrap();
+    let back = store.read_imu_rows(UUID).unwrap();
+    assert_eq!(back[0].stamp, row.stamp);
+    assert_eq!(back[0].pitime, row.pitime);
+}
@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use grpc_tests::data_conv::ImuShort;
+use grpc_tests::data_defs::{Timebase, Timestamp};
+use grpc_tests::drive_sim::DriveSimulator;
+use grpc_tests::fake_gps::encode_fields;
+use grpc_tests::gps::GpsData;
+use grpc_tests::gps_sim::{GpsSimConfig, GpsSimulator, Route};
+use grpc_tests::imu_sim::{ImuSimConfig, MotionProfile};
+use grpc_tests::service::spawn_server;
+use grpc_tests::store::ServerStore;
+use grpc_tests::timesync::{ClockSync, SyncConfig};
+use grpc_tests::ubx::{TIME_VALID_ALL, TIME_VALID_TIME};
+use grpc_tests::upload::Uploader;
+
+const UUID: u64 = 0x1234567890AB;
+const T0: u64 = 1_700_000_000_000;
+
+fn fix(gps_time: u64, pitime: u64) -> GpsData {
+    GpsData {
+        uuid: UUID,
+        pitime,
+        gps_time,
+        status_nsats_vuc: encode_fields(1, 9, true, false, false),
+        hdop: 0.9,
+        time_valid: TIME_VALID_ALL,
+        ..Default::default()
+    }
+}
+
+/// A Pi clock `offset` ms ahead of GPS and gaining `drift_ppm`
+fn drifting(offset: i64, drift_ppm: f64) -> GpsSimConfig {
+    GpsSimConfig { pi_clock_offset: offset, pi_clock_drift: drift_ppm, tunnels: vec![], ..Default::default() }
+}
+
+#[test]
+fn a_fixed_offset_is_found() {
+    let mut sync = ClockSync::new(SyncConfig::default());
+    for i in 0..10 {
+        assert!(sync.observe(&fix(T0 + i * 1000, T0 + i * 1000 + 1234)));
+    }
+    let c = sync.correction(T0 + 5000).unwrap();
+    assert!((c.offset - 1234.0).abs() < 1e-6, "{:?}", c);
+    assert!(c.drift_ppm.abs() < 1e-6);
+    assert_eq!(c.fixes, 10);
+    assert_eq!(sync.gps_time(T0 + 20_000 + 1234), Some(T0 + 20_000));
+}
+
+#[test]
+fn drift_is_found_and_followed() {
+    let config = drifting(-800, 50.0);
+    let truth = config.clone();
+    let sim = GpsSimulator::new(config, Route::camborne(), MotionProfile::default());
+    let mut sync = ClockSync::new(SyncConfig::default());
+    for f in sim.take(600) {
+        sync.observe(&f);
+    }
+
+    let c = sync.correction(truth.pitime(T0 + 599_000)).unwrap();
+    assert!((c.drift_ppm - 50.0).abs() < 1.0, "{:?}", c);
+    assert!(c.rms < 1.0, "{:?}", c);
+    // Ten minutes at 50 ppm is 30 ms: a fixed offset would be out by that
+    for t in [T0, T0 + 300_000, T0 + 599_000, T0 + 650_000] {
+        let corrected = sync.gps_time(truth.pitime(t)).unwrap();
+        assert!(corrected.abs_diff(t) <= 1, "{} vs {}", corrected, t);
+    }
+}
+
+#[test]
+fn late_fixes_are_left_out() {
+    let mut sync = ClockSync::new(SyncConfig::default());
+    for i in 0..60 {
+        // Every tenth fix is read off the serial port 200 ms late
+        let late = if i % 10 == 3 { 200 } else { 0 };
+        sync.observe(&fix(T0 + i * 1000, T0 + i * 1000 + 500 + late));
+    }
+    let c = sync.correction(T0).unwrap();
+    assert!((c.offset - 500.0).abs() < 0.5, "{:?}", c);
+    assert_eq!(c.fixes, 54);
+    assert!(c.rms < 0.5);
+}
+
+#[test]
+fn fixes_without_a_full_time_are_ignored() {
+    let mut sync = ClockSync::new(SyncConfig::default());
+    // NMEA before the first RMC: time of day, no date
+    assert!(!sync.observe(&GpsData { time_valid: TIME_VALID_TIME, ..fix(T0, T0) }));
+    // An old sender with no fix
+    let no_fix = GpsData { time_valid: 0, status_nsats_vuc: encode_fields(0, 0, false, false, false), ..fix(T0, T0) };
+    assert!(!sync.observe(&no_fix));
+    // An old sender with a fix is trusted
+    assert!(sync.observe(&GpsData { time_valid: 0, ..fix(T0, T0) }));
+
+    for i in 1..4 {
+        sync.observe(&fix(T0 + i * 1000, T0 + i * 1000));
+    }
+    // Not yet min_fixes
+    assert_eq!(sync.correction(T0), None);
+    sync.observe(&fix(T0 + 4000, T0 + 4000));
+    assert!(sync.correction(T0).is_some());
+}
+
+#[test]
+fn corrected_rows_record_the_correction() {
+    let mut sync = ClockSync::new(SyncConfig::default());
+    let mut row = ImuShort { pitime: T0 + 2500, ..Default::default() };
+    assert!(!sync.apply(&mut row));
+    assert_eq!((row.gps_time, row.clock_offset), (0, None));
+
+    for i in 0..5 {
+        sync.observe(&fix(T0 + i * 1000, T0 + i * 1000 + 40));
+    }
+    assert!(sync.apply(&mut row));
+    assert_eq!(row.gps_time, T0 + 2460);
+    assert!((row.clock_offset.unwrap() - 40.0).abs() < 1e-6);
+    assert!(row.clock_error.unwrap() < 1e-6);
+    let stamp = Timestamp::from(row.stamp.unwrap());
+    assert_eq!((stamp.as_millis(), stamp.timebase), (T0 + 2460, Timebase::Gps));
+}
+
+#[tokio::test]
+async fn the_server_puts_imu_lines_on_gps_time() {
+    let config = drifting(2_000, -30.0);
+    let truth = config.clone();
+    let mut drive = DriveSimulator::new(ImuSimConfig::default(), config, Route::camborne(), MotionProfile::default());
+    // 100 s of driving
+    let (imu, gps) = drive.run(1000);
+    let imu_period = 100;
+
+    let store = Arc::new(ServerStore::open_in_memory().unwrap());
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let mut uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+    uploader.send_gps(gps).await.unwrap();
+    uploader.send_imu(imu).await.unwrap();
+
+    let rows = store.read_imu_rows(UUID).unwrap();
+    assert_eq!(rows.len(), 1000);
+    for (i, row) in rows.iter().enumerate() {
+        let t = T0 + i as u64 * imu_period;
+        assert_eq!(row.pitime, truth.pitime(t));
+        assert!(row.gps_time.abs_diff(t) <= 1, "line {}: {} vs {}", i, row.gps_time, t);
+        assert!(row.clock_offset.is_some() && row.clock_error.is_some());
+    }
+
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn imu_lines_sent_before_any_fix_are_put_on_gps_time_later() {
+    let config = drifting(-1_500, 20.0);
+    let truth = config.clone();
+    let mut drive = DriveSimulator::new(ImuSimConfig::default(), config, Route::camborne(), MotionProfile::default());
+    let (imu, gps) = drive.run(600);
+    let imu_period = 100;
+
+    let store = Arc::new(ServerStore::open_in_memory().unwrap());
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let mut uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+    uploader.send_imu(imu).await.unwrap();
+    assert!(store.read_imu_rows(UUID).unwrap().iter().all(|r| r.clock_offset.is_none()));
+    uploader.send_gps(gps).await.unwrap();
+
+    let rows = store.read_imu_rows(UUID).unwrap();
+    assert_eq!(rows.len(), 600);
+    for (i, row) in rows.iter().enumerate() {
+        let t = T0 + i as u64 * imu_period;
+        assert_eq!(row.pitime, truth.pitime(t));
+        assert!(row.gps_time.abs_diff(t) <= 1, "line {}: {} vs {}", i, row.gps_time, t);
+        assert!(row.clock_offset.is_some() && row.clock_error.is_some());
+        let stamp = Timestamp::from(row.stamp.unwrap());
+        assert_eq!(stamp.timebase, Timebase::Gps);
+        assert!(stamp.as_millis().abs_diff(t) <= 1, "line {}: {:?} vs {}", i, stamp, t);
+    }
+
+    server.stop().await.unwrap();
+}
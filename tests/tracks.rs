@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use serde_json::Value;
+
+use grpc_tests::fake_gps::encode_fields;
+use grpc_tests::gps::GpsData;
+use grpc_tests::nmea::days_from_civil;
+use grpc_tests::store::{ServerStore, TelemetryStore};
+use grpc_tests::tracks::{export_track, iso_time, segments, SegmentConfig, TrackFormat};
+
+const UUID: u64 = 0x1234567890AB;
+const DAY: u64 = 24 * 3600 * 1000;
+
+fn t0() -> u64 {
+    days_from_civil(2024, 2, 29) as u64 * DAY + 12 * 3600 * 1000
+}
+
+fn fix(sequence: u32, gps_time: u64, valid: bool) -> GpsData {
+    GpsData {
+        uuid: UUID,
+        pitime: gps_time + 3,
+        gps_time,
+        sequence,
+        lat: 50.0 + sequence as f32 * 0.5,
+        lon: -5.25,
+        alt: 80.5,
+        speed: 13.5,
+        track: 270.0,
+        status_nsats_vuc: encode_fields(if valid { 1 } else { 0 }, 8, valid, false, false),
+        ..Default::default()
+    }
+}
+
+/// 10 fixes, a lost fix, 5 more, a minute parked, then 4 more
+fn drive() -> Vec<GpsData> {
+    let mut data = Vec::new();
+    let mut t = t0();
+    for (n, valid, gap) in [(10, true, 1000), (1, false, 1000), (5, true, 1000), (4, true, 61_000)] {
+        for i in 0..n {
+            t += if i == 0 { gap } else { 1000 };
+            data.push(fix(data.len() as u32, t, valid));
+        }
+    }
+    data
+}
+
+#[test]
+fn drives_split_at_fix_loss_and_long_gaps() {
+    let found = segments(&drive(), &SegmentConfig::default());
+    let lengths: Vec<usize> = found.iter().map(|s| s.len()).collect();
+    assert_eq!(lengths, vec![10, 5, 4]);
+    assert_eq!(found[1][0].time, t0() + 12_000);
+
+    // A long enough allowed gap joins the last two
+    let config = SegmentConfig { max_gap: Duration::from_secs(90), ..Default::default() };
+    assert_eq!(segments(&drive(), &config).len(), 2);
+
+    // A lone fix can't be drawn as a line
+    let lone = vec![fix(0, t0(), true), fix(1, t0() + 1000, false)];
+    assert!(segments(&lone, &SegmentConfig::default()).is_empty());
+}
+
+#[test]
+fn gpx_has_a_trkseg_per_segment() {
+    let gpx = export_track("Truck <7>", &drive(), TrackFormat::Gpx, &SegmentConfig::default());
+    assert!(gpx.starts_with("<?xml"));
+    assert!(gpx.contains("<name>Truck &lt;7&gt;</name>"));
+    assert_eq!(gpx.matches("<trkseg>").count(), 3);
+    assert_eq!(gpx.matches("<trkpt ").count(), 19);
+    assert!(gpx.contains("<trkpt lat=\"50\" lon=\"-5.25\"><ele>80.5</ele><time>2024-02-29T12:00:01.000Z</time></trkpt>"));
+}
+
+#[test]
+fn kml_has_a_linestring_per_segment() {
+    let kml = export_track("Truck", &drive(), TrackFormat::Kml, &SegmentConfig::default());
+    assert_eq!(kml.matches("<Placemark>").count(), 3);
+    assert_eq!(kml.matches("<LineString>").count(), 3);
+    // KML puts longitude first
+    assert!(kml.contains("<coordinates>-5.25,50,80.5 -5.25,50.5,80.5"));
+    assert!(kml.contains("<begin>2024-02-29T12:00:01.000Z</begin><end>2024-02-29T12:00:10.000Z</end>"));
+}
+
+#[test]
+fn geojson_is_a_feature_collection_of_linestrings() {
+    let json = export_track("Truck \"7\"", &drive(), TrackFormat::GeoJson, &SegmentConfig::default());
+    let json: Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(json["type"], "FeatureCollection");
+    let features = json["features"].as_array().unwrap();
+    assert_eq!(features.len(), 3);
+    assert_eq!(features[0]["properties"]["name"], "Truck \"7\"");
+    assert_eq!(features[2]["geometry"]["type"], "LineString");
+    let coordinates = features[2]["geometry"]["coordinates"].as_array().unwrap();
+    assert_eq!(coordinates.len(), 4);
+    assert_eq!(coordinates[0][1], 58.0);
+    assert_eq!(coordinates[0][0], -5.25);
+    assert_eq!(features[2]["properties"]["times"].as_array().unwrap().len(), 4);
+}
+
+#[test]
+fn a_time_range_comes_from_the_store() {
+    let store = ServerStore::open_in_memory().unwrap();
+    store.insert_gps(&drive()).unwrap();
+    // Only the first segment
+    let data = store.gps_range(UUID, t0()..t0() + 11_000).unwrap();
+    let gpx = export_track("Truck", &data, TrackFormat::Gpx, &SegmentConfig::default());
+    assert_eq!(gpx.matches("<trkpt ").count(), 10);
+
+    assert_eq!(iso_time(0), "1970-01-01T00:00:00.000Z");
+    assert_eq!(iso_time(t0() + DAY - 250), "2024-03-01T11:59:59.750Z");
+    assert_eq!("GeoJSON".parse(), Ok(TrackFormat::GeoJson));
+    assert!("shp".parse::<TrackFormat>().is_err());
+}
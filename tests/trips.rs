@@ -0,0 +1,273 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use grpc_tests::crash::Crash;
+use grpc_tests::data_conv::ImuShort;
+use grpc_tests::fake_gps::encode_fields;
+use grpc_tests::gps::{GpsData, GpsVec};
+use grpc_tests::imu::ImuVec;
+use grpc_tests::service::spawn_server;
+use grpc_tests::store::{GpsKey, ImuKey, MemoryStore, ServerStore, StoreError, TelemetryStore};
+use grpc_tests::trip::trip_server_client::TripServerClient;
+use grpc_tests::trip::{Trip, TripQuery};
+use grpc_tests::trips::{detect_trips, refresh_trips, TripConfig};
+use grpc_tests::upload::Uploader;
+
+const UUID: u64 = 0x1234567890AB;
+const T0: u64 = 1_700_000_000_000;
+/// m per degree of longitude at 50°N
+const M_PER_DEG: f32 = 71_700.0;
+
+/// What the truck is doing for a stretch of the drive
+#[derive(Clone, Copy)]
+enum Leg {
+    /// Driving east at this speed, m/s
+    Drive(f32),
+    /// Stopped with the engine running
+    Idle,
+    /// Stopped with the engine off
+    Parked,
+    /// The logger off
+    Off,
+}
+
+/// A fix a second and IMU lines at 10 Hz through `legs` of the given
+/// seconds, along the 50th parallel
+fn drive(legs: &[(Leg, u64)]) -> (Vec<GpsData>, Vec<ImuShort>) {
+    let (mut gps, mut imu) = (Vec::new(), Vec::new());
+    let (mut t, mut lon) = (T0, 0.0f32);
+    for &(leg, seconds) in legs {
+        for _ in 0..seconds {
+            let speed = match leg {
+                Leg::Drive(speed) => speed,
+                Leg::Off => {
+                    t += 1000;
+                    continue;
+                }
+                _ => 0.0,
+            };
+            gps.push(GpsData {
+                uuid: UUID,
+                pitime: t,
+                gps_time: t,
+                sequence: gps.len() as u32,
+                lat: 50.0,
+                lon,
+                speed,
+                status_nsats_vuc: encode_fields(3, 9, true, false, false),
+                ..Default::default()
+            });
+            for i in 0..10 {
+                let shake = if matches!(leg, Leg::Parked) { 0.0 } else if i % 2 == 0 { 0.05 } else { -0.05 };
+                imu.push(ImuShort {
+                    uuid: UUID,
+                    pitime: t + i * 100,
+                    sequence: imu.len() as u32,
+                    accel_z: 1.0 + shake,
+                    ..Default::default()
+                });
+            }
+            lon += speed / M_PER_DEG;
+            t += 1000;
+        }
+    }
+    (gps, imu)
+}
+
+#[test]
+fn a_stop_with_the_engine_off_ends_a_trip() {
+    let (gps, imu) = drive(&[
+        (Leg::Parked, 30),
+        (Leg::Drive(10.0), 60),
+        // Traffic lights
+        (Leg::Idle, 90),
+        (Leg::Drive(20.0), 60),
+        (Leg::Parked, 120),
+        (Leg::Drive(15.0), 40),
+        (Leg::Parked, 10),
+    ]);
+    let trips = detect_trips(&gps, &imu, &TripConfig::default());
+    assert_eq!(trips.len(), 2);
+
+    let first = trips[0];
+    assert_eq!((first.start_time, first.end_time), (T0 + 30_000, T0 + 239_000));
+    assert_eq!(first.duration, 209_000);
+    assert_eq!(first.max_speed, 20.0);
+    assert!((first.distance - 1780.0).abs() < 10.0, "{}", first.distance);
+    assert_eq!(first.start_lat, 50.0);
+    assert!(first.end_lon > first.start_lon);
+
+    assert_eq!(trips[1].start_time, T0 + 360_000);
+    assert!((trips[1].distance - 585.0).abs() < 10.0, "{}", trips[1].distance);
+    assert_eq!(trips[1].start_lon, first.end_lon + 20.0 / M_PER_DEG);
+}
+
+#[test]
+fn idling_or_no_imu_waits_longer_before_ending_a_trip() {
+    let legs = [(Leg::Drive(10.0), 60), (Leg::Idle, 200), (Leg::Drive(10.0), 60)];
+    let (gps, imu) = drive(&legs);
+    assert_eq!(detect_trips(&gps, &imu, &TripConfig::default()).len(), 1);
+
+    // Without the IMU a stop is only a stop after max_stop
+    let legs = [(Leg::Drive(10.0), 60), (Leg::Parked, 200), (Leg::Drive(10.0), 60)];
+    let (gps, imu) = drive(&legs);
+    assert_eq!(detect_trips(&gps, &imu, &TripConfig::default()).len(), 2);
+    assert_eq!(detect_trips(&gps, &[], &TripConfig::default()).len(), 1);
+}
+
+#[test]
+fn gaps_and_short_hops_are_not_trips() {
+    let (gps, imu) = drive(&[
+        (Leg::Drive(10.0), 60),
+        (Leg::Off, 180),
+        (Leg::Drive(10.0), 60),
+        (Leg::Parked, 100),
+        // Across the yard
+        (Leg::Drive(3.0), 30),
+    ]);
+    let trips = detect_trips(&gps, &imu, &TripConfig::default());
+    assert_eq!(trips.len(), 2);
+    assert_eq!(trips[0].end_time, T0 + 59_000);
+    assert_eq!(trips[1].start_time, T0 + 240_000);
+
+    // Lines without a fix are no sign of movement either way
+    let mut gps = gps;
+    for d in &mut gps[10..20] {
+        d.status_nsats_vuc = encode_fields(0, 0, false, false, false);
+    }
+    assert_eq!(detect_trips(&gps, &imu, &TripConfig::default()), trips);
+}
+
+#[test]
+fn trips_refreshed_batch_by_batch_match_the_whole_history() {
+    let (gps, imu) = drive(&[
+        (Leg::Drive(10.0), 100),
+        (Leg::Parked, 120),
+        (Leg::Drive(12.0), 100),
+        (Leg::Idle, 60),
+        (Leg::Drive(12.0), 100),
+        (Leg::Parked, 30),
+    ]);
+    let config = TripConfig::default();
+    let whole = detect_trips(&gps, &imu, &config);
+    assert_eq!(whole.len(), 2);
+
+    let store = ServerStore::open_in_memory().unwrap();
+    for (g, i) in gps.chunks(25).zip(imu.chunks(250)) {
+        store.append_imu(i).unwrap();
+        store.append_gps(g).unwrap();
+        refresh_trips(&store, UUID, g[0].gps_time..g[g.len() - 1].gps_time + 1, &config).unwrap();
+    }
+    assert_eq!(store.trips(UUID, 0..u64::MAX).unwrap(), whole);
+    assert_eq!(store.trips(UUID, T0 + 250_000..T0 + 260_000).unwrap(), whole[1..]);
+    assert!(store.trips(UUID + 1, 0..u64::MAX).unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn trips_from_an_upload_are_served() {
+    let store = Arc::new(MemoryStore::new());
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let mut uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+    let (gps, imu) = drive(&[(Leg::Drive(10.0), 60), (Leg::Parked, 90), (Leg::Drive(10.0), 60)]);
+
+    let lines = imu.iter().cloned().map(Into::into).collect();
+    uploader.send_imu(ImuVec { data: lines, uuid: UUID }).await.unwrap();
+    uploader.send_gps(GpsVec { data: gps.clone() }).await.unwrap();
+
+    let mut client = TripServerClient::connect(server.url()).await.unwrap();
+    let query = TripQuery { uuid: UUID, from: 0, to: 0 };
+    let trips = client.list_trips(query).await.unwrap().into_inner().trips;
+    assert_eq!(trips, detect_trips(&gps, &imu, &TripConfig::default()));
+    assert_eq!(trips.len(), 2);
+
+    let later = TripQuery { uuid: UUID, from: T0 + 100_000, to: 0 };
+    assert_eq!(client.list_trips(later).await.unwrap().into_inner().trips, trips[1..]);
+    assert_eq!(client.detect_trips(query).await.unwrap().into_inner().trips, trips);
+
+    let status = client.list_trips(TripQuery::default()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn trips_are_detected_by_the_time_the_server_stops() {
+    let store = Arc::new(MemoryStore::new());
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let mut uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+    let (gps, _) = drive(&[(Leg::Drive(10.0), 60), (Leg::Parked, 90)]);
+    uploader.send_gps(GpsVec { data: gps.clone() }).await.unwrap();
+    server.stop().await.unwrap();
+    let trips = store.trips(UUID, 0..u64::MAX).unwrap();
+    assert_eq!(trips.len(), 1);
+    assert_eq!(trips, detect_trips(&gps, &[], &TripConfig::default()));
+}
+
+/// A store that can't keep trips
+struct NoTrips(MemoryStore);
+
+impl TelemetryStore for NoTrips {
+    fn append_imu(&self, rows: &[ImuShort]) -> Result<usize, StoreError> {
+        self.0.append_imu(rows)
+    }
+
+    fn append_gps(&self, data: &[GpsData]) -> Result<usize, StoreError> {
+        self.0.append_gps(data)
+    }
+
+    fn imu_range(&self, uuid: u64, range: Range<u64>) -> Result<Vec<ImuShort>, StoreError> {
+        self.0.imu_range(uuid, range)
+    }
+
+    fn gps_range(&self, uuid: u64, range: Range<u64>) -> Result<Vec<GpsData>, StoreError> {
+        self.0.gps_range(uuid, range)
+    }
+
+    fn has_imu(&self, key: ImuKey) -> Result<bool, StoreError> {
+        self.0.has_imu(key)
+    }
+
+    fn has_gps(&self, key: GpsKey) -> Result<bool, StoreError> {
+        self.0.has_gps(key)
+    }
+
+    fn uncorrected_imu(&self, uuid: u64) -> Result<Vec<ImuShort>, StoreError> {
+        self.0.uncorrected_imu(uuid)
+    }
+
+    fn update_imu_times(&self, rows: &[ImuShort]) -> Result<usize, StoreError> {
+        self.0.update_imu_times(rows)
+    }
+
+    fn replace_trips(&self, _: u64, _: Range<u64>, _: &[Trip]) -> Result<(), StoreError> {
+        Err("no room for trips".into())
+    }
+
+    fn trips(&self, uuid: u64, range: Range<u64>) -> Result<Vec<Trip>, StoreError> {
+        self.0.trips(uuid, range)
+    }
+
+    fn append_crashes(&self, crashes: &[Crash]) -> Result<usize, StoreError> {
+        self.0.append_crashes(crashes)
+    }
+
+    fn crashes(&self, uuid: u64) -> Result<Vec<Crash>, StoreError> {
+        self.0.crashes(uuid)
+    }
+}
+
+#[tokio::test]
+async fn uploads_are_stored_when_trips_cant_be() {
+    let store = Arc::new(NoTrips(MemoryStore::new()));
+    let server = spawn_server(store.clone(), "127.0.0.1:0").await.unwrap();
+    let mut uploader = Uploader::connect(&server.url(), UUID).await.unwrap();
+    let (gps, _) = drive(&[(Leg::Drive(10.0), 60), (Leg::Parked, 90)]);
+    let reply = uploader.send_gps(GpsVec { data: gps.clone() }).await.unwrap();
+    assert_eq!(reply.stored as usize, gps.len());
+
+    let mut client = TripServerClient::connect(server.url()).await.unwrap();
+    let query = TripQuery { uuid: UUID, from: 0, to: 0 };
+    assert!(client.list_trips(query).await.unwrap().into_inner().trips.is_empty());
+    assert_eq!(client.detect_trips(query).await.unwrap_err().code(), tonic::Code::Internal);
+    server.stop().await.unwrap();
+    assert_eq!(store.gps_range(UUID, 0..u64::MAX).unwrap(), gps);
+}
@@ -0,0 +1,209 @@
+use std::io::Cursor;
+use std::path::Path;
+
+use grpc_tests::fake_gps::decode_fields;
+use grpc_tests::gps::GpsData;
+use grpc_tests::gps_source::{GpsSource, GpsType, UbxGps};
+use grpc_tests::timestamp::Timebase;
+use grpc_tests::ubx::{
+    checksum, decode, Decoder, Frame, Message, NavPvt, NavStatus, UbxError, CLASS_NAV, NAV_PVT, NAV_STATUS,
+    TIME_FULLY_RESOLVED, TIME_VALID_ALL,
+};
+
+const UUID: u64 = 0x1234567890AB;
+
+/// The first NAV-PVT in tests/data/camborne.ubx: a 3D fix at
+/// 2023-11-14 22:13:20 UTC
+const PVT: &str = "b56201075c0050b2110fe7070b0e160d1407190000000000000003010a0bbe3f05fd803be01d5053020030880100dc050000c40900003c0f000004100000ceffffff19160000706d4500c800000080380100a00000000000000000000000000000005c48";
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+}
+
+fn pvt() -> NavPvt {
+    let mut decoder = Decoder::new();
+    decoder.feed(&hex(PVT));
+    match decode(&decoder.next_frame().unwrap().unwrap()).unwrap() {
+        Message::NavPvt(pvt) => pvt,
+        other => panic!("{:?}", other),
+    }
+}
+
+fn captured() -> (Vec<GpsData>, UbxGps<std::fs::File>) {
+    let mut source = UbxGps::open(Path::new("tests/data/camborne.ubx"), UUID).unwrap();
+    let fixes = std::iter::from_fn(|| source.next_fix()).collect();
+    (fixes, source)
+}
+
+#[test]
+fn checksum_matches_the_receiver() {
+    // CFG-PRT poll, as in the u-blox protocol description
+    assert_eq!(checksum(&[0x06, 0x00, 0x00, 0x00]), [0x06, 0x18]);
+    assert_eq!(Frame::new(0x06, 0x00, vec![]).encode(), vec![0xB5, 0x62, 0x06, 0x00, 0x00, 0x00, 0x06, 0x18]);
+    assert_eq!(Frame::new(CLASS_NAV, NAV_PVT, hex(PVT)[6..98].to_vec()).encode(), hex(PVT));
+}
+
+#[test]
+fn frames_can_arrive_a_byte_at_a_time() {
+    let mut stream = b"$GPTXT,garbage\r\n".to_vec();
+    stream.extend(hex(PVT));
+    stream.extend(Frame::new(0x0A, 0x09, vec![1, 2, 3]).encode());
+
+    let mut decoder = Decoder::new();
+    let mut frames = Vec::new();
+    for byte in stream {
+        decoder.feed(&[byte]);
+        while let Some(frame) = decoder.next_frame() {
+            frames.push(frame.unwrap());
+        }
+    }
+    assert_eq!(frames.len(), 2);
+    assert_eq!((frames[0].class, frames[0].id, frames[0].payload.len()), (CLASS_NAV, NAV_PVT, 92));
+    assert_eq!(frames[1], Frame::new(0x0A, 0x09, vec![1, 2, 3]));
+    assert_eq!(decoder.skipped(), 16);
+}
+
+#[test]
+fn a_bad_checksum_loses_only_its_own_frame() {
+    let mut damaged = hex(PVT);
+    damaged[40] ^= 0x10;
+    let mut decoder = Decoder::new();
+    decoder.feed(&damaged);
+    decoder.feed(&hex(PVT));
+
+    assert_eq!(decoder.next_frame(), Some(Err(UbxError::BadChecksum { class: CLASS_NAV, id: NAV_PVT })));
+    assert!(decoder.next_frame().unwrap().is_ok());
+    assert_eq!(decoder.next_frame(), None);
+}
+
+#[test]
+fn wrong_lengths_and_unknown_messages_are_errors() {
+    assert_eq!(
+        decode(&Frame::new(CLASS_NAV, NAV_PVT, vec![0; 84])),
+        Err(UbxError::WrongLength { class: CLASS_NAV, id: NAV_PVT, len: 84 })
+    );
+    assert_eq!(decode(&Frame::new(0x0A, 0x09, vec![])), Err(UbxError::Unsupported { class: 0x0A, id: 0x09 }));
+
+    let mut decoder = Decoder::new();
+    decoder.feed(&[0xB5, 0x62, 0x01, 0x07, 0xFF, 0xFF]);
+    assert_eq!(decoder.next_frame(), Some(Err(UbxError::TooLong(0xFFFF))));
+}
+
+#[test]
+fn nav_pvt_decodes() {
+    let pvt = pvt();
+    assert_eq!((pvt.year, pvt.month, pvt.day, pvt.hour, pvt.min, pvt.sec), (2023, 11, 14, 22, 13, 20));
+    assert_eq!(pvt.itow, 252_818_000);
+    assert_eq!((pvt.fix_type, pvt.num_sv), (3, 11));
+    assert!(pvt.fix_ok());
+    assert_eq!((pvt.lat, pvt.lon), (501_234_560, -49_987_650));
+    assert_eq!((pvt.h_msl, pvt.h_acc, pvt.v_acc), (100_400, 1500, 2500));
+    assert_eq!((pvt.g_speed, pvt.head_mot, pvt.s_acc), (5657, 4_550_000, 200));
+    assert_eq!(pvt.utc_ms(), Some(1_700_000_000_000));
+}
+
+#[test]
+fn nav_pvt_becomes_gps_data() {
+    let d = pvt().to_gps_data(None, None, UUID, 5, 6);
+    assert_eq!((d.uuid, d.pitime, d.sequence, d.gps_time), (UUID, 5, 6, 1_700_000_000_000));
+    assert_eq!((d.lat, d.lon), (50.123456, -4.998765));
+    assert_eq!((d.alt, d.speed, d.track), (100.4, 5.657, 45.5));
+    assert_eq!((d.h_acc, d.v_acc, d.speed_acc), (1.5, 2.5, 0.2));
+    assert_eq!(d.time_valid, TIME_VALID_ALL);
+    assert_eq!(decode_fields(d.status_nsats_vuc), (3, 11, true, false, false));
+    // Without a NAV-DOP, HDOP falls back to PDOP
+    assert_eq!(d.hdop, 1.6);
+}
+
+#[test]
+fn utc_needs_a_valid_date_and_time() {
+    let pvt = pvt();
+    // The receiver rounds to the nearest second, so nano can be negative
+    let early = NavPvt { sec: 21, nano: -250_000_000, ..pvt };
+    assert_eq!(early.utc_ms(), Some(1_700_000_000_750));
+    assert_eq!(NavPvt { valid: 0x02, ..pvt }.utc_ms(), None);
+    assert_eq!(NavPvt { valid: 0x01, ..pvt }.utc_ms(), None);
+    assert_eq!(NavPvt { valid: 0x03, ..pvt }.to_gps_data(None, None, 0, 0, 0).time_valid, 0x03);
+}
+
+#[test]
+fn a_capture_becomes_fixes() {
+    let (fixes, source) = captured();
+    assert_eq!(source.gps_type(), GpsType::UbxType);
+    // Five epochs, one NAV-PVT damaged in transit
+    assert_eq!(fixes.len(), 4);
+    let stats = source.stats();
+    assert_eq!((stats.fixes, stats.errors, stats.unsupported), (4, 1, 1));
+    // The NMEA the receiver also sends is skipped over
+    assert!(stats.skipped > 0);
+    assert_eq!(source.status().unwrap().msss, 604_000);
+
+    let times: Vec<u64> = fixes.iter().map(|f| f.gps_time).collect();
+    let t0 = 1_700_000_000_000;
+    assert_eq!(times, vec![t0, t0 + 1000, t0 + 3000, t0 + 4000]);
+    for (i, fix) in fixes.iter().enumerate() {
+        assert_eq!((fix.uuid, fix.sequence, fix.pitime), (UUID, i as u32, fix.gps_time));
+    }
+    // HDOP comes from the epoch's NAV-DOP
+    assert_eq!(fixes[0].hdop, 0.95);
+}
+
+#[test]
+fn fix_loss_is_flagged() {
+    let (fixes, _) = captured();
+    let lost = fixes[2];
+    assert_eq!(decode_fields(lost.status_nsats_vuc), (0, 0, false, false, false));
+    assert_eq!(lost.hdop, 99.99);
+    // The receiver still knows the time
+    assert_eq!(lost.time_valid, TIME_VALID_ALL);
+    assert!(decode_fields(fixes[3].status_nsats_vuc).2);
+}
+
+#[test]
+fn ubx_can_be_read_from_any_reader() {
+    let mut source = UbxGps::new(Cursor::new(hex(PVT)), UUID);
+    let fix = source.next_fix().unwrap();
+    // Live sources are stamped with the Pi clock
+    assert!(fix.pitime > fix.gps_time);
+    assert_eq!(source.next_fix(), None);
+}
+
+#[test]
+fn gps_time_keeps_the_nanoseconds() {
+    let pvt = NavPvt { nano: 123_456_789, ..pvt() };
+    let stamp = pvt.to_gps_data(None, None, UUID, 0, 0).gps_stamp.unwrap();
+    assert_eq!((stamp.seconds, stamp.nanos), (1_700_000_000, 123_456_789));
+    assert_eq!(stamp.timebase(), Timebase::Gps);
+    assert_eq!(pvt.utc_ms(), Some(1_700_000_000_123));
+    assert_eq!(NavPvt { valid: 0x02, ..pvt }.to_gps_data(None, None, UUID, 0, 0).gps_stamp, None);
+}
+
+#[test]
+fn nav_status_has_the_last_word_on_the_fix() {
+    let pvt = pvt();
+    let status = NavStatus { itow: pvt.itow, gps_fix: 2, flags: 0x0D, ttff: 30_000, msss: 60_000 };
+    let d = pvt.to_gps_data(None, Some(&status), UUID, 0, 0);
+    assert_eq!(decode_fields(d.status_nsats_vuc), (2, 11, true, false, false));
+    assert_eq!(d.time_valid, TIME_VALID_ALL);
+
+    // No gpsFixOk, and no week number so the time is ambiguous
+    let lost = NavStatus { flags: 0x08, ..status };
+    let d = pvt.to_gps_data(None, Some(&lost), UUID, 0, 0);
+    assert_eq!(decode_fields(d.status_nsats_vuc), (0, 11, false, false, false));
+    assert_eq!((d.hdop, d.time_valid), (99.99, TIME_VALID_ALL & !TIME_FULLY_RESOLVED));
+
+    // A NAV-STATUS from another epoch says nothing about this one
+    let stale = NavStatus { itow: pvt.itow - 1000, ..lost };
+    assert_eq!(pvt.to_gps_data(None, Some(&stale), UUID, 0, 0), pvt.to_gps_data(None, None, UUID, 0, 0));
+
+    // The reader applies it to the NAV-PVT that follows
+    let mut payload = pvt.itow.to_le_bytes().to_vec();
+    payload.extend([0, 0x08, 0, 0]);
+    payload.extend(30_000u32.to_le_bytes());
+    payload.extend(60_000u32.to_le_bytes());
+    let mut bytes = Frame::new(CLASS_NAV, NAV_STATUS, payload).encode();
+    bytes.extend(hex(PVT));
+    let fix = UbxGps::new(Cursor::new(bytes), UUID).next_fix().unwrap();
+    assert!(!decode_fields(fix.status_nsats_vuc).2);
+    assert_eq!(fix.time_valid & TIME_FULLY_RESOLVED, 0);
+}
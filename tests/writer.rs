@@ -0,0 +1,314 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rusqlite::Connection;
+
+use grpc_tests::crash::{read_crashes, Crash};
+use grpc_tests::data_conv::ImuShort;
+use grpc_tests::device_db::{open_device_db, DeviceDbConfig};
+use grpc_tests::gps_source::{log_gps, NmeaGps};
+use grpc_tests::imu_source::{run_sampler, FakeImu, SamplerConfig};
+use grpc_tests::writer::{FullPolicy, LogWriter, Record, WriterConfig, WriterStats};
+
+const UUID: u64 = 0x1234567890AB;
+
+fn row(i: u32) -> ImuShort {
+    ImuShort { uuid: UUID, pitime: 1_700_000_000_000 + i as u64, sequence: i, ..Default::default() }
+}
+
+fn device_db(dir: &Path) -> (PathBuf, Connection) {
+    let path = dir.join("imu.db3");
+    let (conn, _) = open_device_db(&path, &DeviceDbConfig::default()).unwrap();
+    (path, conn)
+}
+
+fn sequences(path: &Path) -> Vec<u32> {
+    let conn = Connection::open(path).unwrap();
+    let mut stmt = conn.prepare("SELECT sequence FROM imu ORDER BY sequence").unwrap();
+    let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+    rows.map(|r| r.unwrap()).collect()
+}
+
+/// Holds the database's write lock, as a slow card or the uploader would
+fn stall(path: &Path) -> Connection {
+    let conn = Connection::open(path).unwrap();
+    conn.execute_batch("BEGIN IMMEDIATE").unwrap();
+    conn
+}
+
+/// Wait for the writer to take what's queued, and block on the stall
+fn wait_for(writer: &LogWriter, until: impl Fn(&WriterStats) -> bool) {
+    let start = Instant::now();
+    while !until(&writer.stats()) {
+        assert!(start.elapsed() < Duration::from_secs(5), "{:?}", writer.stats());
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn config(policy: FullPolicy) -> WriterConfig {
+    WriterConfig { capacity: 10, batch: 4, policy }
+}
+
+#[test]
+fn records_are_written_in_batches() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, conn) = device_db(dir.path());
+    let writer = LogWriter::spawn(conn, WriterConfig { capacity: 1000, batch: 50, policy: FullPolicy::Block });
+    for i in 0..500 {
+        assert!(writer.push(row(i)));
+    }
+    let stats = writer.close();
+    assert_eq!((stats.pushed, stats.written, stats.dropped, stats.depth), (500, 500, 0, 0));
+    assert!(stats.batches >= 10 && stats.batches < 500, "{:?}", stats);
+    assert!(stats.max_depth > 0);
+    assert_eq!(sequences(&path), (0..500).collect::<Vec<_>>());
+}
+
+#[test]
+fn drop_oldest_keeps_the_newest() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, conn) = device_db(dir.path());
+    let lock = stall(&path);
+    let writer = LogWriter::spawn(conn, config(FullPolicy::DropOldest));
+    writer.push(row(0));
+    wait_for(&writer, |s| s.depth == 0);
+
+    for i in 1..=15 {
+        assert!(writer.push(row(i)));
+    }
+    let stats = writer.stats();
+    assert_eq!((stats.depth, stats.max_depth, stats.dropped), (10, 10, 5));
+
+    lock.execute_batch("ROLLBACK").unwrap();
+    let stats = writer.close();
+    assert_eq!(stats.written, 11);
+    let mut expected = vec![0];
+    expected.extend(6..=15);
+    assert_eq!(sequences(&path), expected);
+}
+
+#[test]
+fn block_waits_for_room() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, conn) = device_db(dir.path());
+    let lock = stall(&path);
+    let writer = LogWriter::spawn(conn, config(FullPolicy::Block));
+    writer.push(row(0));
+    wait_for(&writer, |s| s.depth == 0);
+
+    thread::scope(|s| {
+        let pusher = s.spawn(|| (1..=15).all(|i| writer.push(row(i))));
+        wait_for(&writer, |s| s.blocked == 1);
+        assert_eq!(writer.stats().depth, 10);
+        lock.execute_batch("ROLLBACK").unwrap();
+        assert!(pusher.join().unwrap());
+    });
+
+    let stats = writer.close();
+    assert_eq!((stats.written, stats.dropped), (16, 0));
+    assert_eq!(sequences(&path), (0..=15).collect::<Vec<_>>());
+}
+
+#[test]
+fn spilled_records_are_stored_once_the_writer_catches_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let spill = dir.path().join("spill");
+    let (path, conn) = device_db(dir.path());
+    let lock = stall(&path);
+    let writer = LogWriter::spawn(conn, config(FullPolicy::Spill(spill.clone())));
+    writer.push(row(0));
+    wait_for(&writer, |s| s.depth == 0);
+
+    for i in 1..=15 {
+        assert!(writer.push(row(i)));
+    }
+    let fix = grpc_tests::gps::GpsData { uuid: UUID, pitime: 5, gps_time: 4, sequence: 1, ..Default::default() };
+    assert!(writer.push(fix));
+    assert_eq!(writer.stats().spilled, 6);
+    assert!(spill.join("imu.pb").exists());
+
+    lock.execute_batch("ROLLBACK").unwrap();
+    let stats = writer.close();
+    assert_eq!((stats.written, stats.unspilled, stats.dropped), (17, 6, 0));
+    assert_eq!(sequences(&path), (0..=15).collect::<Vec<_>>());
+    assert!(!spill.join("imu.pb").exists() && !spill.join("gps.pb").exists());
+
+    let conn = Connection::open(&path).unwrap();
+    let gps: (i64, i64) = conn.query_row("SELECT uuid, pitime FROM gps", [], |r| Ok((r.get(0)?, r.get(1)?))).unwrap();
+    assert_eq!(gps, (UUID as i64, 5));
+}
+
+#[test]
+fn a_spill_left_by_a_crash_is_picked_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let spill = dir.path().join("spill");
+    let (path, conn) = device_db(dir.path());
+    let lock = stall(&path);
+    let writer = LogWriter::spawn(conn, config(FullPolicy::Spill(spill.clone())));
+    writer.push(row(0));
+    wait_for(&writer, |s| s.depth == 0);
+    for i in 1..=12 {
+        writer.push(row(i));
+    }
+    // What the card holds if the power goes now
+    let left = tempfile::tempdir().unwrap();
+    let left_spill = left.path().join("spill");
+    std::fs::create_dir(&left_spill).unwrap();
+    std::fs::copy(spill.join("imu.pb"), left_spill.join("imu.pb")).unwrap();
+    drop(lock);
+    writer.close();
+
+    let (path, conn) = device_db(left.path());
+    let stats = LogWriter::spawn(conn, config(FullPolicy::Spill(left_spill))).close();
+    assert_eq!(stats.unspilled, 2);
+    assert_eq!(sequences(&path), vec![11, 12]);
+}
+
+/// A connection to the device database that fails at once when it's busy
+fn impatient(path: &Path) -> Connection {
+    let conn = Connection::open(path).unwrap();
+    conn.busy_timeout(Duration::ZERO).unwrap();
+    conn
+}
+
+#[test]
+fn a_busy_database_is_tried_again() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = device_db(dir.path());
+    let lock = stall(&path);
+    let writer = LogWriter::spawn(impatient(&path), config(FullPolicy::Block));
+    for i in 0..4 {
+        writer.push(row(i));
+    }
+    wait_for(&writer, |s| s.retried >= 1);
+    assert_eq!(writer.stats().depth, 4);
+
+    lock.execute_batch("ROLLBACK").unwrap();
+    let stats = writer.close();
+    assert_eq!((stats.written, stats.failed, stats.dropped), (4, 0, 0));
+    assert_eq!(sequences(&path), (0..4).collect::<Vec<_>>());
+}
+
+#[test]
+fn spills_stay_on_the_card_until_they_are_stored() {
+    let dir = tempfile::tempdir().unwrap();
+    let spill = dir.path().join("spill");
+    let (path, _) = device_db(dir.path());
+    let lock = stall(&path);
+    let writer = LogWriter::spawn(impatient(&path), config(FullPolicy::Spill(spill.clone())));
+    for i in 0..4 {
+        writer.push(row(i));
+    }
+    // The batch is spilled once it's been tried often enough, and the
+    // spill can't be stored either
+    let stats = writer.close();
+    assert_eq!((stats.written, stats.spilled, stats.unspilled, stats.failed), (0, 4, 0, 0));
+    assert!(spill_has_records(&spill));
+
+    lock.execute_batch("ROLLBACK").unwrap();
+    let stats = LogWriter::spawn(impatient(&path), config(FullPolicy::Spill(spill.clone()))).close();
+    assert_eq!((stats.written, stats.unspilled), (4, 4));
+    assert!(!spill_has_records(&spill));
+    assert_eq!(sequences(&path), (0..4).collect::<Vec<_>>());
+}
+
+fn crash() -> Crash {
+    Crash { uuid: UUID, pitime: row(1).pitime, peak_g: 3.5, window_start: row(0).pitime, window_end: row(2).pitime }
+}
+
+/// An impact and three lines captured around it
+fn capture() -> Vec<Record> {
+    let mut records = vec![Record::Crash(crash())];
+    records.extend((0..3).map(|i| Record::CrashImu(row(i))));
+    records
+}
+
+fn stored_capture(path: &Path) -> (Vec<Crash>, u64) {
+    let conn = Connection::open(path).unwrap();
+    let lines = conn.query_row("SELECT COUNT(*) FROM crash_imu", [], |r| r.get(0)).unwrap();
+    (read_crashes(&conn).unwrap(), lines)
+}
+
+#[test]
+fn crash_captures_are_kept_until_the_database_takes_them() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = device_db(dir.path());
+    let lock = stall(&path);
+    let writer = LogWriter::spawn(impatient(&path), config(FullPolicy::Block));
+    for record in capture() {
+        writer.push(record);
+    }
+    // Past the point an ordinary batch would be given up on
+    wait_for(&writer, |s| s.retried >= 5);
+    let stats = writer.stats();
+    assert_eq!((stats.depth, stats.failed), (4, 0));
+
+    lock.execute_batch("ROLLBACK").unwrap();
+    let stats = writer.close();
+    assert_eq!((stats.written, stats.failed, stats.dropped), (4, 0, 0));
+    assert_eq!(stored_capture(&path), (vec![crash()], 3));
+}
+
+#[test]
+fn crash_captures_the_database_keeps_failing_are_spilled() {
+    let dir = tempfile::tempdir().unwrap();
+    let spill = dir.path().join("spill");
+    let (path, _) = device_db(dir.path());
+    let lock = stall(&path);
+    let writer = LogWriter::spawn(impatient(&path), config(FullPolicy::Spill(spill.clone())));
+    for record in capture() {
+        writer.push(record);
+    }
+    let stats = writer.close();
+    assert_eq!((stats.written, stats.spilled, stats.failed), (0, 4, 0));
+    assert!(spill_has_records(&spill));
+
+    lock.execute_batch("ROLLBACK").unwrap();
+    let stats = LogWriter::spawn(impatient(&path), config(FullPolicy::Spill(spill.clone()))).close();
+    assert_eq!((stats.written, stats.unspilled), (4, 4));
+    assert!(!spill_has_records(&spill));
+    assert_eq!(stored_capture(&path), (vec![crash()], 3));
+    // The capture lines went back to crash_imu, not imu
+    assert!(sequences(&path).is_empty());
+}
+
+fn spill_has_records(dir: &Path) -> bool {
+    std::fs::read_dir(dir).unwrap().any(|entry| entry.unwrap().metadata().unwrap().len() > 0)
+}
+
+#[test]
+fn sensor_loops_share_a_writer() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, conn) = device_db(dir.path());
+    let writer = LogWriter::spawn(conn, WriterConfig::default());
+    let stop = AtomicBool::new(false);
+    let config = SamplerConfig { uuid: UUID, rate_hz: 1000.0, max_samples: Some(200), ..Default::default() };
+
+    let mut gps = NmeaGps::open(Path::new("tests/data/camborne.nmea"), UUID).unwrap();
+    let (imu, fixes) = thread::scope(|s| {
+        let fixes = s.spawn(|| log_gps(&mut gps, &writer, &stop).unwrap());
+        let imu = run_sampler(&mut FakeImu::default(), &writer, &config, &stop).unwrap();
+        (imu, fixes.join().unwrap())
+    });
+    assert_eq!(imu.stored, 200);
+    assert_eq!(fixes, 10);
+
+    let stats = writer.close();
+    assert_eq!(stats.written, 210);
+    let conn = Connection::open(&path).unwrap();
+    let count = |table: &str| -> i64 {
+        conn.query_row(&format!("SELECT count(*) FROM {}", table), [], |r| r.get(0)).unwrap()
+    };
+    assert_eq!((count("imu"), count("gps")), (200, 10));
+}
+
+#[test]
+fn policies_parse() {
+    assert_eq!("block".parse(), Ok(FullPolicy::Block));
+    assert_eq!("drop-oldest".parse(), Ok(FullPolicy::DropOldest));
+    assert_eq!("spill:/tmp/x".parse(), Ok(FullPolicy::Spill("/tmp/x".into())));
+    assert!("spill:".parse::<FullPolicy>().is_err());
+    assert_eq!(Record::from(row(1)), Record::Imu(row(1)));
+}